@@ -0,0 +1,311 @@
+//! Validation of match values in QIDO-RS and MWL-RS query parameters.
+//!
+//! Values are checked before they are forwarded to the origin server, so that a malformed value
+//! is rejected with a precise error instead of a failing (or silently empty) query.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/chtml/part04/sect_C.2.2.2.html>
+
+use dicom::core::chrono::NaiveDate;
+use dicom::core::VR;
+
+/// The maximum length of a UID.
+const MAX_UID_LENGTH: usize = 64;
+
+/// Checks that the raw query parameter value is a valid match value for the VR.
+/// Returns a description of the problem otherwise.
+pub fn validate(vr: VR, raw_value: &str) -> Result<(), String> {
+	match vr {
+		VR::DA | VR::TM | VR::DT => {
+			reject_wildcards(vr, raw_value)?;
+			validate_range(raw_value, |value| match vr {
+				VR::DA => validate_date(value),
+				VR::TM => validate_time(value),
+				_ => validate_date_time(value),
+			})
+		}
+		VR::UI => {
+			reject_wildcards(vr, raw_value)?;
+			raw_value
+				.split(',')
+				.try_for_each(|uid| validate_uid(uid.trim()))
+		}
+		VR::SS | VR::US | VR::SL | VR::UL | VR::SV | VR::UV | VR::FL | VR::FD | VR::DS | VR::IS => {
+			reject_wildcards(vr, raw_value)?;
+			validate_number(vr, raw_value)
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Wildcard matching is not supported for dates, times, UIDs and numbers.
+fn reject_wildcards(vr: VR, raw_value: &str) -> Result<(), String> {
+	if raw_value.contains(['*', '?']) {
+		Err(format!(
+			"wildcards are not supported for VR {}",
+			vr.to_string()
+		))
+	} else {
+		Ok(())
+	}
+}
+
+/// Validates a single value or a range (`<start>-<end>`, `<start>-` or `-<end>`).
+fn validate_range(
+	raw_value: &str,
+	validate_value: impl Fn(&str) -> Result<(), String>,
+) -> Result<(), String> {
+	match raw_value.split_once('-') {
+		None => validate_value(raw_value),
+		Some((start, end)) => {
+			if start.is_empty() && end.is_empty() {
+				return Err(String::from("a range requires a start or an end value"));
+			}
+			if !start.is_empty() {
+				validate_value(start)?;
+			}
+			if !end.is_empty() {
+				validate_value(end)?;
+			}
+			// The formats are fixed-width and most significant first,
+			// so comparing values of the same length lexicographically is sufficient.
+			if !start.is_empty() && start.len() == end.len() && start > end {
+				return Err(format!(
+					"the start of the range {raw_value} is after its end"
+				));
+			}
+			Ok(())
+		}
+	}
+}
+
+/// `YYYYMMDD`
+fn validate_date(value: &str) -> Result<(), String> {
+	if value.len() != 8 || !is_digits(value) {
+		return Err(format!("{value} is not a date in the format YYYYMMDD"));
+	}
+	NaiveDate::parse_from_str(value, "%Y%m%d")
+		.map(|_| ())
+		.map_err(|_| format!("{value} is not a valid calendar date"))
+}
+
+/// `HH[MM[SS[.F{1,6}]]]`
+fn validate_time(value: &str) -> Result<(), String> {
+	let invalid = || format!("{value} is not a time in the format HH[MM[SS[.FFFFFF]]]");
+
+	let (time, fraction) = match value.split_once('.') {
+		Some((time, fraction)) => (time, Some(fraction)),
+		None => (value, None),
+	};
+	if !is_digits(time) || !matches!(time.len(), 2 | 4 | 6) {
+		return Err(invalid());
+	}
+	if let Some(fraction) = fraction {
+		if time.len() != 6 || fraction.is_empty() || fraction.len() > 6 || !is_digits(fraction) {
+			return Err(invalid());
+		}
+	}
+
+	let limits = [24, 60, 60];
+	for (index, limit) in limits.iter().enumerate().take(time.len() / 2) {
+		let component: u32 = time[index * 2..index * 2 + 2]
+			.parse()
+			.map_err(|_| invalid())?;
+		if component >= *limit {
+			return Err(format!("{value} is not a valid time"));
+		}
+	}
+	Ok(())
+}
+
+/// `YYYY[MM[DD[HH[MM[SS[.F{1,6}]]]]]][&ZZXX]`
+fn validate_date_time(value: &str) -> Result<(), String> {
+	let invalid = || {
+		format!("{value} is not a date time in the format YYYY[MM[DD[HH[MM[SS[.FFFFFF]]]]]][&ZZXX]")
+	};
+
+	// The offset from UTC is introduced by "+" or "-".
+	// A "-" would already have been interpreted as a range separator,
+	// so negative offsets cannot be used in this position.
+	let (date_time, offset) = match value.split_once('+') {
+		Some((date_time, offset)) => (date_time, Some(offset)),
+		None => (value, None),
+	};
+	if let Some(offset) = offset {
+		if offset.len() != 4 || !is_digits(offset) {
+			return Err(invalid());
+		}
+	}
+
+	let (date_time, fraction) = match date_time.split_once('.') {
+		Some((date_time, fraction)) => (date_time, Some(fraction)),
+		None => (date_time, None),
+	};
+	if !is_digits(date_time) || !matches!(date_time.len(), 4 | 6 | 8 | 10 | 12 | 14) {
+		return Err(invalid());
+	}
+	if fraction.is_some() && date_time.len() != 14 {
+		return Err(invalid());
+	}
+
+	// Complete partial dates with the first month or day to check the calendar date.
+	let date = match date_time.len() {
+		4 => format!("{date_time}0101"),
+		6 => format!("{date_time}01"),
+		_ => date_time[..8].to_owned(),
+	};
+	validate_date(&date).map_err(|_| format!("{value} is not a valid date time"))?;
+
+	if date_time.len() > 8 {
+		let time = fraction.map_or_else(
+			|| date_time[8..].to_owned(),
+			|fraction| format!("{}.{fraction}", &date_time[8..]),
+		);
+		validate_time(&time).map_err(|_| format!("{value} is not a valid date time"))?;
+	}
+	Ok(())
+}
+
+/// Numbers only support single value matching, as range matching is limited to dates and times.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part05/sect_6.2.html>
+fn validate_number(vr: VR, value: &str) -> Result<(), String> {
+	let valid = match vr {
+		VR::SS => value.parse::<i16>().is_ok(),
+		VR::US => value.parse::<u16>().is_ok(),
+		VR::SL => value.parse::<i32>().is_ok(),
+		VR::UL => value.parse::<u32>().is_ok(),
+		VR::SV => value.parse::<i64>().is_ok(),
+		VR::UV => value.parse::<u64>().is_ok(),
+		VR::FL => is_decimal(value) && value.parse::<f32>().is_ok_and(f32::is_finite),
+		VR::FD => is_decimal(value) && value.parse::<f64>().is_ok_and(f64::is_finite),
+		// Leading and trailing spaces of integer and decimal strings are insignificant.
+		// An integer string is limited to the range of a signed 32-bit integer.
+		VR::IS => value.trim().parse::<i32>().is_ok(),
+		VR::DS => is_decimal(value.trim()) && value.trim().parse::<f64>().is_ok_and(f64::is_finite),
+		_ => true,
+	};
+	if valid {
+		Ok(())
+	} else {
+		Err(format!(
+			"{value} is not a valid number for VR {}",
+			vr.to_string()
+		))
+	}
+}
+
+/// A UID consists of numeric components separated by dots.
+/// Components must not have leading zeros, unless the component is a single zero.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part05/chapter_9.html>
+pub fn validate_uid(uid: &str) -> Result<(), String> {
+	if uid.is_empty() {
+		return Err(String::from("empty UID in UID list"));
+	}
+	if uid.len() > MAX_UID_LENGTH {
+		return Err(format!(
+			"{uid} exceeds the maximum UID length of {MAX_UID_LENGTH} characters"
+		));
+	}
+	let valid = uid.split('.').all(|component| {
+		!component.is_empty()
+			&& is_digits(component)
+			&& (component == "0" || !component.starts_with('0'))
+	});
+	if valid {
+		Ok(())
+	} else {
+		Err(format!("{uid} is not a valid UID"))
+	}
+}
+
+/// A fixed point or floating point representation, which excludes "inf" and "NaN".
+fn is_decimal(value: &str) -> bool {
+	value
+		.bytes()
+		.all(|byte| byte.is_ascii_digit() || b"+-.eE".contains(&byte))
+}
+
+fn is_digits(value: &str) -> bool {
+	value.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dates_and_date_ranges() {
+		assert!(validate(VR::DA, "20240131").is_ok());
+		assert!(validate(VR::DA, "20240101-20240131").is_ok());
+		assert!(validate(VR::DA, "20240101-").is_ok());
+		assert!(validate(VR::DA, "-20240131").is_ok());
+
+		assert!(validate(VR::DA, "2024-01-31").is_err());
+		assert!(validate(VR::DA, "20240230").is_err());
+		assert!(validate(VR::DA, "20240131-20240101").is_err());
+		assert!(validate(VR::DA, "-").is_err());
+		assert!(validate(VR::DA, "2024*").is_err());
+	}
+
+	#[test]
+	fn times_and_time_ranges() {
+		assert!(validate(VR::TM, "07").is_ok());
+		assert!(validate(VR::TM, "0730").is_ok());
+		assert!(validate(VR::TM, "073000.123").is_ok());
+		assert!(validate(VR::TM, "0700-1900").is_ok());
+
+		assert!(validate(VR::TM, "2400").is_err());
+		assert!(validate(VR::TM, "0760").is_err());
+		assert!(validate(VR::TM, "073").is_err());
+		assert!(validate(VR::TM, "0730.5").is_err());
+		assert!(validate(VR::TM, "07:30").is_err());
+	}
+
+	#[test]
+	fn date_times() {
+		assert!(validate(VR::DT, "2024").is_ok());
+		assert!(validate(VR::DT, "20240131073000.5+0100").is_ok());
+		assert!(validate(VR::DT, "202401-202402").is_ok());
+
+		assert!(validate(VR::DT, "202413").is_err());
+		assert!(validate(VR::DT, "2024013125").is_err());
+		assert!(validate(VR::DT, "20240131+1").is_err());
+	}
+
+	#[test]
+	fn uid_lists() {
+		assert!(validate(VR::UI, "1.2.840.10008.1.2").is_ok());
+		assert!(validate(VR::UI, "1.2.3, 1.2.0.4").is_ok());
+
+		assert!(validate(VR::UI, "1.2.03").is_err());
+		assert!(validate(VR::UI, "1.2.").is_err());
+		assert!(validate(VR::UI, "1.2.3,,1.2.4").is_err());
+		assert!(validate(VR::UI, "1.2.*").is_err());
+		assert!(validate(VR::UI, &format!("1.{}", "2".repeat(64))).is_err());
+	}
+
+	#[test]
+	fn numbers() {
+		assert!(validate(VR::IS, "-42").is_ok());
+		assert!(validate(VR::DS, "1.5e3").is_ok());
+		assert!(validate(VR::US, "65535").is_ok());
+		assert!(validate(VR::FD, "0.25").is_ok());
+
+		assert!(validate(VR::IS, "1.5").is_err());
+		assert!(validate(VR::IS, "abc").is_err());
+		assert!(validate(VR::IS, "1-10").is_err());
+		assert!(validate(VR::IS, "2147483648").is_err());
+		assert!(validate(VR::DS, "NaN").is_err());
+		assert!(validate(VR::DS, "inf").is_err());
+		assert!(validate(VR::US, "-1").is_err());
+		assert!(validate(VR::US, "65536").is_err());
+		assert!(validate(VR::FL, "1e39").is_err());
+	}
+
+	#[test]
+	fn wildcards_for_strings() {
+		assert!(validate(VR::PN, "Doe^J*").is_ok());
+		assert!(validate(VR::IS, "1*").is_err());
+	}
+}
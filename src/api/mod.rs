@@ -12,8 +12,10 @@ use serde::{Deserialize, Deserializer};
 
 mod aets;
 mod home;
+pub mod matching;
 pub mod mwl;
 pub mod qido;
+pub mod search;
 pub mod stow;
 pub mod wado;
 
@@ -38,7 +40,7 @@ pub fn routes(base_path: &str) -> Router<AppState> {
 }
 
 /// Match Query Parameters for QIDO and MWL requests.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 #[serde(try_from = "HashMap<String, String>")]
 pub struct MatchCriteria(Vec<(AttributeSelector, PrimitiveValue)>);
 
@@ -46,6 +48,35 @@ impl MatchCriteria {
 	pub fn into_inner(self) -> Vec<(AttributeSelector, PrimitiveValue)> {
 		self.0
 	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &(AttributeSelector, PrimitiveValue)> {
+		self.0.iter()
+	}
+
+	/// Returns the match value for a top-level attribute.
+	pub fn get(&self, tag: Tag) -> Option<&PrimitiveValue> {
+		let selector = AttributeSelector::from(tag);
+		self.0
+			.iter()
+			.find(|(candidate, _)| *candidate == selector)
+			.map(|(_, value)| value)
+	}
+
+	/// Removes the match value for a top-level attribute.
+	pub fn remove(&mut self, tag: Tag) -> Option<PrimitiveValue> {
+		let selector = AttributeSelector::from(tag);
+		let index = self
+			.0
+			.iter()
+			.position(|(candidate, _)| *candidate == selector)?;
+		Some(self.0.remove(index).1)
+	}
+
+	/// Sets the match value for a top-level attribute, replacing any existing value.
+	pub fn set(&mut self, tag: Tag, value: PrimitiveValue) {
+		self.remove(tag);
+		self.0.push((AttributeSelector::from(tag), value));
+	}
 }
 
 impl TryFrom<HashMap<String, String>> for MatchCriteria {
@@ -61,6 +92,7 @@ impl TryFrom<HashMap<String, String>> for MatchCriteria {
 					.and_then(|selector| {
 						to_primitive_value(selector.last_tag(), &value)
 							.map(|primitive| (selector, primitive))
+							.map_err(|err| format!("invalid value for {key}: {err}"))
 					})
 			})
 			.collect::<Result<_, Self::Error>>()?;
@@ -77,6 +109,7 @@ fn to_primitive_value(tag: Tag, raw_value: &str) -> Result<PrimitiveValue, Strin
 		.by_tag(tag)
 		.ok_or_else(|| format!("unknown tag {tag}"))?
 		.vr();
+	matching::validate(vr.relaxed(), raw_value)?;
 	match vr.relaxed() {
 		// String-like VRs, no parsing required
 		VR::AE
@@ -140,7 +173,7 @@ fn to_primitive_value(tag: Tag, raw_value: &str) -> Result<PrimitiveValue, Strin
 	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum IncludeField {
 	All,
 	List(Vec<Tag>),
@@ -211,3 +244,15 @@ where
 {
 	deserializer.deserialize_any(IncludeFieldVisitor)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn invalid_match_value_references_parameter() {
+		let parameters = HashMap::from([(String::from("StudyDate"), String::from("2024-01-31"))]);
+		let err = MatchCriteria::try_from(parameters).unwrap_err();
+		assert!(err.starts_with("invalid value for StudyDate:"), "{err}");
+	}
+}
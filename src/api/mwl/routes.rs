@@ -1,14 +1,14 @@
+use crate::api::search::{aet_url, SearchMediaType};
 use crate::backend::ServiceProvider;
+use crate::types::AE;
 use crate::AppState;
-use axum::http::header;
+use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::Router;
 use axum_extra::extract::Query;
-use axum_streams::StreamBodyAs;
 use dicom::object::InMemDicomObject;
-use dicom_json::DicomJson;
 use futures::TryStreamExt;
 use tracing::instrument;
 
@@ -24,24 +24,19 @@ pub fn routes() -> Router<AppState> {
 }
 
 // MWL-RS implementation
-async fn mwl_handler(provider: ServiceProvider, request: MwlSearchRequest) -> impl IntoResponse {
+async fn mwl_handler(
+	provider: ServiceProvider,
+	aet_url: Option<&str>,
+	media_type: SearchMediaType,
+	request: MwlSearchRequest,
+) -> impl IntoResponse {
 	if let Some(mwl) = provider.mwl {
 		let response = mwl.search(request).await;
 		let matches: Result<Vec<InMemDicomObject>, MwlSearchError> =
 			response.stream.try_collect().await;
 
 		match matches {
-			Ok(matches) => {
-				let json: Vec<DicomJson<InMemDicomObject>> =
-					matches.into_iter().map(DicomJson::from).collect();
-
-				axum::response::Response::builder()
-					.status(StatusCode::OK)
-					.header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
-					.body(StreamBodyAs::json_array(futures::stream::iter(json)))
-					.unwrap()
-					.into_response()
-			}
+			Ok(matches) => media_type.into_response(matches, aet_url),
 			Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
 		}
 	} else {
@@ -56,8 +51,17 @@ async fn mwl_handler(provider: ServiceProvider, request: MwlSearchRequest) -> im
 #[instrument(skip_all)]
 async fn all_workitems(
 	provider: ServiceProvider,
+	State(state): State<AppState>,
+	Path(aet): Path<AE>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<MwlQueryParameters>,
 ) -> impl IntoResponse {
 	let request = MwlSearchRequest { parameters };
-	mwl_handler(provider, request).await
+	mwl_handler(
+		provider,
+		aet_url(&state.config, &aet).as_deref(),
+		media_type,
+		request,
+	)
+	.await
 }
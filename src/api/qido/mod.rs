@@ -1,5 +1,10 @@
+pub mod paging;
+pub mod retrieve;
 mod routes;
 mod service;
+pub mod timezone;
+
+pub use paging::SearchResultCache;
 
 pub use routes::routes;
 pub use service::*;
@@ -0,0 +1,285 @@
+use crate::api::qido::SearchRequest;
+use crate::api::IncludeField;
+use crate::types::{QueryRetrieveLevel, AE, UI};
+use dicom::core::Tag;
+use dicom::object::InMemDicomObject;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identifies a search transaction independent of the requested page.
+///
+/// Two requests share a key if they target the same AET and resource and use the same
+/// match criteria, `includefield` and `fuzzymatching` parameters. `limit` and `offset` are
+/// deliberately excluded, so that all pages of a query map to the same cached result set.
+///
+/// The key contains the complete normalized query instead of a hash of it,
+/// so that different queries never share a result set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+	aet: AE,
+	query_retrieve_level: QueryRetrieveLevel,
+	study_instance_uid: Option<UI>,
+	series_instance_uid: Option<UI>,
+	match_criteria: Vec<(String, String)>,
+	include_field: IncludeField,
+	fuzzy_matching: bool,
+}
+
+impl QueryKey {
+	pub fn new(aet: &str, request: &SearchRequest) -> Self {
+		// The order of match criteria is not significant, but depends on the order of the
+		// query parameters (and the iteration order of the underlying HashMap).
+		let mut match_criteria: Vec<(String, String)> = request
+			.parameters
+			.match_criteria
+			.iter()
+			.map(|(selector, value)| (selector.to_string(), value.to_str().into_owned()))
+			.collect();
+		match_criteria.sort_unstable();
+
+		let include_field = match &request.parameters.include_field {
+			IncludeField::All => IncludeField::All,
+			IncludeField::List(tags) => {
+				let mut tags: Vec<Tag> = tags.clone();
+				tags.sort_unstable();
+				tags.dedup();
+				IncludeField::List(tags)
+			}
+		};
+
+		Self {
+			aet: AE::from(aet),
+			query_retrieve_level: request.query.query_retrieve_level,
+			study_instance_uid: request.query.study_instance_uid.clone(),
+			series_instance_uid: request.query.series_instance_uid.clone(),
+			match_criteria,
+			include_field,
+			fuzzy_matching: request.parameters.fuzzy_matching,
+		}
+	}
+}
+
+/// A short-lived cache for complete QIDO-RS result sets.
+///
+/// The origin server (e.g. a PACS answering a C-FIND) does not support paging on its own.
+/// Without a cache, every page would re-run the full query and the pages would not necessarily
+/// be consistent with each other if the result set changes in between.
+///
+/// The number of cached matches across all result sets is limited by the capacity.
+#[derive(Clone)]
+pub struct SearchResultCache {
+	entries: Arc<Mutex<HashMap<QueryKey, CachedResults>>>,
+	capacity: usize,
+}
+
+struct CachedResults {
+	matches: Arc<[InMemDicomObject]>,
+	expires_at: Instant,
+}
+
+impl SearchResultCache {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			entries: Arc::default(),
+			capacity,
+		}
+	}
+
+	/// Returns the cached result set for the query, if it has not expired yet.
+	pub fn get(&self, key: &QueryKey) -> Option<Arc<[InMemDicomObject]>> {
+		let entries = self.entries.lock().unwrap();
+		entries
+			.get(key)
+			.filter(|entry| entry.expires_at > Instant::now())
+			.map(|entry| Arc::clone(&entry.matches))
+	}
+
+	/// Caches the result set for the given time-to-live.
+	/// Expired entries are evicted on every insertion, followed by the entries that expire first
+	/// until the result set fits into the capacity. Result sets that exceed the capacity on their
+	/// own are not cached.
+	pub fn insert(&self, key: QueryKey, matches: Arc<[InMemDicomObject]>, ttl: Duration) {
+		if matches.len() > self.capacity {
+			return;
+		}
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap();
+		entries.retain(|candidate, entry| entry.expires_at > now && *candidate != key);
+
+		let mut cached: usize = entries.values().map(|entry| entry.matches.len()).sum();
+		while cached + matches.len() > self.capacity {
+			let Some(oldest) = entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.expires_at)
+				.map(|(key, _)| key.clone())
+			else {
+				break;
+			};
+			if let Some(entry) = entries.remove(&oldest) {
+				cached -= entry.matches.len();
+			}
+		}
+
+		entries.insert(
+			key,
+			CachedResults {
+				matches,
+				expires_at: now + ttl,
+			},
+		);
+	}
+}
+
+/// A single page of a (possibly cached) result set.
+pub struct Page {
+	pub matches: Vec<InMemDicomObject>,
+	/// Whether there are further matches after this page.
+	pub has_more: bool,
+}
+
+impl Page {
+	/// The number of matches that have to be read from the origin server: the requested page and
+	/// one more match to know if there are additional results, unless the complete result set
+	/// (up to `max_cached_results`) is read for caching.
+	/// A result set with fewer matches than this is complete.
+	pub const fn max_matches(offset: usize, limit: usize, max_cached_results: usize) -> usize {
+		let page_end = offset.saturating_add(limit);
+		let max_matches = if page_end > max_cached_results {
+			page_end
+		} else {
+			max_cached_results
+		};
+		max_matches.saturating_add(1)
+	}
+
+	pub fn new(matches: &[InMemDicomObject], offset: usize, limit: usize) -> Self {
+		let page: Vec<InMemDicomObject> =
+			matches.iter().skip(offset).take(limit).cloned().collect();
+		let has_more = matches.len() > offset.saturating_add(limit);
+		Self {
+			matches: page,
+			has_more,
+		}
+	}
+}
+
+/// The Warning header value that is returned if there are additional results.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.4.4.html>
+pub fn additional_results_warning(agent: &AE) -> String {
+	format!(r#"299 {agent} "There are additional results that can be requested""#)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::qido::{QueryParameters, ResourceQuery};
+	use axum::extract::Query;
+	use dicom::core::{DataElement, VR};
+	use dicom::dictionary_std::tags;
+
+	fn instances(count: usize) -> Vec<InMemDicomObject> {
+		(0..count)
+			.map(|i| {
+				InMemDicomObject::from_element_iter([DataElement::new(
+					tags::SOP_INSTANCE_UID,
+					VR::UI,
+					format!("1.2.3.{i}"),
+				)])
+			})
+			.collect()
+	}
+
+	#[test]
+	fn page_in_the_middle_has_more() {
+		let matches = instances(5);
+		let page = Page::new(&matches, 1, 2);
+		assert_eq!(page.matches.len(), 2);
+		assert!(page.has_more);
+	}
+
+	#[test]
+	fn last_page_has_no_more() {
+		let matches = instances(5);
+		let page = Page::new(&matches, 3, 2);
+		assert_eq!(page.matches.len(), 2);
+		assert!(!page.has_more);
+
+		let page = Page::new(&matches, 10, 2);
+		assert!(page.matches.is_empty());
+		assert!(!page.has_more);
+	}
+
+	#[test]
+	fn reads_only_the_requested_page_without_cache() {
+		assert_eq!(Page::max_matches(20, 10, 0), 31);
+		assert_eq!(Page::max_matches(20, 10, 1000), 1001);
+		assert_eq!(Page::max_matches(2000, 10, 1000), 2011);
+		assert_eq!(Page::max_matches(usize::MAX, 10, 0), usize::MAX);
+	}
+
+	fn key(aet: &str, query: &str) -> QueryKey {
+		let uri: axum::http::Uri = format!("http://test?{query}").parse().unwrap();
+		let Query(parameters) = Query::<QueryParameters>::try_from_uri(&uri).unwrap();
+		let request = SearchRequest {
+			query: ResourceQuery {
+				query_retrieve_level: QueryRetrieveLevel::Study,
+				study_instance_uid: None,
+				series_instance_uid: None,
+			},
+			parameters,
+		};
+		QueryKey::new(aet, &request)
+	}
+
+	#[test]
+	fn key_contains_the_complete_query() {
+		assert_eq!(
+			key("PACS", "PatientID=1&Modality=CT&limit=1"),
+			key("PACS", "Modality=CT&PatientID=1&offset=10")
+		);
+		assert_eq!(
+			key("PACS", "includefield=PatientAge,PatientSex"),
+			key("PACS", "includefield=PatientSex,PatientAge")
+		);
+		assert_ne!(key("PACS", "PatientID=1"), key("PACS", "PatientID=2"));
+		assert_ne!(key("PACS", "PatientID=1"), key("OTHER", "PatientID=1"));
+		assert_ne!(
+			key("PACS", "PatientName=DOE"),
+			key("PACS", "PatientName=DOE&fuzzymatching=true")
+		);
+	}
+
+	#[test]
+	fn expired_entries_are_not_returned() {
+		let cache = SearchResultCache::new(10);
+		let key = key("PACS", "PatientID=42");
+		cache.insert(key.clone(), instances(1).into(), Duration::ZERO);
+		assert!(cache.get(&key).is_none());
+
+		cache.insert(key.clone(), instances(1).into(), Duration::from_mins(1));
+		assert_eq!(cache.get(&key).map(|matches| matches.len()), Some(1));
+	}
+
+	#[test]
+	fn evicts_entries_beyond_capacity() {
+		let cache = SearchResultCache::new(5);
+		let keys: Vec<QueryKey> = (1..=4)
+			.map(|i| key("PACS", &format!("PatientID={i}")))
+			.collect();
+		cache.insert(keys[0].clone(), instances(2).into(), Duration::from_mins(1));
+		cache.insert(keys[1].clone(), instances(2).into(), Duration::from_mins(2));
+		cache.insert(keys[2].clone(), instances(3).into(), Duration::from_mins(3));
+
+		// The entry that expires first is evicted to make room.
+		assert!(cache.get(&keys[0]).is_none());
+		assert!(cache.get(&keys[1]).is_some());
+		assert!(cache.get(&keys[2]).is_some());
+
+		// Result sets that exceed the capacity are not cached.
+		cache.insert(keys[3].clone(), instances(6).into(), Duration::from_mins(1));
+		assert!(cache.get(&keys[3]).is_none());
+		assert!(cache.get(&keys[2]).is_some());
+	}
+}
@@ -0,0 +1,136 @@
+use crate::api::qido::ResourceQuery;
+use crate::types::QueryRetrieveLevel;
+use dicom::core::{DataElement, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+use url::Url;
+
+/// Returns the WADO-RS URL of the study, series or instance described by a match.
+///
+/// UIDs that are missing in the match are taken from the resource query
+/// (e.g. the study of a "Study's Series" search).
+/// Returns [`None`] if the match does not contain the UIDs required for its level.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.6.3.3.html>
+pub fn retrieve_url(
+	base_url: &Url,
+	aet: &str,
+	query: &ResourceQuery,
+	object: &InMemDicomObject,
+) -> Option<String> {
+	let study =
+		read_uid(object, tags::STUDY_INSTANCE_UID).or_else(|| query.study_instance_uid.clone())?;
+	let series = || {
+		read_uid(object, tags::SERIES_INSTANCE_UID).or_else(|| query.series_instance_uid.clone())
+	};
+
+	let study_url = format!(
+		"{}/aets/{aet}/studies/{study}",
+		base_url.as_str().trim_end_matches('/')
+	);
+	let url = match query.query_retrieve_level {
+		QueryRetrieveLevel::Study => study_url,
+		QueryRetrieveLevel::Series => format!("{study_url}/series/{}", series()?),
+		QueryRetrieveLevel::Image => {
+			let instance = read_uid(object, tags::SOP_INSTANCE_UID)?;
+			format!("{study_url}/series/{}/instances/{instance}", series()?)
+		}
+		QueryRetrieveLevel::Patient | QueryRetrieveLevel::Frame => return None,
+	};
+	Some(url)
+}
+
+/// Sets the `RetrieveURL` of the match to its [`retrieve_url`].
+///
+/// A `RetrieveURL` returned by the origin server is replaced (or removed if the match lacks
+/// the UIDs of its level), so that clients retrieve the resource through this server.
+pub fn populate_retrieve_url(
+	object: &mut InMemDicomObject,
+	base_url: &Url,
+	aet: &str,
+	query: &ResourceQuery,
+) {
+	if let Some(url) = retrieve_url(base_url, aet, query, object) {
+		object.put(DataElement::new(tags::RETRIEVE_URL, VR::UR, url));
+	} else {
+		object.remove_element(tags::RETRIEVE_URL);
+	}
+}
+
+fn read_uid(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+	object
+		.get(tag)
+		.and_then(|element| element.to_str().ok())
+		.map(|uid| uid.trim_end_matches(['\0', ' ']).to_owned())
+		.filter(|uid| !uid.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn instance_url_uses_uids_of_query() {
+		let query = ResourceQuery {
+			query_retrieve_level: QueryRetrieveLevel::Image,
+			study_instance_uid: Some(String::from("1.2")),
+			series_instance_uid: Some(String::from("1.2.3")),
+		};
+		let object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::SOP_INSTANCE_UID,
+			VR::UI,
+			"1.2.3.4",
+		)]);
+		let base_url = Url::parse("http://localhost:8080/").unwrap();
+
+		assert_eq!(
+			retrieve_url(&base_url, "PACS", &query, &object).as_deref(),
+			Some("http://localhost:8080/aets/PACS/studies/1.2/series/1.2.3/instances/1.2.3.4")
+		);
+	}
+
+	#[test]
+	fn series_url_requires_study() {
+		let query = ResourceQuery {
+			query_retrieve_level: QueryRetrieveLevel::Series,
+			study_instance_uid: None,
+			series_instance_uid: None,
+		};
+		let object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::SERIES_INSTANCE_UID,
+			VR::UI,
+			"1.2.3",
+		)]);
+		let base_url = Url::parse("http://localhost:8080/dicomweb").unwrap();
+
+		assert_eq!(retrieve_url(&base_url, "PACS", &query, &object), None);
+	}
+
+	#[test]
+	fn replaces_retrieve_url_of_origin() {
+		let query = ResourceQuery {
+			query_retrieve_level: QueryRetrieveLevel::Study,
+			study_instance_uid: None,
+			series_instance_uid: None,
+		};
+		let base_url = Url::parse("https://pacs.example.com/dicomweb/").unwrap();
+
+		let mut object = InMemDicomObject::from_element_iter([
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2"),
+			DataElement::new(tags::RETRIEVE_URL, VR::UR, "http://origin/studies/1.2"),
+		]);
+		populate_retrieve_url(&mut object, &base_url, "PACS", &query);
+		assert_eq!(
+			read_uid(&object, tags::RETRIEVE_URL).as_deref(),
+			Some("https://pacs.example.com/dicomweb/aets/PACS/studies/1.2")
+		);
+
+		let mut object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::RETRIEVE_URL,
+			VR::UR,
+			"http://origin/studies/1.2",
+		)]);
+		populate_retrieve_url(&mut object, &base_url, "PACS", &query);
+		assert!(object.get(tags::RETRIEVE_URL).is_none());
+	}
+}
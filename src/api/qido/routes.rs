@@ -1,19 +1,25 @@
+use crate::api::qido::paging::{additional_results_warning, Page, QueryKey};
+use crate::api::qido::retrieve::populate_retrieve_url;
+use crate::api::qido::timezone::TimezoneNormalization;
 use crate::api::qido::{QueryParameters, ResourceQuery, SearchError, SearchRequest};
+use crate::api::search::{aet_url, SearchMediaType};
 use crate::backend::ServiceProvider;
-use crate::types::QueryRetrieveLevel;
+use crate::config::{ApplicationEntityConfig, BackendConfig, Service};
+use crate::types::{QueryRetrieveLevel, TimezoneOffset, AE};
 use crate::AppState;
-use axum::extract::Path;
+use axum::extract::{Path, State};
 use axum::http::header;
+use axum::http::HeaderValue;
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::Router;
 use axum_extra::extract::Query;
-use axum_streams::StreamBodyAs;
 use dicom::object::InMemDicomObject;
-use dicom_json::DicomJson;
-use futures::TryStreamExt;
-use tracing::instrument;
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, instrument};
 
 /// HTTP Router for the Search Transaction.
 ///
@@ -30,26 +36,112 @@ pub fn routes() -> Router<AppState> {
 }
 
 // QIDO-RS implementation
-async fn qido_handler(provider: ServiceProvider, request: SearchRequest) -> impl IntoResponse {
+async fn qido_handler(
+	provider: ServiceProvider,
+	state: AppState,
+	aet: AE,
+	media_type: SearchMediaType,
+	request: SearchRequest,
+) -> impl IntoResponse {
 	if let Some(qido) = provider.qido {
-		let response = qido.search(request).await;
-		let matches: Result<Vec<InMemDicomObject>, SearchError> =
-			response.stream.try_collect().await;
-
-		match matches {
-			Ok(matches) => {
-				let json: Vec<DicomJson<InMemDicomObject>> =
-					matches.into_iter().map(DicomJson::from).collect();
-
-				axum::response::Response::builder()
-					.status(StatusCode::OK)
-					.header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
-					.body(StreamBodyAs::json_array(futures::stream::iter(json)))
-					.unwrap()
-					.into_response()
+		let offset = request.parameters.offset;
+		let limit = request.parameters.limit;
+		let key = QueryKey::new(&aet, &request);
+		// Composite AETs use the settings of the AET that provides their QIDO-RS service.
+		let ae_config = state.config.service_aet(&aet, Service::Qido);
+		let qido_config = ae_config.map(|ae_config| &ae_config.qido);
+		let cache_ttl = qido_config.map_or(Duration::ZERO, |qido_config| {
+			Duration::from_millis(qido_config.cache_ttl)
+		});
+		let max_cached_results = if cache_ttl.is_zero() {
+			0
+		} else {
+			qido_config.map_or(0, |qido_config| qido_config.max_cached_results)
+		};
+		let max_matches = Page::max_matches(offset, limit, max_cached_results);
+		let timezone = match origin_timezone(ae_config, &request) {
+			Ok(timezone) => timezone,
+			Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+		};
+
+		let (matches, complete, warnings) = if let Some(cached) = state.search_results.get(&key) {
+			debug!(total = cached.len(), "Using cached result set");
+			(cached, true, Vec::new())
+		} else {
+			let mut request = request;
+			let query = request.query.clone();
+			let normalization = match timezone
+				.map(|timezone| TimezoneNormalization::apply(&mut request, timezone))
+				.transpose()
+			{
+				Ok(normalization) => normalization,
+				Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+			};
+			request.parameters.offset = 0;
+			request.parameters.limit = max_matches;
+
+			let response = qido.search(request).await;
+			// Origin servers with their own paging return at most `max_matches`, so the result set
+			// is only known to be complete if fewer matches were read before filtering.
+			let mut read = 0_usize;
+			let matches: Result<Vec<InMemDicomObject>, SearchError> = response
+				.stream
+				.inspect_ok(|_| read += 1)
+				.try_filter(|object| {
+					let matches = normalization
+						.as_ref()
+						.is_none_or(|normalization| normalization.matches(object));
+					futures::future::ready(matches)
+				})
+				.take(max_matches)
+				.try_collect()
+				.await;
+			let warnings = response.warnings.to_vec();
+
+			match matches {
+				Ok(mut matches) => {
+					if let Some(normalization) = &normalization {
+						for object in &mut matches {
+							normalization.annotate(object);
+						}
+					}
+					// Matches of a group already point at the member they were found in.
+					if !is_group(ae_config) {
+						if let Ok(public_url) = state.config.server.http.public_url() {
+							for object in &mut matches {
+								populate_retrieve_url(object, &public_url, &aet, &query);
+							}
+						}
+					}
+					let complete = matches.len() < max_matches && read < max_matches;
+					let matches: Arc<[InMemDicomObject]> = matches.into();
+					// Partial result sets (e.g. of a group with failed members) are not cached.
+					if complete && warnings.is_empty() && !cache_ttl.is_zero() {
+						state
+							.search_results
+							.insert(key, Arc::clone(&matches), cache_ttl);
+					}
+					(matches, complete, warnings)
+				}
+				Err(err) => {
+					return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+				}
 			}
-			Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
-		}
+		};
+
+		let page = Page::new(&matches, offset, limit);
+		let mut response =
+			media_type.into_response(page.matches, aet_url(&state.config, &aet).as_deref());
+
+		// Matches that were filtered out might have been replaced by further matches of the origin server.
+		let has_more =
+			page.has_more || (!complete && offset.saturating_add(limit) >= matches.len());
+		let warnings = has_more
+			.then(|| additional_results_warning(&state.config.server.aet))
+			.into_iter()
+			.chain(warnings);
+		append_warnings(&mut response, warnings);
+		response
 	} else {
 		(
 			StatusCode::SERVICE_UNAVAILABLE,
@@ -59,9 +151,37 @@ async fn qido_handler(provider: ServiceProvider, request: SearchRequest) -> impl
 	}
 }
 
+fn is_group(ae_config: Option<&ApplicationEntityConfig>) -> bool {
+	ae_config.is_some_and(|ae_config| matches!(ae_config.backend, BackendConfig::Group(_)))
+}
+
+/// Returns the timezone of the origin server, into which the request is normalized.
+/// Requests to group AETs are only validated, as their members apply their own timezones.
+fn origin_timezone(
+	ae_config: Option<&ApplicationEntityConfig>,
+	request: &SearchRequest,
+) -> Result<Option<TimezoneOffset>, String> {
+	if is_group(ae_config) {
+		TimezoneNormalization::validate(request).map(|()| None)
+	} else {
+		Ok(ae_config.and_then(|ae_config| ae_config.qido.timezone))
+	}
+}
+
+fn append_warnings(response: &mut Response, warnings: impl IntoIterator<Item = String>) {
+	for warning in warnings {
+		if let Ok(warning) = HeaderValue::try_from(warning) {
+			response.headers_mut().append(header::WARNING, warning);
+		}
+	}
+}
+
 #[instrument(skip_all)]
 async fn all_studies(
 	provider: ServiceProvider,
+	State(state): State<AppState>,
+	Path(aet): Path<AE>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -72,13 +192,15 @@ async fn all_studies(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
 
 #[instrument(skip_all)]
 async fn studys_series(
 	provider: ServiceProvider,
-	Path((_aet, study)): Path<(String, String)>,
+	State(state): State<AppState>,
+	Path((aet, study)): Path<(AE, String)>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -89,13 +211,15 @@ async fn studys_series(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
 
 #[instrument(skip_all)]
 async fn studys_series_instances(
 	provider: ServiceProvider,
-	Path((_aet, study, series)): Path<(String, String, String)>,
+	State(state): State<AppState>,
+	Path((aet, study, series)): Path<(AE, String, String)>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -106,13 +230,15 @@ async fn studys_series_instances(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
 
 #[instrument(skip_all)]
 async fn studys_instances(
 	provider: ServiceProvider,
-	Path((_aet, study)): Path<(String, String)>,
+	State(state): State<AppState>,
+	Path((aet, study)): Path<(AE, String)>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -123,12 +249,15 @@ async fn studys_instances(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
 
 #[instrument(skip_all)]
 async fn all_series(
 	provider: ServiceProvider,
+	State(state): State<AppState>,
+	Path(aet): Path<AE>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -139,12 +268,15 @@ async fn all_series(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
 
 #[instrument(skip_all)]
 async fn all_instances(
 	provider: ServiceProvider,
+	State(state): State<AppState>,
+	Path(aet): Path<AE>,
+	media_type: SearchMediaType,
 	Query(parameters): Query<QueryParameters>,
 ) -> impl IntoResponse {
 	let request = SearchRequest {
@@ -155,5 +287,5 @@ async fn all_instances(
 		},
 		parameters,
 	};
-	qido_handler(provider, request).await
+	qido_handler(provider, state, aet, media_type, request).await
 }
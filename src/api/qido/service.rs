@@ -4,18 +4,25 @@ use async_trait::async_trait;
 use dicom::object::InMemDicomObject;
 use futures::stream::BoxStream;
 use serde::Deserialize;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 use crate::api::{deserialize_includefield, IncludeField, MatchCriteria};
 
 /// Provides the functionality of a search transaction.
 ///
+/// Implementations return the result set as a stream, which is only read as far as needed.
+/// Paging via `limit` and `offset` is applied by the HTTP layer, see [`crate::api::qido::SearchResultCache`].
+/// The request passed to the service has an `offset` of zero and the number of matches that are
+/// read at most as `limit`, which origin servers with their own paging can use to return enough matches.
+///
 /// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.6.html>
 #[async_trait]
 pub trait QidoService: Send + Sync {
 	async fn search(&self, request: SearchRequest) -> SearchResponse;
 }
 
+#[derive(Clone)]
 pub struct SearchRequest {
 	pub query: ResourceQuery,
 	pub parameters: QueryParameters,
@@ -24,7 +31,7 @@ pub struct SearchRequest {
 /// Query parameters for a QIDO-RS request.
 ///
 /// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_8.3.4-1>
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 #[serde(default)]
 pub struct QueryParameters {
 	#[serde(flatten)]
@@ -52,6 +59,38 @@ impl Default for QueryParameters {
 
 pub struct SearchResponse<'a> {
 	pub stream: BoxStream<'a, Result<InMemDicomObject, SearchError>>,
+	/// Warnings that are returned to the client as `Warning` headers, e.g. of an origin server.
+	/// Implementations can add warnings while the stream is read.
+	pub warnings: SearchWarnings,
+}
+
+impl<'a> SearchResponse<'a> {
+	pub fn new(stream: BoxStream<'a, Result<InMemDicomObject, SearchError>>) -> Self {
+		Self {
+			stream,
+			warnings: SearchWarnings::default(),
+		}
+	}
+}
+
+/// The values of the `Warning` headers of a search response, shared with the stream of matches.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.4.4.html>
+#[derive(Debug, Clone, Default)]
+pub struct SearchWarnings(Arc<Mutex<Vec<String>>>);
+
+impl SearchWarnings {
+	/// Adds the warning, unless the same warning was already added.
+	pub fn push(&self, warning: String) {
+		let mut warnings = self.0.lock().unwrap();
+		if !warnings.contains(&warning) {
+			warnings.push(warning);
+		}
+	}
+
+	pub fn to_vec(&self) -> Vec<String> {
+		self.0.lock().unwrap().clone()
+	}
 }
 
 /// Data used to identify a specific search transaction resource.
@@ -68,7 +107,7 @@ pub struct SearchResponse<'a> {
 ///   series_instance_uid: None
 /// };
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ResourceQuery {
 	/// The query retrieve level.
 	pub query_retrieve_level: QueryRetrieveLevel,
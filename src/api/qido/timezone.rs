@@ -0,0 +1,453 @@
+//! Timezone-aware matching of combined date and time attributes.
+//!
+//! Dates and times in DICOM are local to the origin server and have no timezone on their own.
+//! A client in another timezone can specify its offset with the `TimezoneOffsetFromUTC` query
+//! parameter. The date and time ranges of the query are then converted into the timezone of the
+//! origin server, so that e.g. a query for "today" returns the studies of the client's day.
+//!
+//! Date and time attributes that belong together (e.g. `StudyDate` and `StudyTime`) are matched
+//! as a single date time range.
+//! As C-FIND does not support combined matching without extended negotiation, the origin server
+//! is queried for the covering date range and the results are filtered afterward.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/chtml/part04/sect_C.2.2.2.5.html>
+
+use crate::api::qido::{QidoService, SearchError, SearchRequest, SearchResponse};
+use crate::types::TimezoneOffset;
+use async_trait::async_trait;
+use dicom::core::chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+use dicom::core::header::HasLength;
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+use futures::{StreamExt, TryStreamExt};
+use std::str::FromStr;
+
+/// Date and time attributes that are matched as a combined date time.
+const DATE_TIME_ATTRIBUTES: &[(Tag, Tag)] = &[
+	(tags::STUDY_DATE, tags::STUDY_TIME),
+	(tags::SERIES_DATE, tags::SERIES_TIME),
+	(tags::ACQUISITION_DATE, tags::ACQUISITION_TIME),
+	(tags::CONTENT_DATE, tags::CONTENT_TIME),
+	(
+		tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+		tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+	),
+];
+
+#[derive(Debug, Copy, Clone)]
+enum Bound {
+	Start,
+	End,
+}
+
+/// A combined date time range in the timezone of the origin server.
+#[derive(Debug)]
+struct DateTimeRange {
+	date: Tag,
+	time: Tag,
+	start: Option<NaiveDateTime>,
+	end: Option<NaiveDateTime>,
+}
+
+/// The result of normalizing the date and time criteria of a search request.
+/// Used to filter and annotate the matches returned by the origin server.
+#[derive(Debug)]
+pub struct TimezoneNormalization {
+	origin: TimezoneOffset,
+	ranges: Vec<DateTimeRange>,
+}
+
+impl TimezoneNormalization {
+	/// Rewrites the date and time criteria of the request into the timezone of the origin server.
+	///
+	/// Returns an error if the `TimezoneOffsetFromUTC` query parameter is invalid.
+	pub fn apply(request: &mut SearchRequest, origin: TimezoneOffset) -> Result<Self, String> {
+		let criteria = &mut request.parameters.match_criteria;
+
+		let client = match criteria.remove(tags::TIMEZONE_OFFSET_FROM_UTC) {
+			Some(value) if !value.is_empty() => TimezoneOffset::from_str(&value.to_str())
+				.map_err(|err| format!("invalid value for TimezoneOffsetFromUTC: {err}"))?,
+			_ => origin,
+		};
+		let shift = TimeDelta::seconds(i64::from(
+			origin.as_fixed_offset().local_minus_utc() - client.as_fixed_offset().local_minus_utc(),
+		));
+
+		let mut ranges = Vec::new();
+		for &(date, time) in DATE_TIME_ATTRIBUTES {
+			let Some(date_range) = criteria
+				.get(date)
+				.filter(|value| !value.is_empty())
+				.map(|value| value.to_str().into_owned())
+			else {
+				continue;
+			};
+			let time_range = criteria
+				.get(time)
+				.filter(|value| !value.is_empty())
+				.map(|value| value.to_str().into_owned());
+
+			let (date_start, date_end) = split_range(&date_range);
+			let (time_start, time_end) = time_range.as_deref().map_or((None, None), split_range);
+
+			let start = date_start
+				.map(|date| combine(date, time_start, Bound::Start))
+				.transpose()?
+				.map(|start| start + shift);
+			let end = date_end
+				.map(|date| combine(date, time_end, Bound::End))
+				.transpose()?
+				.map(|end| end + shift);
+
+			// Query the origin server for all days that overlap with the range.
+			// The time is requested as a return key for filtering.
+			criteria.set(
+				date,
+				PrimitiveValue::from(format!(
+					"{}-{}",
+					start
+						.map(|start| start.format("%Y%m%d").to_string())
+						.unwrap_or_default(),
+					end.map(|end| end.format("%Y%m%d").to_string())
+						.unwrap_or_default(),
+				)),
+			);
+			criteria.set(time, PrimitiveValue::Empty);
+
+			ranges.push(DateTimeRange {
+				date,
+				time,
+				start,
+				end,
+			});
+		}
+
+		Ok(Self { origin, ranges })
+	}
+
+	/// Checks the `TimezoneOffsetFromUTC` query parameter and the date and time criteria
+	/// that are rewritten by [`Self::apply`].
+	///
+	/// Used for group AETs, whose members normalize the request in their own timezone,
+	/// so that an invalid request is rejected instead of failing in every member.
+	pub fn validate(request: &SearchRequest) -> Result<(), String> {
+		Self::apply(&mut request.clone(), TimezoneOffset::UTC).map(drop)
+	}
+
+	/// Checks if the match satisfies the combined date time ranges.
+	///
+	/// Matches without a date are kept, as they cannot be evaluated.
+	/// Matches without a time are compared by their date only.
+	pub fn matches(&self, object: &InMemDicomObject) -> bool {
+		self.ranges.iter().all(|range| {
+			let Some(date) = read_str(object, range.date).and_then(|date| parse_date(&date)) else {
+				return true;
+			};
+			read_str(object, range.time)
+				.and_then(|time| parse_time(&time, Bound::Start))
+				.map_or_else(
+					|| {
+						range.start.is_none_or(|start| date >= start.date())
+							&& range.end.is_none_or(|end| date <= end.date())
+					},
+					|time| {
+						let date_time = date.and_time(time);
+						range.start.is_none_or(|start| date_time >= start)
+							&& range.end.is_none_or(|end| date_time <= end)
+					},
+				)
+		})
+	}
+
+	/// Adds the timezone of the origin server to the match, unless it already specifies one.
+	pub fn annotate(&self, object: &mut InMemDicomObject) {
+		if read_str(object, tags::TIMEZONE_OFFSET_FROM_UTC).is_none() {
+			object.put(DataElement::new(
+				tags::TIMEZONE_OFFSET_FROM_UTC,
+				VR::SH,
+				self.origin.to_string(),
+			));
+		}
+	}
+}
+
+/// Applies the timezone normalization of an origin server to the searches of another service.
+///
+/// Used for the members of group AETs, whose searches are not normalized by the QIDO-RS handler,
+/// as each member can have its own timezone.
+pub struct TimezoneQidoService {
+	inner: Box<dyn QidoService>,
+	origin: TimezoneOffset,
+}
+
+impl TimezoneQidoService {
+	pub const fn new(inner: Box<dyn QidoService>, origin: TimezoneOffset) -> Self {
+		Self { inner, origin }
+	}
+}
+
+#[async_trait]
+impl QidoService for TimezoneQidoService {
+	async fn search(&self, mut request: SearchRequest) -> SearchResponse {
+		let normalization = match TimezoneNormalization::apply(&mut request, self.origin) {
+			Ok(normalization) => normalization,
+			Err(err) => {
+				return SearchResponse::new(
+					futures::stream::once(async move {
+						Err(SearchError::Backend { source: err.into() })
+					})
+					.boxed(),
+				)
+			}
+		};
+
+		let response = self.inner.search(request).await;
+		SearchResponse {
+			// Errors are passed as strings, as they are not `Send` while the filter is pending.
+			stream: response
+				.stream
+				.map_err(|err| err.to_string())
+				.try_filter_map(move |mut object| {
+					let matches = normalization.matches(&object);
+					if matches {
+						normalization.annotate(&mut object);
+					}
+					futures::future::ready(Ok(matches.then_some(object)))
+				})
+				.map_err(|err| SearchError::Backend { source: err.into() })
+				.boxed(),
+			warnings: response.warnings,
+		}
+	}
+}
+
+/// Splits a (validated) match value into the start and end of the range.
+/// A single value is a range with identical start and end.
+fn split_range(value: &str) -> (Option<&str>, Option<&str>) {
+	fn non_empty(value: &str) -> Option<&str> {
+		Some(value).filter(|value| !value.is_empty())
+	}
+	match value.split_once('-') {
+		Some((start, end)) => (non_empty(start), non_empty(end)),
+		None => (non_empty(value), non_empty(value)),
+	}
+}
+
+fn combine(date: &str, time: Option<&str>, bound: Bound) -> Result<NaiveDateTime, String> {
+	let date = parse_date(date).ok_or_else(|| format!("{date} is not a valid date"))?;
+	let time = match time {
+		Some(time) => {
+			parse_time(time, bound).ok_or_else(|| format!("{time} is not a valid time"))?
+		}
+		None => match bound {
+			Bound::Start => NaiveTime::MIN,
+			Bound::End => NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999).unwrap_or_default(),
+		},
+	};
+	Ok(date.and_time(time))
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+	NaiveDate::parse_from_str(value, "%Y%m%d").ok()
+}
+
+/// Parses a time in the format `HH[MM[SS[.F{1,6}]]]`.
+/// Omitted components are filled with the earliest or latest value, depending on the bound.
+fn parse_time(value: &str, bound: Bound) -> Option<NaiveTime> {
+	let (time, fraction) = value.split_once('.').unwrap_or((value, ""));
+	if !matches!(time.len(), 2 | 4 | 6) || fraction.len() > 6 {
+		return None;
+	}
+
+	let (padding, fraction_padding) = match bound {
+		Bound::Start => ("0000", '0'),
+		Bound::End => ("5959", '9'),
+	};
+	let time = format!("{time}{}", &padding[time.len() - 2..]);
+	let mut fraction = fraction.to_owned();
+	while fraction.len() < 6 {
+		fraction.push(fraction_padding);
+	}
+
+	NaiveTime::from_hms_micro_opt(
+		time[0..2].parse().ok()?,
+		time[2..4].parse().ok()?,
+		time[4..6].parse().ok()?,
+		fraction.parse().ok()?,
+	)
+}
+
+fn read_str(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+	object
+		.get(tag)
+		.and_then(|element| element.to_str().ok())
+		.map(|value| value.trim_end_matches(['\0', ' ']).to_owned())
+		.filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::qido::{QueryParameters, ResourceQuery};
+	use crate::api::MatchCriteria;
+	use crate::types::QueryRetrieveLevel;
+	use std::collections::HashMap;
+
+	fn request(criteria: &[(&str, &str)]) -> SearchRequest {
+		let criteria: HashMap<String, String> = criteria
+			.iter()
+			.map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+			.collect();
+		SearchRequest {
+			query: ResourceQuery {
+				query_retrieve_level: QueryRetrieveLevel::Study,
+				study_instance_uid: None,
+				series_instance_uid: None,
+			},
+			parameters: QueryParameters {
+				match_criteria: MatchCriteria::try_from(criteria).unwrap(),
+				..QueryParameters::default()
+			},
+		}
+	}
+
+	fn study(date: &str, time: &str) -> InMemDicomObject {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(tags::STUDY_DATE, VR::DA, date),
+			DataElement::new(tags::STUDY_TIME, VR::TM, time),
+		])
+	}
+
+	#[test]
+	fn shifts_date_range_into_origin_timezone() {
+		let mut request = request(&[
+			("StudyDate", "20240101"),
+			("TimezoneOffsetFromUTC", "+0900"),
+		]);
+		let origin = TimezoneOffset::from_str("+0100").unwrap();
+		let normalization = TimezoneNormalization::apply(&mut request, origin).unwrap();
+
+		let criteria = &request.parameters.match_criteria;
+		assert_eq!(
+			criteria.get(tags::STUDY_DATE).map(|value| value.to_str()),
+			Some("20231231-20240101".into())
+		);
+		assert_eq!(criteria.get(tags::STUDY_TIME), Some(&PrimitiveValue::Empty));
+		assert_eq!(criteria.get(tags::TIMEZONE_OFFSET_FROM_UTC), None);
+
+		// 2024-01-01 00:00 to 23:59 in +0900 is 2023-12-31 16:00 to 2024-01-01 15:59 in +0100
+		assert!(!normalization.matches(&study("20231231", "155959")));
+		assert!(normalization.matches(&study("20231231", "160000")));
+		assert!(normalization.matches(&study("20240101", "155959")));
+		assert!(!normalization.matches(&study("20240101", "160000")));
+	}
+
+	#[test]
+	fn matches_combined_date_time_range() {
+		let mut request = request(&[
+			("StudyDate", "20240101-20240102"),
+			("StudyTime", "0800-1200"),
+		]);
+		let origin = TimezoneOffset::from_str("+0000").unwrap();
+		let normalization = TimezoneNormalization::apply(&mut request, origin).unwrap();
+
+		assert!(!normalization.matches(&study("20240101", "0759")));
+		assert!(normalization.matches(&study("20240101", "1300")));
+		assert!(normalization.matches(&study("20240102", "1159")));
+		assert!(!normalization.matches(&study("20240102", "1201")));
+	}
+
+	#[test]
+	fn annotates_origin_timezone() {
+		let normalization = TimezoneNormalization {
+			origin: TimezoneOffset::from_str("-0500").unwrap(),
+			ranges: Vec::new(),
+		};
+		let mut object = study("20240101", "0800");
+		normalization.annotate(&mut object);
+		assert_eq!(
+			read_str(&object, tags::TIMEZONE_OFFSET_FROM_UTC).as_deref(),
+			Some("-0500")
+		);
+	}
+
+	#[test]
+	fn accepts_decoded_plus_sign() {
+		// `?TimezoneOffsetFromUTC=+0900` without percent-encoding
+		let mut request = request(&[
+			("StudyDate", "20240101"),
+			("TimezoneOffsetFromUTC", " 0900"),
+		]);
+		let origin = TimezoneOffset::from_str("+0100").unwrap();
+		TimezoneNormalization::apply(&mut request, origin).unwrap();
+		assert_eq!(
+			request
+				.parameters
+				.match_criteria
+				.get(tags::STUDY_DATE)
+				.map(|value| value.to_str()),
+			Some("20231231-20240101".into())
+		);
+	}
+
+	struct StaticQidoService(Vec<InMemDicomObject>);
+
+	#[async_trait]
+	impl QidoService for StaticQidoService {
+		async fn search(&self, request: SearchRequest) -> SearchResponse {
+			// The origin server receives the covering date range in its own timezone.
+			assert_eq!(
+				request
+					.parameters
+					.match_criteria
+					.get(tags::STUDY_DATE)
+					.map(|value| value.to_str()),
+				Some("20231231-20240101".into())
+			);
+			SearchResponse::new(futures::stream::iter(self.0.clone().into_iter().map(Ok)).boxed())
+		}
+	}
+
+	#[tokio::test]
+	async fn normalizes_searches_of_inner_service() {
+		let qido = TimezoneQidoService::new(
+			Box::new(StaticQidoService(vec![
+				study("20231231", "120000"),
+				study("20231231", "170000"),
+			])),
+			TimezoneOffset::from_str("+0100").unwrap(),
+		);
+		let matches: Vec<InMemDicomObject> = qido
+			.search(request(&[
+				("StudyDate", "20240101"),
+				("TimezoneOffsetFromUTC", "+0900"),
+			]))
+			.await
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+
+		assert_eq!(matches.len(), 1);
+		assert_eq!(
+			read_str(&matches[0], tags::STUDY_TIME).as_deref(),
+			Some("170000")
+		);
+		assert_eq!(
+			read_str(&matches[0], tags::TIMEZONE_OFFSET_FROM_UTC).as_deref(),
+			Some("+0100")
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_client_offset() {
+		let valid = request(&[("TimezoneOffsetFromUTC", "+0100")]);
+		assert!(TimezoneNormalization::validate(&valid).is_ok());
+
+		let mut request = request(&[("TimezoneOffsetFromUTC", "0100")]);
+		assert!(TimezoneNormalization::validate(&request).is_err());
+		let origin = TimezoneOffset::from_str("+0100").unwrap();
+		assert!(TimezoneNormalization::apply(&mut request, origin).is_err());
+	}
+}
@@ -0,0 +1,285 @@
+use crate::api::wado::BulkDataPath;
+use crate::config::AppConfig;
+use crate::utils::xml::NativeDicomModel;
+use axum::extract::FromRequestParts;
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum_streams::StreamBodyAs;
+use dicom::core::ops::AttributeSelector;
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+use dicom_json::DicomJson;
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+const DICOM_JSON: &str = "application/dicom+json";
+const DICOM_XML: &str = "application/dicom+xml";
+
+/// The media type of a search response (QIDO-RS and MWL-RS), negotiated from the Accept header.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.7.5.html>
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SearchMediaType {
+	/// `application/dicom+json`, the default if the client does not express a preference.
+	#[default]
+	Json,
+	/// `multipart/related; type="application/dicom+xml"` with one part per match.
+	MultipartXml,
+}
+
+impl SearchMediaType {
+	/// Selects the supported media type with the highest quality value in an Accept header value.
+	/// Returns [`None`] if none of the media types are acceptable.
+	///
+	/// The quality of a media type is taken from the most specific media range that matches it.
+	/// If media types have the same quality, the one whose media range comes first is selected.
+	fn negotiate(accept: &str) -> Option<Self> {
+		let ranges: Vec<(mime::Mime, f32)> = accept
+			.split(',')
+			.filter_map(|range| range.trim().parse::<mime::Mime>().ok())
+			.map(|range| {
+				let quality = range
+					.get_param("q")
+					.and_then(|quality| quality.as_str().parse::<f32>().ok())
+					.unwrap_or(1.0);
+				(range, quality)
+			})
+			.collect();
+
+		[Self::Json, Self::MultipartXml]
+			.into_iter()
+			.filter_map(|media_type| {
+				ranges
+					.iter()
+					.enumerate()
+					.filter_map(|(position, (range, quality))| {
+						media_type
+							.specificity(range)
+							.map(|specificity| (specificity, Reverse(position), *quality))
+					})
+					.max_by_key(|(specificity, position, _)| (*specificity, *position))
+					.map(|(_, position, quality)| (media_type, position, quality))
+			})
+			.filter(|(_, _, quality)| *quality > 0.0)
+			.max_by(|(_, a_position, a_quality), (_, b_position, b_quality)| {
+				a_quality
+					.total_cmp(b_quality)
+					.then_with(|| a_position.cmp(b_position))
+			})
+			.map(|(media_type, _, _)| media_type)
+	}
+
+	/// Returns how specific the media range matches the media type (`*/*` < `type/*` < `type/subtype`),
+	/// or [`None`] if it does not match.
+	fn specificity(self, range: &mime::Mime) -> Option<u8> {
+		match (self, range.essence_str()) {
+			(Self::Json, "*/*") => Some(0),
+			(Self::Json, "application/*") => Some(1),
+			(Self::Json, "application/json" | DICOM_JSON) => Some(2),
+			(Self::MultipartXml, "multipart/related") => match range.get_param("type") {
+				Some(media_type) if media_type.as_str() == DICOM_XML => Some(2),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
+
+	/// Builds the response for the given matches.
+	///
+	/// `aet_url` is the URL of the AET resource (e.g. `http://localhost:8080/aets/PACS`), used to
+	/// reference the bulk data of instance-level matches in XML responses.
+	/// Without it, the bulk data is inlined.
+	pub fn into_response(self, matches: Vec<InMemDicomObject>, aet_url: Option<&str>) -> Response {
+		match self {
+			Self::Json => {
+				let json: Vec<DicomJson<InMemDicomObject>> =
+					matches.into_iter().map(DicomJson::from).collect();
+
+				Response::builder()
+					.status(StatusCode::OK)
+					.header(CONTENT_TYPE, DICOM_JSON)
+					.body(StreamBodyAs::json_array(futures::stream::iter(json)))
+					.unwrap()
+					.into_response()
+			}
+			Self::MultipartXml => {
+				let boundary = uuid::Uuid::new_v4().simple().to_string();
+				let mut body = String::new();
+				for object in &matches {
+					let instance_url = aet_url.and_then(|aet_url| instance_url(aet_url, object));
+					let resolver = |selector: &AttributeSelector| {
+						instance_url
+							.as_ref()
+							.map(|instance_url| bulk_data_uri(instance_url, selector))
+					};
+					let xml = NativeDicomModel::new(object)
+						.with_bulk_data_uri(&resolver)
+						.to_xml();
+					let _ = write!(
+						body,
+						"--{boundary}\r\nContent-Type: {DICOM_XML}\r\n\r\n{xml}\r\n"
+					);
+				}
+				let _ = write!(body, "--{boundary}--");
+
+				Response::builder()
+					.status(StatusCode::OK)
+					.header(
+						CONTENT_TYPE,
+						format!(r#"multipart/related; type="{DICOM_XML}"; boundary={boundary}"#),
+					)
+					.body(axum::body::Body::from(body))
+					.unwrap()
+			}
+		}
+	}
+}
+
+/// Returns the public URL of the AET resource, including the configured base path.
+pub fn aet_url(config: &AppConfig, aet: &str) -> Option<String> {
+	let public_url = config.server.http.public_url().ok()?;
+	Some(format!(
+		"{}/aets/{aet}",
+		public_url.as_str().trim_end_matches('/')
+	))
+}
+
+/// Returns the WADO-RS URL of the instance, if the match identifies a single instance.
+pub fn instance_url(aet_url: &str, object: &InMemDicomObject) -> Option<String> {
+	let uid = |tag: Tag| {
+		object
+			.get(tag)
+			.and_then(|element| element.to_str().ok())
+			.map(|uid| uid.trim_end_matches(['\0', ' ']).to_owned())
+			.filter(|uid| !uid.is_empty())
+	};
+	Some(format!(
+		"{aet_url}/studies/{}/series/{}/instances/{}",
+		uid(tags::STUDY_INSTANCE_UID)?,
+		uid(tags::SERIES_INSTANCE_UID)?,
+		uid(tags::SOP_INSTANCE_UID)?
+	))
+}
+
+/// Returns the bulk data URI for the attribute of the instance.
+pub fn bulk_data_uri(instance_url: &str, selector: &AttributeSelector) -> String {
+	format!("{instance_url}/bulkdata/{}", BulkDataPath(selector.clone()))
+}
+
+impl<S> FromRequestParts<S> for SearchMediaType
+where
+	S: Send + Sync,
+{
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+		let Some(accept) = parts.headers.get(ACCEPT) else {
+			return Ok(Self::default());
+		};
+
+		accept
+			.to_str()
+			.ok()
+			.and_then(Self::negotiate)
+			.ok_or_else(|| {
+				(
+					StatusCode::NOT_ACCEPTABLE,
+					format!("Supported media types are {DICOM_JSON} and multipart/related; type=\"{DICOM_XML}\""),
+				)
+					.into_response()
+			})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dicom::core::{DataElement, VR};
+
+	#[test]
+	fn negotiate_json() {
+		assert_eq!(
+			SearchMediaType::negotiate("application/dicom+json"),
+			Some(SearchMediaType::Json)
+		);
+		assert_eq!(
+			SearchMediaType::negotiate("application/json"),
+			Some(SearchMediaType::Json)
+		);
+		assert_eq!(
+			SearchMediaType::negotiate("*/*"),
+			Some(SearchMediaType::Json)
+		);
+	}
+
+	#[test]
+	fn negotiate_multipart_xml() {
+		assert_eq!(
+			SearchMediaType::negotiate(r#"multipart/related; type="application/dicom+xml""#),
+			Some(SearchMediaType::MultipartXml)
+		);
+		assert_eq!(
+			SearchMediaType::negotiate(
+				r#"text/html, multipart/related; type="application/dicom+xml", */*"#
+			),
+			Some(SearchMediaType::MultipartXml)
+		);
+	}
+
+	#[test]
+	fn bulk_data_uri_of_instance() {
+		let object = InMemDicomObject::from_element_iter([
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2"),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3"),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4"),
+		]);
+		let url = instance_url("http://localhost:8080/aets/PACS", &object).unwrap();
+
+		assert_eq!(
+			bulk_data_uri(
+				&url,
+				&AttributeSelector::from((tags::ICON_IMAGE_SEQUENCE, 0, tags::PIXEL_DATA))
+			),
+			"http://localhost:8080/aets/PACS/studies/1.2/series/1.2.3/instances/1.2.3.4/bulkdata/00880200/0/7FE00010"
+		);
+		assert_eq!(
+			instance_url(
+				"http://localhost:8080/aets/PACS",
+				&InMemDicomObject::new_empty()
+			),
+			None
+		);
+	}
+
+	#[test]
+	fn negotiate_by_quality() {
+		assert_eq!(
+			SearchMediaType::negotiate(
+				r#"application/dicom+json; q=0.5, multipart/related; type="application/dicom+xml""#
+			),
+			Some(SearchMediaType::MultipartXml)
+		);
+		assert_eq!(
+			SearchMediaType::negotiate(
+				r#"multipart/related; type="application/dicom+xml"; q=0.2, */*; q=0.8"#
+			),
+			Some(SearchMediaType::Json)
+		);
+		assert_eq!(
+			SearchMediaType::negotiate("application/dicom+json; q=0, */*"),
+			None
+		);
+	}
+
+	#[test]
+	fn negotiate_unsupported() {
+		assert_eq!(SearchMediaType::negotiate("text/html"), None);
+		assert_eq!(
+			SearchMediaType::negotiate(r#"multipart/related; type="application/dicom""#),
+			None
+		);
+	}
+}
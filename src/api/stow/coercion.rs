@@ -0,0 +1,232 @@
+//! Coercion of attributes of instances received by STOW-RS, as configured in `stow-rs.coercion`.
+//!
+//! The original values of coerced attributes are recorded in the `OriginalAttributesSequence`.
+//! <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_C.12.1.1.9>
+
+use crate::config::{CoercionAction, CoercionRule};
+use dicom::core::chrono::Local;
+use dicom::core::header::Header;
+use dicom::core::value::DataSetSequence;
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+
+/// Applies the rules to the instance and returns the attributes that were changed.
+/// The changes are recorded with the given AE title as the `ModifyingSystem`.
+pub fn coerce(
+	object: &mut InMemDicomObject,
+	rules: &[CoercionRule],
+	modifying_system: &str,
+) -> Vec<Tag> {
+	let mut original = Vec::new();
+
+	for rule in rules {
+		let element = object.get(rule.tag);
+		let current = element
+			.and_then(|element| element.to_str().ok())
+			.map(|value| value.trim_end_matches(['\0', ' ']).to_owned())
+			.unwrap_or_default();
+		let value = match &rule.action {
+			CoercionAction::Set(value) => Some(value),
+			CoercionAction::Default(value) => current.is_empty().then_some(value),
+			CoercionAction::Map(values) => values.get(&current),
+		};
+		let Some(value) = value.filter(|value| value.to_str() != current) else {
+			continue;
+		};
+
+		if !original
+			.iter()
+			.any(|element: &DataElement<InMemDicomObject>| element.tag() == rule.tag)
+		{
+			// Attributes that did not exist before are recorded without a value.
+			original.push(
+				element
+					.cloned()
+					.unwrap_or_else(|| DataElement::new(rule.tag, rule.vr, PrimitiveValue::Empty)),
+			);
+		}
+		object.put(DataElement::new(rule.tag, rule.vr, value.clone()));
+	}
+
+	let coerced: Vec<Tag> = original.iter().map(Header::tag).collect();
+	if !coerced.is_empty() {
+		record_original_attributes(
+			object,
+			InMemDicomObject::from_element_iter(original),
+			modifying_system,
+		);
+	}
+	coerced
+}
+
+/// Appends an item with the original values to the `OriginalAttributesSequence`.
+fn record_original_attributes(
+	object: &mut InMemDicomObject,
+	original: InMemDicomObject,
+	modifying_system: &str,
+) {
+	let item = InMemDicomObject::from_element_iter([
+		DataElement::new(
+			tags::MODIFIED_ATTRIBUTES_SEQUENCE,
+			VR::SQ,
+			DataSetSequence::from(vec![original]),
+		),
+		DataElement::new(
+			tags::ATTRIBUTE_MODIFICATION_DATE_TIME,
+			VR::DT,
+			Local::now().format("%Y%m%d%H%M%S%z").to_string(),
+		),
+		DataElement::new(tags::MODIFYING_SYSTEM, VR::LO, modifying_system),
+		DataElement::new(
+			tags::SOURCE_OF_PREVIOUS_VALUES,
+			VR::LO,
+			PrimitiveValue::Empty,
+		),
+		DataElement::new(
+			tags::REASON_FOR_THE_ATTRIBUTE_MODIFICATION,
+			VR::CS,
+			"COERCE",
+		),
+	]);
+
+	let mut items = object
+		.take(tags::ORIGINAL_ATTRIBUTES_SEQUENCE)
+		.and_then(|element| element.items().map(<[InMemDicomObject]>::to_vec))
+		.unwrap_or_default();
+	items.push(item);
+	object.put(DataElement::new(
+		tags::ORIGINAL_ATTRIBUTES_SEQUENCE,
+		VR::SQ,
+		DataSetSequence::from(items),
+	));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dicom::core::header::HasLength;
+	use serde_json::json;
+
+	fn rules(rules: serde_json::Value) -> Vec<CoercionRule> {
+		serde_json::from_value(rules).unwrap()
+	}
+
+	#[test]
+	fn applies_rules() {
+		let mut object = InMemDicomObject::from_element_iter([
+			DataElement::new(tags::PATIENT_ID, VR::LO, "123"),
+			DataElement::new(tags::ISSUER_OF_PATIENT_ID, VR::LO, "LOCAL"),
+		]);
+		let rules = rules(json!([
+			{ "tag": "InstitutionName", "set": "Hospital" },
+			{ "tag": "IssuerOfPatientID", "default": "HOSPITAL" },
+			{ "tag": "PatientID", "map": { "123": "A-123" } },
+		]));
+
+		let coerced = coerce(&mut object, &rules, "DICOM-RST");
+		assert_eq!(coerced, [tags::INSTITUTION_NAME, tags::PATIENT_ID]);
+		assert_eq!(
+			object
+				.get(tags::INSTITUTION_NAME)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"Hospital"
+		);
+		assert_eq!(
+			object
+				.get(tags::ISSUER_OF_PATIENT_ID)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"LOCAL"
+		);
+		assert_eq!(
+			object.get(tags::PATIENT_ID).unwrap().to_str().unwrap(),
+			"A-123"
+		);
+
+		let original = &object
+			.get(tags::ORIGINAL_ATTRIBUTES_SEQUENCE)
+			.unwrap()
+			.items()
+			.unwrap()[0];
+		assert_eq!(
+			original
+				.get(tags::MODIFYING_SYSTEM)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"DICOM-RST"
+		);
+		let modified = &original
+			.get(tags::MODIFIED_ATTRIBUTES_SEQUENCE)
+			.unwrap()
+			.items()
+			.unwrap()[0];
+		assert_eq!(
+			modified.get(tags::PATIENT_ID).unwrap().to_str().unwrap(),
+			"123"
+		);
+		assert!(modified.get(tags::INSTITUTION_NAME).unwrap().is_empty());
+	}
+
+	#[test]
+	fn sets_values_of_binary_vrs() {
+		let mut object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::SAMPLES_PER_PIXEL,
+			VR::US,
+			PrimitiveValue::from(3_u16),
+		)]);
+		let rules = rules(json!([
+			{ "tag": "SamplesPerPixel", "map": { "3": "1" } },
+			{ "tag": "ImagerPixelSpacing", "set": "0.5\\0.5" },
+			{ "tag": "(0029,1010)", "vr": "SL", "set": "-1" },
+		]));
+
+		coerce(&mut object, &rules, "DICOM-RST");
+		let value = |tag| {
+			object
+				.get(tag)
+				.unwrap()
+				.value()
+				.primitive()
+				.unwrap()
+				.clone()
+		};
+		assert_eq!(value(tags::SAMPLES_PER_PIXEL), PrimitiveValue::from(1_u16));
+		assert_eq!(object.get(tags::IMAGER_PIXEL_SPACING).unwrap().vr(), VR::DS);
+		assert_eq!(value(Tag(0x0029, 0x1010)), PrimitiveValue::from(-1_i32));
+	}
+
+	#[test]
+	fn rejects_invalid_rules() {
+		for rule in [
+			json!({ "tag": "SamplesPerPixel", "set": "three" }),
+			json!({ "tag": "Rows", "set": "70000" }),
+			json!({ "tag": "StudyDate", "default": "yesterday" }),
+			json!({ "tag": "(0029,1010)", "set": "1" }),
+			json!({ "tag": "ReferencedSeriesSequence", "set": "1" }),
+			json!({ "tag": "PatientID", "vr": "XX", "set": "1" }),
+		] {
+			assert!(
+				serde_json::from_value::<CoercionRule>(rule.clone()).is_err(),
+				"{rule} should be rejected"
+			);
+		}
+	}
+
+	#[test]
+	fn records_nothing_without_changes() {
+		let mut object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::INSTITUTION_NAME,
+			VR::LO,
+			"Hospital",
+		)]);
+		let rules = rules(json!([{ "tag": "InstitutionName", "set": "Hospital" }]));
+
+		assert!(coerce(&mut object, &rules, "DICOM-RST").is_empty());
+		assert!(object.get(tags::ORIGINAL_ATTRIBUTES_SEQUENCE).is_none());
+	}
+}
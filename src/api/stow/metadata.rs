@@ -0,0 +1,515 @@
+//! Assembles instances from STOW-RS requests that contain metadata and bulk data parts.
+//!
+//! The metadata is sent as DICOM JSON, binary attributes (e.g. the pixel data) are replaced by a
+//! `BulkDataURI` that references a separate bulk data part by its `Content-Location` header.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_10.5.1.1>
+
+use crate::api::stow::StoreError;
+use bytes::Bytes;
+use dicom::core::ops::{AttributeSelector, AttributeSelectorStep};
+use dicom::core::value::{PixelFragmentSequence, Value};
+use dicom::core::{PrimitiveValue, Tag};
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::{TransferSyntax, TransferSyntaxIndex};
+use dicom::object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use std::collections::HashMap;
+use std::mem;
+
+/// The content of the bulk data parts with the same `Content-Location`.
+/// Compressed pixel data can be sent as one part per frame.
+#[derive(Debug)]
+struct BulkData {
+	transfer_syntax: Option<String>,
+	parts: Vec<Bytes>,
+}
+
+/// An attribute whose value is stored in a bulk data part.
+#[derive(Debug)]
+struct BulkDataReference {
+	steps: Vec<AttributeSelectorStep>,
+	uri: String,
+}
+
+impl BulkDataReference {
+	fn is_pixel_data(&self) -> bool {
+		self.steps == [AttributeSelectorStep::Tag(tags::PIXEL_DATA)]
+	}
+}
+
+/// A data set whose bulk data parts might not have been received yet.
+#[derive(Debug)]
+struct PendingInstance {
+	object: InMemDicomObject,
+	references: Vec<BulkDataReference>,
+}
+
+impl PendingInstance {
+	fn new(mut dataset: serde_json::Value) -> Result<Self, StoreError> {
+		let mut references = Vec::new();
+		take_bulk_data_uris(&mut dataset, &[], &mut references)?;
+		let object = dicom_json::from_value(dataset)
+			.map_err(|err| StoreError::InvalidMetadata(err.to_string()))?;
+		Ok(Self { object, references })
+	}
+
+	fn number_of_frames(&self) -> usize {
+		self.object
+			.get(tags::NUMBER_OF_FRAMES)
+			.and_then(|element| element.to_int::<usize>().ok())
+			.unwrap_or(1)
+	}
+}
+
+/// Collects the parts of a metadata and bulk data request.
+///
+/// As bulk data parts can precede or follow the metadata that references them,
+/// each instance is assembled as soon as all of its bulk data parts are received.
+/// Bulk data is dropped once it is no longer referenced by an instance that is not yet assembled.
+#[derive(Debug, Default)]
+pub struct MetadataParts {
+	pending: Vec<Result<PendingInstance, StoreError>>,
+	bulk_data: HashMap<String, BulkData>,
+}
+
+impl MetadataParts {
+	/// Adds a metadata part, which contains either a single data set or an array of data sets.
+	pub fn add_metadata(&mut self, data: &[u8]) -> Result<(), StoreError> {
+		let metadata: serde_json::Value = serde_json::from_slice(data)
+			.map_err(|err| StoreError::InvalidMetadata(err.to_string()))?;
+		let datasets = match metadata {
+			serde_json::Value::Array(datasets) => datasets,
+			dataset => vec![dataset],
+		};
+		// Each data set is handled independently, so that an invalid data set does not affect others.
+		self.pending
+			.extend(datasets.into_iter().map(PendingInstance::new));
+		Ok(())
+	}
+
+	/// Adds a bulk data part.
+	/// The transfer syntax is taken from the `transfer-syntax` parameter of its media type.
+	pub fn add_bulk_data(
+		&mut self,
+		location: String,
+		media_type: Option<&mime::Mime>,
+		data: Bytes,
+	) {
+		let transfer_syntax = media_type.and_then(|media_type| {
+			media_type
+				.get_param("transfer-syntax")
+				.map(|uid| uid.as_str().to_owned())
+				.or_else(|| default_transfer_syntax(media_type).map(str::to_owned))
+		});
+		self.bulk_data
+			.entry(location)
+			.or_insert_with(|| BulkData {
+				transfer_syntax,
+				parts: Vec::new(),
+			})
+			.parts
+			.push(data);
+	}
+
+	/// Assembles the instances whose bulk data parts were all received, in the order of their metadata.
+	/// Data sets with invalid metadata are returned as errors.
+	pub fn take_complete(&mut self) -> Vec<Result<FileDicomObject<InMemDicomObject>, StoreError>> {
+		let (complete, pending): (Vec<_>, Vec<_>) = mem::take(&mut self.pending)
+			.into_iter()
+			.partition(|instance| {
+				instance
+					.as_ref()
+					.map_or(true, |instance| self.is_complete(instance))
+			});
+		self.pending = pending;
+
+		complete
+			.into_iter()
+			.map(|instance| {
+				let instance = instance?;
+				let assembled = self.assemble(&instance);
+				self.release(&instance);
+				assembled
+			})
+			.collect()
+	}
+
+	/// Drops the bulk data of the assembled instance, unless it is referenced by a pending instance.
+	fn release(&mut self, instance: &PendingInstance) {
+		for reference in &instance.references {
+			let referenced = self
+				.pending
+				.iter()
+				.flatten()
+				.flat_map(|pending| &pending.references)
+				.any(|pending| pending.uri == reference.uri);
+			if !referenced {
+				self.bulk_data.remove(&reference.uri);
+			}
+		}
+	}
+
+	/// Assembles the remaining instances after all parts are received.
+	/// Instances with missing bulk data are returned as errors.
+	pub fn finish(mut self) -> Vec<Result<FileDicomObject<InMemDicomObject>, StoreError>> {
+		mem::take(&mut self.pending)
+			.into_iter()
+			.map(|instance| instance.and_then(|instance| self.assemble(&instance)))
+			.collect()
+	}
+
+	fn is_complete(&self, instance: &PendingInstance) -> bool {
+		let transfer_syntax = self.transfer_syntax(instance);
+		instance.references.iter().all(|reference| {
+			let Some(bulk_data) = self.bulk_data.get(&reference.uri) else {
+				return false;
+			};
+			// Errors of the transfer syntax are reported when the instance is assembled.
+			!reference.is_pixel_data()
+				|| !is_encapsulated(&transfer_syntax).unwrap_or(false)
+				|| fragments(
+					bulk_data,
+					reference,
+					instance.number_of_frames(),
+					&transfer_syntax,
+				)
+				.is_ok()
+		})
+	}
+
+	/// The transfer syntax of the bulk data part of the pixel data.
+	fn transfer_syntax(&self, instance: &PendingInstance) -> String {
+		instance
+			.references
+			.iter()
+			.find(|reference| reference.is_pixel_data())
+			.and_then(|reference| self.bulk_data.get(&reference.uri))
+			.and_then(|bulk_data| bulk_data.transfer_syntax.clone())
+			.unwrap_or_else(|| String::from(uids::EXPLICIT_VR_LITTLE_ENDIAN))
+	}
+
+	/// The value of the attribute, which is read from its bulk data parts.
+	fn value(
+		&self,
+		instance: &PendingInstance,
+		reference: &BulkDataReference,
+		transfer_syntax: &str,
+	) -> Result<Value<InMemDicomObject>, StoreError> {
+		let bulk_data = self.bulk_data.get(&reference.uri).ok_or_else(|| {
+			StoreError::InvalidMetadata(format!("missing bulk data part {}", reference.uri))
+		})?;
+		if reference.is_pixel_data() && is_encapsulated(transfer_syntax)? {
+			let fragments = fragments(
+				bulk_data,
+				reference,
+				instance.number_of_frames(),
+				transfer_syntax,
+			)?;
+			Ok(Value::PixelSequence(PixelFragmentSequence::new(
+				Vec::<u32>::new(),
+				fragments
+					.into_iter()
+					.map(<[u8]>::to_vec)
+					.collect::<Vec<_>>(),
+			)))
+		} else {
+			Ok(Value::Primitive(PrimitiveValue::from(
+				bulk_data.parts.concat(),
+			)))
+		}
+	}
+
+	fn assemble(
+		&self,
+		instance: &PendingInstance,
+	) -> Result<FileDicomObject<InMemDicomObject>, StoreError> {
+		let mut object = instance.object.clone();
+		let transfer_syntax = self.transfer_syntax(instance);
+
+		for reference in &instance.references {
+			let mut value = Some(self.value(instance, reference, &transfer_syntax)?);
+			let selector = AttributeSelector::new(reference.steps.clone()).ok_or_else(|| {
+				StoreError::InvalidMetadata(format!("invalid attribute for {}", reference.uri))
+			})?;
+			object
+				.update_value_at(selector, |current| {
+					if let Some(value) = value.take() {
+						*current = value;
+					}
+				})
+				.map_err(|err| StoreError::InvalidMetadata(err.to_string()))?;
+		}
+
+		object
+			.with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax))
+			.map_err(|err| StoreError::InvalidMetadata(err.to_string()))
+	}
+}
+
+fn is_encapsulated(transfer_syntax: &str) -> Result<bool, StoreError> {
+	TransferSyntaxRegistry
+		.get(transfer_syntax)
+		.map(TransferSyntax::is_encapsulated_pixel_data)
+		.ok_or_else(|| {
+			StoreError::InvalidMetadata(format!("unsupported transfer syntax {transfer_syntax}"))
+		})
+}
+
+/// Splits encapsulated pixel data into one fragment per frame.
+/// The frames are either sent as separate bulk data parts or concatenated in a single part.
+fn fragments<'a>(
+	bulk_data: &'a BulkData,
+	reference: &BulkDataReference,
+	frames: usize,
+	transfer_syntax: &str,
+) -> Result<Vec<&'a [u8]>, StoreError> {
+	let parts: Vec<&[u8]> = bulk_data.parts.iter().map(Bytes::as_ref).collect();
+	// A single frame (or video stream) may be split into multiple fragments.
+	if frames <= 1 || is_video(transfer_syntax) || parts.len() == frames {
+		return Ok(parts);
+	}
+	let fragments = match parts.as_slice() {
+		[part] => split_codestreams(part),
+		_ => parts,
+	};
+	if fragments.len() == frames {
+		Ok(fragments)
+	} else {
+		Err(StoreError::InvalidMetadata(format!(
+			"bulk data part {} contains {} of {frames} frames",
+			reference.uri,
+			fragments.len()
+		)))
+	}
+}
+
+/// Splits concatenated JPEG, JPEG-LS or JPEG 2000 codestreams (one per frame)
+/// at the end of image marker that is followed by the start of the next codestream.
+/// Within a codestream, 0xFFD9 only occurs as the end of image marker.
+fn split_codestreams(data: &[u8]) -> Vec<&[u8]> {
+	const END_OF_IMAGE: [u8; 2] = [0xFF, 0xD9];
+	const START_OF_IMAGE: [[u8; 2]; 2] = [[0xFF, 0xD8], [0xFF, 0x4F]];
+
+	let mut codestreams = Vec::new();
+	let mut start = 0;
+	for end in 2..data.len() {
+		let next = &data[end..];
+		if data[end - 2..end] == END_OF_IMAGE
+			&& START_OF_IMAGE.iter().any(|marker| next.starts_with(marker))
+		{
+			codestreams.push(&data[start..end]);
+			start = end;
+		}
+	}
+	if start < data.len() {
+		codestreams.push(&data[start..]);
+	}
+	codestreams
+}
+
+/// MPEG-2, MPEG-4 AVC/H.264 and HEVC/H.265 encode all frames in a single stream.
+fn is_video(transfer_syntax: &str) -> bool {
+	transfer_syntax.starts_with("1.2.840.10008.1.2.4.10")
+}
+
+/// Removes the `BulkDataURI` of all attributes (including nested ones) and collects them.
+/// The attributes remain in the data set without a value.
+fn take_bulk_data_uris(
+	dataset: &mut serde_json::Value,
+	parents: &[AttributeSelectorStep],
+	references: &mut Vec<BulkDataReference>,
+) -> Result<(), StoreError> {
+	let Some(dataset) = dataset.as_object_mut() else {
+		return Err(StoreError::InvalidMetadata(String::from(
+			"data set is not a JSON object",
+		)));
+	};
+
+	for (key, element) in dataset {
+		let tag = parse_tag(key)
+			.ok_or_else(|| StoreError::InvalidMetadata(format!("invalid tag {key}")))?;
+		let Some(element) = element.as_object_mut() else {
+			continue;
+		};
+
+		if let Some(uri) = element.remove("BulkDataURI") {
+			let uri = uri.as_str().map(str::to_owned).ok_or_else(|| {
+				StoreError::InvalidMetadata(format!("invalid BulkDataURI for {key}"))
+			})?;
+			let mut steps = parents.to_vec();
+			steps.push(AttributeSelectorStep::Tag(tag));
+			references.push(BulkDataReference { steps, uri });
+		} else if let Some(serde_json::Value::Array(items)) = element.get_mut("Value") {
+			for (index, item) in items.iter_mut().enumerate() {
+				if !item.is_object() {
+					continue;
+				}
+				let mut steps = parents.to_vec();
+				steps.push(AttributeSelectorStep::Nested {
+					tag,
+					item: u32::try_from(index).unwrap_or(u32::MAX),
+				});
+				take_bulk_data_uris(item, &steps, references)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+fn parse_tag(key: &str) -> Option<Tag> {
+	if key.len() != 8 {
+		return None;
+	}
+	let group = u16::from_str_radix(&key[0..4], 16).ok()?;
+	let element = u16::from_str_radix(&key[4..8], 16).ok()?;
+	Some(Tag(group, element))
+}
+
+/// The default transfer syntax for compressed bulk data media types without a `transfer-syntax`.
+/// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_8.7.3-2>
+fn default_transfer_syntax(media_type: &mime::Mime) -> Option<&'static str> {
+	match media_type.essence_str() {
+		"image/jpeg" => Some(uids::JPEG_BASELINE8_BIT),
+		"image/jls" => Some(uids::JPEGLS_LOSSLESS),
+		"image/jp2" => Some(uids::JPEG2000_LOSSLESS),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const METADATA: &str = r#"[{
+		"00080016": { "vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.7"] },
+		"00080018": { "vr": "UI", "Value": ["1.2.3.4"] },
+		"0020000D": { "vr": "UI", "Value": ["1.2.3"] },
+		"7FE00010": { "vr": "OB", "BulkDataURI": "http://localhost/bulk/1" }
+	}]"#;
+
+	#[test]
+	fn assembles_instance_once_bulk_data_is_received() {
+		let mut parts = MetadataParts::default();
+		parts.add_metadata(METADATA.as_bytes()).unwrap();
+		assert!(parts.take_complete().is_empty());
+
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::APPLICATION_OCTET_STREAM),
+			Bytes::from_static(&[1, 2, 3, 4]),
+		);
+		let instances = parts.take_complete();
+		assert_eq!(instances.len(), 1);
+		let instance = instances[0].as_ref().unwrap();
+		assert_eq!(
+			instance.meta().transfer_syntax(),
+			uids::EXPLICIT_VR_LITTLE_ENDIAN
+		);
+		assert_eq!(instance.meta().media_storage_sop_instance_uid(), "1.2.3.4");
+		assert_eq!(
+			instance.get(tags::PIXEL_DATA).unwrap().to_bytes().unwrap(),
+			&[1, 2, 3, 4][..]
+		);
+		assert!(parts.bulk_data.is_empty());
+		assert!(parts.finish().is_empty());
+	}
+
+	#[test]
+	fn encapsulates_compressed_pixel_data() {
+		let mut parts = MetadataParts::default();
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::IMAGE_JPEG),
+			Bytes::from_static(&[0xFF, 0xD8, 0xFF, 0xD9]),
+		);
+		parts.add_metadata(METADATA.as_bytes()).unwrap();
+
+		let instances = parts.take_complete();
+		let instance = instances[0].as_ref().unwrap();
+		assert_eq!(instance.meta().transfer_syntax(), uids::JPEG_BASELINE8_BIT);
+		let fragments = instance.get(tags::PIXEL_DATA).unwrap().fragments().unwrap();
+		assert_eq!(fragments.len(), 1);
+	}
+
+	const MULTI_FRAME_METADATA: &str = r#"{
+		"00080016": { "vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.7"] },
+		"00080018": { "vr": "UI", "Value": ["1.2.3.4"] },
+		"00280008": { "vr": "IS", "Value": [2] },
+		"7FE00010": { "vr": "OB", "BulkDataURI": "http://localhost/bulk/1" }
+	}"#;
+
+	#[test]
+	fn splits_concatenated_frames() {
+		let mut parts = MetadataParts::default();
+		parts.add_metadata(MULTI_FRAME_METADATA.as_bytes()).unwrap();
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::IMAGE_JPEG),
+			Bytes::from_static(&[0xFF, 0xD8, 0x01, 0xFF, 0xD9, 0xFF, 0xD8, 0x02, 0xFF, 0xD9]),
+		);
+
+		let instances = parts.take_complete();
+		let fragments = instances[0]
+			.as_ref()
+			.unwrap()
+			.get(tags::PIXEL_DATA)
+			.unwrap()
+			.fragments()
+			.unwrap();
+		assert_eq!(fragments.len(), 2);
+		assert_eq!(fragments[1][..4], [0xFF, 0xD8, 0x02, 0xFF]);
+	}
+
+	#[test]
+	fn waits_for_a_part_per_frame() {
+		let mut parts = MetadataParts::default();
+		parts.add_metadata(MULTI_FRAME_METADATA.as_bytes()).unwrap();
+		let frame = Bytes::from_static(&[0xFF, 0xD8, 0x01, 0xFF, 0xD9]);
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::IMAGE_JPEG),
+			frame.clone(),
+		);
+		assert!(parts.take_complete().is_empty());
+
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::IMAGE_JPEG),
+			frame,
+		);
+		let instances = parts.take_complete();
+		let element = instances[0]
+			.as_ref()
+			.unwrap()
+			.get(tags::PIXEL_DATA)
+			.unwrap();
+		assert_eq!(element.fragments().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn rejects_missing_frames() {
+		let mut parts = MetadataParts::default();
+		parts.add_metadata(MULTI_FRAME_METADATA.as_bytes()).unwrap();
+		parts.add_bulk_data(
+			String::from("http://localhost/bulk/1"),
+			Some(&mime::IMAGE_JPEG),
+			Bytes::from_static(&[0xFF, 0xD8, 0x01, 0xFF, 0xD9]),
+		);
+		assert!(parts.take_complete().is_empty());
+		assert!(matches!(
+			parts.finish().as_slice(),
+			[Err(StoreError::InvalidMetadata(_))]
+		));
+	}
+
+	#[test]
+	fn rejects_missing_bulk_data() {
+		let mut parts = MetadataParts::default();
+		parts.add_metadata(METADATA.as_bytes()).unwrap();
+		assert!(matches!(
+			parts.finish().as_slice(),
+			[Err(StoreError::InvalidMetadata(_))]
+		));
+	}
+}
@@ -1,5 +1,8 @@
+mod coercion;
+mod metadata;
 mod routes;
 mod service;
+mod validation;
 
 pub use routes::routes;
 pub use service::*;
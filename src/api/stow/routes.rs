@@ -1,17 +1,35 @@
-use crate::api::stow::{StoreError, StoreRequest};
+use crate::api::qido::retrieve::retrieve_url;
+use crate::api::qido::ResourceQuery;
+use crate::api::stow::coercion::coerce;
+use crate::api::stow::metadata::MetadataParts;
+use crate::api::stow::validation::validate;
+use crate::api::stow::{
+	InstanceReference, Rejected, StoreError, StoreRequest, FAILURE_CANNOT_UNDERSTAND,
+	FAILURE_STUDY_MISMATCH, WARNING_COERCION, WARNING_VALIDATION,
+};
 use crate::backend::ServiceProvider;
+use crate::config::{Service, StowConfig, ValidationPolicy};
+use crate::types::{QueryRetrieveLevel, AE, UI};
 use crate::utils::multipart::DicomMultipart;
 use crate::AppState;
 use axum::body::Body;
-use axum::http::header::CONTENT_TYPE;
+use axum::extract::{Path, State};
+use axum::http::header::{CONTENT_LOCATION, CONTENT_TYPE};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::post;
 use axum::Router;
 use bytes::Buf;
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
 use dicom::object::{FileDicomObject, InMemDicomObject};
 use dicom_json::DicomJson;
-use tracing::instrument;
+use futures::stream::BoxStream;
+use futures::{future, stream, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::{instrument, warn};
 
 /// HTTP Router for the Store Transaction
 /// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_10.5>
@@ -24,7 +42,76 @@ pub fn routes() -> Router<AppState> {
 #[instrument(skip_all)]
 async fn studies(
 	provider: ServiceProvider,
-	mut multipart: DicomMultipart<'static>,
+	State(state): State<AppState>,
+	Path(aet): Path<AE>,
+	multipart: DicomMultipart<'static>,
+) -> Result<Response, StoreError> {
+	let config = stow_config(&state, &aet);
+	let server_aet = state.config.server.aet.clone();
+	store(provider, None, None, config, server_aet, multipart).await
+}
+
+#[derive(Deserialize)]
+struct StudyPath {
+	aet: AE,
+	study: UI,
+}
+
+#[instrument(skip_all)]
+async fn study(
+	provider: ServiceProvider,
+	State(state): State<AppState>,
+	Path(StudyPath { aet, study }): Path<StudyPath>,
+	multipart: DicomMultipart<'static>,
+) -> Result<Response, StoreError> {
+	let retrieve_url = state
+		.config
+		.server
+		.http
+		.public_url()
+		.ok()
+		.and_then(|base_url| {
+			let query = ResourceQuery {
+				query_retrieve_level: QueryRetrieveLevel::Study,
+				study_instance_uid: Some(study.clone()),
+				series_instance_uid: None,
+			};
+			retrieve_url(&base_url, &aet, &query, &InMemDicomObject::new_empty())
+		});
+	let config = stow_config(&state, &aet);
+	let server_aet = state.config.server.aet.clone();
+
+	store(
+		provider,
+		Some(study),
+		retrieve_url,
+		config,
+		server_aet,
+		multipart,
+	)
+	.await
+}
+
+/// Composite AETs use the settings of the AET that provides their STOW-RS service.
+fn stow_config(state: &AppState, aet: &str) -> StowConfig {
+	state
+		.config
+		.service_aet(aet, Service::Stow)
+		.map_or_else(StowConfig::default, |ae_config| ae_config.stow.clone())
+}
+
+/// Stores the instances of the multipart request.
+/// The configured coercion rules are applied to each instance before it is checked and stored,
+/// and recorded with the AE title of the server as the modifying system.
+/// If a study is given, instances of other studies are not stored and reported as failed.
+/// Instances with validation problems are rejected or stored with a warning, depending on the policy.
+async fn store(
+	provider: ServiceProvider,
+	study: Option<UI>,
+	retrieve_url: Option<String>,
+	config: StowConfig,
+	server_aet: String,
+	multipart: DicomMultipart<'static>,
 ) -> Result<Response, StoreError> {
 	let Some(stow) = provider.stow else {
 		return Ok((
@@ -34,26 +121,342 @@ async fn studies(
 			.into_response());
 	};
 
-	let mut instances = Vec::new();
+	let rejected: Arc<Mutex<Rejected>> = Arc::default();
+	// Instances that are passed to the backend, but reported with a warning.
+	let warnings: Arc<Warnings> = Arc::default();
+	let instances = {
+		let rejected = Arc::clone(&rejected);
+		let warnings = Arc::clone(&warnings);
+		read_instances(multipart)?
+			.filter_map(move |mut result| {
+				if let Ok(file) = &mut result {
+					if !coerce(file, &config.coercion, &server_aet).is_empty() {
+						add_warning(&warnings, file, WARNING_COERCION, Vec::new());
+					}
+				}
+
+				let rejection = match &result {
+					Ok(file) => {
+						if study
+							.as_deref()
+							.is_some_and(|study| !belongs_to_study(file, study))
+						{
+							Some(failed_instance(file, FAILURE_STUDY_MISMATCH, Vec::new()))
+						} else {
+							match (
+								config.validation,
+								validation_problems(file, config.validation),
+							) {
+								(_, problems) if problems.is_empty() => None,
+								// The attributes with problems tell validation failures apart
+								// from instances that could not be parsed.
+								(ValidationPolicy::Reject, problems) => {
+									Some(failed_instance(file, FAILURE_CANNOT_UNDERSTAND, problems))
+								}
+								(_, problems) => {
+									add_warning(&warnings, file, WARNING_VALIDATION, problems);
+									None
+								}
+							}
+						}
+					}
+					Err(err @ (StoreError::ReadDicomFile(_) | StoreError::InvalidMetadata(_))) => {
+						warn!("Failed to read instance: {err}");
+						rejected
+							.lock()
+							.unwrap_or_else(PoisonError::into_inner)
+							.errors
+							.push(err.to_string());
+						return future::ready(None);
+					}
+					Err(_) => None,
+				};
+
+				if let Some(rejection) = rejection {
+					rejected
+						.lock()
+						.unwrap_or_else(PoisonError::into_inner)
+						.failed_sequence
+						.push(rejection);
+					return future::ready(None);
+				}
+				future::ready(Some(result))
+			})
+			.boxed()
+	};
 
-	while let Some(field) = multipart.next_field().await? {
-		let data = field.bytes().await?;
-		let file = FileDicomObject::from_reader(data.reader())?;
-		instances.push(file);
+	let mut response = stow
+		.store(StoreRequest {
+			instances,
+			rejected,
+		})
+		.await?;
+	let mut warnings = warnings.lock().unwrap_or_else(PoisonError::into_inner);
+	for referenced in &mut response.referenced_sequence {
+		if let Some((warning_reason, problems)) = warnings.remove(&referenced.sop_instance_uid) {
+			referenced.warning_reason.get_or_insert(warning_reason);
+			referenced.failure_attributes.extend(problems);
+		}
 	}
+	drop(warnings);
+	response.retrieve_url = retrieve_url;
 
-	let request = StoreRequest { instances };
-	let response = stow.store(request).await?;
+	let status = response.status();
 	let json = DicomJson::from(InMemDicomObject::from(response));
 
 	Ok(Response::builder()
-		.status(StatusCode::OK)
+		.status(status)
 		.header(CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
 		.body(Body::from(serde_json::to_string(&json).unwrap()))
 		.unwrap())
 }
 
-#[instrument(skip_all)]
-async fn study() -> impl IntoResponse {
-	StatusCode::NOT_IMPLEMENTED
+/// The `WarningReason` and `FailureAttributes` of instances, by their SOP Instance UID.
+type Warnings = Mutex<HashMap<UI, (u16, Vec<Tag>)>>;
+
+/// Records a warning for the instance. If there are multiple warnings, the first reason is kept.
+fn add_warning(
+	warnings: &Warnings,
+	file: &FileDicomObject<InMemDicomObject>,
+	warning_reason: u16,
+	failure_attributes: Vec<Tag>,
+) {
+	warnings
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.entry(UI::from(file.meta().media_storage_sop_instance_uid()))
+		.or_insert((warning_reason, Vec::new()))
+		.1
+		.extend(failure_attributes);
+}
+
+/// Validates the instance, unless validation is disabled.
+/// Returns the attributes with problems, which are logged.
+fn validation_problems(
+	file: &FileDicomObject<InMemDicomObject>,
+	validation: ValidationPolicy,
+) -> Vec<Tag> {
+	if validation == ValidationPolicy::Off {
+		return Vec::new();
+	}
+
+	let mut failure_attributes: Vec<Tag> = Vec::new();
+	for problem in validate(file) {
+		warn!(
+			sop_instance_uid = file.meta().media_storage_sop_instance_uid(),
+			"Validation problem: {problem}"
+		);
+		if !failure_attributes.contains(&problem.tag) {
+			failure_attributes.push(problem.tag);
+		}
+	}
+	failure_attributes
+}
+
+fn failed_instance(
+	file: &FileDicomObject<InMemDicomObject>,
+	failure_reason: u16,
+	failure_attributes: Vec<Tag>,
+) -> InstanceReference {
+	InstanceReference {
+		sop_class_uid: UI::from(file.meta().media_storage_sop_class_uid()),
+		sop_instance_uid: UI::from(file.meta().media_storage_sop_instance_uid()),
+		failure_reason: Some(failure_reason),
+		warning_reason: None,
+		failure_attributes,
+	}
+}
+
+/// Reads the instances of the multipart request, depending on the media type of the root part.
+fn read_instances(
+	multipart: DicomMultipart<'static>,
+) -> Result<BoxStream<'static, Result<FileDicomObject<InMemDicomObject>, StoreError>>, StoreError> {
+	match multipart.root_type() {
+		None | Some("application/dicom") => Ok(read_dicom_instances(multipart).boxed()),
+		Some("application/dicom+json") => Ok(read_metadata_instances(multipart).boxed()),
+		Some(root_type) => Err(StoreError::UnsupportedMediaType(root_type.to_owned())),
+	}
+}
+
+/// Parses the parts of the multipart request one after another.
+/// Only the part that is currently parsed is kept in memory.
+/// Parts that are not valid DICOM files or exceed the upload limit are returned as errors
+/// without ending the stream. Other errors end the stream, as the following parts cannot be read.
+fn read_dicom_instances(
+	multipart: DicomMultipart<'static>,
+) -> impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, StoreError>> {
+	stream::unfold(Some(multipart), |multipart| async move {
+		let mut multipart = multipart?;
+		let field = match multipart.next_field().await {
+			Ok(Some(field)) => field,
+			Ok(None) => return None,
+			Err(err) => return Some((Err(err.into()), None)),
+		};
+		let data = match multipart.read_field(field).await {
+			Ok(data) => data,
+			Err(err @ multer::Error::FieldSizeExceeded { .. }) => {
+				return Some((Err(err.into()), Some(multipart)));
+			}
+			Err(err) => return Some((Err(err.into()), None)),
+		};
+		let file = FileDicomObject::from_reader(data.reader()).map_err(StoreError::from);
+		Some((file, Some(multipart)))
+	})
+}
+
+/// Reads the metadata and bulk data parts of the multipart request.
+/// Each instance is returned as soon as all of its bulk data parts are received,
+/// the remaining instances once the request body is read completely.
+fn read_metadata_instances(
+	multipart: DicomMultipart<'static>,
+) -> impl Stream<Item = Result<FileDicomObject<InMemDicomObject>, StoreError>> {
+	let state = Some((multipart, MetadataParts::default()));
+	stream::unfold(state, |state| async move {
+		let (mut multipart, mut parts) = state?;
+		loop {
+			let field = match multipart.next_field().await {
+				Ok(Some(field)) => field,
+				Ok(None) => return Some((parts.finish(), None)),
+				Err(err) => {
+					let mut instances = parts.finish();
+					instances.push(Err(err.into()));
+					return Some((instances, None));
+				}
+			};
+			match read_metadata_part(&multipart, &mut parts, field).await {
+				Ok(()) => {}
+				// The following parts cannot be read.
+				Err(err @ (StoreError::Stream(_) | StoreError::RequestLimitExceeded)) => {
+					let mut instances = parts.finish();
+					instances.push(Err(err));
+					return Some((instances, None));
+				}
+				Err(err) => return Some((vec![Err(err)], Some((multipart, parts)))),
+			}
+
+			let instances = parts.take_complete();
+			if !instances.is_empty() {
+				return Some((instances, Some((multipart, parts))));
+			}
+		}
+	})
+	.flat_map(stream::iter)
+}
+
+/// Adds a metadata or bulk data part.
+async fn read_metadata_part(
+	multipart: &DicomMultipart<'static>,
+	parts: &mut MetadataParts,
+	field: multer::Field<'static>,
+) -> Result<(), StoreError> {
+	let media_type = field.content_type().cloned();
+	let location = field
+		.headers()
+		.get(CONTENT_LOCATION)
+		.and_then(|location| location.to_str().ok())
+		.map(str::to_owned);
+	let data = multipart.read_field(field).await?;
+
+	match (media_type, location) {
+		(Some(media_type), _) if media_type.essence_str() == "application/dicom+json" => {
+			parts.add_metadata(&data)
+		}
+		(media_type, Some(location)) => {
+			parts.add_bulk_data(location, media_type.as_ref(), data);
+			Ok(())
+		}
+		(_, None) => Err(StoreError::InvalidMetadata(String::from(
+			"bulk data part without Content-Location",
+		))),
+	}
+}
+
+/// Checks if the `StudyInstanceUID` of the instance matches the study of the request.
+fn belongs_to_study(instance: &InMemDicomObject, study: &str) -> bool {
+	instance
+		.get(tags::STUDY_INSTANCE_UID)
+		.and_then(|element| element.to_str().ok())
+		.is_some_and(|uid| uid.trim_end_matches(['\0', ' ']) == study)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::memory::stow::MemoryStowService;
+	use crate::backend::memory::MemoryStore;
+	use crate::backend::tests::instance;
+	use dicom::core::{DataElement, VR};
+	use dicom::object::mem::InMemElement;
+
+	#[test]
+	fn matches_study_instance_uid() {
+		let instance = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::STUDY_INSTANCE_UID,
+			VR::UI,
+			"1.2.3\0",
+		)]);
+		assert!(belongs_to_study(&instance, "1.2.3"));
+		assert!(!belongs_to_study(&instance, "1.2.4"));
+		assert!(!belongs_to_study(&InMemDicomObject::new_empty(), "1.2.3"));
+	}
+
+	#[tokio::test]
+	async fn keeps_rejected_instances_if_a_later_part_fails() {
+		let mut mismatched = Vec::new();
+		instance("1.2.4", "1.2.4.1", "1.2.4.1.1")
+			.write_all(&mut mismatched)
+			.unwrap();
+		let oversized = vec![0; 2048];
+		let mut body = Vec::new();
+		for part in [mismatched, oversized] {
+			body.extend_from_slice(b"--boundary\r\nContent-Type: application/dicom\r\n\r\n");
+			body.extend_from_slice(&part);
+			body.extend_from_slice(b"\r\n");
+		}
+		body.extend_from_slice(b"--boundary--\r\n");
+		let multipart =
+			DicomMultipart::new(Body::from(body), String::from("boundary"), None, 1024, None);
+		let provider = ServiceProvider {
+			qido: None,
+			wado: None,
+			stow: Some(Box::new(MemoryStowService::new(Arc::new(
+				MemoryStore::new(),
+			)))),
+			mwl: None,
+		};
+
+		let response = store(
+			provider,
+			Some(UI::from("1.2.3")),
+			None,
+			StowConfig::default(),
+			String::from("DICOM-RST"),
+			multipart,
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let object: InMemDicomObject = dicom_json::from_slice(&body).unwrap();
+		let failed = object
+			.get(tags::FAILED_SOP_SEQUENCE)
+			.and_then(InMemElement::items)
+			.unwrap();
+		assert_eq!(failed.len(), 1);
+		assert_eq!(
+			failed[0]
+				.get(tags::FAILURE_REASON)
+				.unwrap()
+				.to_int::<u16>()
+				.unwrap(),
+			FAILURE_STUDY_MISMATCH
+		);
+		assert_eq!(
+			object.get(tags::ERROR_COMMENT).unwrap().to_str().unwrap(),
+			StoreError::UploadLimitExceeded.to_string()
+		);
+	}
 }
@@ -4,28 +4,117 @@ use axum::body::Body;
 use axum::http::{Response, StatusCode};
 use axum::response::IntoResponse;
 use dicom::core::value::{DataSetSequence, Value};
-use dicom::core::{DataElement, VR};
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
 use dicom::dicom_value;
 use dicom::dictionary_std::tags;
 use dicom::object::mem::InMemElement;
 use dicom::object::{FileDicomObject, InMemDicomObject};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::sync::{Arc, Mutex, PoisonError};
 use thiserror::Error;
+use tracing::warn;
 
 pub struct StoreRequest {
-	pub instances: Vec<FileDicomObject<InMemDicomObject>>,
+	/// The instances in the order they are read from the request body.
+	/// Instances are parsed lazily, so that they can be stored as soon as they are received.
+	pub instances: BoxStream<'static, Result<FileDicomObject<InMemDicomObject>, StoreError>>,
+	/// The instances and parts that are rejected while the instances are read,
+	/// which are added to the response by the backend.
+	pub rejected: Arc<Mutex<Rejected>>,
 }
 
+/// Instances of a store request that are not passed to the backend, and parts that could not be
+/// read as an instance.
+#[derive(Debug, Default)]
+pub struct Rejected {
+	pub failed_sequence: Vec<InstanceReference>,
+	pub errors: Vec<String>,
+}
+
+/// The instance does not belong to the study of a study-targeted store request.
+/// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_10.5.3-4>
+pub const FAILURE_STUDY_MISMATCH: u16 = 0xA900;
+/// The instance could not be stored for another reason (e.g. the origin server is unavailable).
+pub const FAILURE_PROCESSING: u16 = 0x0110;
+/// The part could not be parsed as an instance, or the instance failed validation.
+/// Validation failures are told apart by their `FailureAttributes`.
+pub const FAILURE_CANNOT_UNDERSTAND: u16 = 0xC000;
+/// The instance was stored, but some of its attributes were coerced.
+/// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_10.5.3-3>
+pub const WARNING_COERCION: u16 = 0xB000;
+/// The instance was stored, although it failed validation.
+/// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_10.5.3-3>
+pub const WARNING_VALIDATION: u16 = 0xB007;
+
 /// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#table_10-11>
 #[derive(Debug)]
 pub struct InstanceReference {
 	pub sop_class_uid: UI,
 	pub sop_instance_uid: UI,
+	/// The `FailureReason` (0008,1197) of an instance in the `FailedSOPSequence`.
+	pub failure_reason: Option<u16>,
+	/// The `WarningReason` (0008,1196) of an instance in the `ReferencedSOPSequence`.
+	pub warning_reason: Option<u16>,
+	/// The `FailureAttributes` (2200,000E) that caused the failure or warning, if known.
+	pub failure_attributes: Vec<Tag>,
 }
 
 #[derive(Debug, Default)]
 pub struct StoreResponse {
+	/// The `RetrieveURL` (0008,1190) of the study the instances were stored in.
+	pub retrieve_url: Option<String>,
 	pub failed_sequence: Vec<InstanceReference>,
 	pub referenced_sequence: Vec<InstanceReference>,
+	/// The errors of parts that could not be read as an instance,
+	/// which are returned in the `ErrorComment` (0000,0902).
+	pub errors: Vec<String>,
+}
+
+impl StoreResponse {
+	/// The HTTP status code of the response.
+	/// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_10.5.3>
+	pub fn status(&self) -> StatusCode {
+		let has_warnings = self
+			.referenced_sequence
+			.iter()
+			.any(|referenced| referenced.warning_reason.is_some());
+
+		if self.failed_sequence.is_empty() && self.errors.is_empty() && !has_warnings {
+			StatusCode::OK
+		} else if self.referenced_sequence.is_empty() {
+			StatusCode::CONFLICT
+		} else {
+			StatusCode::ACCEPTED
+		}
+	}
+
+	/// Adds the instances and parts that were rejected so far.
+	/// They have to be added before [`Self::add_failed_part`], so that they count as processed.
+	pub fn add_rejected(&mut self, rejected: &Mutex<Rejected>) {
+		let mut rejected = rejected.lock().unwrap_or_else(PoisonError::into_inner);
+		self.failed_sequence.append(&mut rejected.failed_sequence);
+		self.errors.append(&mut rejected.errors);
+	}
+
+	/// Records a part of the request that could not be read (e.g. as it exceeds the upload limit)
+	/// in the [errors](Self::errors), so that the references to the instances that were already
+	/// processed are not lost.
+	/// As the part has no SOP Instance UID, it is not added to the `FailedSOPSequence`.
+	///
+	/// If nothing was processed yet, the error is returned instead, so that the request fails
+	/// with the status code of the error (e.g. `413 Payload Too Large`).
+	pub fn add_failed_part(&mut self, err: StoreError) -> Result<(), StoreError> {
+		if self.referenced_sequence.is_empty()
+			&& self.failed_sequence.is_empty()
+			&& self.errors.is_empty()
+		{
+			return Err(err);
+		}
+		warn!("Failed to read part: {err}");
+		self.errors.push(err.to_string());
+		Ok(())
+	}
 }
 
 impl From<StoreResponse> for InMemDicomObject {
@@ -46,7 +135,7 @@ impl From<StoreResponse> for InMemDicomObject {
 		let failed_items = failed_sequence.items_mut().expect("Sequence exists");
 
 		for referenced in response.referenced_sequence {
-			let item = Self::from_element_iter([
+			let mut item = Self::from_element_iter([
 				DataElement::new(
 					tags::REFERENCED_SOP_INSTANCE_UID,
 					VR::UI,
@@ -58,11 +147,19 @@ impl From<StoreResponse> for InMemDicomObject {
 					dicom_value!(Str, referenced.sop_class_uid),
 				),
 			]);
+			if let Some(warning_reason) = referenced.warning_reason {
+				item.put(DataElement::new(
+					tags::WARNING_REASON,
+					VR::US,
+					dicom_value!(U16, warning_reason),
+				));
+			}
+			put_failure_attributes(&mut item, referenced.failure_attributes);
 			referenced_items.push(item);
 		}
 
 		for failed in response.failed_sequence {
-			let item = Self::from_element_iter([
+			let mut item = Self::from_element_iter([
 				DataElement::new(
 					tags::REFERENCED_SOP_INSTANCE_UID,
 					VR::UI,
@@ -74,29 +171,140 @@ impl From<StoreResponse> for InMemDicomObject {
 					dicom_value!(Str, failed.sop_class_uid),
 				),
 			]);
+			if let Some(failure_reason) = failed.failure_reason {
+				item.put(DataElement::new(
+					tags::FAILURE_REASON,
+					VR::US,
+					dicom_value!(U16, failure_reason),
+				));
+			}
+			put_failure_attributes(&mut item, failed.failure_attributes);
 			failed_items.push(item);
 		}
 
+		if let Some(retrieve_url) = response.retrieve_url {
+			object.put(DataElement::new(tags::RETRIEVE_URL, VR::UR, retrieve_url));
+		}
+		if !response.errors.is_empty() {
+			object.put(DataElement::new(
+				tags::ERROR_COMMENT,
+				VR::LO,
+				response.errors.join("; "),
+			));
+		}
 		object.put(referenced_sequence);
 		object.put(failed_sequence);
 		object
 	}
 }
 
+fn put_failure_attributes(item: &mut InMemDicomObject, failure_attributes: Vec<Tag>) {
+	if !failure_attributes.is_empty() {
+		item.put(DataElement::new(
+			tags::FAILURE_ATTRIBUTES,
+			VR::AT,
+			PrimitiveValue::Tags(failure_attributes.into()),
+		));
+	}
+}
+
 /// <https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_10.5.1-1>
 #[async_trait]
 pub trait StowService: Sync + Send {
 	async fn store(&self, request: StoreRequest) -> Result<StoreResponse, StoreError>;
 }
 
+/// The result of storing a single instance.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StoreOutcome {
+	Stored,
+	/// The instance was stored with the `WarningReason`.
+	Warning(u16),
+	/// The instance was not stored for the `FailureReason`.
+	Failed(u16),
+}
+
+/// A backend that stores one instance at a time.
+///
+/// Each instance store is a [`StowService`], which stores the instances of a request as soon as
+/// they are read, up to [`InstanceStore::concurrency`] at a time.
+/// The references are returned in the order of the request.
+#[async_trait]
+pub trait InstanceStore: Sync + Send {
+	/// The maximum number of instances that are stored at a time.
+	fn concurrency(&self) -> usize {
+		1
+	}
+
+	async fn store_instance(&self, instance: FileDicomObject<InMemDicomObject>) -> StoreOutcome;
+}
+
+#[async_trait]
+impl<T: InstanceStore> StowService for T {
+	async fn store(&self, request: StoreRequest) -> Result<StoreResponse, StoreError> {
+		let mut response = StoreResponse::default();
+
+		let rejected = request.rejected;
+		let mut results = request
+			.instances
+			.map(|instance| async move {
+				let instance = instance?;
+				let reference = InstanceReference {
+					sop_class_uid: UI::from(instance.meta().media_storage_sop_class_uid()),
+					sop_instance_uid: UI::from(instance.meta().media_storage_sop_instance_uid()),
+					failure_reason: None,
+					warning_reason: None,
+					failure_attributes: Vec::new(),
+				};
+				let outcome = self.store_instance(instance).await;
+				Ok((reference, outcome))
+			})
+			// At least one instance must be stored at a time to make progress.
+			.buffered(self.concurrency().max(1));
+
+		while let Some(result) = results.next().await {
+			match result {
+				Ok((reference, StoreOutcome::Stored)) => {
+					response.referenced_sequence.push(reference);
+				}
+				Ok((reference, StoreOutcome::Warning(warning_reason))) => {
+					response.referenced_sequence.push(InstanceReference {
+						warning_reason: Some(warning_reason),
+						..reference
+					});
+				}
+				Ok((reference, StoreOutcome::Failed(failure_reason))) => {
+					response.failed_sequence.push(InstanceReference {
+						failure_reason: Some(failure_reason),
+						..reference
+					});
+				}
+				Err(err) => {
+					response.add_rejected(&rejected);
+					response.add_failed_part(err)?;
+				}
+			}
+		}
+		response.add_rejected(&rejected);
+
+		Ok(response)
+	}
+}
+
 #[derive(Debug, Error)]
 pub enum StoreError {
 	#[error("The file exceeds the configured upload size limit")]
 	UploadLimitExceeded,
-	#[error(transparent)]
+	#[error("The request exceeds the configured request size limit, later parts were not read")]
+	RequestLimitExceeded,
+	#[error("Failed to read multipart stream: {0}")]
 	Stream(multer::Error),
 	#[error(transparent)]
 	ReadDicomFile(#[from] dicom::object::ReadError),
+	#[error("Invalid metadata: {0}")]
+	InvalidMetadata(String),
+	#[error("Unsupported media type {0}")]
+	UnsupportedMediaType(String),
 }
 
 impl IntoResponse for StoreError {
@@ -106,6 +314,10 @@ impl IntoResponse for StoreError {
 				.status(StatusCode::PAYLOAD_TOO_LARGE)
 				.body(Body::from("Upload limit exceeded"))
 				.unwrap(),
+			Self::RequestLimitExceeded => Response::builder()
+				.status(StatusCode::PAYLOAD_TOO_LARGE)
+				.body(Body::from("Request limit exceeded"))
+				.unwrap(),
 			Self::Stream(err) => Response::builder()
 				.status(StatusCode::BAD_REQUEST)
 				.body(Body::from(format!(
@@ -116,12 +328,26 @@ impl IntoResponse for StoreError {
 				.status(StatusCode::BAD_REQUEST)
 				.body(Body::from(format!("Failed to read DICOM file: {err:#}")))
 				.unwrap(),
+			Self::InvalidMetadata(err) => Response::builder()
+				.status(StatusCode::BAD_REQUEST)
+				.body(Body::from(format!("Invalid metadata: {err}")))
+				.unwrap(),
+			Self::UnsupportedMediaType(media_type) => Response::builder()
+				.status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+				.body(Body::from(format!("Unsupported media type {media_type}")))
+				.unwrap(),
 		}
 	}
 }
 
 impl From<multer::Error> for StoreError {
 	fn from(error: multer::Error) -> Self {
+		match &error {
+			multer::Error::FieldSizeExceeded { .. } => return Self::UploadLimitExceeded,
+			multer::Error::StreamSizeExceeded { .. } => return Self::RequestLimitExceeded,
+			_ => {}
+		}
+
 		if let multer::Error::StreamReadFailed(stream_error) = &error {
 			let is_limit_exceeded = stream_error
 				.downcast_ref::<axum::Error>()
@@ -137,3 +363,131 @@ impl From<multer::Error> for StoreError {
 		Self::Stream(error)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::tests::instance;
+
+	#[test]
+	fn part_size_limit_is_upload_limit() {
+		let error = StoreError::from(multer::Error::FieldSizeExceeded {
+			limit: 1024,
+			field_name: None,
+		});
+		assert!(matches!(error, StoreError::UploadLimitExceeded));
+		assert_eq!(
+			error.into_response().status(),
+			StatusCode::PAYLOAD_TOO_LARGE
+		);
+	}
+
+	fn reference(failure_reason: Option<u16>, warning_reason: Option<u16>) -> InstanceReference {
+		InstanceReference {
+			sop_class_uid: UI::from("1.2.840.10008.5.1.4.1.1.7"),
+			sop_instance_uid: UI::from("1.2.3"),
+			failure_reason,
+			warning_reason,
+			failure_attributes: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn failed_parts_keep_processed_instances() {
+		let mut response = StoreResponse::default();
+		assert!(matches!(
+			response.add_failed_part(StoreError::UploadLimitExceeded),
+			Err(StoreError::UploadLimitExceeded)
+		));
+
+		response.referenced_sequence.push(reference(None, None));
+		response
+			.add_failed_part(StoreError::UploadLimitExceeded)
+			.unwrap();
+		assert_eq!(response.referenced_sequence.len(), 1);
+		assert!(response.failed_sequence.is_empty());
+		assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+		let object = InMemDicomObject::from(response);
+		assert_eq!(
+			object.get(tags::ERROR_COMMENT).unwrap().to_str().unwrap(),
+			StoreError::UploadLimitExceeded.to_string()
+		);
+	}
+
+	/// Stores, warns about or fails instances depending on their SOP Instance UID.
+	struct FailingStore;
+
+	#[async_trait]
+	impl InstanceStore for FailingStore {
+		fn concurrency(&self) -> usize {
+			2
+		}
+
+		async fn store_instance(
+			&self,
+			instance: FileDicomObject<InMemDicomObject>,
+		) -> StoreOutcome {
+			match instance.meta().media_storage_sop_instance_uid() {
+				"1.2.3.1" => StoreOutcome::Stored,
+				"1.2.3.2" => StoreOutcome::Warning(WARNING_COERCION),
+				_ => StoreOutcome::Failed(FAILURE_PROCESSING),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn instance_stores_keep_the_order_of_the_request() {
+		let response = FailingStore
+			.store(StoreRequest {
+				instances: futures::stream::iter([
+					Ok(instance("1.2.3", "1.2.3.4", "1.2.3.1")),
+					Ok(instance("1.2.3", "1.2.3.4", "1.2.3.3")),
+					Ok(instance("1.2.3", "1.2.3.4", "1.2.3.2")),
+					Err(StoreError::UploadLimitExceeded),
+				])
+				.boxed(),
+				rejected: Arc::default(),
+			})
+			.await
+			.unwrap();
+
+		let uids = |references: &[InstanceReference]| {
+			references
+				.iter()
+				.map(|reference| reference.sop_instance_uid.clone())
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(uids(&response.referenced_sequence), ["1.2.3.1", "1.2.3.2"]);
+		assert_eq!(
+			response.referenced_sequence[1].warning_reason,
+			Some(WARNING_COERCION)
+		);
+		assert_eq!(
+			response.failed_sequence[0].failure_reason,
+			Some(FAILURE_PROCESSING)
+		);
+		assert_eq!(response.failed_sequence.len(), 1);
+		assert_eq!(response.errors.len(), 1);
+	}
+
+	#[test]
+	fn status_depends_on_failures_and_warnings() {
+		let mut response = StoreResponse {
+			referenced_sequence: vec![reference(None, None)],
+			..StoreResponse::default()
+		};
+		assert_eq!(response.status(), StatusCode::OK);
+
+		response
+			.referenced_sequence
+			.push(reference(None, Some(0xB000)));
+		assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+		response.referenced_sequence.clear();
+		response
+			.failed_sequence
+			.push(reference(Some(FAILURE_PROCESSING), None));
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+	}
+}
@@ -0,0 +1,424 @@
+//! Validation of instances received by STOW-RS before they are passed to the backend.
+//!
+//! The checks are limited to what is required to store and retrieve an instance consistently:
+//! the File Meta Information must match the data set, UIDs must be well-formed and the attributes
+//! of the Patient, Study, Series and Image Pixel modules that are common to all IODs must be present.
+//! For the IODs of common SOP Classes, the Type 1 and Type 2 attributes of their mandatory modules
+//! are checked as well.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#chapter_A>
+//! <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#chapter_C>
+
+use crate::api::matching::validate_uid;
+use dicom::core::header::{HasLength, Header};
+use dicom::core::{Tag, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use std::fmt::{Display, Formatter};
+
+/// Type 1 attributes that are required for all composite IODs.
+const REQUIRED: [Tag; 5] = [
+	tags::SOP_CLASS_UID,
+	tags::SOP_INSTANCE_UID,
+	tags::STUDY_INSTANCE_UID,
+	tags::SERIES_INSTANCE_UID,
+	tags::MODALITY,
+];
+
+/// Type 2 attributes of the Patient, General Study and General Series modules.
+/// They must be present, but may be empty.
+const REQUIRED_PRESENT: [Tag; 9] = [
+	tags::PATIENT_NAME,
+	tags::PATIENT_ID,
+	tags::PATIENT_BIRTH_DATE,
+	tags::PATIENT_SEX,
+	tags::STUDY_DATE,
+	tags::STUDY_TIME,
+	tags::REFERRING_PHYSICIAN_NAME,
+	tags::STUDY_ID,
+	tags::ACCESSION_NUMBER,
+];
+
+/// Type 1 attributes of the Image Pixel module, required if the instance contains pixel data.
+/// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_C.7.6.3>
+const REQUIRED_IMAGE_PIXEL: [Tag; 8] = [
+	tags::SAMPLES_PER_PIXEL,
+	tags::PHOTOMETRIC_INTERPRETATION,
+	tags::ROWS,
+	tags::COLUMNS,
+	tags::BITS_ALLOCATED,
+	tags::BITS_STORED,
+	tags::HIGH_BIT,
+	tags::PIXEL_REPRESENTATION,
+];
+
+/// The attributes of the mandatory modules of an IOD, in addition to the attributes above.
+struct Iod {
+	name: &'static str,
+	sop_classes: &'static [&'static str],
+	/// Type 1 attributes, which must be present and not empty.
+	required: &'static [Tag],
+	/// Type 2 attributes, which must be present, but may be empty.
+	required_present: &'static [Tag],
+}
+
+/// The IODs whose modules are validated, by their SOP Classes.
+const IODS: &[Iod] = &[
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.3>
+	Iod {
+		name: "CT Image",
+		sop_classes: &[uids::CT_IMAGE_STORAGE],
+		required: &[
+			tags::FRAME_OF_REFERENCE_UID,
+			tags::PIXEL_SPACING,
+			tags::IMAGE_ORIENTATION_PATIENT,
+			tags::IMAGE_POSITION_PATIENT,
+			tags::IMAGE_TYPE,
+			tags::RESCALE_INTERCEPT,
+			tags::RESCALE_SLOPE,
+		],
+		required_present: &[
+			tags::POSITION_REFERENCE_INDICATOR,
+			tags::MANUFACTURER,
+			tags::INSTANCE_NUMBER,
+			tags::SLICE_THICKNESS,
+			tags::KVP,
+			tags::ACQUISITION_NUMBER,
+		],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.4>
+	Iod {
+		name: "MR Image",
+		sop_classes: &[uids::MR_IMAGE_STORAGE],
+		required: &[
+			tags::FRAME_OF_REFERENCE_UID,
+			tags::PIXEL_SPACING,
+			tags::IMAGE_ORIENTATION_PATIENT,
+			tags::IMAGE_POSITION_PATIENT,
+			tags::IMAGE_TYPE,
+			tags::SCANNING_SEQUENCE,
+			tags::SEQUENCE_VARIANT,
+		],
+		required_present: &[
+			tags::POSITION_REFERENCE_INDICATOR,
+			tags::MANUFACTURER,
+			tags::INSTANCE_NUMBER,
+			tags::SLICE_THICKNESS,
+			tags::SCAN_OPTIONS,
+			tags::MR_ACQUISITION_TYPE,
+			tags::ECHO_TIME,
+			tags::ECHO_TRAIN_LENGTH,
+		],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.2>
+	Iod {
+		name: "CR Image",
+		sop_classes: &[uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE],
+		required: &[],
+		required_present: &[
+			tags::BODY_PART_EXAMINED,
+			tags::VIEW_POSITION,
+			tags::MANUFACTURER,
+			tags::INSTANCE_NUMBER,
+		],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.6>
+	Iod {
+		name: "US Image",
+		sop_classes: &[
+			uids::ULTRASOUND_IMAGE_STORAGE,
+			uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE,
+		],
+		required: &[],
+		required_present: &[tags::IMAGE_TYPE, tags::MANUFACTURER, tags::INSTANCE_NUMBER],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.8>
+	Iod {
+		name: "SC Image",
+		sop_classes: &[uids::SECONDARY_CAPTURE_IMAGE_STORAGE],
+		required: &[tags::CONVERSION_TYPE],
+		required_present: &[tags::INSTANCE_NUMBER],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.45.1>
+	Iod {
+		name: "Encapsulated PDF",
+		sop_classes: &[uids::ENCAPSULATED_PDF_STORAGE],
+		required: &[
+			tags::CONVERSION_TYPE,
+			tags::INSTANCE_NUMBER,
+			tags::BURNED_IN_ANNOTATION,
+			tags::MIME_TYPE_OF_ENCAPSULATED_DOCUMENT,
+			tags::ENCAPSULATED_DOCUMENT,
+		],
+		required_present: &[
+			tags::MANUFACTURER,
+			tags::CONTENT_DATE,
+			tags::CONTENT_TIME,
+			tags::ACQUISITION_DATE_TIME,
+			tags::DOCUMENT_TITLE,
+			tags::CONCEPT_NAME_CODE_SEQUENCE,
+		],
+	},
+	// <https://dicom.nema.org/medical/dicom/current/output/html/part03.html#sect_A.35>
+	Iod {
+		name: "SR Document",
+		sop_classes: &[
+			uids::BASIC_TEXT_SR_STORAGE,
+			uids::ENHANCED_SR_STORAGE,
+			uids::COMPREHENSIVE_SR_STORAGE,
+		],
+		required: &[
+			tags::SERIES_NUMBER,
+			tags::INSTANCE_NUMBER,
+			tags::COMPLETION_FLAG,
+			tags::VERIFICATION_FLAG,
+			tags::CONTENT_DATE,
+			tags::CONTENT_TIME,
+			tags::VALUE_TYPE,
+			tags::CONCEPT_NAME_CODE_SEQUENCE,
+		],
+		required_present: &[
+			tags::MANUFACTURER,
+			tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE,
+		],
+	},
+];
+
+/// A validation problem of a single attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+	pub tag: Tag,
+	pub message: String,
+}
+
+impl Problem {
+	fn new(tag: Tag, message: impl Into<String>) -> Self {
+		Self {
+			tag,
+			message: message.into(),
+		}
+	}
+}
+
+impl Display for Problem {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}: {}", self.tag, self.message)
+	}
+}
+
+/// Validates the instance and returns all problems that were found.
+pub fn validate(file: &FileDicomObject<InMemDicomObject>) -> Vec<Problem> {
+	let mut problems = Vec::new();
+
+	let meta = file.meta();
+	for (tag, meta_tag, meta_value) in [
+		(
+			tags::SOP_CLASS_UID,
+			tags::MEDIA_STORAGE_SOP_CLASS_UID,
+			meta.media_storage_sop_class_uid(),
+		),
+		(
+			tags::SOP_INSTANCE_UID,
+			tags::MEDIA_STORAGE_SOP_INSTANCE_UID,
+			meta.media_storage_sop_instance_uid(),
+		),
+	] {
+		let meta_value = trim(meta_value);
+		if let Some(value) = string(file, tag) {
+			if value != meta_value {
+				problems.push(Problem::new(
+					tag,
+					format!("{value} does not match {meta_value} of the File Meta Information ({meta_tag})"),
+				));
+			}
+		}
+	}
+
+	for tag in REQUIRED {
+		if string(file, tag).is_none_or(str::is_empty) {
+			problems.push(Problem::new(tag, "required attribute is missing or empty"));
+		}
+	}
+	for tag in REQUIRED_PRESENT {
+		if file.get(tag).is_none() {
+			problems.push(Problem::new(tag, "required attribute is missing"));
+		}
+	}
+	let sop_class_uid = string(file, tags::SOP_CLASS_UID).unwrap_or_default();
+	if let Some(iod) = IODS
+		.iter()
+		.find(|iod| iod.sop_classes.contains(&sop_class_uid))
+	{
+		for &tag in iod.required {
+			if file.get(tag).is_none_or(HasLength::is_empty) {
+				problems.push(Problem::new(
+					tag,
+					format!(
+						"attribute required by the {} IOD is missing or empty",
+						iod.name
+					),
+				));
+			}
+		}
+		for &tag in iod.required_present {
+			if file.get(tag).is_none() {
+				problems.push(Problem::new(
+					tag,
+					format!("attribute required by the {} IOD is missing", iod.name),
+				));
+			}
+		}
+	}
+	if file.get(tags::PIXEL_DATA).is_some() {
+		for tag in REQUIRED_IMAGE_PIXEL {
+			if file.get(tag).is_none_or(HasLength::is_empty) {
+				problems.push(Problem::new(tag, "required attribute is missing or empty"));
+			}
+		}
+	}
+
+	validate_uids(file, &mut problems);
+	problems
+}
+
+/// Checks the syntax of all UIDs, including those in sequence items.
+fn validate_uids(object: &InMemDicomObject, problems: &mut Vec<Problem>) {
+	for element in object {
+		if element.vr() == VR::UI {
+			let Ok(uids) = element.to_multi_str() else {
+				continue;
+			};
+			for uid in uids
+				.iter()
+				.map(|uid| trim(uid))
+				.filter(|uid| !uid.is_empty())
+			{
+				if let Err(message) = validate_uid(uid) {
+					problems.push(Problem::new(element.tag(), message));
+				}
+			}
+		} else if let Some(items) = element.items() {
+			for item in items {
+				validate_uids(item, problems);
+			}
+		}
+	}
+}
+
+fn string(object: &InMemDicomObject, tag: Tag) -> Option<&str> {
+	object
+		.get(tag)
+		.and_then(|element| element.string().ok())
+		.map(trim)
+}
+
+fn trim(value: &str) -> &str {
+	value.trim_end_matches(['\0', ' '])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dicom::core::{DataElement, PrimitiveValue};
+	use dicom::object::FileMetaTableBuilder;
+
+	fn instance() -> FileDicomObject<InMemDicomObject> {
+		let mut object = InMemDicomObject::from_element_iter([
+			DataElement::new(
+				tags::SOP_CLASS_UID,
+				VR::UI,
+				uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+			),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3"),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4"),
+			DataElement::new(tags::MODALITY, VR::CS, "OT"),
+			DataElement::new(tags::CONVERSION_TYPE, VR::CS, "WSD"),
+			DataElement::new(tags::INSTANCE_NUMBER, VR::IS, "1"),
+		]);
+		for tag in REQUIRED_PRESENT {
+			object.put(DataElement::new(tag, VR::UN, PrimitiveValue::Empty));
+		}
+		object
+			.with_meta(FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN))
+			.unwrap()
+	}
+
+	#[test]
+	fn accepts_valid_instance() {
+		assert_eq!(validate(&instance()), Vec::new());
+	}
+
+	#[test]
+	fn reports_meta_mismatch() {
+		let mut file = instance();
+		file.put(DataElement::new(
+			tags::SOP_INSTANCE_UID,
+			VR::UI,
+			"1.2.3.4.6",
+		));
+		let problems = validate(&file);
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].tag, tags::SOP_INSTANCE_UID);
+	}
+
+	#[test]
+	fn reports_missing_attributes_and_invalid_uids() {
+		let mut file = instance();
+		file.remove_element(tags::MODALITY);
+		file.remove_element(tags::PATIENT_ID);
+		file.put(DataElement::new(
+			tags::SERIES_INSTANCE_UID,
+			VR::UI,
+			"1.02.3",
+		));
+		file.put(DataElement::new(
+			tags::PIXEL_DATA,
+			VR::OB,
+			PrimitiveValue::from(vec![0_u8; 4]),
+		));
+
+		let tags: Vec<Tag> = validate(&file)
+			.into_iter()
+			.map(|problem| problem.tag)
+			.collect();
+		assert!(tags.contains(&tags::MODALITY));
+		assert!(tags.contains(&tags::PATIENT_ID));
+		assert!(tags.contains(&tags::SERIES_INSTANCE_UID));
+		assert!(tags.contains(&tags::ROWS));
+	}
+
+	#[test]
+	fn reports_missing_attributes_of_iod() {
+		let mut file = instance();
+		file.remove_element(tags::CONVERSION_TYPE);
+		let problems = validate(&file);
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].tag, tags::CONVERSION_TYPE);
+		assert!(problems[0].message.contains("SC Image"));
+
+		file.put(DataElement::new(
+			tags::SOP_CLASS_UID,
+			VR::UI,
+			uids::CT_IMAGE_STORAGE,
+		));
+		file.update_meta(|meta| meta.media_storage_sop_class_uid = uids::CT_IMAGE_STORAGE.into());
+		let tags: Vec<Tag> = validate(&file)
+			.into_iter()
+			.map(|problem| problem.tag)
+			.collect();
+		assert!(!tags.contains(&tags::CONVERSION_TYPE));
+		assert!(tags.contains(&tags::IMAGE_TYPE));
+		assert!(tags.contains(&tags::RESCALE_SLOPE));
+		assert!(tags.contains(&tags::KVP));
+	}
+
+	#[test]
+	fn checks_only_common_attributes_of_unknown_sop_classes() {
+		let mut file = instance();
+		file.remove_element(tags::CONVERSION_TYPE);
+		file.put(DataElement::new(tags::SOP_CLASS_UID, VR::UI, "1.2.3.4.5.6"));
+		file.update_meta(|meta| meta.media_storage_sop_class_uid = "1.2.3.4.5.6".into());
+		assert_eq!(validate(&file), Vec::new());
+	}
+}
@@ -0,0 +1,170 @@
+use crate::api::wado::{ResourceQuery, RetrieveError};
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// How long a retrieved instance is kept in the [`InstanceCache`].
+const INSTANCE_TTL: Duration = Duration::from_mins(1);
+
+type Instance = Arc<FileDicomObject<InMemDicomObject>>;
+
+/// A short-lived cache for instances that are retrieved to extract their bulk data.
+///
+/// Clients usually request all bulk data URIs of the metadata of an instance at once.
+/// Without a cache, every request would retrieve the complete instance from the origin server
+/// again (e.g. with a C-MOVE). Concurrent requests for the same instance share one retrieval.
+///
+/// The number of cached instances is limited by the capacity.
+#[derive(Clone)]
+pub struct InstanceCache {
+	entries: Arc<Mutex<HashMap<ResourceQuery, CachedInstance>>>,
+	capacity: usize,
+	ttl: Duration,
+}
+
+struct CachedInstance {
+	instance: Arc<OnceCell<Instance>>,
+	expires_at: Instant,
+}
+
+impl InstanceCache {
+	pub fn new(capacity: usize) -> Self {
+		Self::with_ttl(capacity, INSTANCE_TTL)
+	}
+
+	fn with_ttl(capacity: usize, ttl: Duration) -> Self {
+		Self {
+			entries: Arc::default(),
+			capacity,
+			ttl,
+		}
+	}
+
+	/// Returns the cached instance, or retrieves and caches it.
+	/// Failed retrievals are not cached, so the next request retrieves the instance again.
+	pub async fn get_or_retrieve<F, R>(
+		&self,
+		query: &ResourceQuery,
+		retrieve: F,
+	) -> Result<Instance, RetrieveError>
+	where
+		F: FnOnce() -> R + Send,
+		R: Future<Output = Result<Instance, RetrieveError>> + Send,
+	{
+		if self.capacity == 0 {
+			return retrieve().await;
+		}
+		let cell = self.entry(query);
+		cell.get_or_try_init(retrieve).await.map(Arc::clone)
+	}
+
+	/// Returns the cache entry of the instance.
+	/// Expired entries are evicted, followed by the entries that expire first
+	/// until a new entry fits into the capacity.
+	fn entry(&self, query: &ResourceQuery) -> Arc<OnceCell<Instance>> {
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		entries.retain(|_, entry| entry.expires_at > now);
+
+		if let Some(entry) = entries.get(query) {
+			return Arc::clone(&entry.instance);
+		}
+		while entries.len() >= self.capacity {
+			let Some(oldest) = entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.expires_at)
+				.map(|(query, _)| query.clone())
+			else {
+				break;
+			};
+			entries.remove(&oldest);
+		}
+		let instance = Arc::new(OnceCell::new());
+		entries.insert(
+			query.clone(),
+			CachedInstance {
+				instance: Arc::clone(&instance),
+				expires_at: now + self.ttl,
+			},
+		);
+		instance
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::tests::instance;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	fn query(instance: &str) -> ResourceQuery {
+		ResourceQuery {
+			aet: String::from("PACS"),
+			study_instance_uid: String::from("1.2.3"),
+			series_instance_uid: Some(String::from("1.2.3.4")),
+			sop_instance_uid: Some(instance.to_owned()),
+		}
+	}
+
+	/// Gets the instance from the cache and returns the number of retrievals so far.
+	async fn get(cache: &InstanceCache, retrievals: &AtomicUsize, sop_instance_uid: &str) -> usize {
+		let retrieved = cache
+			.get_or_retrieve(&query(sop_instance_uid), || async {
+				retrievals.fetch_add(1, Ordering::SeqCst);
+				Ok(Arc::new(instance("1.2.3", "1.2.3.4", sop_instance_uid)))
+			})
+			.await
+			.unwrap();
+		assert_eq!(
+			retrieved.meta().media_storage_sop_instance_uid(),
+			sop_instance_uid
+		);
+		retrievals.load(Ordering::SeqCst)
+	}
+
+	#[tokio::test]
+	async fn evicts_instances_beyond_capacity() {
+		let cache = InstanceCache::new(2);
+		let retrievals = AtomicUsize::new(0);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 1);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 1);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.2").await, 2);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.3").await, 3);
+
+		// The instance that expires first is evicted to make room.
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.3").await, 3);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 4);
+	}
+
+	#[tokio::test]
+	async fn expired_instances_are_retrieved_again() {
+		let cache = InstanceCache::with_ttl(2, Duration::ZERO);
+		let retrievals = AtomicUsize::new(0);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 1);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 2);
+
+		let uncached = InstanceCache::new(0);
+		assert_eq!(get(&uncached, &retrievals, "1.2.3.4.1").await, 3);
+		assert_eq!(get(&uncached, &retrievals, "1.2.3.4.1").await, 4);
+	}
+
+	#[tokio::test]
+	async fn failed_retrievals_are_not_cached() {
+		let cache = InstanceCache::new(2);
+		let failed = cache
+			.get_or_retrieve(&query("1.2.3.4.1"), || async {
+				Err(RetrieveError::NotFound {
+					message: String::from("Instance not found"),
+				})
+			})
+			.await;
+		assert!(matches!(failed, Err(RetrieveError::NotFound { .. })));
+
+		let retrievals = AtomicUsize::new(0);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 1);
+		assert_eq!(get(&cache, &retrievals, "1.2.3.4.1").await, 1);
+	}
+}
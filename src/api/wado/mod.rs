@@ -1,5 +1,10 @@
+mod cache;
 mod routes;
 mod service;
 
+pub use cache::InstanceCache;
+
 pub use routes::routes;
+#[cfg(feature = "s3")]
+pub use routes::{remove_bulkdata, BulkdataRemovalOptions};
 pub use service::*;
@@ -1,6 +1,8 @@
+use crate::api::search::{aet_url, bulk_data_uri, instance_url};
 use crate::api::wado::{
+	BulkDataReference, BulkDataRequest, BulkDataResponse, InstanceCache, InstanceMetadata,
 	MetadataRequest, RenderedResponse, RenderingRequest, RetrieveError, RetrieveInstanceRequest,
-	ThumbnailRequest,
+	ThumbnailRequest, WadoService,
 };
 use crate::backend::dimse::cmove::movescu::MoveError;
 use crate::backend::dimse::wado::DicomMultipartStream;
@@ -9,6 +11,7 @@ use crate::rendering::render_instances;
 use crate::types::UI;
 use crate::AppState;
 use axum::body::Body;
+use axum::extract::State;
 use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use axum::http::{Response, StatusCode, Uri};
 use axum::response::{IntoResponse, Redirect};
@@ -18,11 +21,10 @@ use axum_streams::StreamBodyAs;
 use dicom::core::header::HasLength;
 use dicom::core::{DicomValue, Length, Tag, VR};
 use dicom::dictionary_std::tags;
-use dicom::object::{FileDicomObject, InMemDicomObject};
+use dicom::object::InMemDicomObject;
 use dicom_json::DicomJson;
 use futures::{StreamExt, TryStreamExt};
 use std::pin::Pin;
-use std::sync::Arc;
 use tokio::pin;
 use tracing::{error, instrument, trace};
 
@@ -57,6 +59,7 @@ pub fn routes() -> Router<AppState> {
 		.route("/studies/{study}/bulkdata", get(study_bulkdata))
 		.route("/studies/{study}/series/{series}/bulkdata", get(series_bulkdata))
 		.route("/studies/{study}/series/{series}/instances/{instance}/bulkdata", get(instance_bulkdata))
+		.route("/studies/{study}/series/{series}/instances/{instance}/bulkdata/{*path}", get(bulkdata))
 
 		// https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_10.4.html#sect_10.4.1.1.6
 		.route("/studies/{study}/pixeldata", get(study_pixeldata))
@@ -70,6 +73,24 @@ async fn instance_resource(
 	request: RetrieveInstanceRequest,
 ) -> impl IntoResponse {
 	if let Some(wado) = provider.wado {
+		// The instance can only be redirected to as it is stored, i.e. without transcoding.
+		if request.single_part
+			&& request.query.sop_instance_uid.is_some()
+			&& request
+				.transfer_syntax
+				.as_deref()
+				.is_none_or(|transfer_syntax| transfer_syntax == "*")
+		{
+			match wado.instance_url(&request).await {
+				Ok(Some(url)) => return Redirect::temporary(&url).into_response(),
+				Ok(None) => {}
+				Err(err) => {
+					error!("{err:?}");
+					return err.into_response();
+				}
+			}
+		}
+
 		let transfer_syntax = request.transfer_syntax.clone();
 		let study_instance_uid: UI = request.query.study_instance_uid.clone();
 		let response = wado.retrieve(request).await;
@@ -99,7 +120,7 @@ async fn instance_resource(
 			}
 			Err(err) => {
 				error!("{err:?}");
-				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+				err.into_response()
 			}
 		}
 	} else {
@@ -134,6 +155,7 @@ async fn rendered_resource(
 			let instance_request = RetrieveInstanceRequest {
 				query: request.query,
 				transfer_syntax: None,
+				single_part: false,
 			};
 
 			let stream = wado
@@ -154,13 +176,14 @@ async fn rendered_resource(
 		}
 		Err(err) => {
 			error!("{err:?}");
-			Ok((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+			Ok(err.into_response())
 		}
 	}
 }
 
 async fn metadata_resource(
 	provider: ServiceProvider,
+	state: &AppState,
 	request: MetadataRequest,
 ) -> impl IntoResponse {
 	let Some(wado) = provider.wado else {
@@ -170,23 +193,25 @@ async fn metadata_resource(
 			.unwrap();
 	};
 
+	let aet_url = aet_url(&state.config, &request.query.aet);
 	match wado.metadata(request).await {
 		Ok(response) => {
-			let matches: Result<Vec<Arc<FileDicomObject<InMemDicomObject>>>, MoveError> =
+			let matches: Result<Vec<InstanceMetadata>, MoveError> =
 				response.stream.try_collect().await;
 
 			match matches {
 				Ok(matches) => {
-					let json: Vec<DicomJson<InMemDicomObject>> = matches
+					let json: Result<Vec<serde_json::Value>, serde_json::Error> = matches
 						.into_iter()
-						// FIXME: Cloning the data so we can mutate it
-						.map(|i| i.as_ref().to_owned().into_inner())
-						.map(|mut i| {
-							remove_bulkdata(&mut i, &BulkdataRemovalOptions::default());
-							i
-						})
-						.map(DicomJson::from)
+						.map(|metadata| metadata_json(metadata, aet_url.as_deref()))
 						.collect();
+					let json = match json {
+						Ok(json) => json,
+						Err(err) => {
+							return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+								.into_response()
+						}
+					};
 
 					Response::builder()
 						.status(StatusCode::OK)
@@ -200,11 +225,131 @@ async fn metadata_resource(
 		}
 		Err(err) => {
 			error!("{err:?}");
-			(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			err.into_response()
+		}
+	}
+}
+
+async fn bulkdata_resource(
+	provider: ServiceProvider,
+	instances: &InstanceCache,
+	request: BulkDataRequest,
+) -> Response<Body> {
+	let Some(wado) = provider.wado else {
+		return Response::builder()
+			.status(StatusCode::SERVICE_UNAVAILABLE)
+			.body(Body::from("WADO-RS endpoint is disabled"))
+			.unwrap();
+	};
+
+	let single_part = request.single_part;
+	let bulkdata = match wado.bulkdata(&request).await {
+		Ok(BulkDataResponse(bulkdata)) => Ok(bulkdata),
+		Err(RetrieveError::Unimplemented) => {
+			trace!("Extracting bulk data from the instance");
+			extract_bulkdata(wado.as_ref(), instances, request).await
+		}
+		Err(err) => Err(err),
+	};
+
+	match bulkdata {
+		Ok(bulkdata) if single_part => Response::builder()
+			.header(CONTENT_TYPE, mime::APPLICATION_OCTET_STREAM.as_ref())
+			.body(Body::from(bulkdata))
+			.unwrap(),
+		Ok(bulkdata) => {
+			let boundary = uuid::Uuid::new_v4().simple().to_string();
+			let mut body = Vec::with_capacity(bulkdata.len() + 128);
+			body.extend_from_slice(
+				format!("--{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n")
+					.as_bytes(),
+			);
+			body.extend_from_slice(&bulkdata);
+			body.extend_from_slice(format!("\r\n--{boundary}--").as_bytes());
+
+			Response::builder()
+				.header(
+					CONTENT_TYPE,
+					format!(
+						r#"multipart/related; type="application/octet-stream"; boundary={boundary}"#
+					),
+				)
+				.body(Body::from(body))
+				.unwrap()
+		}
+		Err(err) => {
+			error!("{err:?}");
+			err.into_response()
 		}
 	}
 }
 
+/// Retrieves the instance and extracts the bulk data of the requested attribute.
+/// The instance is cached, so that the other bulk data of the instance can be extracted
+/// without retrieving it again.
+async fn extract_bulkdata(
+	wado: &dyn WadoService,
+	instances: &InstanceCache,
+	request: BulkDataRequest,
+) -> Result<Vec<u8>, RetrieveError> {
+	let not_found = || RetrieveError::NotFound {
+		message: format!("No bulk data found at {}", request.path),
+	};
+
+	let instance = instances
+		.get_or_retrieve(&request.query, || async {
+			let instance_request = RetrieveInstanceRequest {
+				query: request.query.clone(),
+				transfer_syntax: None,
+				single_part: false,
+			};
+			let mut stream = wado.retrieve(instance_request).await?.stream;
+			stream
+				.try_next()
+				.await
+				.map_err(|err| RetrieveError::Backend { source: err.into() })?
+				.ok_or_else(not_found)
+		})
+		.await?;
+
+	match instance.value_at(request.path.0.clone()) {
+		Ok(DicomValue::Primitive(value)) => Ok(value.to_bytes().into_owned()),
+		Ok(DicomValue::PixelSequence(sequence)) => Ok(sequence.fragments().concat()),
+		Ok(DicomValue::Sequence(_)) | Err(_) => Err(not_found()),
+	}
+}
+
+/// Converts the metadata to DICOM JSON.
+/// Bulk data is removed, the references of the backend are returned as `BulkDataURI`.
+/// References without their own URI point to the bulk data resource below the URL of the AET.
+fn metadata_json(
+	metadata: InstanceMetadata,
+	aet_url: Option<&str>,
+) -> Result<serde_json::Value, serde_json::Error> {
+	// FIXME: Cloning the data so we can mutate it
+	let mut instance = metadata.instance.as_ref().to_owned().into_inner();
+	remove_bulkdata(&mut instance, &BulkdataRemovalOptions::default());
+	let instance_url = aet_url.and_then(|aet_url| instance_url(aet_url, &instance));
+
+	let mut json = serde_json::to_value(DicomJson::from(instance))?;
+	if let Some(attributes) = json.as_object_mut() {
+		for BulkDataReference { tag, vr, uri } in metadata.bulkdata {
+			let Some(uri) = uri.or_else(|| {
+				instance_url
+					.as_ref()
+					.map(|instance_url| bulk_data_uri(instance_url, &tag.into()))
+			}) else {
+				continue;
+			};
+			attributes.insert(
+				format!("{:04X}{:04X}", tag.group(), tag.element()),
+				serde_json::json!({ "vr": vr.to_string(), "BulkDataURI": uri }),
+			);
+		}
+	}
+	Ok(json)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BulkdataRemovalOptions {
 	pub max_length: u32,
@@ -216,7 +361,8 @@ impl Default for BulkdataRemovalOptions {
 	}
 }
 
-fn remove_bulkdata(object: &mut InMemDicomObject, options: &BulkdataRemovalOptions) {
+/// Removes binary data and large values, which are not part of the metadata.
+pub fn remove_bulkdata(object: &mut InMemDicomObject, options: &BulkdataRemovalOptions) {
 	object.remove_element(tags::PIXEL_DATA);
 	object.remove_element(tags::FLOAT_PIXEL_DATA);
 	object.remove_element(tags::DOUBLE_FLOAT_PIXEL_DATA);
@@ -281,19 +427,28 @@ async fn instance(
 	instance_resource(provider, request).await
 }
 
-async fn study_metadata(provider: ServiceProvider, request: MetadataRequest) -> impl IntoResponse {
-	metadata_resource(provider, request).await
+async fn study_metadata(
+	provider: ServiceProvider,
+	State(state): State<AppState>,
+	request: MetadataRequest,
+) -> impl IntoResponse {
+	metadata_resource(provider, &state, request).await
 }
 
-async fn series_metadata(provider: ServiceProvider, request: MetadataRequest) -> impl IntoResponse {
-	metadata_resource(provider, request).await
+async fn series_metadata(
+	provider: ServiceProvider,
+	State(state): State<AppState>,
+	request: MetadataRequest,
+) -> impl IntoResponse {
+	metadata_resource(provider, &state, request).await
 }
 
 async fn instance_metadata(
 	provider: ServiceProvider,
+	State(state): State<AppState>,
 	request: MetadataRequest,
 ) -> impl IntoResponse {
-	metadata_resource(provider, request).await
+	metadata_resource(provider, &state, request).await
 }
 
 #[instrument(skip_all)]
@@ -377,7 +532,14 @@ async fn instance_bulkdata() -> impl IntoResponse {
 	StatusCode::NOT_IMPLEMENTED
 }
 
-// TODO: Bulkdata {bulkdataURI}
+#[instrument(skip_all)]
+async fn bulkdata(
+	provider: ServiceProvider,
+	State(state): State<AppState>,
+	request: BulkDataRequest,
+) -> impl IntoResponse {
+	bulkdata_resource(provider, &state.instances, request).await
+}
 
 async fn study_pixeldata() -> impl IntoResponse {
 	StatusCode::NOT_IMPLEMENTED
@@ -394,3 +556,134 @@ async fn instance_pixeldata() -> impl IntoResponse {
 async fn frame_pixeldata() -> impl IntoResponse {
 	StatusCode::NOT_IMPLEMENTED
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::wado::{BulkDataPath, InstanceResponse, ResourceQuery};
+	use async_trait::async_trait;
+	use dicom::core::ops::AttributeSelector;
+	use dicom::core::value::{DataSetSequence, PixelFragmentSequence};
+	use dicom::core::{DataElement, PrimitiveValue};
+	use dicom::dictionary_std::uids;
+	use dicom::object::FileMetaTableBuilder;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+
+	/// Retrieves a single instance with native pixel data and an encapsulated icon image.
+	/// Counts the retrievals.
+	#[derive(Default)]
+	struct InstanceWado(AtomicUsize);
+
+	#[async_trait]
+	impl WadoService for InstanceWado {
+		async fn retrieve(
+			&self,
+			_request: RetrieveInstanceRequest,
+		) -> Result<InstanceResponse, RetrieveError> {
+			self.0.fetch_add(1, Ordering::SeqCst);
+			let icon = InMemDicomObject::from_element_iter([DataElement::new(
+				tags::PIXEL_DATA,
+				VR::OB,
+				PixelFragmentSequence::new(vec![], vec![vec![1_u8, 2], vec![3, 4]]),
+			)]);
+			let instance = InMemDicomObject::from_element_iter([
+				DataElement::new(
+					tags::ICON_IMAGE_SEQUENCE,
+					VR::SQ,
+					DataSetSequence::from(vec![icon]),
+				),
+				DataElement::new(
+					tags::PIXEL_DATA,
+					VR::OW,
+					PrimitiveValue::from(vec![5_u8, 6, 7, 8]),
+				),
+			])
+			.with_meta(
+				FileMetaTableBuilder::new()
+					.transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+					.media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+					.media_storage_sop_instance_uid("1.2.3.4.5"),
+			)
+			.unwrap();
+
+			Ok(InstanceResponse {
+				stream: futures::stream::iter([Ok(Arc::new(instance))]).boxed(),
+			})
+		}
+	}
+
+	fn request(path: AttributeSelector) -> BulkDataRequest {
+		BulkDataRequest {
+			query: ResourceQuery {
+				aet: String::from("PACS"),
+				study_instance_uid: String::from("1.2.3"),
+				series_instance_uid: Some(String::from("1.2.3.4")),
+				sop_instance_uid: Some(String::from("1.2.3.4.5")),
+			},
+			path: BulkDataPath(path),
+			single_part: false,
+		}
+	}
+
+	#[tokio::test]
+	async fn extracts_bulkdata_from_instance() {
+		let wado = InstanceWado::default();
+		let instances = InstanceCache::new(1);
+
+		let pixel_data = extract_bulkdata(&wado, &instances, request(tags::PIXEL_DATA.into()))
+			.await
+			.unwrap();
+		assert_eq!(pixel_data, vec![5, 6, 7, 8]);
+
+		let icon = extract_bulkdata(
+			&wado,
+			&instances,
+			request((tags::ICON_IMAGE_SEQUENCE, 0, tags::PIXEL_DATA).into()),
+		)
+		.await
+		.unwrap();
+		assert_eq!(icon, vec![1, 2, 3, 4]);
+
+		let missing = extract_bulkdata(
+			&wado,
+			&instances,
+			request((tags::ICON_IMAGE_SEQUENCE, 1, tags::PIXEL_DATA).into()),
+		)
+		.await;
+		assert!(matches!(missing, Err(RetrieveError::NotFound { .. })));
+
+		// The instance is only retrieved once for all attributes.
+		assert_eq!(wado.0.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn metadata_references_bulkdata() {
+		let instance = InMemDicomObject::from_element_iter([
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2"),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3"),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4"),
+		])
+		.with_meta(FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN))
+		.unwrap();
+		let metadata = |uri: Option<&str>| InstanceMetadata {
+			instance: Arc::new(instance.clone()),
+			bulkdata: vec![BulkDataReference {
+				tag: tags::PIXEL_DATA,
+				vr: VR::OW,
+				uri: uri.map(String::from),
+			}],
+		};
+
+		let json = metadata_json(metadata(None), Some("http://localhost:8080/aets/PACS")).unwrap();
+		assert_eq!(
+			json["7FE00010"]["BulkDataURI"],
+			"http://localhost:8080/aets/PACS/studies/1.2/series/1.2.3/instances/1.2.3.4/bulkdata/7FE00010"
+		);
+
+		let presigned = "https://s3.example.com/bucket/1.2.3.4.dcm?X-Amz-Signature=abc#bytes=10-19";
+		let json = metadata_json(metadata(Some(presigned)), None).unwrap();
+		assert_eq!(json["7FE00010"]["BulkDataURI"], presigned);
+		assert_eq!(json["7FE00010"]["vr"], "OW");
+	}
+}
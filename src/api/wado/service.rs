@@ -10,11 +10,15 @@ use axum::http::header::ACCEPT;
 use axum::http::request::Parts;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use dicom::core::ops::{AttributeSelector, AttributeSelectorStep};
+use dicom::core::{Tag, VR};
 use dicom::object::{FileDicomObject, InMemDicomObject};
 use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer};
-use std::fmt::{Debug, Formatter};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -36,7 +40,27 @@ pub trait WadoService: Send + Sync {
 		return Err(RetrieveError::Unimplemented);
 	}
 
-	async fn metadata(&self, _request: MetadataRequest) -> Result<InstanceResponse, RetrieveError> {
+	async fn metadata(&self, _request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
+		return Err(RetrieveError::Unimplemented);
+	}
+
+	/// Some backends can hand out a URL from which the client downloads the instance directly,
+	/// e.g. a presigned URL of an object storage. Requests for a single instance that accept
+	/// `application/dicom` are redirected to this URL instead of streaming the instance.
+	async fn instance_url(
+		&self,
+		_request: &RetrieveInstanceRequest,
+	) -> Result<Option<String>, RetrieveError> {
+		Ok(None)
+	}
+
+	/// Some backends can read the bulk data of an instance without retrieving the instance,
+	/// e.g. with a ranged request to an object storage.
+	/// If unimplemented, the bulk data is extracted from the retrieved instance.
+	async fn bulkdata(
+		&self,
+		_request: &BulkDataRequest,
+	) -> Result<BulkDataResponse, RetrieveError> {
 		return Err(RetrieveError::Unimplemented);
 	}
 }
@@ -45,6 +69,15 @@ pub trait WadoService: Send + Sync {
 pub enum RetrieveError {
 	#[error(transparent)]
 	Backend { source: anyhow::Error },
+	/// The requested resource does not exist.
+	#[error("{message}")]
+	NotFound { message: String },
+	/// The backend rejected the request or returned invalid data.
+	#[error(transparent)]
+	BadGateway { source: anyhow::Error },
+	/// The backend is temporarily unavailable.
+	#[error(transparent)]
+	Unavailable { source: anyhow::Error },
 	#[error("Unimplemented")]
 	Unimplemented,
 }
@@ -55,6 +88,13 @@ impl IntoResponse for RetrieveError {
 			Self::Backend { source } => {
 				(StatusCode::INTERNAL_SERVER_ERROR, source.to_string()).into_response()
 			}
+			Self::NotFound { message } => (StatusCode::NOT_FOUND, message).into_response(),
+			Self::BadGateway { source } => {
+				(StatusCode::BAD_GATEWAY, source.to_string()).into_response()
+			}
+			Self::Unavailable { source } => {
+				(StatusCode::SERVICE_UNAVAILABLE, source.to_string()).into_response()
+			}
 			Self::Unimplemented => Response::builder()
 				.status(StatusCode::NOT_IMPLEMENTED)
 				.body(Body::from("This transaction is not implemented."))
@@ -65,6 +105,8 @@ impl IntoResponse for RetrieveError {
 pub struct RetrieveInstanceRequest {
 	pub query: ResourceQuery,
 	pub transfer_syntax: Option<String>,
+	/// Whether the client accepts a single `application/dicom` instance instead of `multipart/related`.
+	pub single_part: bool,
 }
 
 pub struct ThumbnailRequest {
@@ -82,6 +124,75 @@ pub struct MetadataRequest {
 	pub query: ResourceQuery,
 }
 
+/// A request for the bulk data of a single attribute of an instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkDataRequest {
+	pub query: ResourceQuery,
+	pub path: BulkDataPath,
+	/// Whether the client accepts a single `application/octet-stream` instead of `multipart/related`.
+	pub single_part: bool,
+}
+
+/// The path of an attribute in a bulk data URI, relative to the instance.
+///
+/// The path consists of the tags of the attribute and its parent sequences, each followed by the
+/// (zero-based) index of the item that contains the next attribute, e.g. `00880200/0/7FE00010`
+/// for the pixel data of the first item of the Icon Image Sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkDataPath(pub AttributeSelector);
+
+impl Display for BulkDataPath {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		for (index, step) in self.0.iter().enumerate() {
+			if index > 0 {
+				f.write_str("/")?;
+			}
+			match step {
+				AttributeSelectorStep::Tag(tag) => {
+					write!(f, "{:04X}{:04X}", tag.group(), tag.element())?;
+				}
+				AttributeSelectorStep::Nested { tag, item } => {
+					write!(f, "{:04X}{:04X}/{item}", tag.group(), tag.element())?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid bulk data path: {path}")]
+pub struct ParseBulkDataPathError {
+	path: String,
+}
+
+impl FromStr for BulkDataPath {
+	type Err = ParseBulkDataPathError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let error = || ParseBulkDataPathError { path: s.to_owned() };
+
+		let mut segments = s.trim_matches('/').split('/');
+		let mut steps = Vec::new();
+		while let Some(segment) = segments.next() {
+			if segment.len() != 8 {
+				return Err(error());
+			}
+			let tag: Tag = segment.parse().map_err(|_| error())?;
+			let step = match segments.next() {
+				Some(item) => AttributeSelectorStep::Nested {
+					tag,
+					item: item.parse().map_err(|_| error())?,
+				},
+				None => AttributeSelectorStep::Tag(tag),
+			};
+			steps.push(step);
+		}
+
+		AttributeSelector::new(steps).map(Self).ok_or_else(error)
+	}
+}
+
 /// <https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_8.3.5.html#table_8.3.5-1>
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct RetrieveRenderedQueryParameters {
@@ -185,6 +296,56 @@ fn extract_transfer_syntax_from_accept(accept_header: &str) -> Option<String> {
 	None
 }
 
+/// Whether one of the media ranges of an Accept header value is the given single part media type
+/// (e.g. `application/dicom`), i.e. the client accepts a single part that is not wrapped in
+/// `multipart/related`.
+fn accepts_single_part(accept_header: &str, single_part_type: &str) -> bool {
+	accept_header.split(',').any(|media_range| {
+		media_range
+			.split(';')
+			.next()
+			.is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(single_part_type))
+	})
+}
+
+impl<S> FromRequestParts<S> for BulkDataRequest
+where
+	AppState: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let Path(query): Path<ResourceQuery> = Path::from_request_parts(parts, state)
+			.await
+			.map_err(PathRejection::into_response)?;
+		let Path(params): Path<HashMap<String, String>> = Path::from_request_parts(parts, state)
+			.await
+			.map_err(PathRejection::into_response)?;
+
+		let path = params
+			.get("path")
+			.map(String::as_str)
+			.unwrap_or_default()
+			.parse()
+			.map_err(|err: ParseBulkDataPathError| {
+				(StatusCode::BAD_REQUEST, err.to_string()).into_response()
+			})?;
+
+		let single_part = parts
+			.headers
+			.get(ACCEPT)
+			.and_then(|accept| accept.to_str().ok())
+			.is_some_and(|accept| accepts_single_part(accept, "application/octet-stream"));
+
+		Ok(Self {
+			query,
+			path,
+			single_part,
+		})
+	}
+}
+
 impl<S> FromRequestParts<S> for RetrieveInstanceRequest
 where
 	AppState: FromRef<S>,
@@ -208,9 +369,14 @@ where
 			.as_ref()
 			.and_then(|accept_str| extract_transfer_syntax_from_accept(accept_str));
 
+		let single_part = accept
+			.as_ref()
+			.is_some_and(|accept_str| accepts_single_part(accept_str, "application/dicom"));
+
 		Ok(Self {
 			query,
 			transfer_syntax,
+			single_part,
 		})
 	}
 }
@@ -237,7 +403,54 @@ pub struct InstanceResponse {
 
 pub struct RenderedResponse(pub Vec<u8>);
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// The bulk data of an attribute. Encapsulated pixel data consists of the concatenated fragments.
+pub struct BulkDataResponse(pub Vec<u8>);
+
+pub struct MetadataResponse {
+	pub stream: BoxStream<'static, Result<InstanceMetadata, MoveError>>,
+}
+
+impl From<InstanceResponse> for MetadataResponse {
+	fn from(response: InstanceResponse) -> Self {
+		Self {
+			stream: response.stream.map_ok(InstanceMetadata::from).boxed(),
+		}
+	}
+}
+
+/// The metadata of an instance.
+pub struct InstanceMetadata {
+	pub instance: Arc<FileDicomObject<InMemDicomObject>>,
+	/// Bulk data attributes that are not part of the instance (e.g. as it was only read up to
+	/// its pixel data), but are referenced by a `BulkDataURI`.
+	pub bulkdata: Vec<BulkDataReference>,
+}
+
+impl From<Arc<FileDicomObject<InMemDicomObject>>> for InstanceMetadata {
+	fn from(instance: Arc<FileDicomObject<InMemDicomObject>>) -> Self {
+		Self {
+			instance,
+			bulkdata: Vec::new(),
+		}
+	}
+}
+
+impl From<FileDicomObject<InMemDicomObject>> for InstanceMetadata {
+	fn from(instance: FileDicomObject<InMemDicomObject>) -> Self {
+		Arc::new(instance).into()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkDataReference {
+	pub tag: Tag,
+	pub vr: VR,
+	/// A URI from which the client reads the bulk data directly, e.g. a presigned URL.
+	/// If not set, the bulk data resource of the instance is referenced.
+	pub uri: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 pub struct ResourceQuery {
 	#[serde(rename = "aet")]
 	pub aet: AE,
@@ -473,6 +686,7 @@ pub enum IccProfile {
 mod tests {
 	use axum::extract::Query;
 	use axum::http::Uri;
+	use dicom::dictionary_std::tags;
 
 	use super::*;
 
@@ -551,6 +765,52 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_accepts_single_part() {
+		const DICOM: &str = "application/dicom";
+		assert!(accepts_single_part("application/dicom", DICOM));
+		assert!(accepts_single_part(
+			"application/dicom; transfer-syntax=*",
+			DICOM
+		));
+		assert!(accepts_single_part(
+			"multipart/related; type=\"application/dicom\", application/dicom",
+			DICOM
+		));
+		assert!(!accepts_single_part(
+			"multipart/related; type=\"application/dicom\"",
+			DICOM
+		));
+		assert!(!accepts_single_part("*/*", DICOM));
+		assert!(accepts_single_part(
+			"application/octet-stream",
+			"application/octet-stream"
+		));
+	}
+
+	#[test]
+	fn parse_bulkdata_path() {
+		let path: BulkDataPath = "00880200/1/7FE00010".parse().unwrap();
+		assert_eq!(
+			path,
+			BulkDataPath(AttributeSelector::from((
+				tags::ICON_IMAGE_SEQUENCE,
+				1,
+				tags::PIXEL_DATA
+			)))
+		);
+		assert_eq!(path.to_string(), "00880200/1/7FE00010");
+		assert_eq!(
+			"7FE00010".parse::<BulkDataPath>().unwrap(),
+			BulkDataPath(AttributeSelector::from(tags::PIXEL_DATA))
+		);
+
+		assert!("".parse::<BulkDataPath>().is_err());
+		assert!("00880200/1".parse::<BulkDataPath>().is_err());
+		assert!("00880200/x/7FE00010".parse::<BulkDataPath>().is_err());
+		assert!("PixelData".parse::<BulkDataPath>().is_err());
+	}
+
 	#[test]
 	fn parse_rendered_query_params() {
 		let uri =
@@ -0,0 +1,463 @@
+//! A backend that forwards requests to an origin server that implements `DICOMweb`,
+//! e.g. another archive or a cloud healthcare API.
+//!
+//! All services of the AET share one HTTP client, which sends the configured headers with every request.
+
+pub mod mwl;
+pub mod qido;
+pub mod stow;
+pub mod wado;
+
+use crate::api::wado::RetrieveError;
+use crate::api::{IncludeField, MatchCriteria};
+use crate::config::{AppConfig, BackendConfig, DicomWebConfig};
+use crate::types::AE;
+use anyhow::Context;
+use async_stream::try_stream;
+use dicom::core::ops::AttributeSelectorStep;
+use dicom::core::Tag;
+use dicom::object::InMemDicomObject;
+use dicom_json::DicomJson;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::info;
+use url::Url;
+
+/// The media type of DICOM JSON responses.
+const DICOM_JSON: &str = "application/dicom+json";
+
+/// The HTTP clients of all AETs that forward requests to an origin server.
+/// Clients are created once, so that connections are reused across requests.
+#[derive(Clone, Default)]
+pub struct DicomWebClients(HashMap<AE, DicomWebClient>);
+
+impl DicomWebClients {
+	/// Creates the clients of all AETs with the `DICOMWEB` backend.
+	///
+	/// # Errors
+	/// Returns an error if the URL or a header of an AET is invalid.
+	pub fn new(config: &AppConfig) -> anyhow::Result<Self> {
+		let mut clients = HashMap::new();
+		for ae_config in &config.aets {
+			if let BackendConfig::DicomWeb(dicomweb_config) = &ae_config.backend {
+				let client = DicomWebClient::new(dicomweb_config).with_context(|| {
+					format!("Invalid DICOMweb backend of AET {}", ae_config.aet)
+				})?;
+				info!(
+					aet = ae_config.aet,
+					url = dicomweb_config.url,
+					"Created DICOMweb client"
+				);
+				clients.insert(ae_config.aet.clone(), client);
+			}
+		}
+		Ok(Self(clients))
+	}
+
+	#[inline]
+	pub fn get(&self, aet: &str) -> Option<&DicomWebClient> {
+		self.0.get(aet)
+	}
+}
+
+/// A client for the origin server of an AET. Clones share the same connection pool.
+#[derive(Clone)]
+pub struct DicomWebClient {
+	http: reqwest::Client,
+	url: Url,
+}
+
+impl DicomWebClient {
+	/// # Errors
+	/// Returns an error if the URL or a header is invalid, or an environment variable of a header is not set.
+	pub fn new(config: &DicomWebConfig) -> anyhow::Result<Self> {
+		let url = Url::parse(&config.url).with_context(|| format!("Invalid URL {}", config.url))?;
+		if url.cannot_be_a_base() {
+			anyhow::bail!("Invalid URL {}", config.url);
+		}
+
+		let mut headers = HeaderMap::new();
+		for (name, value) in &config.headers {
+			let value = value
+				.resolve()
+				.with_context(|| format!("Failed to resolve the value of header {name}"))?;
+			let mut value = HeaderValue::from_str(&value)
+				.with_context(|| format!("Invalid value of header {name}"))?;
+			// Headers usually contain credentials, which must not be logged.
+			value.set_sensitive(true);
+			let name = HeaderName::try_from(name.as_str())
+				.with_context(|| format!("Invalid header name {name}"))?;
+			headers.insert(name, value);
+		}
+
+		let http = reqwest::Client::builder()
+			.default_headers(headers)
+			.user_agent(crate::IMPLEMENTATION_VERSION_NAME)
+			.connect_timeout(Duration::from_secs(5))
+			.build()?;
+
+		Ok(Self { http, url })
+	}
+
+	/// The URL of a resource below the base URL, e.g. `["studies", "1.2.3", "metadata"]`.
+	/// The segments are percent-encoded.
+	pub fn url<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Url {
+		let mut url = self.url.clone();
+		url.path_segments_mut()
+			.expect("URL should be a base")
+			.pop_if_empty()
+			.extend(segments);
+		url
+	}
+
+	pub fn get(&self, url: Url) -> RequestBuilder {
+		self.http.get(url)
+	}
+
+	pub fn post(&self, url: Url) -> RequestBuilder {
+		self.http.post(url)
+	}
+
+	/// Sends the request and waits at most `timeout` for the response.
+	/// Responses with an error status are returned as [`DicomWebError::Status`].
+	///
+	/// # Errors
+	/// Returns an error if the request fails, times out or the response has an error status.
+	pub async fn send(
+		request: RequestBuilder,
+		timeout: Duration,
+	) -> Result<Response, DicomWebError> {
+		let response = tokio::time::timeout(timeout, request.send())
+			.await
+			.map_err(|_| DicomWebError::Timeout(timeout))??;
+
+		Self::check_status(response).await
+	}
+
+	/// Returns responses with an error status as [`DicomWebError::Status`].
+	///
+	/// # Errors
+	/// Returns an error if the response has an error status.
+	pub async fn check_status(response: Response) -> Result<Response, DicomWebError> {
+		let status = response.status();
+		if status.is_success() {
+			return Ok(response);
+		}
+		let message = response.text().await.unwrap_or_default();
+		Err(DicomWebError::Status { status, message })
+	}
+}
+
+/// Errors of requests to the origin server, classified by how they should be handled.
+#[derive(Debug, Error)]
+pub enum DicomWebError {
+	/// The origin server could not be reached or the response could not be read.
+	#[error("Request to DICOMweb server failed: {0}")]
+	Request(#[from] reqwest::Error),
+	/// The origin server did not respond in time.
+	#[error("DICOMweb server did not respond within {0:?}")]
+	Timeout(Duration),
+	/// The origin server responded with an error status.
+	#[error("DICOMweb server responded with {status}: {message}")]
+	Status { status: StatusCode, message: String },
+	/// The response could not be parsed.
+	#[error("Invalid response from DICOMweb server: {0}")]
+	InvalidResponse(String),
+}
+
+impl From<DicomWebError> for RetrieveError {
+	fn from(err: DicomWebError) -> Self {
+		match &err {
+			DicomWebError::Status {
+				status: StatusCode::NOT_FOUND,
+				..
+			} => Self::NotFound {
+				message: err.to_string(),
+			},
+			DicomWebError::Timeout(_)
+			| DicomWebError::Status {
+				status:
+					StatusCode::TOO_MANY_REQUESTS
+					| StatusCode::BAD_GATEWAY
+					| StatusCode::SERVICE_UNAVAILABLE
+					| StatusCode::GATEWAY_TIMEOUT,
+				..
+			} => Self::Unavailable { source: err.into() },
+			DicomWebError::Request(source) if source.is_connect() || source.is_timeout() => {
+				Self::Unavailable { source: err.into() }
+			}
+			_ => Self::BadGateway { source: err.into() },
+		}
+	}
+}
+
+/// The query parameters of a search request that are forwarded to the origin server.
+struct SearchParameters<'a> {
+	match_criteria: &'a MatchCriteria,
+	include_field: &'a IncludeField,
+	fuzzy_matching: bool,
+	offset: usize,
+	limit: usize,
+}
+
+/// Appends the query parameters of a search request.
+///
+/// `limit` is always sent, as origin servers (e.g. cloud healthcare APIs) apply their own
+/// default page size otherwise, which would silently truncate the result set.
+fn append_search_parameters(url: &mut Url, parameters: &SearchParameters) {
+	let SearchParameters {
+		match_criteria,
+		include_field,
+		fuzzy_matching,
+		offset,
+		limit,
+	} = *parameters;
+	let mut query = url.query_pairs_mut();
+	for (selector, value) in match_criteria.iter() {
+		let key = selector
+			.iter()
+			.map(|step| match step {
+				AttributeSelectorStep::Tag(tag) | AttributeSelectorStep::Nested { tag, .. } => {
+					tag_key(*tag)
+				}
+			})
+			.collect::<Vec<_>>()
+			.join(".");
+		// Multiple values are only used for UID list matching, which uses commas.
+		query.append_pair(&key, &value.to_multi_str().join(","));
+	}
+	match include_field {
+		IncludeField::All => {
+			query.append_pair("includefield", "all");
+		}
+		IncludeField::List(tags) => {
+			for tag in tags {
+				query.append_pair("includefield", &tag_key(*tag));
+			}
+		}
+	}
+	if fuzzy_matching {
+		query.append_pair("fuzzymatching", "true");
+	}
+	if offset > 0 {
+		query.append_pair("offset", &offset.to_string());
+	}
+	query.append_pair("limit", &limit.to_string());
+}
+
+/// The tag as used in query parameters and DICOM JSON, e.g. `0020000D`.
+fn tag_key(tag: Tag) -> String {
+	format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+/// Reads a DICOM JSON array object by object, so that the response body is not buffered completely.
+/// Responses without content (`204 No Content`) contain no objects.
+fn read_json(response: Response) -> BoxStream<'static, Result<InMemDicomObject, DicomWebError>> {
+	let mut body = response.bytes_stream();
+	let stream = try_stream! {
+		let mut splitter = JsonArraySplitter::default();
+		while let Some(chunk) = body.next().await {
+			for element in splitter.push(&chunk?)? {
+				let object: DicomJson<InMemDicomObject> = serde_json::from_slice(&element)
+					.map_err(|err| DicomWebError::InvalidResponse(err.to_string()))?;
+				yield object.into_inner();
+			}
+		}
+		splitter.finish()?;
+	};
+	stream.boxed()
+}
+
+/// Splits a JSON array into the encoded elements while it is read.
+/// Only the structure of the array is checked, the elements are parsed separately.
+#[derive(Default)]
+struct JsonArraySplitter {
+	/// The bytes of the current element.
+	element: Vec<u8>,
+	/// The nesting depth within the current element.
+	depth: usize,
+	in_string: bool,
+	escaped: bool,
+	position: ArrayPosition,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum ArrayPosition {
+	#[default]
+	Before,
+	Inside,
+	After,
+}
+
+impl JsonArraySplitter {
+	/// Reads the next chunk of the array and returns the elements that were completed by it.
+	fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<u8>>, DicomWebError> {
+		let invalid = || DicomWebError::InvalidResponse(String::from("expected a JSON array"));
+		let mut elements = Vec::new();
+		for &byte in chunk {
+			if self.in_string {
+				self.element.push(byte);
+				if self.escaped {
+					self.escaped = false;
+				} else if byte == b'\\' {
+					self.escaped = true;
+				} else if byte == b'"' {
+					self.in_string = false;
+				}
+				continue;
+			}
+			match byte {
+				byte if byte.is_ascii_whitespace() && self.depth == 0 => {}
+				b'[' if self.position == ArrayPosition::Before => {
+					self.position = ArrayPosition::Inside;
+				}
+				_ if self.position != ArrayPosition::Inside => return Err(invalid()),
+				b',' | b']' if self.depth == 0 => {
+					if !self.element.is_empty() {
+						elements.push(std::mem::take(&mut self.element));
+					}
+					if byte == b']' {
+						self.position = ArrayPosition::After;
+					}
+				}
+				b'{' | b'[' => {
+					self.depth += 1;
+					self.element.push(byte);
+				}
+				b'}' | b']' => {
+					self.depth = self.depth.checked_sub(1).ok_or_else(invalid)?;
+					self.element.push(byte);
+				}
+				b'"' => {
+					self.in_string = true;
+					self.element.push(byte);
+				}
+				byte => self.element.push(byte),
+			}
+		}
+		Ok(elements)
+	}
+
+	/// Checks that the array is complete, unless the response was empty.
+	fn finish(&self) -> Result<(), DicomWebError> {
+		if self.position == ArrayPosition::Inside {
+			return Err(DicomWebError::InvalidResponse(String::from(
+				"incomplete JSON array",
+			)));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::HeaderValueConfig;
+	use axum::Router;
+	use dicom::core::PrimitiveValue;
+	use dicom::dictionary_std::tags;
+	use tokio::net::TcpListener;
+
+	/// Serves the router on a random local port, as a stand-in for the origin server.
+	pub async fn serve(router: Router) -> DicomWebClient {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		tokio::spawn(async move { axum::serve(listener, router).await });
+
+		DicomWebClient::new(&DicomWebConfig {
+			url: format!("http://{address}/dicom-web/"),
+			headers: HashMap::from([(
+				String::from("Authorization"),
+				HeaderValueConfig::Plain(String::from("Bearer secret")),
+			)]),
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn encodes_search_parameters() {
+		let client = DicomWebClient::new(&DicomWebConfig {
+			url: String::from("https://pacs.example.com/dicom-web"),
+			headers: HashMap::new(),
+		})
+		.unwrap();
+
+		let mut url = client.url(["studies", "1.2.3", "series"]);
+		let mut match_criteria = MatchCriteria::try_from(HashMap::from([(
+			String::from("00400100.00080060"),
+			String::from("CT"),
+		)]))
+		.unwrap();
+		match_criteria.set(
+			tags::SERIES_INSTANCE_UID,
+			PrimitiveValue::Strs(vec![String::from("1.2"), String::from("1.3")].into()),
+		);
+		append_search_parameters(
+			&mut url,
+			&SearchParameters {
+				match_criteria: &match_criteria,
+				include_field: &IncludeField::List(vec![tags::PATIENT_NAME]),
+				fuzzy_matching: true,
+				offset: 0,
+				limit: 201,
+			},
+		);
+
+		assert_eq!(
+			url.as_str(),
+			"https://pacs.example.com/dicom-web/studies/1.2.3/series\
+			?00400100.00080060=CT&0020000E=1.2%2C1.3&includefield=00100010&fuzzymatching=true&limit=201"
+		);
+	}
+
+	#[test]
+	fn splits_json_array_across_chunks() {
+		let json = br#" [ {"a": "x,]}\"{"}, {"b": [1, {"c": 2}]} ] "#;
+		let mut splitter = JsonArraySplitter::default();
+		let mut elements = Vec::new();
+		for chunk in json.chunks(3) {
+			elements.extend(splitter.push(chunk).unwrap());
+		}
+		splitter.finish().unwrap();
+
+		assert_eq!(
+			elements,
+			vec![
+				br#"{"a": "x,]}\"{"}"#.to_vec(),
+				br#"{"b": [1, {"c": 2}]}"#.to_vec()
+			]
+		);
+
+		let mut truncated = JsonArraySplitter::default();
+		truncated.push(br#"[{"a": 1},"#).unwrap();
+		assert!(truncated.finish().is_err());
+		assert!(JsonArraySplitter::default().push(b"{}").is_err());
+		assert!(JsonArraySplitter::default().push(b"[}]").is_err());
+		assert!(JsonArraySplitter::default().finish().is_ok());
+	}
+
+	#[test]
+	fn classifies_status_codes() {
+		let error = |status| DicomWebError::Status {
+			status,
+			message: String::new(),
+		};
+		assert!(matches!(
+			RetrieveError::from(error(StatusCode::NOT_FOUND)),
+			RetrieveError::NotFound { .. }
+		));
+		assert!(matches!(
+			RetrieveError::from(error(StatusCode::SERVICE_UNAVAILABLE)),
+			RetrieveError::Unavailable { .. }
+		));
+		assert!(matches!(
+			RetrieveError::from(error(StatusCode::UNAUTHORIZED)),
+			RetrieveError::BadGateway { .. }
+		));
+	}
+}
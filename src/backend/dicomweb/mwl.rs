@@ -0,0 +1,74 @@
+use crate::api::mwl::{MwlSearchError, MwlSearchRequest, MwlSearchResponse, MwlService};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::ACCEPT;
+use reqwest::Response;
+use std::time::Duration;
+use tracing::warn;
+
+use super::{
+	append_search_parameters, read_json, DicomWebClient, DicomWebError, SearchParameters,
+	DICOM_JSON,
+};
+
+/// Forwards MWL-RS requests to the origin server.
+pub struct DicomWebMwlService {
+	client: DicomWebClient,
+	timeout: Duration,
+}
+
+impl DicomWebMwlService {
+	pub const fn new(client: DicomWebClient, timeout: Duration) -> Self {
+		Self { client, timeout }
+	}
+
+	async fn search_upstream(&self, request: &MwlSearchRequest) -> Result<Response, DicomWebError> {
+		let mut url = self.client.url(["modality-scheduled-procedure-steps"]);
+		append_search_parameters(
+			&mut url,
+			&SearchParameters {
+				match_criteria: &request.parameters.match_criteria,
+				include_field: &request.parameters.include_field,
+				fuzzy_matching: request.parameters.fuzzy_matching,
+				offset: request.parameters.offset,
+				limit: request.parameters.limit,
+			},
+		);
+
+		DicomWebClient::send(
+			self.client.get(url).header(ACCEPT, DICOM_JSON),
+			self.timeout,
+		)
+		.await
+	}
+}
+
+#[async_trait]
+impl MwlService for DicomWebMwlService {
+	async fn search(&self, request: MwlSearchRequest) -> MwlSearchResponse {
+		match self.search_upstream(&request).await {
+			Ok(response) => MwlSearchResponse {
+				stream: read_json(response)
+					.map_err(|err| {
+						warn!("Failed to read worklist of DICOMweb server: {err}");
+						MwlSearchError::Backend {
+							source: err.to_string().into(),
+						}
+					})
+					.boxed(),
+			},
+			Err(err) => {
+				warn!("Failed to search worklist of DICOMweb server: {err}");
+				let message = err.to_string();
+				MwlSearchResponse {
+					stream: futures::stream::once(async move {
+						Err(MwlSearchError::Backend {
+							source: message.into(),
+						})
+					})
+					.boxed(),
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,175 @@
+use crate::api::qido::{QidoService, ResourceQuery, SearchError, SearchRequest, SearchResponse};
+use crate::types::QueryRetrieveLevel;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::{ACCEPT, WARNING};
+use reqwest::Response;
+use std::time::Duration;
+use tracing::warn;
+
+use super::{
+	append_search_parameters, read_json, DicomWebClient, DicomWebError, SearchParameters,
+	DICOM_JSON,
+};
+
+/// Forwards QIDO-RS requests to the origin server.
+///
+/// The `limit` of the request is forwarded, so that the origin server does not truncate the result set
+/// to its default page size. Its `Warning` headers are passed through to the client.
+pub struct DicomWebQidoService {
+	client: DicomWebClient,
+	timeout: Duration,
+}
+
+impl DicomWebQidoService {
+	pub const fn new(client: DicomWebClient, timeout: Duration) -> Self {
+		Self { client, timeout }
+	}
+
+	async fn search_upstream(&self, request: &SearchRequest) -> Result<Response, DicomWebError> {
+		let segments = resource_segments(&request.query).ok_or_else(|| {
+			DicomWebError::InvalidResponse(format!(
+				"Unsupported query retrieve level {:?}",
+				request.query.query_retrieve_level
+			))
+		})?;
+		let mut url = self.client.url(segments);
+		append_search_parameters(
+			&mut url,
+			&SearchParameters {
+				match_criteria: &request.parameters.match_criteria,
+				include_field: &request.parameters.include_field,
+				fuzzy_matching: request.parameters.fuzzy_matching,
+				offset: request.parameters.offset,
+				limit: request.parameters.limit,
+			},
+		);
+
+		DicomWebClient::send(
+			self.client.get(url).header(ACCEPT, DICOM_JSON),
+			self.timeout,
+		)
+		.await
+	}
+}
+
+#[async_trait]
+impl QidoService for DicomWebQidoService {
+	async fn search(&self, request: SearchRequest) -> SearchResponse {
+		match self.search_upstream(&request).await {
+			Ok(response) => {
+				let warnings: Vec<String> = response
+					.headers()
+					.get_all(WARNING)
+					.iter()
+					.filter_map(|warning| warning.to_str().ok())
+					.map(str::to_owned)
+					.collect();
+				let stream = read_json(response)
+					.map_err(|err| {
+						warn!("Failed to read search results of DICOMweb server: {err}");
+						SearchError::Backend {
+							source: err.to_string().into(),
+						}
+					})
+					.boxed();
+
+				let response = SearchResponse::new(stream);
+				for warning in warnings {
+					response.warnings.push(warning);
+				}
+				response
+			}
+			Err(err) => {
+				warn!("Failed to search DICOMweb server: {err}");
+				let message = err.to_string();
+				SearchResponse::new(
+					futures::stream::once(async move {
+						Err(SearchError::Backend {
+							source: message.into(),
+						})
+					})
+					.boxed(),
+				)
+			}
+		}
+	}
+}
+
+/// The path segments of the search resource, e.g. `studies/{study}/series` for the series of a study.
+fn resource_segments(query: &ResourceQuery) -> Option<Vec<&str>> {
+	let study = query.study_instance_uid.as_deref();
+	let series = query.series_instance_uid.as_deref();
+	let segments = match (query.query_retrieve_level, study, series) {
+		(QueryRetrieveLevel::Study, _, _) => vec!["studies"],
+		(QueryRetrieveLevel::Series, Some(study), _) => vec!["studies", study, "series"],
+		(QueryRetrieveLevel::Series, None, _) => vec!["series"],
+		(QueryRetrieveLevel::Image, Some(study), Some(series)) => {
+			vec!["studies", study, "series", series, "instances"]
+		}
+		(QueryRetrieveLevel::Image, Some(study), None) => vec!["studies", study, "instances"],
+		(QueryRetrieveLevel::Image, None, _) => vec!["instances"],
+		(QueryRetrieveLevel::Patient | QueryRetrieveLevel::Frame, _, _) => return None,
+	};
+	Some(segments)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::qido::QueryParameters;
+	use crate::backend::dicomweb::tests::serve;
+	use axum::extract::RawQuery;
+	use axum::http::HeaderMap;
+	use axum::routing::get;
+	use axum::{Json, Router};
+	use dicom::dictionary_std::tags;
+	use dicom::object::InMemDicomObject;
+
+	#[tokio::test]
+	async fn forwards_search_to_resource() {
+		let router = Router::new().route(
+			"/dicom-web/studies/{study}/series",
+			get(|headers: HeaderMap, RawQuery(query): RawQuery| async move {
+				assert_eq!(headers["authorization"], "Bearer secret");
+				assert_eq!(query.as_deref(), Some("includefield=all&limit=42"));
+				(
+					[(WARNING, r#"299 ORIGIN "There are additional results""#)],
+					Json(serde_json::json!([
+						{ "0020000E": { "vr": "UI", "Value": ["1.2.3.4"] } }
+					])),
+				)
+			}),
+		);
+		let qido = DicomWebQidoService::new(serve(router).await, Duration::from_secs(5));
+
+		let request = SearchRequest {
+			query: ResourceQuery {
+				query_retrieve_level: QueryRetrieveLevel::Series,
+				study_instance_uid: Some(String::from("1.2.3")),
+				series_instance_uid: None,
+			},
+			parameters: QueryParameters {
+				include_field: crate::api::IncludeField::All,
+				limit: 42,
+				..QueryParameters::default()
+			},
+		};
+		let response = qido.search(request).await;
+		let matches: Vec<InMemDicomObject> = response.stream.try_collect().await.unwrap();
+
+		assert_eq!(matches.len(), 1);
+		assert_eq!(
+			response.warnings.to_vec(),
+			vec![String::from(r#"299 ORIGIN "There are additional results""#)]
+		);
+		assert_eq!(
+			matches[0]
+				.element(tags::SERIES_INSTANCE_UID)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"1.2.3.4"
+		);
+	}
+}
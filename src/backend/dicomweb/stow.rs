@@ -0,0 +1,365 @@
+use crate::api::stow::{
+	InstanceReference, StoreError, StoreRequest, StoreResponse, StowService, FAILURE_PROCESSING,
+};
+use crate::types::UI;
+use async_trait::async_trait;
+use bytes::Bytes;
+use dicom::core::{PrimitiveValue, Tag};
+use dicom::dictionary_std::tags;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use futures::{future, stream, StreamExt};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{Body, StatusCode};
+use std::convert::Infallible;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{DicomWebClient, DicomWebError, DICOM_JSON};
+
+/// Forwards STOW-RS requests to the origin server.
+///
+/// The instances of a request are streamed to the origin server in a single request,
+/// as soon as they have been read and coerced.
+pub struct DicomWebStowService {
+	client: DicomWebClient,
+	timeout: Duration,
+}
+
+/// The parts of a request, which are recorded while they are streamed to the origin server.
+#[derive(Default)]
+struct Parts {
+	/// The instances that were sent to the origin server.
+	sent: Vec<InstanceReference>,
+	/// The instances that could not be encoded.
+	failed: Vec<InstanceReference>,
+	/// The parts that could not be read.
+	errors: Vec<StoreError>,
+}
+
+impl DicomWebStowService {
+	pub const fn new(client: DicomWebClient, timeout: Duration) -> Self {
+		Self { client, timeout }
+	}
+
+	/// Sends the multipart body and returns the store response of the origin server, if there is one.
+	/// The timeout starts when the body has been sent, as uploading large instances can take a while.
+	async fn store_upstream(
+		&self,
+		body: Body,
+		boundary: &str,
+		uploaded: oneshot::Receiver<()>,
+	) -> Result<Option<InMemDicomObject>, DicomWebError> {
+		let request = self
+			.client
+			.post(self.client.url(["studies"]))
+			.header(
+				CONTENT_TYPE,
+				format!(r#"multipart/related; type="application/dicom"; boundary={boundary}"#),
+			)
+			.header(ACCEPT, DICOM_JSON)
+			.body(body)
+			.send();
+		tokio::pin!(request);
+
+		let response = tokio::select! {
+			response = &mut request => response?,
+			// Also completes if the body was dropped, e.g. because the connection failed.
+			_ = uploaded => tokio::time::timeout(self.timeout, request)
+				.await
+				.map_err(|_| DicomWebError::Timeout(self.timeout))??,
+		};
+
+		let body = match DicomWebClient::check_status(response).await {
+			Ok(response) => response.bytes().await?.to_vec(),
+			// Failed instances are reported in the body of `409 Conflict` responses.
+			Err(DicomWebError::Status {
+				status: StatusCode::CONFLICT,
+				message,
+			}) => message.into_bytes(),
+			Err(err) => return Err(err),
+		};
+		if body.is_empty() {
+			return Ok(None);
+		}
+		dicom_json::from_slice(&body)
+			.map(Some)
+			.map_err(|err| DicomWebError::InvalidResponse(err.to_string()))
+	}
+}
+
+#[async_trait]
+impl StowService for DicomWebStowService {
+	async fn store(&self, request: StoreRequest) -> Result<StoreResponse, StoreError> {
+		let boundary = Uuid::new_v4().simple().to_string();
+		let parts: Arc<Mutex<Parts>> = Arc::default();
+
+		// The request to the origin server is only sent if there is an instance to send.
+		let mut instances = request.instances;
+		let mut first = None;
+		while let Some(result) = instances.next().await {
+			first = encode_part(&parts, result, &boundary);
+			if first.is_some() {
+				break;
+			}
+		}
+
+		let mut response = match first {
+			None => StoreResponse::default(),
+			Some(first) => {
+				let (upload_finished, uploaded) = oneshot::channel();
+				let rest = {
+					let parts = Arc::clone(&parts);
+					let boundary = boundary.clone();
+					instances.filter_map(move |result| {
+						future::ready(encode_part(&parts, result, &boundary))
+					})
+				};
+				let end = {
+					let boundary = boundary.clone();
+					stream::once(async move {
+						let _ = upload_finished.send(());
+						Bytes::from(format!("--{boundary}--"))
+					})
+				};
+				let body = Body::wrap_stream(
+					stream::iter([first])
+						.chain(rest)
+						.chain(end)
+						.map(Ok::<_, Infallible>),
+				);
+
+				let result = self.store_upstream(body, &boundary, uploaded).await;
+				let sent =
+					mem::take(&mut parts.lock().unwrap_or_else(PoisonError::into_inner).sent);
+				let response = match result {
+					Ok(Some(object)) => StoreResponse {
+						retrieve_url: None,
+						failed_sequence: references(&object, tags::FAILED_SOP_SEQUENCE),
+						referenced_sequence: references(&object, tags::REFERENCED_SOP_SEQUENCE),
+						..StoreResponse::default()
+					},
+					// Without a response body, the status code indicates that all instances were stored.
+					Ok(None) => StoreResponse {
+						referenced_sequence: sent,
+						..StoreResponse::default()
+					},
+					Err(err) => {
+						warn!("Failed to store instances in DICOMweb server: {err}");
+						StoreResponse {
+							failed_sequence: sent
+								.into_iter()
+								.map(|reference| InstanceReference {
+									failure_reason: Some(FAILURE_PROCESSING),
+									..reference
+								})
+								.collect(),
+							..StoreResponse::default()
+						}
+					}
+				};
+				info!(
+					stored = response.referenced_sequence.len(),
+					failed = response.failed_sequence.len(),
+					"Stored instances in DICOMweb server"
+				);
+				response
+			}
+		};
+
+		let Parts { failed, errors, .. } =
+			mem::take(&mut *parts.lock().unwrap_or_else(PoisonError::into_inner));
+		response.failed_sequence.extend(failed);
+		response.add_rejected(&request.rejected);
+		for err in errors {
+			response.add_failed_part(err)?;
+		}
+		Ok(response)
+	}
+}
+
+/// Encodes the instance as a part of the multipart body.
+/// Returns [`None`] if the part could not be read or the instance could not be encoded,
+/// which is recorded in the parts.
+fn encode_part(
+	parts: &Mutex<Parts>,
+	result: Result<FileDicomObject<InMemDicomObject>, StoreError>,
+	boundary: &str,
+) -> Option<Bytes> {
+	let record = || parts.lock().unwrap_or_else(PoisonError::into_inner);
+	let instance = match result {
+		Ok(instance) => instance,
+		Err(err) => {
+			record().errors.push(err);
+			return None;
+		}
+	};
+	let reference = InstanceReference {
+		sop_class_uid: UI::from(instance.meta().media_storage_sop_class_uid()),
+		sop_instance_uid: UI::from(instance.meta().media_storage_sop_instance_uid()),
+		failure_reason: None,
+		warning_reason: None,
+		failure_attributes: Vec::new(),
+	};
+
+	let mut part = format!("--{boundary}\r\nContent-Type: application/dicom\r\n\r\n").into_bytes();
+	if let Err(err) = instance.write_all(&mut part) {
+		warn!(
+			sop_instance_uid = reference.sop_instance_uid,
+			"Failed to encode instance: {err}"
+		);
+		record().failed.push(InstanceReference {
+			failure_reason: Some(FAILURE_PROCESSING),
+			..reference
+		});
+		return None;
+	}
+	part.extend(b"\r\n");
+	record().sent.push(reference);
+	Some(Bytes::from(part))
+}
+
+/// Reads the items of the `ReferencedSOPSequence` or `FailedSOPSequence` of a store response.
+fn references(response: &InMemDicomObject, sequence: Tag) -> Vec<InstanceReference> {
+	let Some(items) = response.get(sequence).and_then(|element| element.items()) else {
+		return Vec::new();
+	};
+	items
+		.iter()
+		.map(|item| {
+			let uid = |tag| {
+				item.get(tag)
+					.and_then(|element| element.to_str().ok())
+					.map(|uid| uid.trim_end_matches(['\0', ' ']).to_owned())
+					.unwrap_or_default()
+			};
+			let reason = |tag| {
+				item.get(tag)
+					.and_then(|element| element.to_int::<u16>().ok())
+			};
+			let failure_attributes = match item
+				.get(tags::FAILURE_ATTRIBUTES)
+				.and_then(|element| element.value().primitive())
+			{
+				Some(PrimitiveValue::Tags(tags)) => tags.to_vec(),
+				_ => Vec::new(),
+			};
+			InstanceReference {
+				sop_class_uid: uid(tags::REFERENCED_SOP_CLASS_UID),
+				sop_instance_uid: uid(tags::REFERENCED_SOP_INSTANCE_UID),
+				failure_reason: reason(tags::FAILURE_REASON),
+				warning_reason: reason(tags::WARNING_REASON),
+				failure_attributes,
+			}
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::dicomweb::tests::serve;
+	use crate::backend::dicomweb::DicomWebClient;
+	use crate::backend::tests::instance;
+	use crate::config::DicomWebConfig;
+	use axum::http::{header, HeaderMap};
+	use axum::routing::post;
+	use axum::Router;
+	use futures::StreamExt;
+	use std::collections::HashMap;
+
+	fn request() -> StoreRequest {
+		StoreRequest {
+			instances: futures::stream::iter([Ok(instance("1.2.3", "1.2.3.4", "1.2.3.4.5"))])
+				.boxed(),
+			rejected: Arc::default(),
+		}
+	}
+
+	#[tokio::test]
+	async fn reports_failures_of_dicomweb_server() {
+		let router = Router::new().route(
+			"/dicom-web/studies",
+			post(|headers: HeaderMap| async move {
+				assert!(headers[header::CONTENT_TYPE]
+					.to_str()
+					.unwrap()
+					.starts_with("multipart/related; type=\"application/dicom\""));
+				(
+					StatusCode::CONFLICT,
+					[(header::CONTENT_TYPE, DICOM_JSON)],
+					serde_json::json!({
+						"00081198": { "vr": "SQ", "Value": [{
+							"00081150": { "vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.7"] },
+							"00081155": { "vr": "UI", "Value": ["1.2.3.4.5"] },
+							"00081197": { "vr": "US", "Value": [42752] }
+						}] }
+					})
+					.to_string(),
+				)
+			}),
+		);
+		let stow = DicomWebStowService::new(serve(router).await, Duration::from_secs(5));
+
+		let response = stow.store(request()).await.unwrap();
+
+		assert!(response.referenced_sequence.is_empty());
+		assert_eq!(response.failed_sequence.len(), 1);
+		assert_eq!(response.failed_sequence[0].sop_instance_uid, "1.2.3.4.5");
+		assert_eq!(response.failed_sequence[0].failure_reason, Some(0xA700));
+	}
+
+	#[tokio::test]
+	async fn failed_parts_keep_sent_instances() {
+		let router = Router::new().route(
+			"/dicom-web/studies",
+			post(|body: Bytes| async move {
+				assert!(body.ends_with(b"--"));
+				StatusCode::OK
+			}),
+		);
+		let stow = DicomWebStowService::new(serve(router).await, Duration::from_secs(5));
+
+		let response = stow
+			.store(StoreRequest {
+				instances: futures::stream::iter([
+					Ok(instance("1.2.3", "1.2.3.4", "1.2.3.4.5")),
+					Err(StoreError::UploadLimitExceeded),
+				])
+				.boxed(),
+				rejected: Arc::default(),
+			})
+			.await
+			.unwrap();
+
+		assert_eq!(response.referenced_sequence.len(), 1);
+		assert_eq!(
+			response.referenced_sequence[0].sop_instance_uid,
+			"1.2.3.4.5"
+		);
+		assert!(response.failed_sequence.is_empty());
+		assert_eq!(response.errors.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn unreachable_server_fails_all_instances() {
+		// Nothing listens on the discard port.
+		let client = DicomWebClient::new(&DicomWebConfig {
+			url: String::from("http://127.0.0.1:9/dicom-web"),
+			headers: HashMap::new(),
+		})
+		.unwrap();
+		let stow = DicomWebStowService::new(client, Duration::from_secs(5));
+
+		let response = stow.store(request()).await.unwrap();
+
+		assert_eq!(response.failed_sequence.len(), 1);
+		assert_eq!(
+			response.failed_sequence[0].failure_reason,
+			Some(FAILURE_PROCESSING)
+		);
+	}
+}
@@ -0,0 +1,223 @@
+use crate::api::wado::{
+	InstanceMetadata, InstanceResponse, MetadataRequest, MetadataResponse, ResourceQuery,
+	RetrieveError, RetrieveInstanceRequest, WadoService,
+};
+use crate::backend::dimse::cmove::movescu::MoveError;
+use crate::utils::multipart::DicomMultipart;
+use async_trait::async_trait;
+use bytes::Buf;
+use dicom::dictionary_std::uids;
+use dicom::object::{FileDicomObject, FileMetaTableBuilder};
+use futures::StreamExt;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use super::{read_json, DicomWebClient, DicomWebError, DICOM_JSON};
+
+/// Forwards WADO-RS requests to the origin server.
+///
+/// Rendered resources are not forwarded, but rendered from the retrieved instances,
+/// as not all origin servers support them.
+pub struct DicomWebWadoService {
+	client: DicomWebClient,
+	/// The time to wait for the response. Reading the instances is not limited.
+	timeout: Duration,
+}
+
+impl DicomWebWadoService {
+	pub const fn new(client: DicomWebClient, timeout: Duration) -> Self {
+		Self { client, timeout }
+	}
+}
+
+#[async_trait]
+impl WadoService for DicomWebWadoService {
+	async fn retrieve(
+		&self,
+		request: RetrieveInstanceRequest,
+	) -> Result<InstanceResponse, RetrieveError> {
+		let url = self.client.url(resource_segments(&request.query));
+		let mut accept = String::from(r#"multipart/related; type="application/dicom""#);
+		if let Some(transfer_syntax) = &request.transfer_syntax {
+			let _ = write!(accept, "; transfer-syntax={transfer_syntax}");
+		}
+		let response =
+			DicomWebClient::send(self.client.get(url).header(ACCEPT, accept), self.timeout).await?;
+
+		let content_type = response
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|value| value.to_str().ok())
+			.unwrap_or_default()
+			.to_owned();
+		// Some servers return a single instance without a multipart wrapper.
+		if content_type.starts_with("application/dicom") {
+			let bytes = response.bytes().await.map_err(DicomWebError::from)?;
+			let file = FileDicomObject::from_reader(bytes.reader()).map_err(|err| {
+				RetrieveError::from(DicomWebError::InvalidResponse(err.to_string()))
+			})?;
+			return Ok(InstanceResponse {
+				stream: futures::stream::once(async move { Ok(Arc::new(file)) }).boxed(),
+			});
+		}
+
+		let boundary = DicomMultipart::parse_boundary(&content_type).map_err(|err| {
+			DicomWebError::InvalidResponse(format!("Unexpected Content-Type {content_type}: {err}"))
+		})?;
+		let multipart = multer::Multipart::new(response.bytes_stream(), boundary);
+		let stream = futures::stream::unfold(Some(multipart), |multipart| async move {
+			let mut multipart = multipart?;
+			let bytes = match multipart.next_field().await {
+				Ok(Some(field)) => field.bytes().await,
+				Ok(None) => return None,
+				Err(err) => Err(err),
+			};
+			match bytes {
+				Ok(bytes) => {
+					let file = FileDicomObject::from_reader(bytes.reader())
+						.map(Arc::new)
+						.map_err(|err| {
+							warn!("Failed to read instance from DICOMweb server: {err}");
+							MoveError::OperationFailed
+						});
+					Some((file, Some(multipart)))
+				}
+				Err(err) => {
+					warn!("Failed to read response of DICOMweb server: {err}");
+					Some((Err(MoveError::OperationFailed), None))
+				}
+			}
+		});
+
+		Ok(InstanceResponse {
+			stream: stream.boxed(),
+		})
+	}
+
+	/// Bulk data is not part of the metadata, as clients cannot access the bulk data URIs of the origin server.
+	async fn metadata(&self, request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
+		let mut segments = resource_segments(&request.query);
+		segments.push("metadata");
+		let url = self.client.url(segments);
+		let response = DicomWebClient::send(
+			self.client.get(url).header(ACCEPT, DICOM_JSON),
+			self.timeout,
+		)
+		.await?;
+
+		#[allow(clippy::result_large_err)]
+		let stream = read_json(response).filter_map(|result| async move {
+			match result {
+				Ok(object) => object
+					.with_meta(
+						FileMetaTableBuilder::new()
+							.transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+					)
+					.inspect_err(|err| warn!("Skipped metadata of DICOMweb server: {err}"))
+					.ok()
+					.map(|file| Ok(InstanceMetadata::from(file))),
+				Err(err) => {
+					warn!("Failed to read metadata of DICOMweb server: {err}");
+					Some(Err(MoveError::OperationFailed))
+				}
+			}
+		});
+
+		Ok(MetadataResponse {
+			stream: stream.boxed(),
+		})
+	}
+}
+
+/// The path segments of the study, series or instance.
+fn resource_segments(query: &ResourceQuery) -> Vec<&str> {
+	let mut segments = vec!["studies", query.study_instance_uid.as_str()];
+	if let Some(series) = &query.series_instance_uid {
+		segments.extend(["series", series.as_str()]);
+		if let Some(instance) = &query.sop_instance_uid {
+			segments.extend(["instances", instance.as_str()]);
+		}
+	}
+	segments
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::dicomweb::tests::serve;
+	use crate::backend::tests::instance;
+	use axum::http::{header, StatusCode};
+	use axum::routing::get;
+	use axum::Router;
+	use dicom::dictionary_std::tags;
+	use futures::TryStreamExt;
+
+	fn query(series: Option<&str>) -> ResourceQuery {
+		ResourceQuery {
+			aet: String::from("UPSTREAM"),
+			study_instance_uid: String::from("1.2.3"),
+			series_instance_uid: series.map(String::from),
+			sop_instance_uid: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn reads_multipart_response() {
+		let mut part = Vec::new();
+		instance("1.2.3", "1.2.3.4", "1.2.3.4.5")
+			.write_all(&mut part)
+			.unwrap();
+		let mut body = b"--boundary\r\nContent-Type: application/dicom\r\n\r\n".to_vec();
+		body.extend(part);
+		body.extend(b"\r\n--boundary--");
+
+		let router = Router::new().route(
+			"/dicom-web/studies/{study}/series/{series}",
+			get(move || async move {
+				(
+					[(
+						header::CONTENT_TYPE,
+						r#"multipart/related; type="application/dicom"; boundary=boundary"#,
+					)],
+					body,
+				)
+			}),
+		);
+		let wado = DicomWebWadoService::new(serve(router).await, Duration::from_secs(5));
+
+		let response = wado
+			.retrieve(RetrieveInstanceRequest {
+				query: query(Some("1.2.3.4")),
+				transfer_syntax: None,
+				single_part: false,
+			})
+			.await
+			.unwrap();
+		let instances: Vec<_> = response.stream.try_collect().await.unwrap();
+
+		assert_eq!(instances.len(), 1);
+		assert_eq!(
+			instances[0]
+				.element(tags::SOP_INSTANCE_UID)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"1.2.3.4.5"
+		);
+	}
+
+	#[tokio::test]
+	async fn missing_resource_is_not_found() {
+		let router = Router::new().route(
+			"/dicom-web/studies/{study}/metadata",
+			get(|| async { StatusCode::NOT_FOUND }),
+		);
+		let wado = DicomWebWadoService::new(serve(router).await, Duration::from_secs(5));
+
+		let result = wado.metadata(MetadataRequest { query: query(None) }).await;
+		assert!(matches!(result, Err(RetrieveError::NotFound { .. })));
+	}
+}
@@ -24,6 +24,10 @@ pub struct ClientAssociationOptions {
 	pub called_aet: String,
 	pub abstract_syntax: String,
 	pub transfer_syntaxes: Vec<String>,
+	/// Proposes each transfer syntax in its own presentation context, so that the SCP can accept
+	/// all of them and the caller can choose the one that matches its data set.
+	/// Otherwise, all transfer syntaxes are proposed in a single presentation context.
+	pub context_per_transfer_syntax: bool,
 	pub address: SocketAddr,
 }
 
@@ -68,10 +72,23 @@ impl ClientAssociation {
 		let called_aet = options.called_aet;
 		let address = options.address;
 
-		let options = dicom::ul::ClientAssociationOptions::new()
+		let association_options = dicom::ul::ClientAssociationOptions::new()
 			.calling_ae_title(calling_aet.clone())
-			.called_ae_title(called_aet.clone())
-			.with_presentation_context(options.abstract_syntax, options.transfer_syntaxes);
+			.called_ae_title(called_aet.clone());
+		let options = if options.context_per_transfer_syntax {
+			options.transfer_syntaxes.into_iter().fold(
+				association_options,
+				|association_options, transfer_syntax| {
+					association_options.with_presentation_context(
+						options.abstract_syntax.clone(),
+						vec![transfer_syntax],
+					)
+				},
+			)
+		} else {
+			association_options
+				.with_presentation_context(options.abstract_syntax, options.transfer_syntaxes)
+		};
 
 		let _handle = thread::Builder::new()
 			.name(String::from("calling_aet"))
@@ -187,6 +187,9 @@ pub struct AssociationManager {
 pub struct PresentationParameter {
 	pub abstract_syntax_uid: UI,
 	pub transfer_syntax_uids: Vec<UI>,
+	/// Whether each transfer syntax is proposed in its own presentation context,
+	/// see [`ClientAssociationOptions::context_per_transfer_syntax`].
+	pub context_per_transfer_syntax: bool,
 }
 
 impl PartialEq for PresentationParameter {
@@ -213,6 +216,7 @@ impl Manager for AssociationManager {
 			called_aet: self.called_aet.clone(),
 			abstract_syntax: param.abstract_syntax_uid.clone(),
 			transfer_syntaxes: param.transfer_syntax_uids.clone(),
+			context_per_transfer_syntax: param.context_per_transfer_syntax,
 			address: self.address,
 		};
 
@@ -263,7 +267,6 @@ impl AssociationPools {
 	pub fn new(config: &AppConfig) -> Self {
 		let mut pools = HashMap::with_capacity(config.server.dimse.len());
 		for ae_config in &config.aets {
-			#[allow(irrefutable_let_patterns)] // the lint is not aware of the S3 feature
 			if let BackendConfig::Dimse(dimse_config) = &ae_config.backend {
 				let pool_size = dimse_config.pool.size;
 
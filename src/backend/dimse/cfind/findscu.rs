@@ -56,16 +56,19 @@ impl FindServiceClassUser {
 					uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND,
 				),
 				transfer_syntax_uids,
+				context_per_transfer_syntax: false,
 			},
 			QueryInformationModel::Patient => PresentationParameter {
 				abstract_syntax_uid: String::from(
 					uids::PATIENT_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND,
 				),
 				transfer_syntax_uids,
+				context_per_transfer_syntax: false,
 			},
 			QueryInformationModel::Worklist => PresentationParameter {
 				abstract_syntax_uid: String::from(uids::MODALITY_WORKLIST_INFORMATION_MODEL_FIND),
 				transfer_syntax_uids,
+				context_per_transfer_syntax: false,
 			},
 		};
 
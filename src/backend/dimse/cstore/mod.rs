@@ -55,20 +55,26 @@ pub struct CompositeStoreResponse {
 	pub message_id: US,
 	pub sop_class_uid: UI,
 	pub sop_instance_uid: UI,
+	pub status: US,
+	pub error_comment: Option<String>,
 }
 
 impl From<CompositeStoreResponse> for DicomMessage {
 	#[rustfmt::skip]
 	fn from(response: CompositeStoreResponse) -> Self {
-        let command = InMemDicomObject::command_from_element_iter([
+        let mut command = InMemDicomObject::command_from_element_iter([
             DataElement::new(tags::AFFECTED_SOP_CLASS_UID, VR::UI, dicom_value!(Str, response.sop_class_uid)),
             DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [0x8001])),
             DataElement::new(tags::MESSAGE_ID_BEING_RESPONDED_TO, VR::US, dicom_value!(U16, [response.message_id])),
             DataElement::new(tags::COMMAND_DATA_SET_TYPE, VR::US, dicom_value!(U16, [DATA_SET_MISSING])),
-            DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [0x00000])),
+            DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [response.status])),
             DataElement::new(tags::AFFECTED_SOP_INSTANCE_UID, VR::UI, dicom_value!(Str, response.sop_instance_uid))
         ]);
 
+        if let Some(error_comment) = response.error_comment {
+            command.put_element(DataElement::new(tags::ERROR_COMMENT, VR::LO, dicom_value!(Str, error_comment)));
+        }
+
         Self {
             command,
             data: None,
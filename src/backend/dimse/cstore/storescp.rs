@@ -144,6 +144,8 @@ impl StoreServiceClassProvider {
 				sop_instance_uid: UI::from(sop_instance_uid.clone()),
 				sop_class_uid: UI::from(sop_class_uid.clone()),
 				message_id,
+				status: 0,
+				error_comment: None,
 			};
 
 			association
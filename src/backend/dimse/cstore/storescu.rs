@@ -1,36 +1,101 @@
 use crate::backend::dimse::association;
 use crate::backend::dimse::cstore::CompositeStoreRequest;
 use crate::backend::dimse::{
-	next_message_id, DicomMessageReader, DicomMessageWriter, ReadError, WriteError,
+	next_message_id, DicomMessageReader, DicomMessageWriter, NegotiationError, ReadError,
+	StatusType, WriteError,
 };
 use crate::types::{Priority, UI, US};
 use association::pool::{AssociationPool, PoolError, PresentationParameter};
-use association::AssociationError;
+use association::{Association, AssociationError};
+use dicom::dictionary_std::tags;
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::mem::InMemElement;
 use dicom::object::{FileDicomObject, InMemDicomObject};
+use dicom::pixeldata::Transcode;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom::ul::pdu::PresentationContextNegotiated;
 use std::time::Duration;
 use thiserror::Error;
-use tracing::trace;
+use tracing::{debug, trace};
 
 pub struct StoreServiceClassUser {
 	pool: AssociationPool,
 	timeout: Duration,
+	fallback_transfer_syntaxes: Vec<UI>,
 }
 
 impl StoreServiceClassUser {
-	pub const fn new(pool: AssociationPool, timeout: Duration) -> Self {
-		Self { pool, timeout }
+	pub const fn new(
+		pool: AssociationPool,
+		timeout: Duration,
+		fallback_transfer_syntaxes: Vec<UI>,
+	) -> Self {
+		Self {
+			pool,
+			timeout,
+			fallback_transfer_syntaxes,
+		}
 	}
 
+	/// Sends the instance to the SCP.
+	///
+	/// The transfer syntax of the instance is proposed along with the fallback transfer syntaxes,
+	/// each in its own presentation context.
+	/// The instance is only transcoded if its transfer syntax was not accepted on the association
+	/// (which may be reused from an instance with another transfer syntax), into the first fallback
+	/// that was accepted.
+	///
+	/// # Errors
+	/// Returns [`StoreError::Failed`] if the C-STORE-RSP has a failure status.
 	#[allow(clippy::significant_drop_tightening)]
-	pub async fn store(&self, file: FileDicomObject<InMemDicomObject>) -> Result<(), StoreError> {
+	pub async fn store(
+		&self,
+		mut file: FileDicomObject<InMemDicomObject>,
+	) -> Result<StoreStatus, StoreError> {
+		let transfer_syntax = UI::from(file.meta().transfer_syntax());
+		let mut transfer_syntax_uids = vec![transfer_syntax.clone()];
+		transfer_syntax_uids.extend(
+			self.fallback_transfer_syntaxes
+				.iter()
+				.filter(|fallback| **fallback != transfer_syntax)
+				.cloned(),
+		);
+
 		let association = self
 			.pool
 			.get(PresentationParameter {
 				abstract_syntax_uid: UI::from(file.meta().media_storage_sop_class_uid().to_owned()),
-				transfer_syntax_uids: vec![UI::from(file.meta().transfer_syntax())],
+				transfer_syntax_uids,
+				context_per_transfer_syntax: true,
 			})
 			.await?;
 
+		let presentation_context = association
+			.presentation_contexts()
+			.iter()
+			.find(|pctx| accepted_transfer_syntax(pctx) == transfer_syntax)
+			.or_else(|| {
+				self.fallback_transfer_syntaxes.iter().find_map(|fallback| {
+					association
+						.presentation_contexts()
+						.iter()
+						.find(|pctx| accepted_transfer_syntax(pctx) == fallback)
+				})
+			})
+			.ok_or(NegotiationError::NoPresentationContext)?;
+		let accepted = accepted_transfer_syntax(presentation_context);
+		if accepted != transfer_syntax {
+			let target = TransferSyntaxRegistry
+				.get(accepted)
+				.ok_or_else(|| NegotiationError::UnknownTransferSyntax(UI::from(accepted)))?;
+			debug!(
+				from = transfer_syntax,
+				to = accepted,
+				"Transcoding instance to accepted transfer syntax"
+			);
+			file.transcode(target)?;
+		}
+
 		let request = CompositeStoreRequest {
 			affected_sop_class_uid: file.meta().media_storage_sop_class_uid.clone(),
 			affected_sop_instance_uid: file.meta().media_storage_sop_instance_uid.clone(),
@@ -42,23 +107,289 @@ impl StoreServiceClassUser {
 		};
 
 		association
-			.write_message(request, None, self.timeout)
+			.write_message(request, Some(presentation_context.id), self.timeout)
 			.await?;
 		trace!("Sent C-STORE-RQ");
 
-		association.read_message(self.timeout).await?;
+		let response = association.read_message(self.timeout).await?;
 		trace!("Received C-STORE-RSP");
 
-		Ok(())
+		let status = response
+			.command
+			.get(tags::STATUS)
+			.map(InMemElement::to_int::<US>)
+			.and_then(Result::ok)
+			.ok_or(ReadError::MissingAttribute(tags::STATUS))?;
+		let error_comment = response
+			.command
+			.get(tags::ERROR_COMMENT)
+			.map(InMemElement::to_str)
+			.and_then(Result::ok)
+			.map(|comment| comment.trim_end_matches(['\0', ' ']).to_owned())
+			.filter(|comment| !comment.is_empty());
+
+		match StatusType::try_from(status) {
+			Ok(StatusType::Success) => Ok(StoreStatus::Success),
+			Ok(StatusType::Warning) => Ok(StoreStatus::Warning {
+				status,
+				error_comment,
+			}),
+			// Statuses that are not defined (e.g. 0xCxxx) are failures specific to the SOP class.
+			_ => Err(StoreError::Failed {
+				status,
+				error_comment,
+			}),
+		}
 	}
 }
 
+fn accepted_transfer_syntax(pctx: &PresentationContextNegotiated) -> &str {
+	pctx.transfer_syntax.trim_end_matches('\0')
+}
+
+/// The status of a C-STORE-RSP for an instance that was stored.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreStatus {
+	Success,
+	/// The instance was stored, but the SCP reported a warning (e.g. coerced attributes).
+	Warning {
+		status: US,
+		error_comment: Option<String>,
+	},
+}
+
 #[derive(Debug, Error)]
 pub enum StoreError {
+	#[error(
+		"C-STORE failed with status {status:#06X}: {}",
+		.error_comment.as_deref().unwrap_or("no error comment")
+	)]
+	Failed {
+		status: US,
+		error_comment: Option<String>,
+	},
 	#[error(transparent)]
 	Read(#[from] ReadError),
 	#[error(transparent)]
 	Write(#[from] WriteError),
 	#[error(transparent)]
 	Association(#[from] PoolError<AssociationError>),
+	#[error(transparent)]
+	Negotiation(#[from] NegotiationError),
+	#[error(transparent)]
+	Transcode(#[from] dicom::pixeldata::TranscodeError),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::dimse::association::pool::{AssociationManager, Pool};
+	use crate::backend::dimse::association::server::{ServerAssociation, ServerAssociationOptions};
+	use crate::backend::dimse::cstore::CompositeStoreResponse;
+	use dicom::core::value::{PixelFragmentSequence, Value};
+	use dicom::core::{DataElement, PrimitiveValue, VR};
+	use dicom::dictionary_std::uids;
+	use dicom::object::FileMetaTableBuilder;
+	use std::net::SocketAddr;
+	use tokio::net::TcpListener;
+	use tokio::sync::oneshot;
+
+	/// The data set and transfer syntax of a C-STORE-RQ received by the stand-in SCP.
+	type Received = (String, Option<InMemDicomObject>);
+
+	/// Spawns a Store SCP that accepts uncompressed transfer syntaxes only
+	/// and responds to a single C-STORE-RQ with the given status.
+	async fn stand_in_scp(
+		status: US,
+		error_comment: Option<&'static str>,
+	) -> (SocketAddr, oneshot::Receiver<Received>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let (received_tx, received_rx) = oneshot::channel();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let tcp_stream = stream.into_std().unwrap();
+			tcp_stream.set_nonblocking(false).unwrap();
+			let association = ServerAssociation::new(ServerAssociationOptions {
+				aet: String::from("STAND-IN"),
+				tcp_stream,
+				uncompressed: true,
+			})
+			.await
+			.unwrap();
+
+			let request = association
+				.read_message(Duration::from_secs(5))
+				.await
+				.unwrap();
+			let response = CompositeStoreResponse {
+				message_id: request
+					.command
+					.get(tags::MESSAGE_ID)
+					.map(InMemElement::to_int)
+					.and_then(Result::ok)
+					.unwrap(),
+				sop_class_uid: UI::from(uids::SECONDARY_CAPTURE_IMAGE_STORAGE),
+				sop_instance_uid: UI::from("1.2.3.4"),
+				status,
+				error_comment: error_comment.map(String::from),
+			};
+			association
+				.write_message(
+					response,
+					request.presentation_context_id,
+					Duration::from_secs(5),
+				)
+				.await
+				.unwrap();
+
+			let transfer_syntax = association
+				.presentation_contexts()
+				.iter()
+				.find(|pctx| Some(pctx.id) == request.presentation_context_id)
+				.unwrap()
+				.transfer_syntax
+				.trim_end_matches('\0')
+				.to_owned();
+			let _ = received_tx.send((transfer_syntax, request.data));
+			// Keep the association open until the response is received
+			tokio::time::sleep(Duration::from_secs(1)).await;
+		});
+
+		(address, received_rx)
+	}
+
+	fn instance(transfer_syntax: &str) -> FileDicomObject<InMemDicomObject> {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(
+				tags::SOP_CLASS_UID,
+				VR::UI,
+				uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+			),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4"),
+		])
+		.with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax))
+		.unwrap()
+	}
+
+	async fn store(
+		address: SocketAddr,
+		file: FileDicomObject<InMemDicomObject>,
+	) -> Result<StoreStatus, StoreError> {
+		let pool = Pool::new(
+			AssociationManager {
+				address,
+				calling_aet: String::from("DICOM-RST"),
+				called_aet: String::from("STAND-IN"),
+			},
+			1,
+			Duration::from_secs(5),
+		);
+
+		StoreServiceClassUser::new(
+			pool,
+			Duration::from_secs(5),
+			vec![UI::from(uids::EXPLICIT_VR_LITTLE_ENDIAN)],
+		)
+		.store(file)
+		.await
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn returns_success() {
+		let (address, _) = stand_in_scp(0x0000, None).await;
+		let file = instance(uids::EXPLICIT_VR_LITTLE_ENDIAN);
+		assert_eq!(store(address, file).await.unwrap(), StoreStatus::Success);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn returns_warning() {
+		let (address, _) = stand_in_scp(0xB000, Some("Coerced PatientName")).await;
+		let file = instance(uids::EXPLICIT_VR_LITTLE_ENDIAN);
+		assert_eq!(
+			store(address, file).await.unwrap(),
+			StoreStatus::Warning {
+				status: 0xB000,
+				error_comment: Some(String::from("Coerced PatientName")),
+			}
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn fails_on_refused_instance() {
+		let (address, _) = stand_in_scp(0xA700, Some("Out of resources")).await;
+		let file = instance(uids::EXPLICIT_VR_LITTLE_ENDIAN);
+		let err = store(address, file).await.unwrap_err();
+		assert!(matches!(
+			&err,
+			StoreError::Failed {
+				status: 0xA700,
+				error_comment: Some(comment),
+			} if comment == "Out of resources"
+		));
+		assert_eq!(
+			err.to_string(),
+			"C-STORE failed with status 0xA700: Out of resources"
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn keeps_accepted_transfer_syntax() {
+		let (address, received) = stand_in_scp(0x0000, None).await;
+		let file = instance(uids::IMPLICIT_VR_LITTLE_ENDIAN);
+		assert_eq!(store(address, file).await.unwrap(), StoreStatus::Success);
+
+		// The fallback is accepted as well, but the instance is sent as is.
+		let (transfer_syntax, _) = received.await.unwrap();
+		assert_eq!(transfer_syntax, uids::IMPLICIT_VR_LITTLE_ENDIAN);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn transcodes_to_fallback_transfer_syntax() {
+		let mut jpeg = Vec::new();
+		image::GrayImage::from_pixel(8, 8, image::Luma([128]))
+			.write_to(
+				&mut std::io::Cursor::new(&mut jpeg),
+				image::ImageFormat::Jpeg,
+			)
+			.unwrap();
+
+		let mut file = instance(uids::JPEG_BASELINE8_BIT);
+		for element in [
+			DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)),
+			DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2"),
+			DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(8_u16)),
+			DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(8_u16)),
+			DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(8_u16)),
+			DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(8_u16)),
+			DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(7_u16)),
+			DataElement::new(
+				tags::PIXEL_REPRESENTATION,
+				VR::US,
+				PrimitiveValue::from(0_u16),
+			),
+			DataElement::new(
+				tags::PIXEL_DATA,
+				VR::OB,
+				Value::PixelSequence(PixelFragmentSequence::new(Vec::<u32>::new(), vec![jpeg])),
+			),
+		] {
+			file.put(element);
+		}
+
+		let (address, received) = stand_in_scp(0x0000, None).await;
+		assert_eq!(store(address, file).await.unwrap(), StoreStatus::Success);
+
+		let (transfer_syntax, data) = received.await.unwrap();
+		assert_eq!(transfer_syntax, uids::EXPLICIT_VR_LITTLE_ENDIAN);
+		let pixel_data = data
+			.unwrap()
+			.get(tags::PIXEL_DATA)
+			.unwrap()
+			.to_bytes()
+			.unwrap()
+			.len();
+		assert_eq!(pixel_data, 64);
+	}
 }
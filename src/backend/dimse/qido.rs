@@ -103,10 +103,8 @@ impl QidoService for DimseQidoService {
 			.map_err(|err| SearchError::Backend {
 				source: Box::new(err),
 			})
-			.skip(request.parameters.offset)
-			.take(request.parameters.limit)
 			.boxed();
 
-		SearchResponse { stream }
+		SearchResponse::new(stream)
 	}
 }
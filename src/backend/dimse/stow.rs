@@ -1,56 +1,60 @@
-use crate::api::stow::{InstanceReference, StoreError, StoreRequest, StoreResponse, StowService};
+use crate::api::stow::{InstanceStore, StoreOutcome, FAILURE_PROCESSING};
 use crate::backend::dimse::association;
-use crate::backend::dimse::cstore::storescu::StoreServiceClassUser;
-use crate::types::UI;
+use crate::backend::dimse::cstore::storescu;
+use crate::backend::dimse::cstore::storescu::{StoreServiceClassUser, StoreStatus};
+use crate::config::StowConfig;
 use association::pool::AssociationPool;
 use async_trait::async_trait;
+use dicom::object::{FileDicomObject, InMemDicomObject};
 use std::time::Duration;
-use tracing::info;
+use tracing::{info, warn};
 
 pub struct DimseStowService {
 	storescu: StoreServiceClassUser,
+	concurrency: usize,
 }
 
 impl DimseStowService {
-	pub const fn new(pool: AssociationPool, timeout: Duration) -> Self {
-		let storescu = StoreServiceClassUser::new(pool, timeout);
-		Self { storescu }
+	pub fn new(pool: AssociationPool, timeout: Duration, config: &StowConfig) -> Self {
+		let storescu =
+			StoreServiceClassUser::new(pool, timeout, config.fallback_transfer_syntaxes.clone());
+		Self {
+			storescu,
+			concurrency: config.concurrency,
+		}
 	}
 }
 
 #[async_trait]
-impl StowService for DimseStowService {
-	async fn store(&self, request: StoreRequest) -> Result<StoreResponse, StoreError> {
-		let mut referenced_sequence = Vec::new();
-		let mut failed_sequence = Vec::new();
-
-		for instance in request.instances {
-			let sop_instance_uid = UI::from(instance.meta().media_storage_sop_instance_uid());
-			let sop_class_uid = UI::from(instance.meta().media_storage_sop_class_uid());
-
-			let response = self.storescu.store(instance).await;
+impl InstanceStore for DimseStowService {
+	fn concurrency(&self) -> usize {
+		self.concurrency
+	}
 
-			match response {
-				Ok(()) => {
-					info!(sop_instance_uid, "Successfully stored instance");
-					referenced_sequence.push(InstanceReference {
-						sop_class_uid,
-						sop_instance_uid,
-					});
-				}
-				Err(err) => {
-					info!(sop_instance_uid, "Failed to store instance: {err}",);
-					failed_sequence.push(InstanceReference {
-						sop_class_uid,
-						sop_instance_uid,
-					});
+	async fn store_instance(&self, instance: FileDicomObject<InMemDicomObject>) -> StoreOutcome {
+		let sop_instance_uid = instance.meta().media_storage_sop_instance_uid().to_owned();
+		match self.storescu.store(instance).await {
+			Ok(StoreStatus::Success) => {
+				info!(sop_instance_uid, "Successfully stored instance");
+				StoreOutcome::Stored
+			}
+			Ok(StoreStatus::Warning {
+				status,
+				error_comment,
+			}) => {
+				warn!(
+					sop_instance_uid,
+					status, error_comment, "Stored instance with warning"
+				);
+				StoreOutcome::Warning(status)
+			}
+			Err(err) => {
+				warn!(sop_instance_uid, "Failed to store instance: {err}");
+				match err {
+					storescu::StoreError::Failed { status, .. } => StoreOutcome::Failed(status),
+					_ => StoreOutcome::Failed(FAILURE_PROCESSING),
 				}
 			}
 		}
-
-		Ok(StoreResponse {
-			failed_sequence,
-			referenced_sequence,
-		})
 	}
 }
@@ -1,6 +1,6 @@
 use crate::api::wado::{
-	InstanceResponse, MetadataRequest, RenderedResponse, RenderingRequest, RetrieveError,
-	RetrieveInstanceRequest, WadoService,
+	InstanceResponse, MetadataRequest, MetadataResponse, RenderedResponse, RenderingRequest,
+	RetrieveError, RetrieveInstanceRequest, WadoService,
 };
 use crate::backend::dimse::association;
 use crate::backend::dimse::cmove::movescu::{MoveError, MoveServiceClassUser};
@@ -104,12 +104,14 @@ impl WadoService for DimseWadoService {
 		Ok(RenderedResponse(render_output))
 	}
 
-	async fn metadata(&self, request: MetadataRequest) -> Result<InstanceResponse, RetrieveError> {
+	async fn metadata(&self, request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
 		self.retrieve(RetrieveInstanceRequest {
 			query: request.query,
 			transfer_syntax: None,
+			single_part: false,
 		})
 		.await
+		.map(MetadataResponse::from)
 	}
 }
 
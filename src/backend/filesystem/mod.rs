@@ -0,0 +1,58 @@
+//! A backend that stores instances as Part 10 files in a local directory.
+//!
+//! Instances are stored as `{path}/{study}/{series}/{instance}.dcm`.
+//! QIDO-RS requests are answered from an [`InstanceIndex`](crate::backend::index::InstanceIndex),
+//! which is populated by scanning the directory on startup and by storing instances.
+
+pub mod qido;
+pub mod stow;
+pub mod wado;
+
+use std::path::{Path, PathBuf};
+
+/// The file extension of stored instances.
+const EXTENSION: &str = "dcm";
+
+/// The path of the instance, or `None` if one of the UIDs cannot be used as a file name.
+pub fn instance_path(root: &Path, study: &str, series: &str, instance: &str) -> Option<PathBuf> {
+	if !is_file_name(instance) {
+		return None;
+	}
+	// Not `with_extension`, which would replace the last component of the UID.
+	Some(resource_path(root, study, Some(series))?.join(format!("{instance}.{EXTENSION}")))
+}
+
+/// The directory of the study or series, or `None` if one of the UIDs cannot be used as a file name.
+pub fn resource_path(root: &Path, study: &str, series: Option<&str>) -> Option<PathBuf> {
+	let mut path = root.join(is_file_name(study).then_some(study)?);
+	if let Some(series) = series {
+		path.push(is_file_name(series).then_some(series)?);
+	}
+	Some(path)
+}
+
+/// UIDs consist of digits and dots, which also prevents them from escaping the directory (e.g. `..`).
+fn is_file_name(uid: &str) -> bool {
+	uid.bytes().any(|byte| byte.is_ascii_digit())
+		&& uid
+			.bytes()
+			.all(|byte| byte.is_ascii_digit() || byte == b'.')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rejects_paths_outside_of_root() {
+		let root = Path::new("/archive");
+		assert_eq!(
+			instance_path(root, "1.2", "1.2.3", "1.2.3.4"),
+			Some(PathBuf::from("/archive/1.2/1.2.3/1.2.3.4.dcm"))
+		);
+		assert_eq!(instance_path(root, "..", "1.2.3", "1.2.3.4"), None);
+		assert_eq!(instance_path(root, "1.2", "../..", "1.2.3.4"), None);
+		assert_eq!(instance_path(root, "1.2", "1.2.3", "/etc/passwd"), None);
+		assert_eq!(resource_path(root, "", None), None);
+	}
+}
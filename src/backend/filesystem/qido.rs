@@ -0,0 +1,218 @@
+use crate::api::qido::{QidoService, SearchRequest, SearchResponse};
+use crate::backend::index::{InstanceIndex, InstanceIndexes};
+use crate::config::{AppConfig, BackendConfig};
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom::object::OpenFileOptions;
+use futures::StreamExt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::EXTENSION;
+
+/// Answers QIDO-RS requests from the index of the instances in the directory.
+pub struct FilesystemQidoService {
+	index: Arc<InstanceIndex>,
+}
+
+impl FilesystemQidoService {
+	pub const fn new(index: Arc<InstanceIndex>) -> Self {
+		Self { index }
+	}
+}
+
+#[async_trait]
+impl QidoService for FilesystemQidoService {
+	async fn search(&self, request: SearchRequest) -> SearchResponse {
+		let matches = self.index.search(&request.query, &request.parameters);
+		SearchResponse::new(futures::stream::iter(matches.into_iter().map(Ok)).boxed())
+	}
+}
+
+/// Scans the directories of all filesystem AETs whose index should be populated on startup.
+/// The scans run in the background, so that the server is available in the meantime.
+pub fn spawn_scans(config: &AppConfig, indexes: &InstanceIndexes) {
+	for ae_config in &config.aets {
+		let BackendConfig::Filesystem(filesystem_config) = &ae_config.backend else {
+			continue;
+		};
+		let Some(index) = indexes.get(&ae_config.aet) else {
+			continue;
+		};
+		if !filesystem_config.index.scan {
+			continue;
+		}
+
+		let aet = ae_config.aet.clone();
+		let path = filesystem_config.path.clone();
+		let index = Arc::clone(index);
+		tokio::task::spawn_blocking(move || match scan(&path, &index) {
+			Ok(count) => info!(aet, count, "Indexed instances in directory"),
+			Err(err) => error!(aet, path = %path.display(), "Failed to scan directory: {err}"),
+		});
+	}
+}
+
+/// Adds all instances in the directory that are not indexed yet and returns their number.
+///
+/// Instances are only read up to the pixel data, which is not indexed.
+fn scan(root: &Path, index: &InstanceIndex) -> io::Result<usize> {
+	let mut indexed = 0;
+	for study in entries(root)? {
+		let study = study?;
+		for series in entries(&study.path())? {
+			let series = series?;
+			for instance in entries(&series.path())? {
+				let path = instance?.path();
+				if path
+					.extension()
+					.is_none_or(|extension| extension != EXTENSION)
+				{
+					continue;
+				}
+				// Instances are stored as {study}/{series}/{instance}.dcm
+				let uids = (
+					study.file_name().to_string_lossy().into_owned(),
+					series.file_name().to_string_lossy().into_owned(),
+					path.file_stem().unwrap_or_default().to_string_lossy(),
+				);
+				if index.contains(&uids.0, &uids.1, &uids.2) {
+					continue;
+				}
+
+				match OpenFileOptions::new()
+					.read_until(tags::PIXEL_DATA)
+					.open_file(&path)
+				{
+					Ok(file) => {
+						if index.insert(&file) {
+							indexed += 1;
+						}
+					}
+					Err(err) => warn!(
+						path = %path.display(),
+						"Skipped file that could not be indexed: {err}"
+					),
+				}
+			}
+		}
+	}
+	Ok(indexed)
+}
+
+/// Lists the entries of the directory. Files and missing directories have no entries.
+fn entries(path: &Path) -> io::Result<Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>> {
+	if path.is_dir() {
+		Ok(Box::new(fs::read_dir(path)?))
+	} else {
+		Ok(Box::new(std::iter::empty()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::qido::{QueryParameters, ResourceQuery};
+	use crate::api::stow::{StoreRequest, StowService};
+	use crate::api::wado::{self, RetrieveInstanceRequest, WadoService};
+	use crate::backend::filesystem::stow::FilesystemStowService;
+	use crate::backend::filesystem::wado::FilesystemWadoService;
+	use crate::backend::tests::instance;
+	use crate::config::{FilesystemConfig, IndexConfig, StowConfig};
+	use crate::types::QueryRetrieveLevel;
+	use futures::TryStreamExt;
+
+	async fn search(index: &Arc<InstanceIndex>, level: QueryRetrieveLevel) -> usize {
+		FilesystemQidoService::new(Arc::clone(index))
+			.search(SearchRequest {
+				query: ResourceQuery {
+					query_retrieve_level: level,
+					study_instance_uid: Some(String::from("1.1")),
+					series_instance_uid: None,
+				},
+				parameters: QueryParameters::default(),
+			})
+			.await
+			.stream
+			.try_collect::<Vec<_>>()
+			.await
+			.unwrap()
+			.len()
+	}
+
+	#[tokio::test]
+	async fn stores_retrieves_and_searches_instances() {
+		let directory =
+			std::env::temp_dir().join(format!("dicom-rst-filesystem-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&directory);
+		let config = FilesystemConfig {
+			path: directory.join("instances"),
+			index: IndexConfig {
+				path: Some(directory.join("index.redb")),
+				scan: true,
+			},
+		};
+		let index_path = config.index.path.as_deref().unwrap();
+
+		let index = Arc::new(InstanceIndex::open(index_path).unwrap());
+		let stow = FilesystemStowService::new(&config, &StowConfig::default(), Arc::clone(&index));
+		let response = stow
+			.store(StoreRequest {
+				instances: futures::stream::iter([
+					Ok(instance("1.1", "1.1.1", "1.1.1.1")),
+					Ok(instance("1.1", "1.1.1", "1.1.1.2")),
+					Ok(instance("1.1", "1.1.2", "1.1.2.1")),
+				])
+				.boxed(),
+				rejected: Arc::default(),
+			})
+			.await
+			.unwrap();
+		assert_eq!(response.referenced_sequence.len(), 3);
+		assert!(response.failed_sequence.is_empty());
+
+		let instances: Vec<_> = FilesystemWadoService::new(&config)
+			.retrieve(RetrieveInstanceRequest {
+				query: wado::ResourceQuery {
+					aet: String::from("ARCHIVE"),
+					study_instance_uid: String::from("1.1"),
+					series_instance_uid: Some(String::from("1.1.1")),
+					sop_instance_uid: None,
+				},
+				transfer_syntax: None,
+				single_part: false,
+			})
+			.await
+			.unwrap()
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+		let uids: Vec<_> = instances
+			.iter()
+			.map(|instance| instance.meta().media_storage_sop_instance_uid())
+			.collect();
+		assert_eq!(uids, ["1.1.1.1", "1.1.1.2"]);
+
+		assert_eq!(search(&index, QueryRetrieveLevel::Study).await, 1);
+		assert_eq!(search(&index, QueryRetrieveLevel::Series).await, 2);
+		assert_eq!(search(&index, QueryRetrieveLevel::Image).await, 3);
+
+		// After a restart, the instances are found in the persisted index.
+		drop(stow);
+		drop(index);
+		let index = Arc::new(InstanceIndex::open(index_path).unwrap());
+		assert_eq!(search(&index, QueryRetrieveLevel::Image).await, 3);
+
+		// Without the persisted index, the instances are found by scanning the directory.
+		let index = Arc::new(InstanceIndex::in_memory());
+		assert_eq!(scan(&config.path, &index).unwrap(), 3);
+		assert_eq!(scan(&config.path, &index).unwrap(), 0);
+		assert_eq!(search(&index, QueryRetrieveLevel::Series).await, 2);
+
+		fs::remove_dir_all(&directory).unwrap();
+	}
+}
@@ -0,0 +1,110 @@
+use crate::api::stow::{
+	InstanceStore, StoreOutcome, FAILURE_CANNOT_UNDERSTAND, FAILURE_PROCESSING,
+};
+use crate::backend::index::InstanceIndex;
+use crate::config::{FilesystemConfig, StowConfig};
+use crate::types::UI;
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::instance_path;
+
+/// Stores instances as `{path}/{study}/{series}/{instance}.dcm`, the layout that is read by
+/// [`super::wado::FilesystemWadoService`].
+/// Existing files are replaced, so storing the same instance again is idempotent.
+pub struct FilesystemStowService {
+	path: PathBuf,
+	concurrency: usize,
+	index: Arc<InstanceIndex>,
+}
+
+impl FilesystemStowService {
+	pub fn new(
+		config: &FilesystemConfig,
+		stow_config: &StowConfig,
+		index: Arc<InstanceIndex>,
+	) -> Self {
+		Self {
+			path: config.path.clone(),
+			concurrency: stow_config.concurrency,
+			index,
+		}
+	}
+}
+
+/// Writes the instance to a temporary file, which then replaces the file at the path.
+/// Readers never see a partially written file.
+fn write(instance: &FileDicomObject<InMemDicomObject>, path: &Path) -> anyhow::Result<()> {
+	if let Some(directory) = path.parent() {
+		fs::create_dir_all(directory)?;
+	}
+	let temporary = path.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+	let result = instance
+		.write_to_file(&temporary)
+		.map_err(anyhow::Error::from)
+		.and_then(|()| fs::rename(&temporary, path).map_err(anyhow::Error::from));
+	if result.is_err() {
+		let _ = fs::remove_file(&temporary);
+	}
+	result
+}
+
+#[async_trait]
+impl InstanceStore for FilesystemStowService {
+	fn concurrency(&self) -> usize {
+		self.concurrency
+	}
+
+	async fn store_instance(&self, instance: FileDicomObject<InMemDicomObject>) -> StoreOutcome {
+		let sop_instance_uid = UI::from(instance.meta().media_storage_sop_instance_uid());
+		let uid = |tag| {
+			instance
+				.get(tag)
+				.and_then(|element| element.to_str().ok())
+				.map(|uid| uid.trim_end_matches(['\0', ' ']).to_owned())
+		};
+		let path = match (
+			uid(tags::STUDY_INSTANCE_UID),
+			uid(tags::SERIES_INSTANCE_UID),
+			uid(tags::SOP_INSTANCE_UID),
+		) {
+			(Some(study), Some(series), Some(instance)) => {
+				instance_path(&self.path, &study, &series, &instance)
+			}
+			_ => None,
+		};
+		let Some(path) = path else {
+			warn!(
+				sop_instance_uid,
+				"Instance has no valid StudyInstanceUID, SeriesInstanceUID or SOPInstanceUID"
+			);
+			return StoreOutcome::Failed(FAILURE_CANNOT_UNDERSTAND);
+		};
+
+		let instance = Arc::new(instance);
+		let file = Arc::clone(&instance);
+		let target = path.clone();
+		let result = tokio::task::spawn_blocking(move || write(&file, &target))
+			.await
+			.map_err(anyhow::Error::from)
+			.and_then(|result| result);
+
+		match result {
+			Ok(()) => {
+				info!(sop_instance_uid, path = %path.display(), "Successfully stored instance");
+				self.index.insert(&instance);
+				StoreOutcome::Stored
+			}
+			Err(err) => {
+				warn!(sop_instance_uid, path = %path.display(), "Failed to store instance: {err:#}");
+				StoreOutcome::Failed(FAILURE_PROCESSING)
+			}
+		}
+	}
+}
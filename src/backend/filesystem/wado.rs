@@ -0,0 +1,153 @@
+use crate::api::wado::{
+	InstanceResponse, MetadataRequest, MetadataResponse, ResourceQuery, RetrieveError,
+	RetrieveInstanceRequest, WadoService,
+};
+use crate::backend::dimse::cmove::movescu::MoveError;
+use crate::config::FilesystemConfig;
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use dicom::object::{FileDicomObject, InMemDicomObject, OpenFileOptions, ReadError};
+use futures::StreamExt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+use super::{instance_path, resource_path, EXTENSION};
+
+/// The number of files that are read concurrently.
+const CONCURRENCY: usize = 8;
+
+type Instance = FileDicomObject<InMemDicomObject>;
+
+pub struct FilesystemWadoService {
+	path: PathBuf,
+}
+
+impl FilesystemWadoService {
+	pub fn new(config: &FilesystemConfig) -> Self {
+		Self {
+			path: config.path.clone(),
+		}
+	}
+
+	/// The files of all instances of the resource, ordered by their UIDs.
+	async fn instance_paths(&self, query: &ResourceQuery) -> Result<Vec<PathBuf>, RetrieveError> {
+		let root = self.path.clone();
+		let query = query.clone();
+		tokio::task::spawn_blocking(move || instance_paths(&root, &query))
+			.await
+			.map_err(|err| RetrieveError::Backend { source: err.into() })?
+	}
+
+	/// Reads the files concurrently. Files that cannot be read are skipped with a warning.
+	fn read_all(
+		paths: Vec<PathBuf>,
+		read: fn(&Path) -> Result<Instance, ReadError>,
+	) -> InstanceResponse {
+		let stream = futures::stream::iter(paths)
+			.map(move |path| async move {
+				let result = tokio::task::spawn_blocking(move || {
+					read(&path).inspect_err(|err| {
+						warn!(path = %path.display(), "Skipped file: {err}");
+					})
+				})
+				.await;
+				match result {
+					Ok(Ok(file)) => Some(Ok(Arc::new(file))),
+					Ok(Err(_)) => None,
+					Err(_) => Some(Err(MoveError::OperationFailed)),
+				}
+			})
+			.buffered(CONCURRENCY)
+			.filter_map(|result| async move { result });
+
+		InstanceResponse {
+			stream: stream.boxed(),
+		}
+	}
+}
+
+#[async_trait]
+impl WadoService for FilesystemWadoService {
+	async fn retrieve(
+		&self,
+		request: RetrieveInstanceRequest,
+	) -> Result<InstanceResponse, RetrieveError> {
+		let paths = self.instance_paths(&request.query).await?;
+		Ok(Self::read_all(paths, |path| {
+			OpenFileOptions::new().open_file(path)
+		}))
+	}
+
+	/// The metadata is read from the files up to their pixel data.
+	async fn metadata(&self, request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
+		let paths = self.instance_paths(&request.query).await?;
+		let response = Self::read_all(paths, |path| {
+			OpenFileOptions::new()
+				.read_until(tags::PIXEL_DATA)
+				.open_file(path)
+		});
+		Ok(response.into())
+	}
+}
+
+/// The files of all instances of the resource, ordered by their UIDs.
+fn instance_paths(root: &Path, query: &ResourceQuery) -> Result<Vec<PathBuf>, RetrieveError> {
+	let not_found = || RetrieveError::NotFound {
+		message: format!("No instances found for study {}", query.study_instance_uid),
+	};
+
+	let mut paths = Vec::new();
+	match (&query.series_instance_uid, &query.sop_instance_uid) {
+		(Some(series), Some(instance)) => {
+			let path = instance_path(root, &query.study_instance_uid, series, instance)
+				.ok_or_else(not_found)?;
+			if path.is_file() {
+				paths.push(path);
+			}
+		}
+		(series, _) => {
+			let directory = resource_path(root, &query.study_instance_uid, series.as_deref())
+				.ok_or_else(not_found)?;
+			// The series of a study are one level deeper than the instances of a series.
+			let series_directories = if series.is_some() {
+				vec![directory]
+			} else {
+				list(&directory, Path::is_dir)?
+			};
+			for directory in series_directories {
+				paths.extend(list(&directory, |path| {
+					path.extension()
+						.is_some_and(|extension| extension == EXTENSION)
+				})?);
+			}
+		}
+	}
+
+	if paths.is_empty() {
+		return Err(not_found());
+	}
+	paths.sort();
+	Ok(paths)
+}
+
+/// Lists the entries of the directory that match the filter.
+/// A missing directory has no entries.
+fn list(directory: &Path, filter: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>, RetrieveError> {
+	let entries = match std::fs::read_dir(directory) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(RetrieveError::Backend { source: err.into() }),
+	};
+	let mut paths = Vec::new();
+	for entry in entries {
+		let path = entry
+			.map_err(|err| RetrieveError::Backend { source: err.into() })?
+			.path();
+		if filter(&path) {
+			paths.push(path);
+		}
+	}
+	Ok(paths)
+}
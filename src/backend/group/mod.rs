@@ -0,0 +1,3 @@
+//! Virtual AETs that aggregate other AETs.
+
+pub mod qido;
@@ -0,0 +1,285 @@
+use crate::api::qido::retrieve::retrieve_url;
+use crate::api::qido::{QidoService, SearchError, SearchRequest, SearchResponse, SearchWarnings};
+use crate::types::{QueryRetrieveLevel, AE};
+use async_stream::stream;
+use async_trait::async_trait;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use std::collections::HashSet;
+use tracing::warn;
+use url::Url;
+
+/// Searches multiple AETs concurrently and merges their results.
+///
+/// Members that fail are skipped with a `Warning` header, so that a single unavailable origin
+/// server does not fail the whole search. The search only fails if all members fail.
+/// Matches that are found by multiple members are returned once, from the first member in the
+/// configured order.
+///
+/// At most `limit` matches of the request are read from each member, and the results of a member
+/// are only merged once the results of the previous members have been read.
+pub struct FederatedQidoService {
+	members: Vec<(AE, Box<dyn QidoService>)>,
+	base_url: Url,
+	/// The AET of this server, which is the agent of the warnings.
+	agent: AE,
+}
+
+impl FederatedQidoService {
+	pub const fn new(members: Vec<(AE, Box<dyn QidoService>)>, base_url: Url, agent: AE) -> Self {
+		Self {
+			members,
+			base_url,
+			agent,
+		}
+	}
+}
+
+/// The AET of a member, its matches (or the error of its search) and its warnings.
+type MemberResult<'a> = (&'a AE, Result<Vec<InMemDicomObject>, String>, Vec<String>);
+
+#[async_trait]
+impl QidoService for FederatedQidoService {
+	async fn search(&self, request: SearchRequest) -> SearchResponse {
+		let limit = request.parameters.limit;
+		let searches: Vec<BoxFuture<'_, MemberResult>> = self
+			.members
+			.iter()
+			.map(|(aet, qido)| {
+				let request = request.clone();
+				async move {
+					let response = qido.search(request).await;
+					let matches: Result<Vec<InMemDicomObject>, String> = response
+						.stream
+						.take(limit)
+						.map_err(|err| err.to_string())
+						.try_collect()
+						.await;
+					(aet, matches, response.warnings.to_vec())
+				}
+				.boxed()
+			})
+			.collect();
+		// The members are searched concurrently, but their results are returned in order.
+		let mut results = futures::stream::iter(searches).buffered(self.members.len().max(1));
+
+		let warnings = SearchWarnings::default();
+		let response_warnings = warnings.clone();
+		let level = request.query.query_retrieve_level;
+		let stream = stream! {
+			let mut seen = HashSet::new();
+			let mut errors = Vec::new();
+			while let Some((aet, matches, member_warnings)) = results.next().await {
+				for warning in member_warnings {
+					warnings.push(warning);
+				}
+				let matches = match matches {
+					Ok(matches) => matches,
+					Err(err) => {
+						warn!(aet, "Skipped group member due to failed search: {err}");
+						warnings.push(format!(
+							r#"299 {} "The search of {aet} failed, the results are incomplete""#,
+							self.agent
+						));
+						errors.push(format!("{aet}: {err}"));
+						continue;
+					}
+				};
+
+				for mut object in matches {
+					if let Some(uid) = unique_key(level, &object) {
+						if !seen.insert(uid) {
+							continue;
+						}
+					}
+					object.put(DataElement::new(
+						tags::RETRIEVE_AE_TITLE,
+						VR::AE,
+						PrimitiveValue::from(aet.as_str()),
+					));
+					if let Some(url) = retrieve_url(&self.base_url, aet, &request.query, &object) {
+						object.put(DataElement::new(tags::RETRIEVE_URL, VR::UR, url));
+					}
+					yield Ok(object);
+				}
+			}
+
+			if !self.members.is_empty() && errors.len() == self.members.len() {
+				yield Err(format!("All group members failed: {}", errors.join(", ")));
+			}
+		};
+
+		SearchResponse {
+			// Errors are passed as strings, as they are not `Send` while the stream is pending.
+			stream: stream
+				.map_err(|message| SearchError::Backend {
+					source: message.into(),
+				})
+				.boxed(),
+			warnings: response_warnings,
+		}
+	}
+}
+
+/// Returns the UID that identifies a match on the query retrieve level.
+fn unique_key(level: QueryRetrieveLevel, object: &InMemDicomObject) -> Option<String> {
+	let tag = match level {
+		QueryRetrieveLevel::Study => tags::STUDY_INSTANCE_UID,
+		QueryRetrieveLevel::Series => tags::SERIES_INSTANCE_UID,
+		QueryRetrieveLevel::Image => tags::SOP_INSTANCE_UID,
+		QueryRetrieveLevel::Patient | QueryRetrieveLevel::Frame => return None,
+	};
+	object
+		.get(tag)
+		.and_then(|element| element.to_str().ok())
+		.map(|uid| uid.trim_end_matches(['\0', ' ']).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::qido::{QueryParameters, ResourceQuery};
+
+	struct StaticQidoService(Result<Vec<&'static str>, &'static str>);
+
+	#[async_trait]
+	impl QidoService for StaticQidoService {
+		async fn search(&self, _request: SearchRequest) -> SearchResponse {
+			let stream = match self.0.clone() {
+				Ok(uids) => futures::stream::iter(uids)
+					.map(|uid| {
+						Ok(InMemDicomObject::from_element_iter([DataElement::new(
+							tags::STUDY_INSTANCE_UID,
+							VR::UI,
+							uid,
+						)]))
+					})
+					.boxed(),
+				Err(err) => futures::stream::once(async move {
+					Err(SearchError::Backend { source: err.into() })
+				})
+				.boxed(),
+			};
+			SearchResponse::new(stream)
+		}
+	}
+
+	fn request() -> SearchRequest {
+		SearchRequest {
+			query: ResourceQuery {
+				query_retrieve_level: QueryRetrieveLevel::Study,
+				study_instance_uid: None,
+				series_instance_uid: None,
+			},
+			parameters: QueryParameters::default(),
+		}
+	}
+
+	fn service(members: Vec<(&str, StaticQidoService)>) -> FederatedQidoService {
+		FederatedQidoService::new(
+			members
+				.into_iter()
+				.map(|(aet, qido)| (aet.to_owned(), Box::new(qido) as Box<dyn QidoService>))
+				.collect(),
+			Url::parse("http://localhost:8080/").unwrap(),
+			AE::from("DICOM-RST"),
+		)
+	}
+
+	#[tokio::test]
+	async fn merges_and_deduplicates_members() {
+		let service = service(vec![
+			("A", StaticQidoService(Ok(vec!["1.1", "1.2"]))),
+			("B", StaticQidoService(Ok(vec!["1.2", "1.3"]))),
+		]);
+		let matches: Vec<InMemDicomObject> = service
+			.search(request())
+			.await
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+
+		let origins: Vec<(String, String)> = matches
+			.iter()
+			.map(|object| {
+				(
+					unique_key(QueryRetrieveLevel::Study, object).unwrap(),
+					object
+						.get(tags::RETRIEVE_AE_TITLE)
+						.unwrap()
+						.to_str()
+						.unwrap()
+						.into_owned(),
+				)
+			})
+			.collect();
+		assert_eq!(
+			origins,
+			vec![
+				(String::from("1.1"), String::from("A")),
+				(String::from("1.2"), String::from("A")),
+				(String::from("1.3"), String::from("B")),
+			]
+		);
+		assert_eq!(
+			matches[2]
+				.get(tags::RETRIEVE_URL)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"http://localhost:8080/aets/B/studies/1.3"
+		);
+	}
+
+	#[tokio::test]
+	async fn tolerates_failed_member() {
+		let service = service(vec![
+			("A", StaticQidoService(Err("connection refused"))),
+			("B", StaticQidoService(Ok(vec!["1.1"]))),
+		]);
+		let response = service.search(request()).await;
+		let matches: Vec<InMemDicomObject> = response.stream.try_collect().await.unwrap();
+		assert_eq!(matches.len(), 1);
+		assert_eq!(
+			response.warnings.to_vec(),
+			vec![String::from(
+				r#"299 DICOM-RST "The search of A failed, the results are incomplete""#
+			)]
+		);
+	}
+
+	#[tokio::test]
+	async fn reads_at_most_limit_matches_per_member() {
+		let service = service(vec![
+			("A", StaticQidoService(Ok(vec!["1.1", "1.2", "1.3"]))),
+			("B", StaticQidoService(Ok(vec!["1.4", "1.5", "1.6"]))),
+		]);
+		let mut request = request();
+		request.parameters.limit = 2;
+		let matches: Vec<InMemDicomObject> = service
+			.search(request)
+			.await
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+
+		let uids: Vec<String> = matches
+			.iter()
+			.filter_map(|object| unique_key(QueryRetrieveLevel::Study, object))
+			.collect();
+		assert_eq!(uids, vec!["1.1", "1.2", "1.4", "1.5"]);
+	}
+
+	#[tokio::test]
+	async fn fails_if_all_members_fail() {
+		let service = service(vec![("A", StaticQidoService(Err("connection refused")))]);
+		let matches: Result<Vec<InMemDicomObject>, SearchError> =
+			service.search(request()).await.stream.try_collect().await;
+		assert!(matches.is_err());
+	}
+}
@@ -0,0 +1,938 @@
+//! An index of instance attributes for backends that cannot search their storage directly.
+//!
+//! The index keeps the non-binary attributes of every known instance as DICOM JSON in an embedded
+//! database and answers QIDO-RS requests from them. If a database file is configured, the index
+//! survives restarts without rescanning the storage.
+//!
+//! Match criteria for the [`INDEXED_ATTRIBUTES`] are looked up in a secondary table, so that only
+//! the studies, series or instances that can match are read. Other searches read every instance
+//! of the queried study (or of all studies), so their cost grows with the size of the index.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/chtml/part04/sect_C.2.2.2.html>
+
+use crate::api::qido::{
+	QueryParameters, ResourceQuery, INSTANCE_SEARCH_TAGS, SERIES_SEARCH_TAGS, STUDY_SEARCH_TAGS,
+};
+use crate::api::{IncludeField, MatchCriteria};
+use crate::config::{AppConfig, BackendConfig};
+use crate::types::{QueryRetrieveLevel, AE, UI};
+use anyhow::Context;
+use dicom::core::dictionary::DataDictionaryEntry;
+use dicom::core::header::Header;
+use dicom::core::ops::{AttributeSelector, AttributeSelectorStep};
+use dicom::core::value::DataSetSequence;
+use dicom::core::{DataDictionary, DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::{InMemDicomObject, StandardDataDictionary};
+use redb::backends::InMemoryBackend;
+use redb::{Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Study, Series and SOP Instance UID of an indexed instance.
+pub type InstanceKey = (UI, UI, UI);
+
+/// The indexed instances as DICOM JSON, by their Study, Series and SOP Instance UID.
+const INSTANCES: TableDefinition<(&str, &str, &str), &str> = TableDefinition::new("instances");
+
+/// The attributes that are indexed in [`ATTRIBUTES`].
+const INDEXED_ATTRIBUTES: [Tag; 4] = [
+	tags::PATIENT_ID,
+	tags::ACCESSION_NUMBER,
+	tags::STUDY_DATE,
+	tags::MODALITY,
+];
+
+/// The indexed instances by the values of their [`INDEXED_ATTRIBUTES`],
+/// as (tag, value, Study Instance UID, Series Instance UID, SOP Instance UID).
+const ATTRIBUTES: TableDefinition<(u32, &str, &str, &str, &str), ()> =
+	TableDefinition::new("attributes");
+
+/// Sorts after every UID and attribute value, as the upper bound of key ranges.
+const MAX: &str = "\u{10FFFF}";
+
+pub struct InstanceIndex {
+	database: Database,
+}
+
+impl InstanceIndex {
+	/// Creates an index that is only kept in memory.
+	pub fn in_memory() -> Self {
+		Database::builder()
+			.create_with_backend(InMemoryBackend::new())
+			.map_err(redb::Error::from)
+			.and_then(Self::new)
+			.expect("in-memory index should be created")
+	}
+
+	/// Opens the index from its database file, which is created if it does not exist yet.
+	///
+	/// # Errors
+	/// Returns an error if the database cannot be opened or created.
+	pub fn open(path: &Path) -> Result<Self, redb::Error> {
+		if let Some(parent) = path
+			.parent()
+			.filter(|parent| !parent.as_os_str().is_empty())
+		{
+			fs::create_dir_all(parent)?;
+		}
+		let index = Self::new(Database::create(path)?)?;
+
+		info!(
+			path = %path.display(),
+			instances = index.database.begin_read()?.open_table(INSTANCES)?.len()?,
+			"Opened instance index"
+		);
+		Ok(index)
+	}
+
+	fn new(database: Database) -> Result<Self, redb::Error> {
+		// The tables are created up front, so that they can always be opened for reading.
+		let transaction = database.begin_write()?;
+		{
+			let instances = transaction.open_table(INSTANCES)?;
+			let mut attributes = transaction.open_table(ATTRIBUTES)?;
+			// Indexes that were created before the attributes were indexed are migrated once.
+			if attributes.is_empty()? && !instances.is_empty()? {
+				info!("Indexing the attributes of the indexed instances");
+				for entry in instances.iter()? {
+					let (key, json) = entry?;
+					let (study, series, instance) = key.value();
+					let Ok(object) = dicom_json::from_str::<InMemDicomObject>(json.value()) else {
+						continue;
+					};
+					for (tag, value) in indexed_values(&object) {
+						attributes.insert((tag, value.as_str(), study, series, instance), ())?;
+					}
+				}
+			}
+		}
+		transaction.commit()?;
+		Ok(Self { database })
+	}
+
+	/// Adds the instance to the index, replacing a previous entry of the same instance.
+	/// Returns `false` if the instance has no Study, Series or SOP Instance UID,
+	/// or could not be written to the index.
+	pub fn insert(&self, object: &InMemDicomObject) -> bool {
+		let Some((study, series, instance)) = instance_key(object) else {
+			return false;
+		};
+
+		let result = dicom_json::to_string(&without_bulk_data(object))
+			.map_err(|err| redb::Error::Corrupted(err.to_string()))
+			.and_then(|json| {
+				let key = (study.as_str(), series.as_str(), instance.as_str());
+				let transaction = self.database.begin_write()?;
+				{
+					let mut instances = transaction.open_table(INSTANCES)?;
+					let mut attributes = transaction.open_table(ATTRIBUTES)?;
+					// The attributes of a replaced entry are no longer indexed.
+					let previous = instances
+						.insert(key, json.as_str())?
+						.and_then(|previous| dicom_json::from_str(previous.value()).ok());
+					for (tag, value) in previous.iter().flat_map(indexed_values) {
+						attributes.remove((tag, value.as_str(), key.0, key.1, key.2))?;
+					}
+					for (tag, value) in indexed_values(object) {
+						attributes.insert((tag, value.as_str(), key.0, key.1, key.2), ())?;
+					}
+				}
+				transaction.commit()?;
+				Ok(())
+			});
+		if let Err(err) = result {
+			warn!(sop_instance_uid = instance, "Failed to write index: {err}");
+			return false;
+		}
+		true
+	}
+
+	/// Whether the instance is already indexed.
+	pub fn contains(&self, study: &str, series: &str, instance: &str) -> bool {
+		let result = self
+			.database
+			.begin_read()
+			.map_err(redb::Error::from)
+			.and_then(|transaction| {
+				Ok(transaction
+					.open_table(INSTANCES)?
+					.get((study, series, instance))?
+					.is_some())
+			});
+		result
+			.inspect_err(|err| warn!(sop_instance_uid = instance, "Failed to read index: {err}"))
+			.unwrap_or(false)
+	}
+
+	/// Returns the indexed instances whose UIDs start with one of the prefixes, ordered by their UIDs.
+	/// Empty UIDs match all, e.g. `(study, "", "")` returns all instances of the study.
+	/// Entries that cannot be read are skipped.
+	fn entries<'a>(
+		&self,
+		prefixes: impl IntoIterator<Item = (&'a str, &'a str, &'a str)>,
+	) -> Vec<(InstanceKey, InMemDicomObject)> {
+		let mut entries = Vec::new();
+		let result = self
+			.database
+			.begin_read()
+			.map_err(redb::Error::from)
+			.and_then(|transaction| {
+				let table = transaction.open_table(INSTANCES)?;
+				for prefix in prefixes {
+					for entry in table.range(prefix..)? {
+						let (key, json) = entry?;
+						let (s, se, i) = key.value();
+						let differs = |prefix: &str, uid: &str| !prefix.is_empty() && prefix != uid;
+						if differs(prefix.0, s) || differs(prefix.1, se) || differs(prefix.2, i) {
+							break;
+						}
+						match dicom_json::from_str::<InMemDicomObject>(json.value()) {
+							Ok(object) => {
+								entries.push(((s.to_owned(), se.to_owned(), i.to_owned()), object));
+							}
+							Err(err) => {
+								warn!(sop_instance_uid = i, "Skipped invalid index entry: {err}");
+							}
+						}
+					}
+				}
+				Ok(())
+			});
+		if let Err(err) = result {
+			warn!("Failed to read index: {err}");
+		}
+		entries
+	}
+
+	/// Returns the studies, series or instances (depending on the level) that can match the
+	/// criteria for the [`INDEXED_ATTRIBUTES`], as prefixes for [`Self::entries`].
+	/// Returns `None` if no criterion can be looked up, so that all instances must be read.
+	fn candidates(
+		&self,
+		level: QueryRetrieveLevel,
+		match_criteria: &MatchCriteria,
+	) -> Option<BTreeSet<InstanceKey>> {
+		let mut candidates: Option<BTreeSet<InstanceKey>> = None;
+		for (selector, value) in match_criteria.iter() {
+			let AttributeSelectorStep::Tag(tag) = selector.first_step() else {
+				continue;
+			};
+			// The modalities of a study are the modalities of its instances.
+			let tag = match *tag {
+				tags::MODALITIES_IN_STUDY if level == QueryRetrieveLevel::Study => tags::MODALITY,
+				tag => tag,
+			};
+			if !INDEXED_ATTRIBUTES.contains(&tag) {
+				continue;
+			}
+			let Some((start, end)) = value_range(tag, value) else {
+				continue;
+			};
+			let keys = match self.lookup(tag, &start, &end) {
+				Ok(keys) => keys,
+				Err(err) => {
+					warn!("Failed to read index: {err}");
+					return None;
+				}
+			};
+			let keys: BTreeSet<InstanceKey> = keys
+				.into_iter()
+				.map(|(study, series, instance)| match level {
+					QueryRetrieveLevel::Study => (study, UI::new(), UI::new()),
+					QueryRetrieveLevel::Series => (study, series, UI::new()),
+					_ => (study, series, instance),
+				})
+				.collect();
+			candidates = Some(match candidates {
+				Some(candidates) => candidates.intersection(&keys).cloned().collect(),
+				None => keys,
+			});
+		}
+		candidates
+	}
+
+	/// Returns the instances with a value of the attribute between `start` and `end`.
+	fn lookup(
+		&self,
+		tag: Tag,
+		start: &Bound<String>,
+		end: &Bound<String>,
+	) -> Result<Vec<InstanceKey>, redb::Error> {
+		let tag = tag_key(tag);
+		let start = match start {
+			Bound::Included(start) | Bound::Excluded(start) => start.as_str(),
+			Bound::Unbounded => "",
+		};
+		let end = match end {
+			Bound::Included(end) | Bound::Excluded(end) => end.as_str(),
+			Bound::Unbounded => MAX,
+		};
+
+		let transaction = self.database.begin_read()?;
+		let table = transaction.open_table(ATTRIBUTES)?;
+		let mut keys = Vec::new();
+		for entry in table.range((tag, start, "", "", "")..=(tag, end, MAX, MAX, MAX))? {
+			let (key, ()) = entry.map(|(key, value)| (key, value.value()))?;
+			let (_, _, study, series, instance) = key.value();
+			keys.push((study.to_owned(), series.to_owned(), instance.to_owned()));
+		}
+		Ok(keys)
+	}
+
+	/// Returns the indexed instances of the study, series or instance.
+	pub fn instances(
+		&self,
+		study: &str,
+		series: Option<&str>,
+		instance: Option<&str>,
+	) -> Vec<InMemDicomObject> {
+		// An empty UID would match all series or instances.
+		if study.is_empty() || series == Some("") || instance == Some("") {
+			return Vec::new();
+		}
+		self.entries([(study, series.unwrap_or(""), instance.unwrap_or(""))])
+			.into_iter()
+			.map(|(_, object)| object)
+			.collect()
+	}
+
+	/// Returns all matches of the resource query, ordered by their UIDs.
+	pub fn search(
+		&self,
+		query: &ResourceQuery,
+		parameters: &QueryParameters,
+	) -> Vec<InMemDicomObject> {
+		let level = query.query_retrieve_level;
+		let study = query.study_instance_uid.as_deref().unwrap_or("");
+		let instances = self
+			.candidates(level, &parameters.match_criteria)
+			.map_or_else(
+				|| self.entries([(study, "", "")]),
+				|candidates| {
+					self.entries(
+						candidates
+							.iter()
+							.filter(|(s, _, _)| study.is_empty() || s == study)
+							.map(|(s, se, i)| (s.as_str(), se.as_str(), i.as_str())),
+					)
+				},
+			);
+
+		// Instances of the same study or series are grouped to compute their aggregate attributes.
+		let mut groups: BTreeMap<(&str, &str, &str), Vec<&InMemDicomObject>> = BTreeMap::new();
+		for ((study, series, instance), object) in &instances {
+			if query
+				.series_instance_uid
+				.as_ref()
+				.is_some_and(|uid| uid != series)
+			{
+				continue;
+			}
+			let key = match level {
+				QueryRetrieveLevel::Study => (study.as_str(), "", ""),
+				QueryRetrieveLevel::Series => (study.as_str(), series.as_str(), ""),
+				_ => (study.as_str(), series.as_str(), instance.as_str()),
+			};
+			groups.entry(key).or_default().push(object);
+		}
+
+		groups
+			.into_values()
+			.map(|objects| record(level, &objects))
+			.filter(|record| matches_criteria(record, &parameters.match_criteria))
+			.map(|record| response(level, record, parameters))
+			.collect()
+	}
+}
+
+/// The indexes of all AETs that search an index instead of their storage.
+#[derive(Clone, Default)]
+pub struct InstanceIndexes(HashMap<AE, Arc<InstanceIndex>>);
+
+impl InstanceIndexes {
+	/// Opens the indexes of all AETs that have an index configured.
+	///
+	/// # Errors
+	/// Returns an error if a database cannot be opened.
+	pub fn new(config: &AppConfig) -> anyhow::Result<Self> {
+		let mut indexes = HashMap::new();
+		for ae_config in &config.aets {
+			let index_config = match &ae_config.backend {
+				#[cfg(feature = "s3")]
+				BackendConfig::S3(s3_config) => s3_config.index.as_ref(),
+				BackendConfig::Filesystem(filesystem_config) => Some(&filesystem_config.index),
+				_ => None,
+			};
+			let Some(index_config) = index_config else {
+				continue;
+			};
+			let index = match &index_config.path {
+				Some(path) => InstanceIndex::open(path).with_context(|| {
+					format!(
+						"Failed to open the index {} of {}",
+						path.display(),
+						ae_config.aet
+					)
+				})?,
+				None => InstanceIndex::in_memory(),
+			};
+			indexes.insert(ae_config.aet.clone(), Arc::new(index));
+		}
+		Ok(Self(indexes))
+	}
+
+	#[inline]
+	pub fn get(&self, aet: &str) -> Option<&Arc<InstanceIndex>> {
+		self.0.get(aet)
+	}
+}
+
+/// The key of the instance, or `None` if it has no Study, Series or SOP Instance UID.
+pub fn instance_key(object: &InMemDicomObject) -> Option<InstanceKey> {
+	let uid = |tag| {
+		object
+			.get(tag)
+			.and_then(|element| element.to_str().ok())
+			.map(|uid| trim(&uid).to_owned())
+			.filter(|uid| !uid.is_empty())
+	};
+	Some((
+		uid(tags::STUDY_INSTANCE_UID)?,
+		uid(tags::SERIES_INSTANCE_UID)?,
+		uid(tags::SOP_INSTANCE_UID)?,
+	))
+}
+
+/// The values of the [`INDEXED_ATTRIBUTES`] of the instance, by their [`tag_key`].
+fn indexed_values(object: &InMemDicomObject) -> Vec<(u32, String)> {
+	INDEXED_ATTRIBUTES
+		.iter()
+		.filter_map(|&tag| Some((tag, object.get(tag)?.to_multi_str().ok()?)))
+		.flat_map(|(tag, values)| {
+			values
+				.iter()
+				.map(|value| trim(value).to_owned())
+				.filter(|value| !value.is_empty())
+				.map(|value| (tag_key(tag), value))
+				.collect::<Vec<_>>()
+		})
+		.collect()
+}
+
+fn tag_key(tag: Tag) -> u32 {
+	(u32::from(tag.group()) << 16) | u32::from(tag.element())
+}
+
+/// The range of attribute values that can match the match value, or `None` if any value can match.
+/// Wildcards are looked up by the prefix before the first wildcard.
+fn value_range(tag: Tag, value: &PrimitiveValue) -> Option<(Bound<String>, Bound<String>)> {
+	if matches!(value, PrimitiveValue::Empty) {
+		return None;
+	}
+	let value = value.to_str();
+	let value = trim(&value);
+	if tag == tags::STUDY_DATE {
+		let (start, end) = value.split_once('-').unwrap_or((value, value));
+		let bound = |value: &str| {
+			if value.is_empty() {
+				Bound::Unbounded
+			} else {
+				Bound::Included(value.to_owned())
+			}
+		};
+		return Some((bound(start), bound(end)));
+	}
+	match value.find(['*', '?']) {
+		Some(0) => None,
+		Some(wildcard) => Some((
+			Bound::Included(value[..wildcard].to_owned()),
+			Bound::Included(format!("{}{MAX}", &value[..wildcard])),
+		)),
+		None => Some((
+			Bound::Included(value.to_owned()),
+			Bound::Included(value.to_owned()),
+		)),
+	}
+}
+
+/// Pixel data and other binary attributes are not searchable and would only bloat the index.
+fn without_bulk_data(object: &InMemDicomObject) -> InMemDicomObject {
+	InMemDicomObject::from_element_iter(object.iter().filter_map(|element| {
+		if element.tag().group() == 0x7FE0
+			|| matches!(
+				element.vr(),
+				VR::OB | VR::OW | VR::OF | VR::OD | VR::OL | VR::OV | VR::UN
+			) {
+			return None;
+		}
+		Some(element.items().map_or_else(
+			|| element.clone(),
+			|items| {
+				DataElement::new(
+					element.tag(),
+					VR::SQ,
+					DataSetSequence::from(items.iter().map(without_bulk_data).collect::<Vec<_>>()),
+				)
+			},
+		))
+	}))
+}
+
+/// Creates the record of a study, series or instance from its instances.
+/// The attributes of the first instance are used for the whole study or series.
+fn record(level: QueryRetrieveLevel, objects: &[&InMemDicomObject]) -> InMemDicomObject {
+	let mut record = objects[0].clone();
+	let count = |value: usize| PrimitiveValue::from(value.to_string());
+
+	match level {
+		QueryRetrieveLevel::Study => {
+			let modalities: BTreeSet<String> = objects
+				.iter()
+				.filter_map(|object| object.get(tags::MODALITY))
+				.filter_map(|element| element.to_str().ok())
+				.map(|modality| trim(&modality).to_owned())
+				.filter(|modality| !modality.is_empty())
+				.collect();
+			let series: BTreeSet<String> = objects
+				.iter()
+				.filter_map(|object| object.get(tags::SERIES_INSTANCE_UID))
+				.filter_map(|element| element.to_str().ok())
+				.map(std::borrow::Cow::into_owned)
+				.collect();
+
+			record.put(DataElement::new(
+				tags::MODALITIES_IN_STUDY,
+				VR::CS,
+				PrimitiveValue::Strs(modalities.into_iter().collect()),
+			));
+			record.put(DataElement::new(
+				tags::NUMBER_OF_STUDY_RELATED_SERIES,
+				VR::IS,
+				count(series.len()),
+			));
+			record.put(DataElement::new(
+				tags::NUMBER_OF_STUDY_RELATED_INSTANCES,
+				VR::IS,
+				count(objects.len()),
+			));
+		}
+		QueryRetrieveLevel::Series => {
+			record.put(DataElement::new(
+				tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+				VR::IS,
+				count(objects.len()),
+			));
+		}
+		_ => {}
+	}
+	if level != QueryRetrieveLevel::Series {
+		record.put(DataElement::new(
+			tags::INSTANCE_AVAILABILITY,
+			VR::CS,
+			"ONLINE",
+		));
+	}
+	record
+}
+
+/// Reduces the record to the attributes that are returned for the query parameters.
+fn response(
+	level: QueryRetrieveLevel,
+	record: InMemDicomObject,
+	parameters: &QueryParameters,
+) -> InMemDicomObject {
+	let IncludeField::List(include_fields) = &parameters.include_field else {
+		return record;
+	};
+
+	let default_tags = match level {
+		QueryRetrieveLevel::Study => STUDY_SEARCH_TAGS,
+		QueryRetrieveLevel::Series => SERIES_SEARCH_TAGS,
+		_ => INSTANCE_SEARCH_TAGS,
+	};
+	let mut returned: Vec<Tag> = vec![tags::STUDY_INSTANCE_UID];
+	if level != QueryRetrieveLevel::Study {
+		returned.push(tags::SERIES_INSTANCE_UID);
+	}
+	returned.extend(default_tags);
+	returned.extend(include_fields);
+	returned.extend(parameters.match_criteria.iter().map(
+		|(selector, _)| match selector.first_step() {
+			AttributeSelectorStep::Tag(tag) | AttributeSelectorStep::Nested { tag, .. } => *tag,
+		},
+	));
+
+	let mut response = InMemDicomObject::new_empty();
+	for tag in returned {
+		if let Some(element) = record.get(tag) {
+			response.put(element.clone());
+		}
+	}
+	response
+}
+
+/// Whether the object matches all match criteria.
+pub fn matches_criteria(object: &InMemDicomObject, match_criteria: &MatchCriteria) -> bool {
+	match_criteria
+		.iter()
+		.all(|(selector, value)| matches(object, selector, value))
+}
+
+/// Whether the attribute matches the match value.
+fn matches(
+	record: &InMemDicomObject,
+	selector: &AttributeSelector,
+	value: &PrimitiveValue,
+) -> bool {
+	// Universal matching
+	if matches!(value, PrimitiveValue::Empty) {
+		return true;
+	}
+	let Ok(actual) = record.value_at(selector.clone()) else {
+		return false;
+	};
+
+	let vr = StandardDataDictionary
+		.by_tag(selector.last_tag())
+		.map_or(VR::UN, |entry| entry.vr().relaxed());
+	match vr {
+		VR::SS | VR::US | VR::SL | VR::UL | VR::SV | VR::UV | VR::FL | VR::FD | VR::DS | VR::IS => {
+			let (Ok(actual), Ok(expected)) = (actual.to_multi_float64(), value.to_float64()) else {
+				return false;
+			};
+			actual
+				.iter()
+				.any(|actual| (actual - expected).abs() < f64::EPSILON)
+		}
+		_ => {
+			let Ok(actual) = actual.to_multi_str() else {
+				return false;
+			};
+			let expected = value.to_multi_str();
+			actual
+				.iter()
+				.map(|actual| trim(actual))
+				.any(|actual| match vr {
+					// UID list matching
+					VR::UI => expected.iter().any(|expected| expected == actual),
+					VR::DA | VR::TM | VR::DT => expected
+						.first()
+						.is_some_and(|expected| matches_range(actual, expected)),
+					_ => expected
+						.first()
+						.is_some_and(|expected| matches_wildcard(actual, expected, vr == VR::PN)),
+				})
+		}
+	}
+}
+
+/// Range matching of dates and times.
+///
+/// The formats are fixed-width and most significant first, so values are compared
+/// lexicographically. The end of the range is compared with the same precision as the value,
+/// so that `-1200` includes `120000.123`.
+fn matches_range(actual: &str, expected: &str) -> bool {
+	let (start, end) = expected.split_once('-').unwrap_or((expected, expected));
+	let before_end = end.is_empty() || actual.get(..end.len()).unwrap_or(actual) <= end;
+	(start.is_empty() || actual >= start) && before_end
+}
+
+/// Single value matching with the wildcards `*` (any sequence of characters) and `?` (any single
+/// character). Person names are compared case-insensitively.
+fn matches_wildcard(actual: &str, pattern: &str, ignore_case: bool) -> bool {
+	let normalize = |value: &str| -> Vec<char> {
+		if ignore_case {
+			value.to_lowercase().chars().collect()
+		} else {
+			value.chars().collect()
+		}
+	};
+	let actual = normalize(actual);
+	let pattern = normalize(pattern);
+
+	// Classic greedy matching that backtracks to the last `*`.
+	let (mut a, mut p) = (0, 0);
+	let mut backtrack = None;
+	while a < actual.len() {
+		if p < pattern.len() && (pattern[p] == '?' || pattern[p] == actual[a]) {
+			a += 1;
+			p += 1;
+		} else if p < pattern.len() && pattern[p] == '*' {
+			backtrack = Some((p, a));
+			p += 1;
+		} else if let Some((star, position)) = backtrack {
+			p = star + 1;
+			a = position + 1;
+			backtrack = Some((star, position + 1));
+		} else {
+			return false;
+		}
+	}
+	pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn trim(value: &str) -> &str {
+	value.trim_end_matches(['\0', ' '])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn instance(study: &str, series: &str, instance: &str, modality: &str) -> InMemDicomObject {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, instance),
+			DataElement::new(tags::MODALITY, VR::CS, modality),
+			DataElement::new(tags::PATIENT_NAME, VR::PN, "Doe^John"),
+			DataElement::new(tags::STUDY_DATE, VR::DA, "20240115"),
+			DataElement::new(tags::INSTANCE_NUMBER, VR::IS, "2"),
+			DataElement::new(
+				tags::PIXEL_DATA,
+				VR::OW,
+				PrimitiveValue::from(vec![0_u8; 4]),
+			),
+		])
+	}
+
+	fn index() -> InstanceIndex {
+		let index = InstanceIndex::in_memory();
+		index.insert(&instance("1.1", "1.1.1", "1.1.1.1", "CT"));
+		index.insert(&instance("1.1", "1.1.1", "1.1.1.2", "CT"));
+		index.insert(&instance("1.1", "1.1.2", "1.1.2.1", "SR"));
+		index.insert(&instance("1.2", "1.2.1", "1.2.1.1", "MR"));
+		index
+	}
+
+	fn query(level: QueryRetrieveLevel, study: Option<&str>) -> ResourceQuery {
+		ResourceQuery {
+			query_retrieve_level: level,
+			study_instance_uid: study.map(str::to_owned),
+			series_instance_uid: None,
+		}
+	}
+
+	fn parameters(criteria: &[(&str, &str)]) -> QueryParameters {
+		let criteria: HashMap<String, String> = criteria
+			.iter()
+			.map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+			.collect();
+		QueryParameters {
+			match_criteria: MatchCriteria::try_from(criteria).unwrap(),
+			..QueryParameters::default()
+		}
+	}
+
+	fn string(object: &InMemDicomObject, tag: Tag) -> String {
+		object.get(tag).unwrap().to_str().unwrap().into_owned()
+	}
+
+	#[test]
+	fn searches_studies_with_aggregates() {
+		let studies = index().search(
+			&query(QueryRetrieveLevel::Study, None),
+			&QueryParameters::default(),
+		);
+		assert_eq!(studies.len(), 2);
+		assert_eq!(string(&studies[0], tags::STUDY_INSTANCE_UID), "1.1");
+		assert_eq!(string(&studies[0], tags::MODALITIES_IN_STUDY), "CT\\SR");
+		assert_eq!(
+			string(&studies[0], tags::NUMBER_OF_STUDY_RELATED_SERIES),
+			"2"
+		);
+		assert_eq!(
+			string(&studies[0], tags::NUMBER_OF_STUDY_RELATED_INSTANCES),
+			"3"
+		);
+		// Instance-level attributes are not returned by default.
+		assert!(studies[0].get(tags::SOP_INSTANCE_UID).is_none());
+		assert!(studies[0].get(tags::PIXEL_DATA).is_none());
+	}
+
+	#[test]
+	fn applies_match_criteria() {
+		let index = index();
+		let search = |level, study, criteria: &[(&str, &str)]| {
+			index.search(&query(level, study), &parameters(criteria))
+		};
+
+		assert_eq!(
+			search(
+				QueryRetrieveLevel::Study,
+				None,
+				&[("ModalitiesInStudy", "MR")]
+			)
+			.len(),
+			1
+		);
+		assert_eq!(
+			search(QueryRetrieveLevel::Study, None, &[("PatientName", "doe*")]).len(),
+			2
+		);
+		assert_eq!(
+			search(
+				QueryRetrieveLevel::Study,
+				None,
+				&[("StudyDate", "20240101-20240131")]
+			)
+			.len(),
+			2
+		);
+		assert!(search(
+			QueryRetrieveLevel::Study,
+			None,
+			&[("StudyDate", "20240116-")]
+		)
+		.is_empty());
+		assert_eq!(
+			search(
+				QueryRetrieveLevel::Series,
+				Some("1.1"),
+				&[("Modality", "CT")]
+			)
+			.len(),
+			1
+		);
+		assert_eq!(
+			search(
+				QueryRetrieveLevel::Image,
+				None,
+				&[("SOPInstanceUID", "1.1.1.2,1.2.1.1")]
+			)
+			.len(),
+			2
+		);
+		assert_eq!(
+			search(QueryRetrieveLevel::Image, None, &[("InstanceNumber", "2")]).len(),
+			4
+		);
+	}
+
+	#[test]
+	fn looks_up_indexed_attributes() {
+		let index = index();
+		let candidates = |level, criteria: &[(&str, &str)]| {
+			index
+				.candidates(level, &parameters(criteria).match_criteria)
+				.map(|candidates| candidates.into_iter().collect::<Vec<_>>())
+		};
+		let key = |study: &str, series: &str, instance: &str| {
+			(study.to_owned(), series.to_owned(), instance.to_owned())
+		};
+
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Study, &[("ModalitiesInStudy", "SR")]),
+			Some(vec![key("1.1", "", "")])
+		);
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Series, &[("Modality", "C*")]),
+			Some(vec![key("1.1", "1.1.1", "")])
+		);
+		assert_eq!(
+			candidates(
+				QueryRetrieveLevel::Image,
+				&[("StudyDate", "20240101-"), ("Modality", "MR")]
+			),
+			Some(vec![key("1.2", "1.2.1", "1.2.1.1")])
+		);
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Study, &[("StudyDate", "-20231231")]),
+			Some(Vec::new())
+		);
+		// Criteria without an indexed attribute or prefix read all instances.
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Study, &[("PatientName", "Doe*")]),
+			None
+		);
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Study, &[("Modality", "*T")]),
+			None
+		);
+
+		// Replaced entries are no longer found by their previous values.
+		index.insert(&instance("1.2", "1.2.1", "1.2.1.1", "CT"));
+		assert_eq!(
+			candidates(QueryRetrieveLevel::Study, &[("Modality", "MR")]),
+			Some(Vec::new())
+		);
+	}
+
+	#[test]
+	fn returns_included_fields() {
+		let mut parameters = parameters(&[]);
+		parameters.include_field = IncludeField::List(vec![tags::MODALITY]);
+		let series = index().search(&query(QueryRetrieveLevel::Study, None), &parameters);
+		assert!(series[0].get(tags::MODALITY).is_some());
+		assert!(series[0].get(tags::INSTANCE_NUMBER).is_none());
+
+		parameters.include_field = IncludeField::All;
+		let series = index().search(&query(QueryRetrieveLevel::Study, None), &parameters);
+		assert!(series[0].get(tags::INSTANCE_NUMBER).is_some());
+	}
+
+	#[test]
+	fn matches_wildcards() {
+		assert!(matches_wildcard("DOE^JOHN", "DOE*", false));
+		assert!(matches_wildcard("DOE^JOHN", "D?E^*N", false));
+		assert!(matches_wildcard("Doe^John", "doe^john", true));
+		assert!(!matches_wildcard("Doe^John", "doe^john", false));
+		assert!(!matches_wildcard("DOE", "DOE?", false));
+		assert!(matches_wildcard("", "*", false));
+	}
+
+	#[test]
+	fn matches_ranges() {
+		assert!(matches_range("120000.123", "-1200"));
+		assert!(!matches_range("120100", "-1200"));
+		assert!(matches_range("20240115", "20240115"));
+		assert!(matches_range("20240115", "20240101-"));
+		assert!(!matches_range("20240115", "20240116-20240120"));
+	}
+
+	#[test]
+	fn persists_instances() {
+		let path =
+			std::env::temp_dir().join(format!("dicom-rst-index-{}.redb", std::process::id()));
+		let _ = fs::remove_file(&path);
+
+		let index = InstanceIndex::open(&path).unwrap();
+		assert!(index.insert(&instance("1.1", "1.1.1", "1.1.1.1", "CT")));
+		assert!(index.insert(&instance("1.1", "1.1.1", "1.1.1.1", "MR")));
+		drop(index);
+
+		let index = InstanceIndex::open(&path).unwrap();
+		assert!(index.contains("1.1", "1.1.1", "1.1.1.1"));
+		assert!(!index.contains("1.1", "1.1.1", "1.1.1.2"));
+		// The second entry replaced the first one.
+		let search = |modality| {
+			index.search(
+				&query(QueryRetrieveLevel::Image, None),
+				&parameters(&[("Modality", modality)]),
+			)
+		};
+		assert_eq!(search("MR").len(), 1);
+		assert!(search("CT").is_empty());
+		drop(index);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn returns_instances_of_study() {
+		let index = index();
+		assert_eq!(index.instances("1.1", None, None).len(), 3);
+		assert_eq!(index.instances("1.1", Some("1.1.1"), None).len(), 2);
+		assert_eq!(
+			index.instances("1.1", Some("1.1.1"), Some("1.1.1.2")).len(),
+			1
+		);
+		assert!(index.instances("1", None, None).is_empty());
+	}
+}
@@ -0,0 +1,288 @@
+//! A backend that keeps all instances in memory, e.g. for tests of `DICOMweb` clients and demos.
+//!
+//! Instances are lost on restart. The store can be seeded from a directory of DICOM files on startup,
+//! so that every run starts with the same data.
+
+pub mod mwl;
+pub mod qido;
+pub mod stow;
+pub mod wado;
+
+use crate::api::qido::{QueryParameters, ResourceQuery};
+use crate::api::MatchCriteria;
+use crate::backend::index::{instance_key, matches_criteria, InstanceIndex, InstanceKey};
+use crate::config::{AppConfig, BackendConfig};
+use crate::types::AE;
+use dicom::dictionary_std::uids;
+use dicom::object::{FileDicomObject, InMemDicomObject, OpenFileOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+type Instance = FileDicomObject<InMemDicomObject>;
+
+/// The instances and worklist items of an AET.
+pub struct MemoryStore {
+	/// Answers QIDO-RS requests, like the index of the filesystem backend.
+	index: InstanceIndex,
+	instances: RwLock<BTreeMap<InstanceKey, Arc<Instance>>>,
+	workitems: RwLock<Vec<InMemDicomObject>>,
+}
+
+impl Default for MemoryStore {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl MemoryStore {
+	pub fn new() -> Self {
+		Self {
+			index: InstanceIndex::in_memory(),
+			instances: RwLock::new(BTreeMap::new()),
+			workitems: RwLock::new(Vec::new()),
+		}
+	}
+
+	/// Adds the instance, replacing a previous instance with the same UIDs.
+	/// Returns `false` if the instance has no Study, Series or SOP Instance UID.
+	pub fn insert(&self, instance: Instance) -> bool {
+		let Some(key) = instance_key(&instance) else {
+			return false;
+		};
+		self.index.insert(&instance);
+		self.instances
+			.write()
+			.expect("store lock should not be poisoned")
+			.insert(key, Arc::new(instance));
+		true
+	}
+
+	/// Returns the instances of the study, series or instance, ordered by their UIDs.
+	pub fn instances(
+		&self,
+		study: &str,
+		series: Option<&str>,
+		instance: Option<&str>,
+	) -> Vec<Arc<Instance>> {
+		let instances = self
+			.instances
+			.read()
+			.expect("store lock should not be poisoned");
+		// The instances are ordered by their UIDs, so the matches follow the first possible key.
+		let start = (
+			study.to_owned(),
+			series.unwrap_or_default().to_owned(),
+			instance.unwrap_or_default().to_owned(),
+		);
+		let differs = |uid: Option<&str>, other: &str| uid.is_some_and(|uid| uid != other);
+		let mut matches = Vec::new();
+		for ((s, se, i), matched) in instances.range(start..) {
+			// Without a series, the instances of the study are not ordered by their SOP Instance UID.
+			if s != study || differs(series, se) || (series.is_some() && differs(instance, i)) {
+				break;
+			}
+			if !differs(instance, i) {
+				matches.push(Arc::clone(matched));
+			}
+		}
+		drop(instances);
+		matches
+	}
+
+	/// Returns all matches of the resource query, see [`InstanceIndex::search`].
+	pub fn search(
+		&self,
+		query: &ResourceQuery,
+		parameters: &QueryParameters,
+	) -> Vec<InMemDicomObject> {
+		self.index.search(query, parameters)
+	}
+
+	pub fn add_workitem(&self, workitem: InMemDicomObject) {
+		self.workitems
+			.write()
+			.expect("store lock should not be poisoned")
+			.push(workitem);
+	}
+
+	/// Returns the worklist items that match all match criteria, in the order they were added.
+	pub fn workitems(&self, match_criteria: &MatchCriteria) -> Vec<InMemDicomObject> {
+		self.workitems
+			.read()
+			.expect("store lock should not be poisoned")
+			.iter()
+			.filter(|workitem| matches_criteria(workitem, match_criteria))
+			.cloned()
+			.collect()
+	}
+
+	/// Loads all DICOM files in the directory and its subdirectories.
+	/// Files of the Modality Worklist Information Model are added as worklist items.
+	///
+	/// Returns the number of loaded instances and worklist items.
+	///
+	/// # Errors
+	/// Returns an error if a directory cannot be read. Files that cannot be read are skipped.
+	pub fn seed(&self, directory: &Path) -> io::Result<(usize, usize)> {
+		let (mut instances, mut workitems) = (0, 0);
+		for entry in fs::read_dir(directory)? {
+			let path = entry?.path();
+			if path.is_dir() {
+				let (i, w) = self.seed(&path)?;
+				instances += i;
+				workitems += w;
+				continue;
+			}
+
+			let file = match OpenFileOptions::new().open_file(&path) {
+				Ok(file) => file,
+				Err(err) => {
+					warn!(path = %path.display(), "Skipped file that could not be read: {err}");
+					continue;
+				}
+			};
+			let sop_class_uid = file
+				.meta()
+				.media_storage_sop_class_uid()
+				.trim_end_matches(['\0', ' ']);
+			if sop_class_uid == uids::MODALITY_WORKLIST_INFORMATION_MODEL_FIND {
+				self.add_workitem(file.into_inner());
+				workitems += 1;
+			} else if self.insert(file) {
+				instances += 1;
+			} else {
+				warn!(
+					path = %path.display(),
+					"Skipped file without StudyInstanceUID, SeriesInstanceUID or SOPInstanceUID"
+				);
+			}
+		}
+		Ok((instances, workitems))
+	}
+}
+
+/// The stores of all AETs with the `MEMORY` backend.
+#[derive(Clone, Default)]
+pub struct MemoryStores(HashMap<AE, Arc<MemoryStore>>);
+
+impl MemoryStores {
+	/// Creates the stores of all AETs with the `MEMORY` backend and loads their seed data.
+	/// Seeding is not done in the background, so that the data is available as soon as the server is.
+	///
+	/// # Errors
+	/// Returns an error if a seed directory cannot be read.
+	pub fn new(config: &AppConfig) -> io::Result<Self> {
+		let mut stores = HashMap::new();
+		for ae_config in &config.aets {
+			let BackendConfig::Memory(memory_config) = &ae_config.backend else {
+				continue;
+			};
+			let store = MemoryStore::new();
+			if let Some(seed) = &memory_config.seed {
+				let (instances, workitems) = store.seed(seed)?;
+				info!(
+					aet = ae_config.aet,
+					path = %seed.display(),
+					instances,
+					workitems,
+					"Loaded seed data"
+				);
+			}
+			stores.insert(ae_config.aet.clone(), Arc::new(store));
+		}
+		Ok(Self(stores))
+	}
+
+	#[inline]
+	pub fn get(&self, aet: &str) -> Option<&Arc<MemoryStore>> {
+		self.0.get(aet)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::tests::instance;
+	use dicom::core::{DataElement, VR};
+	use dicom::dictionary_std::tags;
+	use dicom::object::FileMetaTableBuilder;
+
+	#[test]
+	fn seeds_instances_and_workitems() {
+		let directory = std::env::temp_dir().join(format!("dicom-rst-seed-{}", std::process::id()));
+		fs::create_dir_all(directory.join("1.1")).unwrap();
+		instance("1.1", "1.1.1", "1.1.1.1")
+			.write_to_file(directory.join("1.1").join("1.1.1.1.dcm"))
+			.unwrap();
+		InMemDicomObject::from_element_iter([DataElement::new(
+			tags::PATIENT_NAME,
+			VR::PN,
+			"Doe^John",
+		)])
+		.with_meta(
+			FileMetaTableBuilder::new()
+				.transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+				.media_storage_sop_class_uid(uids::MODALITY_WORKLIST_INFORMATION_MODEL_FIND)
+				.media_storage_sop_instance_uid("1.2.3"),
+		)
+		.unwrap()
+		.write_to_file(directory.join("workitem.wl"))
+		.unwrap();
+		fs::write(directory.join("README.md"), "Not a DICOM file").unwrap();
+
+		let store = MemoryStore::new();
+		let result = store.seed(&directory);
+		fs::remove_dir_all(&directory).unwrap();
+
+		assert_eq!(result.unwrap(), (1, 1));
+		assert_eq!(store.instances("1.1", None, None).len(), 1);
+		assert_eq!(
+			store
+				.workitems(&QueryParameters::default().match_criteria)
+				.len(),
+			1
+		);
+	}
+
+	#[test]
+	fn finds_instances_by_their_uids() {
+		let store = MemoryStore::new();
+		for (study, series, sop_instance) in [
+			("1.1", "1.1.1", "1.1.1.1"),
+			("1.1", "1.1.1", "1.1.1.2"),
+			("1.1", "1.1.2", "1.1.2.1"),
+			("1.10", "1.10.1", "1.10.1.1"),
+		] {
+			assert!(store.insert(instance(study, series, sop_instance)));
+		}
+		let uids = |instances: Vec<Arc<Instance>>| {
+			instances
+				.iter()
+				.map(|instance| instance.meta().media_storage_sop_instance_uid().to_owned())
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(
+			uids(store.instances("1.1", None, None)),
+			["1.1.1.1", "1.1.1.2", "1.1.2.1"]
+		);
+		assert_eq!(
+			uids(store.instances("1.1", Some("1.1.1"), None)),
+			["1.1.1.1", "1.1.1.2"]
+		);
+		assert_eq!(
+			uids(store.instances("1.1", Some("1.1.1"), Some("1.1.1.2"))),
+			["1.1.1.2"]
+		);
+		assert_eq!(
+			uids(store.instances("1.1", None, Some("1.1.2.1"))),
+			["1.1.2.1"]
+		);
+		assert!(store.instances("1.1", Some("1.1.3"), None).is_empty());
+		assert!(store.instances("1.2", None, None).is_empty());
+	}
+}
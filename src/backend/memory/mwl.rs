@@ -0,0 +1,111 @@
+use crate::api::mwl::{MwlSearchRequest, MwlSearchResponse, MwlService};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::sync::Arc;
+
+use super::MemoryStore;
+
+/// Answers MWL-RS requests from the worklist items in memory.
+///
+/// Worklist items are returned with all of their attributes, regardless of `includefield`.
+pub struct MemoryMwlService {
+	store: Arc<MemoryStore>,
+}
+
+impl MemoryMwlService {
+	pub const fn new(store: Arc<MemoryStore>) -> Self {
+		Self { store }
+	}
+}
+
+#[async_trait]
+impl MwlService for MemoryMwlService {
+	async fn search(&self, request: MwlSearchRequest) -> MwlSearchResponse {
+		let parameters = request.parameters;
+		let workitems = self
+			.store
+			.workitems(&parameters.match_criteria)
+			.into_iter()
+			.skip(parameters.offset)
+			.take(parameters.limit);
+		MwlSearchResponse {
+			stream: futures::stream::iter(workitems.map(Ok)).boxed(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::mwl::MwlQueryParameters;
+	use crate::api::MatchCriteria;
+	use dicom::core::{DataElement, VR};
+	use dicom::dictionary_std::tags;
+	use dicom::object::InMemDicomObject;
+	use futures::TryStreamExt;
+	use std::collections::HashMap;
+
+	fn workitem(patient_name: &str, modality: &str) -> InMemDicomObject {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(tags::PATIENT_NAME, VR::PN, patient_name),
+			DataElement::new(
+				tags::SCHEDULED_PROCEDURE_STEP_SEQUENCE,
+				VR::SQ,
+				dicom::core::value::DataSetSequence::from(vec![
+					InMemDicomObject::from_element_iter([DataElement::new(
+						tags::MODALITY,
+						VR::CS,
+						modality,
+					)]),
+				]),
+			),
+		])
+	}
+
+	#[tokio::test]
+	async fn filters_workitems() {
+		let store = Arc::new(MemoryStore::new());
+		store.add_workitem(workitem("Doe^John", "CT"));
+		store.add_workitem(workitem("Doe^Jane", "MR"));
+		store.add_workitem(workitem("Roe^Richard", "CT"));
+		let mwl = MemoryMwlService::new(store);
+
+		let search = |criteria: &[(&str, &str)], offset| {
+			let criteria: HashMap<String, String> = criteria
+				.iter()
+				.map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+				.collect();
+			mwl.search(MwlSearchRequest {
+				parameters: MwlQueryParameters {
+					match_criteria: MatchCriteria::try_from(criteria).unwrap(),
+					offset,
+					..MwlQueryParameters::default()
+				},
+			})
+		};
+
+		let workitems: Vec<_> = search(&[("PatientName", "Doe*")], 0)
+			.await
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+		assert_eq!(workitems.len(), 2);
+
+		let workitems: Vec<_> = search(&[("00400100.00080060", "CT")], 1)
+			.await
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+		assert_eq!(workitems.len(), 1);
+		assert_eq!(
+			workitems[0]
+				.element(tags::PATIENT_NAME)
+				.unwrap()
+				.to_str()
+				.unwrap(),
+			"Roe^Richard"
+		);
+	}
+}
@@ -0,0 +1,25 @@
+use crate::api::qido::{QidoService, SearchRequest, SearchResponse};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::sync::Arc;
+
+use super::MemoryStore;
+
+/// Answers QIDO-RS requests from the index of the instances in memory.
+pub struct MemoryQidoService {
+	store: Arc<MemoryStore>,
+}
+
+impl MemoryQidoService {
+	pub const fn new(store: Arc<MemoryStore>) -> Self {
+		Self { store }
+	}
+}
+
+#[async_trait]
+impl QidoService for MemoryQidoService {
+	async fn search(&self, request: SearchRequest) -> SearchResponse {
+		let matches = self.store.search(&request.query, &request.parameters);
+		SearchResponse::new(futures::stream::iter(matches.into_iter().map(Ok)).boxed())
+	}
+}
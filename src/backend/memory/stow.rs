@@ -0,0 +1,85 @@
+use crate::api::stow::{InstanceStore, StoreOutcome, FAILURE_CANNOT_UNDERSTAND};
+use async_trait::async_trait;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use super::MemoryStore;
+
+/// Stores instances in memory. Storing the same instance again replaces it.
+pub struct MemoryStowService {
+	store: Arc<MemoryStore>,
+}
+
+impl MemoryStowService {
+	pub const fn new(store: Arc<MemoryStore>) -> Self {
+		Self { store }
+	}
+}
+
+#[async_trait]
+impl InstanceStore for MemoryStowService {
+	async fn store_instance(&self, instance: FileDicomObject<InMemDicomObject>) -> StoreOutcome {
+		let sop_instance_uid = instance.meta().media_storage_sop_instance_uid().to_owned();
+		if self.store.insert(instance) {
+			info!(sop_instance_uid, "Successfully stored instance");
+			StoreOutcome::Stored
+		} else {
+			warn!(
+				sop_instance_uid,
+				"Instance has no StudyInstanceUID, SeriesInstanceUID or SOPInstanceUID"
+			);
+			StoreOutcome::Failed(FAILURE_CANNOT_UNDERSTAND)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::stow::{StoreRequest, StowService};
+	use crate::api::wado::{ResourceQuery, RetrieveInstanceRequest, WadoService};
+	use crate::backend::memory::wado::MemoryWadoService;
+	use crate::backend::tests::instance;
+	use futures::{StreamExt, TryStreamExt};
+
+	#[tokio::test]
+	async fn stored_instances_can_be_retrieved() {
+		let store = Arc::new(MemoryStore::new());
+		let stow = MemoryStowService::new(Arc::clone(&store));
+		let wado = MemoryWadoService::new(store);
+
+		let response = stow
+			.store(StoreRequest {
+				instances: futures::stream::iter([
+					Ok(instance("1.1", "1.1.1", "1.1.1.1")),
+					Ok(instance("1.1", "1.1.2", "1.1.2.1")),
+					Ok(instance("1.2", "1.2.1", "1.2.1.1")),
+				])
+				.boxed(),
+				rejected: Arc::default(),
+			})
+			.await
+			.unwrap();
+		assert_eq!(response.referenced_sequence.len(), 3);
+
+		let instances: Vec<_> = wado
+			.retrieve(RetrieveInstanceRequest {
+				query: ResourceQuery {
+					aet: String::from("MEMORY"),
+					study_instance_uid: String::from("1.1"),
+					series_instance_uid: None,
+					sop_instance_uid: None,
+				},
+				transfer_syntax: None,
+				single_part: false,
+			})
+			.await
+			.unwrap()
+			.stream
+			.try_collect()
+			.await
+			.unwrap();
+		assert_eq!(instances.len(), 2);
+	}
+}
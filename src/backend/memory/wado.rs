@@ -0,0 +1,50 @@
+use crate::api::wado::{
+	InstanceResponse, MetadataRequest, MetadataResponse, ResourceQuery, RetrieveError,
+	RetrieveInstanceRequest, WadoService,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::sync::Arc;
+
+use super::MemoryStore;
+
+pub struct MemoryWadoService {
+	store: Arc<MemoryStore>,
+}
+
+impl MemoryWadoService {
+	pub const fn new(store: Arc<MemoryStore>) -> Self {
+		Self { store }
+	}
+
+	/// The instances of the resource, ordered by their UIDs.
+	fn instances(&self, query: &ResourceQuery) -> Result<InstanceResponse, RetrieveError> {
+		let instances = self.store.instances(
+			&query.study_instance_uid,
+			query.series_instance_uid.as_deref(),
+			query.sop_instance_uid.as_deref(),
+		);
+		if instances.is_empty() {
+			return Err(RetrieveError::NotFound {
+				message: format!("No instances found for study {}", query.study_instance_uid),
+			});
+		}
+		Ok(InstanceResponse {
+			stream: futures::stream::iter(instances.into_iter().map(Ok)).boxed(),
+		})
+	}
+}
+
+#[async_trait]
+impl WadoService for MemoryWadoService {
+	async fn retrieve(
+		&self,
+		request: RetrieveInstanceRequest,
+	) -> Result<InstanceResponse, RetrieveError> {
+		self.instances(&request.query)
+	}
+
+	async fn metadata(&self, request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
+		self.instances(&request.query).map(MetadataResponse::from)
+	}
+}
@@ -2,15 +2,24 @@ use crate::api::mwl::MwlService;
 use crate::api::qido::QidoService;
 use crate::api::stow::StowService;
 use crate::api::wado::WadoService;
-use crate::config::BackendConfig;
+use crate::config::{
+	ApplicationEntityConfig, BackendConfig, CompositeConfig, GroupConfig, Service,
+};
+use crate::types::AE;
 use crate::AppState;
 use axum::extract::{FromRef, FromRequestParts, Path};
 use axum::http::request::Parts;
 use axum::http::StatusCode;
 use serde::Deserialize;
+use std::sync::Arc;
 use std::time::Duration;
 
+pub mod dicomweb;
 pub mod dimse;
+pub mod filesystem;
+pub mod group;
+pub mod index;
+pub mod memory;
 
 #[cfg(feature = "s3")]
 pub mod s3;
@@ -41,57 +50,459 @@ where
 
 		let state = AppState::from_ref(state);
 
+		Self::new(&state, &aet)
+	}
+}
+
+impl ServiceProvider {
+	/// Creates the services for the AET.
+	///
+	/// # Errors
+	/// Returns an error response if the AET (or an AET that it references) is not configured.
+	pub fn new(state: &AppState, aet: &str) -> Result<Self, (StatusCode, String)> {
+		Self::resolve(state, aet, None, &mut Vec::new())
+	}
+
+	/// Creates the services for the AET, resolving the AETs of group and composite AETs recursively.
+	/// Only the given service is created, or all services if it is [`None`].
+	/// `parents` contains the group and composite AETs that are currently being resolved to detect cycles.
+	fn resolve(
+		state: &AppState,
+		aet: &str,
+		service: Option<Service>,
+		parents: &mut Vec<AE>,
+	) -> Result<Self, (StatusCode, String)> {
+		if parents.iter().any(|parent| parent == aet) {
+			return Err((
+				StatusCode::INTERNAL_SERVER_ERROR,
+				format!("AET {aet} references itself"),
+			));
+		}
+
 		let ae_config = state
 			.config
 			.aets
-			.into_iter()
+			.iter()
 			.find(|aet_config| aet_config.aet == aet)
 			.ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown AET {aet}")))?;
 
+		let wanted = |candidate: Service| service.is_none_or(|service| service == candidate);
+
 		// TODO: Use a singleton to avoid re-creating on every request.
-		let provider = match ae_config.backend {
-			BackendConfig::Dimse { .. } => {
-				use crate::backend::dimse::mwl::DimseMwlService;
-				use crate::backend::dimse::qido::DimseQidoService;
-				use crate::backend::dimse::stow::DimseStowService;
-				use crate::backend::dimse::wado::DimseWadoService;
+		let provider = match &ae_config.backend {
+			BackendConfig::Dimse { .. } => Self::dimse(state, ae_config, wanted),
+			#[cfg(feature = "s3")]
+			BackendConfig::S3(config) => {
+				use crate::backend::s3::qido::S3QidoService;
+				use crate::backend::s3::stow::S3StowService;
+				use crate::backend::s3::wado::S3WadoService;
 
-				let pool = state.pools.get(&ae_config.aet).expect("pool should exist");
+				let index = state.indexes.get(&ae_config.aet);
 
 				Self {
-					qido: Some(Box::new(DimseQidoService::new(
-						pool.to_owned(),
-						Duration::from_millis(ae_config.qido.timeout),
-					))),
-					wado: Some(Box::new(DimseWadoService::new(
-						pool.to_owned(),
-						state.mediator,
-						Duration::from_millis(ae_config.wado.timeout),
-						ae_config.wado.clone(),
-					))),
-					stow: Some(Box::new(DimseStowService::new(
-						pool.to_owned(),
-						Duration::from_millis(ae_config.stow.timeout),
-					))),
-					mwl: Some(Box::new(DimseMwlService::new(
-						pool.to_owned(),
-						Duration::from_millis(ae_config.mwl.timeout),
-					))),
+					qido: index.filter(|_| wanted(Service::Qido)).map(|index| {
+						Box::new(S3QidoService::new(Arc::clone(index))) as Box<dyn QidoService>
+					}),
+					wado: wanted(Service::Wado).then(|| {
+						Box::new(S3WadoService::new(config, index.cloned())) as Box<dyn WadoService>
+					}),
+					stow: wanted(Service::Stow).then(|| {
+						Box::new(S3StowService::new(config, &ae_config.stow, index.cloned()))
+							as Box<dyn StowService>
+					}),
+					mwl: None,
 				}
 			}
-			#[cfg(feature = "s3")]
-			BackendConfig::S3(config) => {
-				use crate::backend::s3::wado::S3WadoService;
+			BackendConfig::Filesystem(config) => {
+				use crate::backend::filesystem::qido::FilesystemQidoService;
+				use crate::backend::filesystem::stow::FilesystemStowService;
+				use crate::backend::filesystem::wado::FilesystemWadoService;
+
+				let index = state
+					.indexes
+					.get(&ae_config.aet)
+					.expect("index should exist");
 
 				Self {
-					qido: None,
-					wado: Some(Box::new(S3WadoService::new(&config))),
-					stow: None,
+					qido: wanted(Service::Qido).then(|| {
+						Box::new(FilesystemQidoService::new(Arc::clone(index)))
+							as Box<dyn QidoService>
+					}),
+					wado: wanted(Service::Wado).then(|| {
+						Box::new(FilesystemWadoService::new(config)) as Box<dyn WadoService>
+					}),
+					stow: wanted(Service::Stow).then(|| {
+						Box::new(FilesystemStowService::new(
+							config,
+							&ae_config.stow,
+							Arc::clone(index),
+						)) as Box<dyn StowService>
+					}),
 					mwl: None,
 				}
 			}
+			BackendConfig::DicomWeb(_) => Self::dicomweb(state, ae_config, wanted),
+			BackendConfig::Memory(_) => Self::memory(state, ae_config, wanted),
+			BackendConfig::Group(_) if !wanted(Service::Qido) => Self::disabled(),
+			BackendConfig::Group(config) => Self::group(state, ae_config, config, parents)?,
+			BackendConfig::Composite(config) => {
+				Self::composite(state, ae_config, config, service, parents)?
+			}
 		};
 
 		Ok(provider)
 	}
+
+	/// A provider without any services.
+	const fn disabled() -> Self {
+		Self {
+			qido: None,
+			wado: None,
+			stow: None,
+			mwl: None,
+		}
+	}
+
+	/// Creates the `wanted` services for an AET that keeps its instances in memory.
+	fn memory(
+		state: &AppState,
+		ae_config: &ApplicationEntityConfig,
+		wanted: impl Fn(Service) -> bool,
+	) -> Self {
+		use crate::backend::memory::mwl::MemoryMwlService;
+		use crate::backend::memory::qido::MemoryQidoService;
+		use crate::backend::memory::stow::MemoryStowService;
+		use crate::backend::memory::wado::MemoryWadoService;
+
+		let store = state
+			.stores
+			.get(&ae_config.aet)
+			.expect("store should exist");
+
+		Self {
+			qido: wanted(Service::Qido).then(|| {
+				Box::new(MemoryQidoService::new(Arc::clone(store))) as Box<dyn QidoService>
+			}),
+			wado: wanted(Service::Wado).then(|| {
+				Box::new(MemoryWadoService::new(Arc::clone(store))) as Box<dyn WadoService>
+			}),
+			stow: wanted(Service::Stow).then(|| {
+				Box::new(MemoryStowService::new(Arc::clone(store))) as Box<dyn StowService>
+			}),
+			mwl: wanted(Service::Mwl)
+				.then(|| Box::new(MemoryMwlService::new(Arc::clone(store))) as Box<dyn MwlService>),
+		}
+	}
+
+	/// Creates the `wanted` services for an AET that forwards requests to a DIMSE application entity.
+	fn dimse(
+		state: &AppState,
+		ae_config: &ApplicationEntityConfig,
+		wanted: impl Fn(Service) -> bool,
+	) -> Self {
+		use crate::backend::dimse::mwl::DimseMwlService;
+		use crate::backend::dimse::qido::DimseQidoService;
+		use crate::backend::dimse::stow::DimseStowService;
+		use crate::backend::dimse::wado::DimseWadoService;
+
+		let pool = state.pools.get(&ae_config.aet).expect("pool should exist");
+
+		Self {
+			qido: wanted(Service::Qido).then(|| {
+				Box::new(DimseQidoService::new(
+					pool.to_owned(),
+					Duration::from_millis(ae_config.qido.timeout),
+				)) as Box<dyn QidoService>
+			}),
+			wado: wanted(Service::Wado).then(|| {
+				Box::new(DimseWadoService::new(
+					pool.to_owned(),
+					state.mediator.clone(),
+					Duration::from_millis(ae_config.wado.timeout),
+					ae_config.wado.clone(),
+				)) as Box<dyn WadoService>
+			}),
+			stow: wanted(Service::Stow).then(|| {
+				Box::new(DimseStowService::new(
+					pool.to_owned(),
+					Duration::from_millis(ae_config.stow.timeout),
+					&ae_config.stow,
+				)) as Box<dyn StowService>
+			}),
+			mwl: wanted(Service::Mwl).then(|| {
+				Box::new(DimseMwlService::new(
+					pool.to_owned(),
+					Duration::from_millis(ae_config.mwl.timeout),
+				)) as Box<dyn MwlService>
+			}),
+		}
+	}
+
+	/// Creates the `wanted` services for an AET that forwards requests to a `DICOMweb` origin server.
+	fn dicomweb(
+		state: &AppState,
+		ae_config: &ApplicationEntityConfig,
+		wanted: impl Fn(Service) -> bool,
+	) -> Self {
+		use crate::backend::dicomweb::mwl::DicomWebMwlService;
+		use crate::backend::dicomweb::qido::DicomWebQidoService;
+		use crate::backend::dicomweb::stow::DicomWebStowService;
+		use crate::backend::dicomweb::wado::DicomWebWadoService;
+
+		let client = state
+			.clients
+			.get(&ae_config.aet)
+			.expect("client should exist");
+
+		Self {
+			qido: wanted(Service::Qido).then(|| {
+				Box::new(DicomWebQidoService::new(
+					client.clone(),
+					Duration::from_millis(ae_config.qido.timeout),
+				)) as Box<dyn QidoService>
+			}),
+			wado: wanted(Service::Wado).then(|| {
+				Box::new(DicomWebWadoService::new(
+					client.clone(),
+					Duration::from_millis(ae_config.wado.timeout),
+				)) as Box<dyn WadoService>
+			}),
+			stow: wanted(Service::Stow).then(|| {
+				Box::new(DicomWebStowService::new(
+					client.clone(),
+					Duration::from_millis(ae_config.stow.timeout),
+				)) as Box<dyn StowService>
+			}),
+			mwl: wanted(Service::Mwl).then(|| {
+				Box::new(DicomWebMwlService::new(
+					client.clone(),
+					Duration::from_millis(ae_config.mwl.timeout),
+				)) as Box<dyn MwlService>
+			}),
+		}
+	}
+
+	/// Creates the services for a group AET, which federate the services of its members.
+	fn group(
+		state: &AppState,
+		ae_config: &ApplicationEntityConfig,
+		config: &GroupConfig,
+		parents: &mut Vec<AE>,
+	) -> Result<Self, (StatusCode, String)> {
+		use crate::api::qido::timezone::TimezoneQidoService;
+		use crate::backend::group::qido::FederatedQidoService;
+
+		let aet = &ae_config.aet;
+		parents.push(aet.clone());
+		let mut members = Vec::with_capacity(config.members.len());
+		for member in &config.members {
+			let provider = Self::resolve(state, member, Some(Service::Qido), parents).map_err(
+				|(_, err)| {
+					(
+						StatusCode::INTERNAL_SERVER_ERROR,
+						format!("Invalid member {member} of group AET {aet}: {err}"),
+					)
+				},
+			)?;
+			let Some(mut qido) = provider.qido else {
+				continue;
+			};
+			// Each member searches in the timezone of the AET that provides its QIDO-RS service,
+			// unless it is a group with its own members.
+			let timezone = state
+				.config
+				.service_aet(member, Service::Qido)
+				.filter(|ae_config| !matches!(ae_config.backend, BackendConfig::Group(_)))
+				.and_then(|ae_config| ae_config.qido.timezone);
+			if let Some(timezone) = timezone {
+				qido = Box::new(TimezoneQidoService::new(qido, timezone));
+			}
+			members.push((member.clone(), qido));
+		}
+		parents.pop();
+
+		let base_url = state
+			.config
+			.server
+			.http
+			.public_url()
+			.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+		Ok(Self {
+			qido: Some(Box::new(FederatedQidoService::new(
+				members,
+				base_url,
+				state.config.server.aet.clone(),
+			))),
+			wado: None,
+			stow: None,
+			mwl: None,
+		})
+	}
+
+	/// Creates the services for a composite AET, which uses the service implementations of other AETs.
+	/// Only the given service is created, or all services if it is [`None`].
+	fn composite(
+		state: &AppState,
+		ae_config: &ApplicationEntityConfig,
+		config: &CompositeConfig,
+		service: Option<Service>,
+		parents: &mut Vec<AE>,
+	) -> Result<Self, (StatusCode, String)> {
+		let aet = &ae_config.aet;
+		parents.push(aet.clone());
+		let mut resolve = |candidate: Service| {
+			if service.is_some_and(|service| service != candidate) {
+				return Ok(Self::disabled());
+			}
+			config.services.get(candidate).map_or_else(
+				|| Ok(Self::disabled()),
+				|target| {
+					Self::resolve(state, target, Some(candidate), parents).map_err(|(_, err)| {
+						(
+							StatusCode::INTERNAL_SERVER_ERROR,
+							format!("Invalid service AET {target} of composite AET {aet}: {err}"),
+						)
+					})
+				},
+			)
+		};
+		let provider = Self {
+			qido: resolve(Service::Qido)?.qido,
+			wado: resolve(Service::Wado)?.wado,
+			stow: resolve(Service::Stow)?.stow,
+			mwl: resolve(Service::Mwl)?.mwl,
+		};
+		parents.pop();
+
+		Ok(provider)
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use crate::api::qido::SearchResultCache;
+	use crate::api::wado::InstanceCache;
+	use crate::backend::dicomweb::DicomWebClients;
+	use crate::backend::dimse::association::pool::AssociationPools;
+	use crate::backend::dimse::cmove::MoveMediator;
+	use crate::backend::index::InstanceIndexes;
+	use crate::backend::memory::MemoryStores;
+	use crate::config::AppConfig;
+	use config::{Config, File, FileFormat};
+	use dicom::core::{DataElement, VR};
+	use dicom::dictionary_std::{tags, uids};
+	use dicom::object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject};
+
+	/// A minimal Secondary Capture instance, as a test fixture for the backends.
+	pub fn instance(
+		study: &str,
+		series: &str,
+		instance: &str,
+	) -> FileDicomObject<InMemDicomObject> {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(
+				tags::SOP_CLASS_UID,
+				VR::UI,
+				uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+			),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, instance),
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, study),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, series),
+			DataElement::new(tags::MODALITY, VR::CS, "OT"),
+		])
+		.with_meta(FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN))
+		.unwrap()
+	}
+
+	fn state(yaml: &str) -> AppState {
+		let config: AppConfig = Config::builder()
+			.add_source(File::from_str(yaml, FileFormat::Yaml))
+			.build()
+			.unwrap()
+			.try_deserialize()
+			.unwrap();
+		AppState {
+			pools: AssociationPools::new(&config),
+			mediator: MoveMediator::new(&config),
+			search_results: SearchResultCache::new(config.server.http.search_cache_size),
+			instances: InstanceCache::new(config.server.http.instance_cache_size),
+			indexes: InstanceIndexes::new(&config).unwrap(),
+			clients: DicomWebClients::new(&config).unwrap(),
+			stores: MemoryStores::new(&config).unwrap(),
+			config,
+		}
+	}
+
+	#[test]
+	fn composes_services_of_other_aets() {
+		let state = state(
+			"
+            aets:
+              - aet: INDEX
+                backend: MEMORY
+              - aet: ARCHIVE
+                backend: MEMORY
+              - aet: ROUTED
+                backend: COMPOSITE
+                services:
+                  qido-rs: INDEX
+                  stow-rs: ARCHIVE
+            ",
+		);
+
+		let provider = ServiceProvider::new(&state, "ROUTED").unwrap();
+		assert!(provider.qido.is_some());
+		assert!(provider.wado.is_none());
+		assert!(provider.stow.is_some());
+		assert!(provider.mwl.is_none());
+
+		let Err((status, _)) = ServiceProvider::new(&state, "UNKNOWN") else {
+			panic!("unknown AET should be rejected");
+		};
+		assert_eq!(status, StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn creates_only_the_requested_service() {
+		let state = state(
+			"
+            aets:
+              - aet: INDEX
+                backend: MEMORY
+            ",
+		);
+
+		let provider =
+			ServiceProvider::resolve(&state, "INDEX", Some(Service::Qido), &mut Vec::new())
+				.unwrap();
+		assert!(provider.qido.is_some());
+		assert!(provider.wado.is_none());
+		assert!(provider.stow.is_none());
+		assert!(provider.mwl.is_none());
+	}
+
+	#[test]
+	fn rejects_cyclic_references() {
+		let state = state(
+			"
+            aets:
+              - aet: A
+                backend: COMPOSITE
+                services:
+                  qido-rs: B
+              - aet: B
+                backend: GROUP
+                members:
+                  - A
+            ",
+		);
+
+		let Err((status, message)) = ServiceProvider::new(&state, "A") else {
+			panic!("cyclic references should be rejected");
+		};
+		assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+		assert!(message.ends_with("AET A references itself"), "{message}");
+	}
 }
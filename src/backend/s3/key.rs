@@ -0,0 +1,244 @@
+//! Object keys of instances, as configured by `key-template`.
+
+use crate::api::wado::ResourceQuery;
+use crate::config::{KeySegment, KeyTemplate};
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use dicom::object::InMemDicomObject;
+use std::collections::HashMap;
+
+/// Attribute values that are used in object keys.
+pub type KeyValues = HashMap<Tag, String>;
+
+impl KeyTemplate {
+	/// The attributes that are used in the template.
+	pub fn attributes(&self) -> impl Iterator<Item = Tag> + '_ {
+		self.0.iter().filter_map(|segment| match segment {
+			KeySegment::Attribute(tag) => Some(*tag),
+			KeySegment::Literal(_) => None,
+		})
+	}
+
+	/// The key of the instance. Returns `None` if one of its UIDs is missing.
+	///
+	/// Other attributes may be missing or empty.
+	pub fn render(&self, object: &InMemDicomObject) -> Option<String> {
+		let mut key = String::new();
+		for segment in &self.0 {
+			match segment {
+				KeySegment::Literal(literal) => key.push_str(literal),
+				KeySegment::Attribute(tag) => {
+					let value = value(object, *tag);
+					if value.is_empty() && is_uid(*tag) {
+						return None;
+					}
+					key.push_str(&value);
+				}
+			}
+		}
+		Some(key)
+	}
+
+	/// The values of the attributes in the template that are identical for all instances.
+	///
+	/// This is used to find the instances of a resource if the template contains attributes that
+	/// are not part of its URL, e.g. the Patient ID of a study.
+	pub fn common_values(&self, objects: &[InMemDicomObject]) -> KeyValues {
+		let mut values = KeyValues::new();
+		for tag in self.attributes() {
+			let mut objects = objects.iter().map(|object| value(object, tag));
+			let Some(first) = objects.next() else {
+				break;
+			};
+			if objects.all(|value| value == first) {
+				values.insert(tag, first);
+			}
+		}
+		values
+	}
+
+	/// The longest key prefix that can be rendered from the known attribute values.
+	pub fn prefix(&self, values: &KeyValues) -> String {
+		let mut prefix = String::new();
+		for segment in &self.0 {
+			match segment {
+				KeySegment::Literal(literal) => prefix.push_str(literal),
+				KeySegment::Attribute(tag) => match values.get(tag) {
+					Some(value) => prefix.push_str(value),
+					None => break,
+				},
+			}
+		}
+		prefix
+	}
+
+	/// Extracts the attribute values from the key.
+	/// Returns `None` if the key does not match the template.
+	pub fn parse(&self, key: &str) -> Option<KeyValues> {
+		let mut values = KeyValues::new();
+		let mut rest = key;
+		for (index, segment) in self.0.iter().enumerate() {
+			match segment {
+				KeySegment::Literal(literal) => rest = rest.strip_prefix(literal.as_str())?,
+				KeySegment::Attribute(tag) => {
+					let end = match self.0.get(index + 1) {
+						Some(KeySegment::Literal(next)) => rest.find(next.as_str())?,
+						_ => rest.len(),
+					};
+					let value = &rest[..end];
+					if value.contains('/') {
+						return None;
+					}
+					if values
+						.insert(*tag, value.to_owned())
+						.is_some_and(|previous| previous != value)
+					{
+						return None;
+					}
+					rest = &rest[end..];
+				}
+			}
+		}
+		rest.is_empty().then_some(values)
+	}
+}
+
+impl ResourceQuery {
+	/// The attribute values of the resource that are known from its URL.
+	pub fn key_values(&self) -> KeyValues {
+		let mut values =
+			KeyValues::from([(tags::STUDY_INSTANCE_UID, self.study_instance_uid.clone())]);
+		if let Some(series) = &self.series_instance_uid {
+			values.insert(tags::SERIES_INSTANCE_UID, series.clone());
+		}
+		if let Some(instance) = &self.sop_instance_uid {
+			values.insert(tags::SOP_INSTANCE_UID, instance.clone());
+		}
+		values
+	}
+}
+
+/// Whether the parsed key contains the expected values.
+pub fn matches(parsed: &KeyValues, expected: &KeyValues) -> bool {
+	expected
+		.iter()
+		.all(|(tag, value)| parsed.get(tag).is_none_or(|parsed| parsed == value))
+}
+
+/// The value of the attribute as used in keys.
+/// A `/` is replaced by `_`, so that the value does not change the layout.
+fn value(object: &InMemDicomObject, tag: Tag) -> String {
+	object
+		.get(tag)
+		.and_then(|element| element.to_str().ok())
+		.map(|value| value.trim_end_matches(['\0', ' ']).replace('/', "_"))
+		.unwrap_or_default()
+}
+
+const fn is_uid(tag: Tag) -> bool {
+	matches!(
+		tag,
+		tags::STUDY_INSTANCE_UID | tags::SERIES_INSTANCE_UID | tags::SOP_INSTANCE_UID
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dicom::core::{DataElement, VR};
+
+	fn template(template: &str) -> KeyTemplate {
+		KeyTemplate::try_from(String::from(template)).unwrap()
+	}
+
+	fn instance() -> InMemDicomObject {
+		InMemDicomObject::from_element_iter([
+			DataElement::new(tags::PATIENT_ID, VR::LO, "A/1"),
+			DataElement::new(tags::STUDY_DATE, VR::DA, "20240115"),
+			DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3\0"),
+			DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4"),
+			DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+		])
+	}
+
+	#[test]
+	fn rejects_invalid_templates() {
+		for invalid in [
+			"{StudyInstanceUID}/{SeriesInstanceUID}",
+			"{StudyInstanceUID}/{SOPInstanceUID}",
+			"{StudyInstanceUID}{SOPInstanceUID}",
+			"{Unknown}/{SOPInstanceUID}",
+			"{SOPInstanceUID",
+		] {
+			assert!(
+				KeyTemplate::try_from(String::from(invalid)).is_err(),
+				"{invalid}"
+			);
+		}
+	}
+
+	#[test]
+	fn renders_and_parses_keys() {
+		let default = KeyTemplate::default();
+		let key = default.render(&instance()).unwrap();
+		assert_eq!(key, "1.2.3/1.2.3.4/1.2.3.4.5.dcm");
+		assert_eq!(
+			default.parse(&key).unwrap()[&tags::SERIES_INSTANCE_UID],
+			"1.2.3.4"
+		);
+		assert_eq!(default.parse("1.2.3/1.2.3.4/1.2.3.4.5.json"), None);
+
+		let archive = template(
+			"{PatientID}/{StudyDate}/{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}",
+		);
+		let key = archive.render(&instance()).unwrap();
+		assert_eq!(key, "A_1/20240115/1.2.3/1.2.3.4/1.2.3.4.5");
+		let values = archive.parse(&key).unwrap();
+		assert_eq!(values[&tags::PATIENT_ID], "A_1");
+		assert_eq!(values[&tags::SOP_INSTANCE_UID], "1.2.3.4.5");
+		assert_eq!(archive.parse("A_1/20240115/1.2.3"), None);
+
+		let mut without_uid = instance();
+		without_uid.remove_element(tags::SERIES_INSTANCE_UID);
+		assert_eq!(archive.render(&without_uid), None);
+	}
+
+	#[test]
+	fn renders_prefix_of_known_values() {
+		let archive =
+			template("{PatientID}/{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}");
+		let query = ResourceQuery {
+			aet: String::new(),
+			study_instance_uid: String::from("1.2.3"),
+			series_instance_uid: None,
+			sop_instance_uid: None,
+		};
+		let mut values = query.key_values();
+		assert_eq!(archive.prefix(&values), "");
+
+		values.insert(tags::PATIENT_ID, String::from("A_1"));
+		assert_eq!(archive.prefix(&values), "A_1/1.2.3/");
+		assert_eq!(KeyTemplate::default().prefix(&values), "1.2.3/");
+	}
+
+	#[test]
+	fn finds_common_values() {
+		let archive = template(
+			"{PatientID}/{StudyDate}/{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}",
+		);
+		let mut other = instance();
+		other.put(DataElement::new(tags::STUDY_DATE, VR::DA, "20240116"));
+		other.put(DataElement::new(
+			tags::SOP_INSTANCE_UID,
+			VR::UI,
+			"1.2.3.4.6",
+		));
+
+		let values = archive.common_values(&[instance(), other]);
+		assert_eq!(values[&tags::PATIENT_ID], "A_1");
+		assert_eq!(values[&tags::SERIES_INSTANCE_UID], "1.2.3.4");
+		assert!(!values.contains_key(&tags::STUDY_DATE));
+		assert!(!values.contains_key(&tags::SOP_INSTANCE_UID));
+		assert!(archive.common_values(&[]).is_empty());
+	}
+}
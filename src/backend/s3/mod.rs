@@ -1,11 +1,124 @@
+pub mod key;
+pub mod qido;
+pub mod stow;
 pub mod wado;
 
-use crate::api::wado::ResourceQuery;
+use crate::api::wado::RetrieveError;
+use crate::config::{S3Config, S3EndpointStyle};
+use aws_config::retry::RetryConfig;
+use aws_config::stalled_stream_protection::StalledStreamProtectionConfig;
+use aws_config::timeout::TimeoutConfig;
+use aws_config::{AppName, Region};
 use aws_sdk_s3 as s3;
-use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::BehaviorVersion;
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
 use aws_sdk_s3::operation::list_objects_v2::{ListObjectsV2Error, ListObjectsV2Output};
 use aws_sdk_s3::types::Object;
-use tracing::error;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+/// The maximum number of attempts of a request, including the first one.
+/// Transient errors (e.g. timeouts or throttling) are retried with exponential backoff.
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// Creates a S3 client for the configured endpoint.
+pub fn create_client(config: &S3Config) -> s3::Client {
+	info!("Using S3 endpoint {}", &config.endpoint);
+	let mut builder = s3::config::Builder::new()
+		.endpoint_url(&config.endpoint)
+		.region(config.region.clone().map(Region::new))
+		.behavior_version(BehaviorVersion::latest())
+		.force_path_style(matches!(config.endpoint_style, S3EndpointStyle::Path))
+		.retry_config(RetryConfig::adaptive().with_max_attempts(MAX_ATTEMPTS))
+		// Causes issues with long-running requests and high concurrency.
+		// It's okay to stall for some time.
+		// TODO: Maybe make grace_period configurable instead?
+		.stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+		.timeout_config(
+			TimeoutConfig::builder()
+				.connect_timeout(Duration::from_secs(5))
+				.read_timeout(Duration::from_secs(20))
+				.operation_timeout(Duration::from_mins(1))
+				.build(),
+		)
+		.app_name(AppName::new("DICOM-RST").expect("valid app name"));
+
+	if let Some(credentials) = &config.credentials {
+		if let Ok(resolved_secrets) = credentials.resolve() {
+			builder = builder.credentials_provider(resolved_secrets);
+		} else {
+			warn!("Failed to resolve credentials. Check your environment variables.");
+		}
+	}
+
+	s3::Client::from_conf(builder.build())
+}
+
+/// Errors of S3 operations, classified by how they should be handled.
+#[derive(Debug, Error)]
+pub enum S3Error {
+	/// The object (or the bucket) does not exist.
+	#[error("{key} does not exist")]
+	NotFound { key: String },
+	/// A transient error, e.g. a timeout or throttling, that persisted after the retries of the client.
+	#[error("S3 is unavailable: {message}")]
+	Unavailable { message: String },
+	/// The request was rejected by S3, e.g. due to missing permissions.
+	#[error("S3 request failed: {message}")]
+	Request { message: String },
+	/// The object is not a valid DICOM file.
+	#[error("{key} is corrupt: {message}")]
+	Corrupt { key: String, message: String },
+}
+
+impl S3Error {
+	/// Classifies the error of a S3 operation on the key by its HTTP status.
+	pub fn from_sdk<E>(err: &SdkError<E, HttpResponse>, key: &str) -> Self
+	where
+		E: std::error::Error + 'static,
+	{
+		let status = err
+			.raw_response()
+			.map(|response| response.status().as_u16());
+		let message = DisplayErrorContext(err).to_string();
+		match (err, status) {
+			(_, Some(404)) => Self::NotFound {
+				key: key.to_owned(),
+			},
+			(SdkError::TimeoutError(_) | SdkError::DispatchFailure(_), _)
+			| (_, Some(429 | 500 | 502 | 503 | 504)) => Self::Unavailable { message },
+			_ => Self::Request { message },
+		}
+	}
+}
+
+impl From<S3Error> for RetrieveError {
+	fn from(err: S3Error) -> Self {
+		match err {
+			S3Error::NotFound { .. } => Self::NotFound {
+				message: err.to_string(),
+			},
+			S3Error::Unavailable { .. } => Self::Unavailable { source: err.into() },
+			S3Error::Request { .. } | S3Error::Corrupt { .. } => {
+				Self::BadGateway { source: err.into() }
+			}
+		}
+	}
+}
+
+/// The key of the metadata sidecar of an instance, its key with `.json` instead of `.dcm`.
+pub fn sidecar_key(key: &str) -> String {
+	format!("{}.json", key.strip_suffix(".dcm").unwrap_or(key))
+}
+
+/// Whether the key is the key of a metadata sidecar, which is stored next to the instances.
+pub fn is_sidecar_key(key: &str) -> bool {
+	std::path::Path::new(key)
+		.extension()
+		.is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
 
 pub trait S3ClientExt {
 	/// Recursively collects objects
@@ -22,31 +135,6 @@ impl S3ClientExt for s3::Client {
 	}
 }
 
-impl ResourceQuery {
-	pub fn to_s3_prefix(&self) -> String {
-		let mut prefix = String::new();
-
-		match (
-			&self.study_instance_uid,
-			&self.series_instance_uid,
-			&self.sop_instance_uid,
-		) {
-			(study, Some(series), Some(instance)) => {
-				prefix.push_str(&format!("{study}/{series}/{instance}"));
-			}
-			(study, Some(series), None) => {
-				prefix.push_str(&format!("{study}/{series}/"));
-			}
-			(study, None, None) => {
-				prefix.push_str(&format!("{study}/"));
-			}
-			_ => {}
-		}
-
-		prefix
-	}
-}
-
 pub struct CollectObjectsFluentBuilder<'a> {
 	handle: &'a s3::Client,
 	bucket: String,
@@ -84,16 +172,7 @@ impl<'a> CollectObjectsFluentBuilder<'a> {
 			match self.list_next(continuation_token).await {
 				Ok(response) => {
 					if let Some(response_objects) = response.contents {
-						for response_object in response_objects {
-							// skip non-dicom files
-							if response_object
-								.key
-								.as_ref()
-								.is_some_and(|k| k.ends_with(".dcm"))
-							{
-								objects.push(response_object);
-							}
-						}
+						objects.extend(response_objects);
 					}
 					if response.is_truncated.unwrap_or(false) {
 						continuation_token = response.next_continuation_token;
@@ -103,7 +182,7 @@ impl<'a> CollectObjectsFluentBuilder<'a> {
 				}
 				Err(err) => {
 					error!("{err:?}");
-					return Err(anyhow::Error::new(err));
+					return Err(S3Error::from_sdk(&err, &self.bucket));
 				}
 			}
 		}
@@ -112,4 +191,69 @@ impl<'a> CollectObjectsFluentBuilder<'a> {
 	}
 }
 
-type CollectObjectError = anyhow::Error;
+type CollectObjectError = S3Error;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use aws_sdk_s3::operation::get_object::GetObjectError;
+	use aws_sdk_s3::primitives::SdkBody;
+	use aws_sdk_s3::types::error::NoSuchKey;
+
+	fn service_error(status: u16) -> SdkError<GetObjectError, HttpResponse> {
+		SdkError::service_error(
+			GetObjectError::NoSuchKey(NoSuchKey::builder().build()),
+			HttpResponse::new(status.try_into().unwrap(), SdkBody::empty()),
+		)
+	}
+
+	#[test]
+	fn classifies_errors() {
+		assert!(matches!(
+			S3Error::from_sdk(&service_error(404), "key"),
+			S3Error::NotFound { key } if key == "key"
+		));
+		assert!(matches!(
+			S3Error::from_sdk(&service_error(503), "key"),
+			S3Error::Unavailable { .. }
+		));
+		assert!(matches!(
+			S3Error::from_sdk(
+				&SdkError::<GetObjectError, HttpResponse>::timeout_error("timeout"),
+				"key"
+			),
+			S3Error::Unavailable { .. }
+		));
+		assert!(matches!(
+			S3Error::from_sdk(&service_error(403), "key"),
+			S3Error::Request { .. }
+		));
+	}
+
+	#[test]
+	fn maps_errors_to_status() {
+		use axum::http::StatusCode;
+		use axum::response::IntoResponse;
+
+		let status = |err: S3Error| RetrieveError::from(err).into_response().status();
+		assert_eq!(
+			status(S3Error::NotFound {
+				key: String::from("key")
+			}),
+			StatusCode::NOT_FOUND
+		);
+		assert_eq!(
+			status(S3Error::Unavailable {
+				message: String::from("timeout")
+			}),
+			StatusCode::SERVICE_UNAVAILABLE
+		);
+		assert_eq!(
+			status(S3Error::Corrupt {
+				key: String::from("key"),
+				message: String::from("invalid preamble")
+			}),
+			StatusCode::BAD_GATEWAY
+		);
+	}
+}
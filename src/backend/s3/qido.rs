@@ -0,0 +1,100 @@
+use crate::api::qido::{QidoService, SearchRequest, SearchResponse};
+use crate::backend::index::{InstanceIndex, InstanceIndexes};
+use crate::config::{AppConfig, BackendConfig, S3Config};
+use async_trait::async_trait;
+use dicom::dictionary_std::tags;
+use futures::StreamExt;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::wado::S3WadoService;
+use super::{create_client, is_sidecar_key, S3ClientExt};
+
+/// Answers QIDO-RS requests from the index of the instances in the bucket.
+/// S3 has no means to search the objects by their attributes.
+pub struct S3QidoService {
+	index: Arc<InstanceIndex>,
+}
+
+impl S3QidoService {
+	pub const fn new(index: Arc<InstanceIndex>) -> Self {
+		Self { index }
+	}
+}
+
+#[async_trait]
+impl QidoService for S3QidoService {
+	async fn search(&self, request: SearchRequest) -> SearchResponse {
+		let matches = self.index.search(&request.query, &request.parameters);
+		SearchResponse::new(futures::stream::iter(matches.into_iter().map(Ok)).boxed())
+	}
+}
+
+/// Scans the buckets of all S3 AETs whose index should be populated on startup.
+/// The scans run in the background, so that the server is available in the meantime.
+pub fn spawn_scans(config: &AppConfig, indexes: &InstanceIndexes) {
+	for ae_config in &config.aets {
+		let BackendConfig::S3(s3_config) = &ae_config.backend else {
+			continue;
+		};
+		let (Some(index_config), Some(index)) = (&s3_config.index, indexes.get(&ae_config.aet))
+		else {
+			continue;
+		};
+		if !index_config.scan {
+			continue;
+		}
+
+		let aet = ae_config.aet.clone();
+		let s3_config = s3_config.clone();
+		let index = Arc::clone(index);
+		tokio::spawn(async move {
+			match scan(&s3_config, &index).await {
+				Ok(count) => info!(aet, count, "Indexed instances in S3 bucket"),
+				Err(err) => error!(aet, "Failed to scan S3 bucket: {err:#}"),
+			}
+		});
+	}
+}
+
+/// Adds all instances in the bucket that are not indexed yet and returns their number.
+///
+/// Instances are only read up to the pixel data with range requests, as the pixel data is not indexed.
+async fn scan(config: &S3Config, index: &InstanceIndex) -> anyhow::Result<usize> {
+	let s3 = create_client(config);
+	let objects = s3.collect_objects().bucket(&config.bucket).send().await?;
+
+	// The UIDs in the keys tell which instances are indexed already.
+	let missing = objects
+		.into_iter()
+		.filter_map(|object| object.key)
+		.filter(|key| !is_sidecar_key(key))
+		.filter(|key| {
+			config.key_template.parse(key).is_some_and(|values| {
+				let uid = |tag| values.get(&tag).map_or("", String::as_str);
+				!index.contains(
+					uid(tags::STUDY_INSTANCE_UID),
+					uid(tags::SERIES_INSTANCE_UID),
+					uid(tags::SOP_INSTANCE_UID),
+				)
+			})
+		});
+
+	let wado = &S3WadoService::new(config, None);
+	let indexed = futures::stream::iter(missing)
+		.map(|key| async move {
+			let header = wado.read_header(&key).await?;
+			anyhow::Ok(index.insert(&header.file))
+		})
+		.buffer_unordered(config.concurrency.max(1))
+		.filter_map(|result| async move {
+			result
+				.inspect_err(|err| warn!("Skipped object that could not be indexed: {err:#}"))
+				.ok()
+				.filter(|inserted| *inserted)
+		})
+		.count()
+		.await;
+
+	Ok(indexed)
+}
@@ -0,0 +1,259 @@
+use crate::api::stow::{
+	InstanceStore, StoreOutcome, FAILURE_CANNOT_UNDERSTAND, FAILURE_PROCESSING,
+};
+use crate::api::wado::{remove_bulkdata, BulkdataRemovalOptions};
+use crate::backend::index::InstanceIndex;
+use crate::config::{KeyTemplate, S3Config, StowConfig};
+use anyhow::Context;
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::Bytes;
+use dicom::core::header::{HasLength, Header};
+use dicom::core::{DataElement, PrimitiveValue};
+use dicom::dictionary_std::tags;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use super::{create_client, sidecar_key};
+
+const CONTENT_TYPE: &str = "application/dicom";
+const SIDECAR_CONTENT_TYPE: &str = "application/dicom+json";
+
+/// Stores instances under the keys of the configured `key-template`, the layout that is read by [`super::wado::S3WadoService`].
+/// Existing objects are overwritten, so storing the same instance again is idempotent.
+pub struct S3StowService {
+	s3: Arc<aws_sdk_s3::Client>,
+	bucket: String,
+	part_size: usize,
+	concurrency: usize,
+	index: Option<Arc<InstanceIndex>>,
+	metadata_sidecars: bool,
+	key_template: KeyTemplate,
+}
+
+impl S3StowService {
+	pub fn new(
+		config: &S3Config,
+		stow_config: &StowConfig,
+		index: Option<Arc<InstanceIndex>>,
+	) -> Self {
+		let s3 = create_client(config);
+
+		Self {
+			s3: Arc::new(s3),
+			bucket: config.bucket.clone(),
+			part_size: config.part_size.max(S3Config::MIN_PART_SIZE),
+			concurrency: stow_config.concurrency,
+			index,
+			metadata_sidecars: config.metadata_sidecars,
+			key_template: config.key_template.clone(),
+		}
+	}
+
+	async fn put(&self, key: &str, data: Bytes) -> anyhow::Result<()> {
+		if data.len() > self.part_size {
+			return self.put_multipart(key, data).await;
+		}
+
+		self.s3
+			.put_object()
+			.bucket(&self.bucket)
+			.key(key)
+			.content_type(CONTENT_TYPE)
+			.body(ByteStream::from(data))
+			.send()
+			.await?;
+		Ok(())
+	}
+
+	/// Uploads the instance in parts of `part-size` bytes.
+	/// The upload is aborted if a part cannot be uploaded, so that no incomplete parts are kept.
+	async fn put_multipart(&self, key: &str, data: Bytes) -> anyhow::Result<()> {
+		let upload = self
+			.s3
+			.create_multipart_upload()
+			.bucket(&self.bucket)
+			.key(key)
+			.content_type(CONTENT_TYPE)
+			.send()
+			.await?;
+		let upload_id = upload
+			.upload_id()
+			.context("Multipart upload has no upload id")?;
+
+		let result = self.upload_parts(key, upload_id, data).await;
+		if result.is_err() {
+			if let Err(err) = self
+				.s3
+				.abort_multipart_upload()
+				.bucket(&self.bucket)
+				.key(key)
+				.upload_id(upload_id)
+				.send()
+				.await
+			{
+				warn!(key, "Failed to abort multipart upload: {err}");
+			}
+		}
+		result
+	}
+
+	async fn upload_parts(&self, key: &str, upload_id: &str, data: Bytes) -> anyhow::Result<()> {
+		let mut parts = Vec::new();
+		for (index, offset) in (0..data.len()).step_by(self.part_size).enumerate() {
+			let part_number = i32::try_from(index + 1)?;
+			let end = data.len().min(offset + self.part_size);
+			let part = self
+				.s3
+				.upload_part()
+				.bucket(&self.bucket)
+				.key(key)
+				.upload_id(upload_id)
+				.part_number(part_number)
+				.body(ByteStream::from(data.slice(offset..end)))
+				.send()
+				.await?;
+			parts.push(
+				CompletedPart::builder()
+					.set_e_tag(part.e_tag)
+					.part_number(part_number)
+					.build(),
+			);
+		}
+
+		self.s3
+			.complete_multipart_upload()
+			.bucket(&self.bucket)
+			.key(key)
+			.upload_id(upload_id)
+			.multipart_upload(
+				CompletedMultipartUpload::builder()
+					.set_parts(Some(parts))
+					.build(),
+			)
+			.send()
+			.await?;
+		Ok(())
+	}
+
+	/// Writes the metadata of the instance to its sidecar object.
+	/// If this fails, an existing sidecar is removed, so that it cannot contain outdated metadata.
+	async fn put_sidecar(&self, key: &str, instance: &FileDicomObject<InMemDicomObject>) {
+		let key = sidecar_key(key);
+		let result = match dicom_json::to_vec(&sidecar(instance)) {
+			Ok(json) => self
+				.s3
+				.put_object()
+				.bucket(&self.bucket)
+				.key(&key)
+				.content_type(SIDECAR_CONTENT_TYPE)
+				.body(ByteStream::from(json))
+				.send()
+				.await
+				.map(|_| ())
+				.map_err(anyhow::Error::from),
+			Err(err) => Err(err.into()),
+		};
+		if let Err(err) = result {
+			warn!(key, "Failed to store metadata sidecar: {err:#}");
+			if let Err(err) = self
+				.s3
+				.delete_object()
+				.bucket(&self.bucket)
+				.key(&key)
+				.send()
+				.await
+			{
+				warn!(key, "Failed to remove metadata sidecar: {err}");
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl InstanceStore for S3StowService {
+	fn concurrency(&self) -> usize {
+		self.concurrency
+	}
+
+	async fn store_instance(&self, instance: FileDicomObject<InMemDicomObject>) -> StoreOutcome {
+		let sop_instance_uid = instance.meta().media_storage_sop_instance_uid();
+		let Some(key) = self.key_template.render(&instance) else {
+			warn!(
+				sop_instance_uid,
+				"Instance has no StudyInstanceUID, SeriesInstanceUID or SOPInstanceUID"
+			);
+			return StoreOutcome::Failed(FAILURE_CANNOT_UNDERSTAND);
+		};
+
+		let mut data = Vec::new();
+		if let Err(err) = instance.write_all(&mut data) {
+			warn!(sop_instance_uid, "Failed to write instance: {err}");
+			return StoreOutcome::Failed(FAILURE_PROCESSING);
+		}
+
+		match self.put(&key, Bytes::from(data)).await {
+			Ok(()) => {
+				info!(sop_instance_uid, key, "Successfully stored instance");
+				if self.metadata_sidecars {
+					self.put_sidecar(&key, &instance).await;
+				}
+				if let Some(index) = &self.index {
+					index.insert(&instance);
+				}
+				StoreOutcome::Stored
+			}
+			Err(err) => {
+				warn!(sop_instance_uid, key, "Failed to store instance: {err:#}");
+				StoreOutcome::Failed(FAILURE_PROCESSING)
+			}
+		}
+	}
+}
+
+/// The metadata of the instance that is written to its sidecar.
+/// Bulk data is removed, but the pixel data is kept without a value, so that the metadata read
+/// from the sidecar references the pixel data like the metadata read from the instance.
+pub(super) fn sidecar(instance: &InMemDicomObject) -> InMemDicomObject {
+	let mut metadata = InMemDicomObject::from_element_iter(
+		instance
+			.iter()
+			.filter(|element| element.tag() != tags::PIXEL_DATA)
+			.cloned(),
+	);
+	remove_bulkdata(&mut metadata, &BulkdataRemovalOptions::default());
+	if let Some(pixel_data) = instance
+		.get(tags::PIXEL_DATA)
+		.filter(|pixel_data| !pixel_data.is_empty())
+	{
+		metadata.put(DataElement::new(
+			tags::PIXEL_DATA,
+			pixel_data.vr(),
+			PrimitiveValue::Empty,
+		));
+	}
+	metadata
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::tests::instance;
+
+	#[test]
+	fn key_matches_wado_layout() {
+		let instance = instance("1.2.3", "1.2.3.4", "1.2.3.4.5");
+
+		let template = KeyTemplate::default();
+		assert_eq!(
+			template.render(&instance).as_deref(),
+			Some("1.2.3/1.2.3.4/1.2.3.4.5.dcm")
+		);
+
+		let mut instance = instance;
+		instance.remove_element(tags::SERIES_INSTANCE_UID);
+		assert_eq!(template.render(&instance), None);
+	}
+}
@@ -1,70 +1,360 @@
 use crate::api::wado::{
-	InstanceResponse, MetadataRequest, RenderedResponse, RenderingRequest, RetrieveError,
-	RetrieveInstanceRequest, WadoService,
+	BulkDataReference, BulkDataRequest, BulkDataResponse, InstanceMetadata, InstanceResponse,
+	MetadataRequest, MetadataResponse, RenderedResponse, RenderingRequest, ResourceQuery,
+	RetrieveError, RetrieveInstanceRequest, WadoService,
 };
 use crate::backend::dimse::cmove::movescu::MoveError;
-use crate::config::{S3Config, S3EndpointStyle};
+use crate::backend::index::InstanceIndex;
+use crate::config::{KeyTemplate, S3Config};
+use crate::rendering::render_instances;
 use async_trait::async_trait;
-use aws_config::retry::RetryConfig;
-use aws_config::stalled_stream_protection::StalledStreamProtectionConfig;
-use aws_config::timeout::TimeoutConfig;
-use aws_config::{AppName, Region};
-use aws_sdk_s3::config::BehaviorVersion;
-use bytes::Buf;
-use dicom::object::FileDicomObject;
-use futures::StreamExt;
+use aws_sdk_s3::presigning::PresigningConfig;
+use bytes::{Buf, Bytes};
+use dicom::core::ops::AttributeSelector;
+use dicom::core::VR;
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::{Codec, TransferSyntaxIndex};
+use dicom::object::meta::FileMetaTable;
+use dicom::object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject, OpenFileOptions};
+use dicom::parser::dataset::{DataSetReader, DataToken};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use futures::stream::BoxStream;
+use futures::{Future, StreamExt};
+use std::cell::Cell;
+use std::io::Read;
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::log::trace;
 use tracing::{info, warn};
 
-use super::S3ClientExt;
+use super::{create_client, is_sidecar_key, key, sidecar_key, S3ClientExt, S3Error};
 
+/// Instances are read in ranges of this size (growing by a factor of four) until their pixel data
+/// is reached, so that the pixel data does not have to be downloaded to read the metadata.
+const HEADER_RANGE: usize = 64 * 1024;
+
+/// Reading the body of an object is retried this many times after errors.
+/// Failed requests are already retried by the S3 client, see [`super::MAX_ATTEMPTS`].
+const MAX_RETRIES: u32 = 3;
+
+/// The delay before the first retry, which is doubled for every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+type Instance = FileDicomObject<InMemDicomObject>;
+
+/// An instance that was read up to its pixel data.
+pub(super) struct Header {
+	pub(super) file: Instance,
+	pixel_data: PixelData,
+}
+
+/// The pixel data of an instance, as located by the parser.
+/// Only the top-level pixel data is considered, not the pixel data of nested data sets (e.g. icons).
+#[derive(Debug, PartialEq, Eq)]
+enum PixelData {
+	/// Native pixel data at the position in the object.
+	Native(ValuePosition),
+	/// Encapsulated pixel data, which consists of fragments and has no single value.
+	Encapsulated,
+	/// The data set is compressed, so the pixel data cannot be located without decompressing it.
+	Unknown,
+	Missing,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ValuePosition {
+	vr: VR,
+	offset: usize,
+	length: usize,
+}
+
+impl ValuePosition {
+	/// The HTTP range of the value.
+	fn range(&self) -> String {
+		format!("bytes={}-{}", self.offset, self.offset + self.length - 1)
+	}
+}
+
+#[derive(Clone)]
 pub struct S3WadoService {
 	s3: Arc<aws_sdk_s3::Client>,
 	concurrency: usize,
 	bucket: String,
+	metadata_sidecars: bool,
+	key_template: KeyTemplate,
+	index: Option<Arc<InstanceIndex>>,
+	presigned_urls_expire_in: Option<Duration>,
 }
 
 impl S3WadoService {
-	pub fn new(config: &S3Config) -> Self {
-		info!("Using S3 endpoint {}", &config.endpoint);
-		let mut builder = aws_sdk_s3::config::Builder::new()
-			.endpoint_url(&config.endpoint)
-			.region(config.region.clone().map(Region::new))
-			.behavior_version(BehaviorVersion::latest())
-			.force_path_style(matches!(config.endpoint_style, S3EndpointStyle::Path))
-			.retry_config(RetryConfig::adaptive())
-			// Causes issues with long-running requests and high concurrency.
-			// It's okay to stall for some time.
-			// TODO: Maybe make grace_period configurable instead?
-			.stalled_stream_protection(StalledStreamProtectionConfig::disabled())
-			.timeout_config(
-				TimeoutConfig::builder()
-					.connect_timeout(Duration::from_secs(5))
-					.read_timeout(Duration::from_secs(20))
-					.operation_timeout(Duration::from_secs(60))
-					.build(),
-			)
-			.app_name(AppName::new("DICOM-RST").expect("valid app name"));
-
-		if let Some(credentials) = &config.credentials {
-			if let Ok(resolved_secrets) = credentials.resolve() {
-				builder = builder.credentials_provider(resolved_secrets);
-			} else {
-				warn!("Failed to resolve credentials. Check your environment variables.");
-			}
-		}
-
-		let sdk_config = builder.build();
-		let s3 = aws_sdk_s3::Client::from_conf(sdk_config);
+	pub fn new(config: &S3Config, index: Option<Arc<InstanceIndex>>) -> Self {
+		let s3 = create_client(config);
 
 		Self {
 			s3: Arc::new(s3),
 			bucket: config.bucket.clone(),
 			concurrency: config.concurrency,
+			metadata_sidecars: config.metadata_sidecars,
+			key_template: config.key_template.clone(),
+			index,
+			presigned_urls_expire_in: config
+				.presigned_urls
+				.as_ref()
+				.map(|presigned_urls| Duration::from_secs(presigned_urls.expires_in)),
 		}
 	}
+
+	/// The keys of all instances of the resource, in lexicographical order.
+	///
+	/// The objects are listed below the longest key prefix that is known from the UIDs of the resource.
+	/// If the key template contains other attributes (e.g. `{PatientID}/{StudyInstanceUID}/...`),
+	/// their values are looked up in the index, if enabled. Otherwise, the listing starts at the first
+	/// unknown attribute and the keys are filtered by the UIDs they contain.
+	async fn instance_keys(&self, query: &ResourceQuery) -> Result<Vec<String>, RetrieveError> {
+		let mut values = query.key_values();
+		if let Some(index) = &self.index {
+			if self
+				.key_template
+				.attributes()
+				.any(|tag| !values.contains_key(&tag))
+			{
+				let instances = index.instances(
+					&query.study_instance_uid,
+					query.series_instance_uid.as_deref(),
+					query.sop_instance_uid.as_deref(),
+				);
+				for (tag, value) in self.key_template.common_values(&instances) {
+					values.entry(tag).or_insert(value);
+				}
+			}
+		}
+		let prefix = self.key_template.prefix(&values);
+		if prefix.is_empty() {
+			warn!(
+				bucket = self.bucket,
+				"Listing all objects, as the key prefix of the resource is unknown"
+			);
+		}
+
+		let objects = self
+			.s3
+			.collect_objects()
+			.bucket(&self.bucket)
+			.prefix(&prefix)
+			.send()
+			.await?;
+		info!("Found {} objects.", objects.len());
+
+		let mut keys: Vec<String> = objects
+			.into_iter()
+			.filter_map(|object| object.key)
+			.filter(|key| !is_sidecar_key(key))
+			.filter(|key| {
+				self.key_template
+					.parse(key)
+					.is_some_and(|parsed| key::matches(&parsed, &values))
+			})
+			.collect();
+		if keys.is_empty() {
+			return Err(RetrieveError::NotFound {
+				message: format!("No instances found below key prefix \"{prefix}\""),
+			});
+		}
+		keys.sort();
+		Ok(keys)
+	}
+
+	/// Reads the object (or the HTTP range of it).
+	/// Failed requests are retried by the S3 client, but it cannot retry reading the body of a
+	/// response, which is retried here with exponential backoff.
+	async fn get(&self, key: &str, range: Option<String>) -> Result<Bytes, S3Error> {
+		let mut retries = 0;
+		let mut backoff = INITIAL_BACKOFF;
+		loop {
+			let object = self
+				.s3
+				.get_object()
+				.bucket(&self.bucket)
+				.key(key)
+				.set_range(range.clone())
+				.send()
+				.await
+				.map_err(|err| S3Error::from_sdk(&err, key))?;
+			match object.body.collect().await {
+				Ok(body) => return Ok(body.into_bytes()),
+				Err(err) if retries < MAX_RETRIES => {
+					retries += 1;
+					warn!(key, retries, "Retrying to read object: {err}");
+					tokio::time::sleep(backoff).await;
+					backoff *= 2;
+				}
+				Err(err) => {
+					return Err(S3Error::Unavailable {
+						message: err.to_string(),
+					})
+				}
+			}
+		}
+	}
+
+	/// Creates a presigned URL for reading the object, optionally restricted to a range.
+	async fn presign(
+		&self,
+		key: &str,
+		expires_in: Duration,
+		range: Option<String>,
+	) -> Result<String, S3Error> {
+		let config = PresigningConfig::expires_in(expires_in).map_err(|err| S3Error::Request {
+			message: err.to_string(),
+		})?;
+		let request = self
+			.s3
+			.get_object()
+			.bucket(&self.bucket)
+			.key(key)
+			.set_range(range)
+			.presigned(config)
+			.await
+			.map_err(|err| S3Error::from_sdk(&err, key))?;
+		Ok(request.uri().to_owned())
+	}
+
+	async fn read_instance(&self, key: &str) -> Result<Instance, S3Error> {
+		trace!("Streaming {key}");
+		let bytes = self.get(key, None).await?;
+		FileDicomObject::from_reader(bytes.reader()).map_err(|err| S3Error::Corrupt {
+			key: key.to_owned(),
+			message: err.to_string(),
+		})
+	}
+	/// Reads the instance up to its pixel data, using range requests to avoid downloading the pixel data.
+	pub(super) async fn read_header(&self, key: &str) -> Result<Header, S3Error> {
+		let mut length = HEADER_RANGE;
+		loop {
+			let bytes = self
+				.get(key, Some(format!("bytes=0-{}", length - 1)))
+				.await?;
+			// S3 returns the whole object if it is smaller than the requested range.
+			let complete = bytes.len() < length;
+			let Some(pixel_data) = locate_pixel_data(&bytes, complete) else {
+				length *= 4;
+				continue;
+			};
+
+			let result = OpenFileOptions::new()
+				.read_until(tags::PIXEL_DATA)
+				.from_reader(bytes.as_ref());
+			match result {
+				Ok(file) => return Ok(Header { file, pixel_data }),
+				Err(err) if complete => {
+					return Err(S3Error::Corrupt {
+						key: key.to_owned(),
+						message: err.to_string(),
+					})
+				}
+				_ => length *= 4,
+			}
+		}
+	}
+
+	/// Reads the metadata sidecar of the instance, if it exists.
+	async fn read_sidecar(&self, key: &str) -> Result<Option<Instance>, S3Error> {
+		let key = sidecar_key(key);
+		let bytes = match self.get(&key, None).await {
+			Ok(bytes) => bytes,
+			Err(S3Error::NotFound { .. }) => return Ok(None),
+			Err(err) => return Err(err),
+		};
+		let corrupt = |message: String| S3Error::Corrupt {
+			key: key.clone(),
+			message,
+		};
+		let metadata: InMemDicomObject =
+			dicom_json::from_slice(&bytes).map_err(|err| corrupt(err.to_string()))?;
+		let file = metadata
+			.with_meta(FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN))
+			.map_err(|err| corrupt(err.to_string()))?;
+		Ok(Some(file))
+	}
+
+	async fn read_metadata(&self, key: &str) -> Result<InstanceMetadata, S3Error> {
+		// The position of the pixel data, which is needed for presigned URLs, is not part of the sidecars.
+		if self.metadata_sidecars && self.presigned_urls_expire_in.is_none() {
+			match self.read_sidecar(key).await {
+				Ok(Some(file)) => return Ok(sidecar_metadata(file)),
+				Ok(None) => trace!("No metadata sidecar for {key}"),
+				Err(err) => warn!(key, "Failed to read metadata sidecar: {err}"),
+			}
+		}
+		let header = self.read_header(key).await?;
+		let mut bulkdata = pixel_data_references(&header.pixel_data);
+		// The range is part of the signature, so clients have to send it as `Range` header.
+		if let (PixelData::Native(position), Some(expires_in)) =
+			(&header.pixel_data, self.presigned_urls_expire_in)
+		{
+			let range = position.range();
+			let uri = self.presign(key, expires_in, Some(range.clone())).await?;
+			for reference in &mut bulkdata {
+				reference.uri = Some(format!("{uri}#{range}"));
+			}
+		}
+		Ok(InstanceMetadata {
+			instance: Arc::new(header.file),
+			bulkdata,
+		})
+	}
+
+	/// Reads the objects concurrently.
+	///
+	/// Corrupt objects and objects that were deleted in the meantime are skipped with a warning,
+	/// so that a single object does not fail the whole resource. If the first object cannot be read
+	/// due to another error, the error is returned instead of the stream.
+	async fn read_all<T, F, Fut>(
+		&self,
+		keys: Vec<String>,
+		read: F,
+	) -> Result<BoxStream<'static, Result<T, MoveError>>, RetrieveError>
+	where
+		T: Send + 'static,
+		F: Fn(Self, String) -> Fut + Send + 'static,
+		Fut: Future<Output = Result<T, S3Error>> + Send + 'static,
+	{
+		let service = self.clone();
+		let stream = futures::stream::iter(keys)
+			.map(move |key| tokio::spawn(read(service.clone(), key)))
+			.buffer_unordered(self.concurrency.max(1))
+			.filter_map(|result| async move {
+				let result = result.unwrap_or_else(|err| {
+					Err(S3Error::Request {
+						message: err.to_string(),
+					})
+				});
+				match result {
+					Ok(item) => Some(Ok(item)),
+					Err(err @ (S3Error::NotFound { .. } | S3Error::Corrupt { .. })) => {
+						warn!("Skipped object: {err}");
+						None
+					}
+					Err(err) => Some(Err(err)),
+				}
+			});
+
+		let mut stream = Box::pin(stream.peekable());
+		if matches!(stream.as_mut().peek().await, Some(Err(_))) {
+			if let Some(Err(err)) = stream.next().await {
+				return Err(err.into());
+			}
+		}
+
+		#[allow(clippy::result_large_err)]
+		let stream = stream.map(|result| {
+			result.map_err(|err| {
+				warn!("Failed to read object: {err}");
+				MoveError::OperationFailed
+			})
+		});
+		Ok(stream.boxed())
+	}
 }
 
 #[async_trait]
@@ -73,57 +363,362 @@ impl WadoService for S3WadoService {
 		&self,
 		request: RetrieveInstanceRequest,
 	) -> Result<InstanceResponse, RetrieveError> {
-		let prefix = &request.query.to_s3_prefix();
-		info!("Requesting {} from S3", prefix);
-		let client = self.s3.clone();
-		let bucket = self.bucket.clone();
+		info!(
+			study = request.query.study_instance_uid,
+			series = request.query.series_instance_uid,
+			instance = request.query.sop_instance_uid,
+			"Requesting instances from S3"
+		);
+		let keys = self.instance_keys(&request.query).await?;
 
-		let objects = client
-			.collect_objects()
-			.bucket(&self.bucket)
-			.prefix(prefix)
-			.send()
-			.await
-			.map_err(|err| RetrieveError::Backend { source: err })?;
-		info!("Found {} objects.", objects.len());
+		let stream = self
+			.read_all(keys, |service, key| async move {
+				service.read_instance(&key).await.map(Arc::new)
+			})
+			.await?;
+		Ok(InstanceResponse { stream })
+	}
 
-		let stream = futures::stream::iter(objects)
-			.map(move |object| {
-				let client = client.clone();
-				let bucket = bucket.clone();
-				trace!("Streaming {}", object.key.as_ref().unwrap());
-				tokio::spawn(async move {
-					let object = client
-						.get_object()
-						.bucket(&bucket)
-						.key(object.key.unwrap())
-						.send()
+	/// Only the first instance with pixel data is downloaded completely.
+	/// The other instances are only read until it is known that they do not contain pixel data.
+	async fn render(&self, request: &RenderingRequest) -> Result<RenderedResponse, RetrieveError> {
+		let keys = self.instance_keys(&request.query).await?;
+
+		for key in keys {
+			let result = match self.read_header(&key).await {
+				Ok(Header {
+					pixel_data: PixelData::Missing,
+					..
+				}) => Ok(None),
+				Ok(_) => self.read_instance(&key).await.map(Some),
+				Err(err) => Err(err),
+			};
+			match result {
+				Ok(Some(file)) if file.get(tags::PIXEL_DATA).is_some() => {
+					let mut stream = futures::stream::iter([Arc::new(file)]);
+					return render_instances(&mut stream, &request.options)
 						.await
-						.unwrap();
-
-					// TODO: Do not unwrap
-					let bytes = object.body.collect().await.unwrap().reader();
-					Result::<_, MoveError>::Ok(Arc::new(
-						FileDicomObject::from_reader(bytes).unwrap(),
-					))
-				})
+						.map(RenderedResponse)
+						.map_err(|source| RetrieveError::Backend { source });
+				}
+				Ok(_) => {}
+				Err(err @ (S3Error::NotFound { .. } | S3Error::Corrupt { .. })) => {
+					warn!("Skipped object: {err}");
+				}
+				Err(err) => return Err(err.into()),
+			}
+		}
+
+		Err(RetrieveError::NotFound {
+			message: String::from("No instance with pixel data found"),
+		})
+	}
+
+	/// The metadata is read from the metadata sidecars if enabled,
+	/// otherwise (or if there is no sidecar) from the instances up to their pixel data.
+	/// In the latter case, the pixel data is referenced by a `BulkDataURI`,
+	/// which is a presigned URL of its byte range if presigned URLs are enabled.
+	async fn metadata(&self, request: MetadataRequest) -> Result<MetadataResponse, RetrieveError> {
+		let keys = self.instance_keys(&request.query).await?;
+
+		let stream = self
+			.read_all(keys, |service, key| async move {
+				service.read_metadata(&key).await
 			})
-			.buffer_unordered(self.concurrency)
-			.map(|res| {
-				res.map_err(|_| MoveError::OperationFailed)
-					.and_then(|res| res)
-			});
+			.await?;
+		Ok(MetadataResponse { stream })
+	}
+
+	/// Returns a presigned URL of the instance, if presigned URLs are enabled.
+	async fn instance_url(
+		&self,
+		request: &RetrieveInstanceRequest,
+	) -> Result<Option<String>, RetrieveError> {
+		let Some(expires_in) = self.presigned_urls_expire_in else {
+			return Ok(None);
+		};
+		if request.query.sop_instance_uid.is_none() {
+			return Ok(None);
+		}
+		// There is at least one key, otherwise the instance was not found.
+		let keys = self.instance_keys(&request.query).await?;
+		let url = self.presign(&keys[0], expires_in, None).await?;
+		Ok(Some(url))
+	}
+
+	/// Native pixel data is read from its range within the object,
+	/// other bulk data is extracted from the retrieved instance.
+	async fn bulkdata(&self, request: &BulkDataRequest) -> Result<BulkDataResponse, RetrieveError> {
+		if request.path.0 != AttributeSelector::from(tags::PIXEL_DATA)
+			|| request.query.sop_instance_uid.is_none()
+		{
+			return Err(RetrieveError::Unimplemented);
+		}
+		// There is at least one key, otherwise the instance was not found.
+		let keys = self.instance_keys(&request.query).await?;
+		let header = self.read_header(&keys[0]).await?;
+		match header.pixel_data {
+			PixelData::Native(position) => {
+				let bytes = self.get(&keys[0], Some(position.range())).await?;
+				Ok(BulkDataResponse(bytes.to_vec()))
+			}
+			PixelData::Missing => Err(RetrieveError::NotFound {
+				message: String::from("The instance does not contain pixel data"),
+			}),
+			PixelData::Encapsulated | PixelData::Unknown => Err(RetrieveError::Unimplemented),
+		}
+	}
+}
+
+/// Counts the bytes that are read from the inner reader.
+struct CountingReader<'a, R> {
+	inner: R,
+	count: &'a Cell<usize>,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		self.count.set(self.count.get() + read);
+		Ok(read)
+	}
+}
+
+/// References the pixel data of an instance that was read up to its pixel data.
+/// The pixel data is read (or extracted) by the bulk data resource.
+fn pixel_data_references(pixel_data: &PixelData) -> Vec<BulkDataReference> {
+	let vr = match pixel_data {
+		PixelData::Native(position) => position.vr,
+		PixelData::Encapsulated => VR::OB,
+		PixelData::Unknown | PixelData::Missing => return Vec::new(),
+	};
+	vec![BulkDataReference {
+		tag: tags::PIXEL_DATA,
+		vr,
+		uri: None,
+	}]
+}
 
-		Ok(InstanceResponse {
-			stream: stream.boxed(),
+/// The metadata read from a sidecar, whose pixel data (without a value) is referenced as bulk data.
+fn sidecar_metadata(mut file: Instance) -> InstanceMetadata {
+	let bulkdata = file
+		.take_element(tags::PIXEL_DATA)
+		.ok()
+		.map(|pixel_data| BulkDataReference {
+			tag: tags::PIXEL_DATA,
+			vr: pixel_data.vr(),
+			uri: None,
 		})
+		.into_iter()
+		.collect();
+	InstanceMetadata {
+		instance: Arc::new(file),
+		bulkdata,
+	}
+}
+
+/// Locates the top-level pixel data in the (possibly truncated) object with the parser,
+/// so that the pixel data of nested data sets is not mistaken for it.
+///
+/// Returns `None` if the object is truncated before the pixel data or the end of the data set
+/// could be reached. If the object is complete but cannot be parsed, the pixel data is
+/// [`PixelData::Missing`] and the error is left to reading the object.
+fn locate_pixel_data(bytes: &[u8], complete: bool) -> Option<PixelData> {
+	let incomplete = || complete.then_some(PixelData::Missing);
+
+	let mut source = bytes;
+	if source.get(128..132) == Some(b"DICM") {
+		source = &source[128..];
+	}
+	let Ok(meta) = FileMetaTable::from_reader(&mut source) else {
+		return incomplete();
+	};
+	let data_set_offset = bytes.len() - source.len();
+	let Some(transfer_syntax) = TransferSyntaxRegistry.get(meta.transfer_syntax()) else {
+		return incomplete();
+	};
+	if !matches!(
+		transfer_syntax.codec(),
+		Codec::None | Codec::EncapsulatedPixelData(..)
+	) {
+		// The positions in a compressed data set are not positions in the object.
+		return complete.then_some(PixelData::Unknown);
 	}
 
-	async fn render(&self, _request: &RenderingRequest) -> Result<RenderedResponse, RetrieveError> {
-		Err(RetrieveError::Unimplemented)
+	let count = Cell::new(0);
+	let reader = CountingReader {
+		inner: source,
+		count: &count,
+	};
+	let Ok(tokens) = DataSetReader::new_with_ts(reader, transfer_syntax) else {
+		return incomplete();
+	};
+	// The number of open sequences, i.e. whether the tokens belong to a nested data set.
+	let mut depth = 0_usize;
+	for token in tokens {
+		match token {
+			Ok(DataToken::SequenceStart { tag, .. }) if depth == 0 && tag > tags::PIXEL_DATA => {
+				return Some(PixelData::Missing)
+			}
+			Ok(DataToken::PixelSequenceStart) if depth == 0 => {
+				return Some(PixelData::Encapsulated)
+			}
+			Ok(DataToken::SequenceStart { .. } | DataToken::PixelSequenceStart) => depth += 1,
+			Ok(DataToken::SequenceEnd) => depth = depth.saturating_sub(1),
+			Ok(DataToken::ElementHeader(header))
+				if depth == 0 && header.tag >= tags::PIXEL_DATA =>
+			{
+				if header.tag != tags::PIXEL_DATA {
+					return Some(PixelData::Missing);
+				}
+				// The value follows the element header, which was just read.
+				return Some(match header.len.get() {
+					Some(0) | None => PixelData::Missing,
+					Some(length) => PixelData::Native(ValuePosition {
+						vr: header.vr,
+						offset: data_set_offset + count.get(),
+						length: length as usize,
+					}),
+				});
+			}
+			Ok(_) => {}
+			Err(_) => return incomplete(),
+		}
 	}
+	incomplete()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::s3::stow::sidecar;
+	use dicom::core::value::{DataSetSequence, PixelFragmentSequence};
+	use dicom::core::{DataElement, PrimitiveValue, Tag};
+
+	fn encode(transfer_syntax: &str, elements: Vec<DataElement<InMemDicomObject>>) -> Vec<u8> {
+		let file = InMemDicomObject::from_element_iter(
+			[
+				DataElement::new(
+					tags::SOP_CLASS_UID,
+					VR::UI,
+					uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+				),
+				DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+				DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(2_u16)),
+			]
+			.into_iter()
+			.chain(elements),
+		)
+		.with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax))
+		.unwrap();
+		let mut bytes = Vec::new();
+		file.write_all(&mut bytes).unwrap();
+		bytes
+	}
+
+	fn pixel_data(value: Vec<u8>) -> DataElement<InMemDicomObject> {
+		DataElement::new(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(value))
+	}
+
+	#[test]
+	fn locates_pixel_data_value() {
+		for transfer_syntax in [
+			uids::EXPLICIT_VR_LITTLE_ENDIAN,
+			uids::IMPLICIT_VR_LITTLE_ENDIAN,
+		] {
+			let bytes = encode(transfer_syntax, vec![pixel_data(vec![1, 2, 3, 4])]);
+			let Some(PixelData::Native(position)) = locate_pixel_data(&bytes, true) else {
+				panic!("pixel data not found in {transfer_syntax}");
+			};
+			assert_eq!(position.length, 4);
+			assert_eq!(&bytes[position.offset..position.offset + 4], &[1, 2, 3, 4]);
+			assert_eq!(
+				position.range(),
+				format!("bytes={}-{}", position.offset, position.offset + 3)
+			);
+		}
+	}
+
+	#[test]
+	fn ignores_pixel_data_of_nested_data_sets() {
+		let icon = InMemDicomObject::from_element_iter([pixel_data(vec![9, 9])]);
+		// The value of the private attribute looks like the element header of the pixel data.
+		let bytes = encode(
+			uids::EXPLICIT_VR_LITTLE_ENDIAN,
+			vec![
+				DataElement::new(
+					tags::ICON_IMAGE_SEQUENCE,
+					VR::SQ,
+					DataSetSequence::from(vec![icon]),
+				),
+				DataElement::new(
+					Tag(0x0029, 0x1010),
+					VR::OB,
+					PrimitiveValue::from(vec![
+						0xE0_u8, 0x7F, 0x10, 0x00, b'O', b'B', 0, 0, 2, 0, 0, 0,
+					]),
+				),
+				pixel_data(vec![1, 2, 3, 4]),
+			],
+		);
+
+		let Some(PixelData::Native(position)) = locate_pixel_data(&bytes, true) else {
+			panic!("pixel data not found");
+		};
+		assert_eq!(&bytes[position.offset..position.offset + 4], &[1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn sidecar_references_pixel_data_like_the_instance() {
+		let encapsulated = DataElement::new(
+			tags::PIXEL_DATA,
+			VR::OB,
+			PixelFragmentSequence::new(Vec::<u32>::new(), vec![vec![0xFF_u8, 0xD8, 0xFF, 0xD9]]),
+		);
+		for (transfer_syntax, pixel_data) in [
+			(
+				uids::EXPLICIT_VR_LITTLE_ENDIAN,
+				pixel_data(vec![1, 2, 3, 4]),
+			),
+			(uids::JPEG_BASELINE8_BIT, encapsulated),
+		] {
+			let bytes = encode(transfer_syntax, vec![pixel_data]);
+			let from_header = pixel_data_references(&locate_pixel_data(&bytes, true).unwrap());
+
+			let instance = OpenFileOptions::new()
+				.from_reader(bytes.as_slice())
+				.unwrap();
+			let json = dicom_json::to_vec(&sidecar(&instance)).unwrap();
+			let metadata: InMemDicomObject = dicom_json::from_slice(&json).unwrap();
+			let from_sidecar = sidecar_metadata(
+				metadata
+					.with_meta(
+						FileMetaTableBuilder::new()
+							.transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+					)
+					.unwrap(),
+			);
+
+			assert_eq!(from_header.len(), 1, "{transfer_syntax}");
+			assert_eq!(from_sidecar.bulkdata, from_header, "{transfer_syntax}");
+			assert!(from_sidecar.instance.get(tags::PIXEL_DATA).is_none());
+		}
+	}
+
+	#[test]
+	fn locates_missing_pixel_data() {
+		let icon = InMemDicomObject::from_element_iter([pixel_data(vec![9, 9])]);
+		let bytes = encode(
+			uids::EXPLICIT_VR_LITTLE_ENDIAN,
+			vec![DataElement::new(
+				tags::ICON_IMAGE_SEQUENCE,
+				VR::SQ,
+				DataSetSequence::from(vec![icon]),
+			)],
+		);
 
-	async fn metadata(&self, _request: MetadataRequest) -> Result<InstanceResponse, RetrieveError> {
-		Err(RetrieveError::Unimplemented)
+		assert_eq!(locate_pixel_data(&bytes, true), Some(PixelData::Missing));
+		// A truncated object might end before the pixel data.
+		assert_eq!(locate_pixel_data(&bytes[..bytes.len() - 4], false), None);
 	}
 }
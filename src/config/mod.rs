@@ -1,9 +1,18 @@
-use crate::types::AE;
+use crate::types::{TimezoneOffset, AE, UI};
 use crate::DEFAULT_AET;
 
+use dicom::core::dictionary::DataDictionaryEntry;
+use dicom::core::value::ConvertValueError;
+use dicom::core::{DataDictionary, PrimitiveValue, Tag, VR};
+#[cfg(feature = "s3")]
+use dicom::dictionary_std::tags;
+use dicom::dictionary_std::uids;
+use dicom::object::StandardDataDictionary;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -41,6 +50,118 @@ pub enum BackendConfig {
 	#[cfg(feature = "s3")]
 	#[serde(rename = "S3")]
 	S3(S3Config),
+	#[serde(rename = "GROUP")]
+	Group(GroupConfig),
+	#[serde(rename = "FILESYSTEM")]
+	Filesystem(FilesystemConfig),
+	#[serde(rename = "DICOMWEB")]
+	DicomWeb(DicomWebConfig),
+	#[serde(rename = "MEMORY")]
+	Memory(MemoryConfig),
+	#[serde(rename = "COMPOSITE")]
+	Composite(CompositeConfig),
+}
+
+/// A virtual AET that uses the services of other AETs, e.g. to search an index and store in a PACS.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CompositeConfig {
+	pub services: CompositeServicesConfig,
+}
+
+/// The AET whose implementation is used for each service. Services without an AET are disabled.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CompositeServicesConfig {
+	#[serde(default, rename = "qido-rs")]
+	pub qido: Option<AE>,
+	#[serde(default, rename = "wado-rs")]
+	pub wado: Option<AE>,
+	#[serde(default, rename = "stow-rs")]
+	pub stow: Option<AE>,
+	#[serde(default, rename = "mwl-rs")]
+	pub mwl: Option<AE>,
+}
+
+impl CompositeServicesConfig {
+	/// Returns the AET that provides the service.
+	pub const fn get(&self, service: Service) -> Option<&AE> {
+		match service {
+			Service::Qido => self.qido.as_ref(),
+			Service::Wado => self.wado.as_ref(),
+			Service::Stow => self.stow.as_ref(),
+			Service::Mwl => self.mwl.as_ref(),
+		}
+	}
+}
+
+/// The `DICOMweb` services of an AET.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+	Qido,
+	Wado,
+	Stow,
+	Mwl,
+}
+
+/// An archive that only keeps instances in memory, e.g. for tests and demos.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MemoryConfig {
+	/// A directory of DICOM files that are loaded on startup.
+	/// Files of the Modality Worklist Information Model are loaded as worklist items.
+	#[serde(default)]
+	pub seed: Option<PathBuf>,
+}
+
+/// An origin server that implements `DICOMweb`, to which all requests are forwarded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DicomWebConfig {
+	/// The base URL of the origin server, e.g. `https://pacs.example.com/dicom-web`.
+	pub url: String,
+	/// Headers that are sent with every request, e.g. `Authorization`.
+	#[serde(default)]
+	pub headers: HashMap<String, HeaderValueConfig>,
+}
+
+/// The value of a header, either as plain text or read from an environment variable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderValueConfig {
+	#[serde(rename_all = "kebab-case")]
+	Env {
+		env: String,
+	},
+	Plain(String),
+}
+
+impl HeaderValueConfig {
+	pub fn resolve(&self) -> Result<String, std::env::VarError> {
+		match self {
+			Self::Env { env } => std::env::var(env),
+			Self::Plain(value) => Ok(value.clone()),
+		}
+	}
+}
+
+/// An archive of Part 10 files in a local directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FilesystemConfig {
+	/// The directory that contains the instances as `{study}/{series}/{instance}.dcm`.
+	pub path: PathBuf,
+	/// The index that is used to answer QIDO-RS requests.
+	#[serde(default)]
+	pub index: IndexConfig,
+}
+
+/// A virtual AET that aggregates the search results of other AETs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupConfig {
+	/// The AETs that are searched, in order of precedence.
+	pub members: Vec<AE>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -65,6 +186,166 @@ pub struct S3Config {
 	pub credentials: Option<S3CredentialsConfig>,
 	#[serde(default)]
 	pub endpoint_style: S3EndpointStyle,
+	/// Instances larger than this size (in bytes) are stored using multipart uploads.
+	#[serde(default = "S3Config::default_part_size")]
+	pub part_size: usize,
+	/// Whether the metadata of stored instances is also written to a JSON sidecar object.
+	#[serde(default)]
+	pub metadata_sidecars: bool,
+	/// Enables QIDO-RS using an index of the stored instances.
+	#[serde(default)]
+	pub index: Option<IndexConfig>,
+	/// The layout of the object keys of instances.
+	#[serde(default)]
+	pub key_template: KeyTemplate,
+	/// Enables redirects to presigned URLs instead of streaming instances through the server.
+	#[serde(default)]
+	pub presigned_urls: Option<PresignedUrlsConfig>,
+}
+
+/// A template for the object keys of instances, e.g. `{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}.dcm`.
+/// Placeholders contain the keyword or tag of an attribute.
+#[cfg(feature = "s3")]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyTemplate(pub Vec<KeySegment>);
+
+#[cfg(feature = "s3")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySegment {
+	Literal(String),
+	Attribute(Tag),
+}
+
+#[cfg(feature = "s3")]
+impl KeyTemplate {
+	pub const DEFAULT: &'static str = "{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}.dcm";
+}
+
+#[cfg(feature = "s3")]
+impl Default for KeyTemplate {
+	fn default() -> Self {
+		Self::try_from(String::from(Self::DEFAULT)).expect("default key template should be valid")
+	}
+}
+
+#[cfg(feature = "s3")]
+impl TryFrom<String> for KeyTemplate {
+	type Error = String;
+
+	fn try_from(template: String) -> Result<Self, Self::Error> {
+		let mut segments = Vec::new();
+		let mut rest = template.as_str();
+		while let Some(start) = rest.find('{') {
+			if start > 0 {
+				segments.push(KeySegment::Literal(rest[..start].to_owned()));
+			} else if matches!(segments.last(), Some(KeySegment::Attribute(_))) {
+				// The boundary between the values could not be determined when parsing a key.
+				return Err(format!(
+					"placeholders in key template {template} must be separated by text"
+				));
+			}
+			let end = rest[start..]
+				.find('}')
+				.ok_or_else(|| format!("unclosed placeholder in key template {template}"))?
+				+ start;
+			let name = &rest[start + 1..end];
+			let tag = StandardDataDictionary
+				.parse_tag(name)
+				.ok_or_else(|| format!("unknown attribute {name} in key template {template}"))?;
+			segments.push(KeySegment::Attribute(tag));
+			rest = &rest[end + 1..];
+		}
+		if !rest.is_empty() {
+			segments.push(KeySegment::Literal(rest.to_owned()));
+		}
+
+		// Instances of a resource are found by the UIDs in their keys.
+		for tag in [
+			tags::STUDY_INSTANCE_UID,
+			tags::SERIES_INSTANCE_UID,
+			tags::SOP_INSTANCE_UID,
+		] {
+			if !segments.contains(&KeySegment::Attribute(tag)) {
+				return Err(format!(
+					"key template {template} must contain the Study, Series and SOP Instance UID"
+				));
+			}
+		}
+		Ok(Self(segments))
+	}
+}
+
+/// The index of instance attributes that is used to answer QIDO-RS requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IndexConfig {
+	/// The database file of the index. If not set, the index is only kept in memory.
+	#[serde(default)]
+	pub path: Option<PathBuf>,
+	/// Whether instances that are missing in the index are added by scanning the storage on startup.
+	#[serde(default = "IndexConfig::default_scan")]
+	pub scan: bool,
+}
+
+impl IndexConfig {
+	pub const fn default_scan() -> bool {
+		true
+	}
+}
+
+impl Default for IndexConfig {
+	fn default() -> Self {
+		Self {
+			path: None,
+			scan: Self::default_scan(),
+		}
+	}
+}
+
+/// Presigned URLs that allow clients to download objects directly from S3.
+#[cfg(feature = "s3")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PresignedUrlsConfig {
+	/// The time (in seconds) until presigned URLs expire.
+	#[serde(default = "PresignedUrlsConfig::default_expires_in")]
+	pub expires_in: u64,
+}
+
+#[cfg(feature = "s3")]
+impl PresignedUrlsConfig {
+	pub const fn default_expires_in() -> u64 {
+		300
+	}
+}
+
+#[cfg(feature = "s3")]
+impl S3Config {
+	/// S3 requires parts of multipart uploads (except the last one) to be at least 5 MiB.
+	pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+	pub const fn default_part_size() -> usize {
+		16 * 1024 * 1024
+	}
+
+	/// Checks that the instances of a study can be found without listing the whole bucket.
+	/// The values of attributes in front of the Study Instance UID are not part of the request URL,
+	/// so they can only be looked up in the index.
+	fn validate(&self) -> Result<(), String> {
+		let unknown = self
+			.key_template
+			.0
+			.iter()
+			.take_while(|segment| **segment != KeySegment::Attribute(tags::STUDY_INSTANCE_UID))
+			.any(|segment| matches!(segment, KeySegment::Attribute(_)));
+		if unknown && self.index.is_none() {
+			return Err(String::from(
+				"a key template with attributes in front of the StudyInstanceUID requires an index",
+			));
+		}
+		Ok(())
+	}
 }
 
 #[cfg(feature = "s3")]
@@ -134,11 +415,36 @@ impl S3CredentialsConfig {
 #[serde(rename_all = "kebab-case")]
 pub struct QidoConfig {
 	pub timeout: u64,
+	/// How long (in milliseconds) complete result sets are cached for paging.
+	#[serde(default = "QidoConfig::default_cache_ttl")]
+	pub cache_ttl: u64,
+	/// Result sets with more matches are not cached and only read up to the requested page.
+	#[serde(default = "QidoConfig::default_max_cached_results")]
+	pub max_cached_results: usize,
+	/// The timezone of the origin server, used to normalize date and time matching.
+	/// If not set, dates and times are passed through as-is.
+	#[serde(default)]
+	pub timezone: Option<TimezoneOffset>,
+}
+
+impl QidoConfig {
+	pub const fn default_cache_ttl() -> u64 {
+		60_000
+	}
+
+	pub const fn default_max_cached_results() -> usize {
+		10_000
+	}
 }
 
 impl Default for QidoConfig {
 	fn default() -> Self {
-		Self { timeout: 30_000 }
+		Self {
+			timeout: 30_000,
+			cache_ttl: Self::default_cache_ttl(),
+			max_cached_results: Self::default_max_cached_results(),
+			timezone: None,
+		}
 	}
 }
 
@@ -175,11 +481,155 @@ pub enum RetrieveMode {
 #[serde(rename_all = "kebab-case")]
 pub struct StowConfig {
 	pub timeout: u64,
+	/// How many instances of a request are stored concurrently.
+	#[serde(default = "StowConfig::default_concurrency")]
+	pub concurrency: usize,
+	/// Transfer syntaxes that are proposed in addition to the transfer syntax of an instance.
+	/// If only a fallback is accepted, the instance is transcoded before it is sent.
+	#[serde(default = "StowConfig::default_fallback_transfer_syntaxes")]
+	pub fallback_transfer_syntaxes: Vec<UI>,
+	/// How instances that fail validation are handled.
+	#[serde(default)]
+	pub validation: ValidationPolicy,
+	/// Rules that change attributes of the instances before they are stored.
+	#[serde(default)]
+	pub coercion: Vec<CoercionRule>,
+}
+
+/// A rule that changes an attribute of STOW-RS instances before they are stored.
+/// The values are parsed according to the VR of the attribute when the configuration is loaded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "CoercionRuleConfig")]
+pub struct CoercionRule {
+	pub tag: Tag,
+	pub vr: VR,
+	pub action: CoercionAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum CoercionAction {
+	/// Sets the value, replacing any existing value.
+	Set(PrimitiveValue),
+	/// Sets the value if the attribute is missing or empty.
+	Default(PrimitiveValue),
+	/// Replaces the values that are contained in the map. Other values are kept.
+	/// The keys are the string representation of the parsed values, e.g. `1` for `1.0` in a FL attribute.
+	Map(HashMap<String, PrimitiveValue>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CoercionRuleConfig {
+	/// The keyword or tag of the attribute, e.g. `InstitutionName` or `(0008,0080)`.
+	#[serde(deserialize_with = "deserialize_tag")]
+	tag: Tag,
+	/// The VR of the attribute. Required for attributes that are not in the data dictionary.
+	vr: Option<String>,
+	#[serde(flatten)]
+	action: CoercionActionConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CoercionActionConfig {
+	Set(String),
+	Default(String),
+	Map(HashMap<String, String>),
+}
+
+impl TryFrom<CoercionRuleConfig> for CoercionRule {
+	type Error = String;
+
+	fn try_from(config: CoercionRuleConfig) -> Result<Self, Self::Error> {
+		let tag = config.tag;
+		let vr = match config.vr {
+			Some(vr) => VR::from_str(&vr).map_err(|_| format!("unknown VR {vr} for {tag}"))?,
+			None => StandardDataDictionary
+				.by_tag(tag)
+				.map(|entry| entry.vr().relaxed())
+				.ok_or_else(|| format!("the VR of {tag} must be configured"))?,
+		};
+		let parse = |value: &str| {
+			coercion_value(vr, value).map_err(|err| format!("invalid value for {tag}: {err}"))
+		};
+		let action = match config.action {
+			CoercionActionConfig::Set(value) => CoercionAction::Set(parse(&value)?),
+			CoercionActionConfig::Default(value) => CoercionAction::Default(parse(&value)?),
+			CoercionActionConfig::Map(values) => CoercionAction::Map(
+				values
+					.iter()
+					.map(|(from, to)| Ok((parse(from)?.to_str().into_owned(), parse(to)?)))
+					.collect::<Result<_, String>>()?,
+			),
+		};
+
+		Ok(Self { tag, vr, action })
+	}
+}
+
+/// Parses the value of an attribute with the VR. Multiple values are separated by a backslash.
+fn coercion_value(vr: VR, value: &str) -> Result<PrimitiveValue, String> {
+	if value.is_empty() {
+		return Ok(PrimitiveValue::Empty);
+	}
+	let text = PrimitiveValue::Strs(value.split('\\').map(str::to_owned).collect());
+	let invalid = |err: ConvertValueError| format!("{value} is not a valid {vr} value ({err})");
+
+	Ok(match vr {
+		VR::AE | VR::AS | VR::CS | VR::LO | VR::PN | VR::SH | VR::UC | VR::UI => text,
+		VR::LT | VR::ST | VR::UR | VR::UT => PrimitiveValue::from(value),
+		VR::DA => text.to_multi_date().map(|_| text).map_err(invalid)?,
+		VR::DT => text.to_multi_datetime().map(|_| text).map_err(invalid)?,
+		VR::TM => text.to_multi_time().map(|_| text).map_err(invalid)?,
+		VR::DS => text.to_multi_float64().map(|_| text).map_err(invalid)?,
+		VR::IS => text.to_multi_int::<i32>().map(|_| text).map_err(invalid)?,
+		VR::US => PrimitiveValue::U16(text.to_multi_int().map_err(invalid)?.into()),
+		VR::SS => PrimitiveValue::I16(text.to_multi_int().map_err(invalid)?.into()),
+		VR::UL => PrimitiveValue::U32(text.to_multi_int().map_err(invalid)?.into()),
+		VR::SL => PrimitiveValue::I32(text.to_multi_int().map_err(invalid)?.into()),
+		VR::UV => PrimitiveValue::U64(text.to_multi_int().map_err(invalid)?.into()),
+		VR::SV => PrimitiveValue::I64(text.to_multi_int().map_err(invalid)?.into()),
+		VR::FL => PrimitiveValue::F32(text.to_multi_float32().map_err(invalid)?.into()),
+		VR::FD => PrimitiveValue::F64(text.to_multi_float64().map_err(invalid)?.into()),
+		_ => return Err(format!("attributes with VR {vr} cannot be coerced")),
+	})
+}
+
+/// The handling of STOW-RS instances with validation problems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValidationPolicy {
+	/// Instances are not validated.
+	#[default]
+	Off,
+	/// Instances are stored, but reported with a warning.
+	Warn,
+	/// Instances are not stored and reported as failed.
+	Reject,
+}
+
+impl StowConfig {
+	pub const fn default_concurrency() -> usize {
+		1
+	}
+
+	pub fn default_fallback_transfer_syntaxes() -> Vec<UI> {
+		vec![
+			UI::from(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+			UI::from(uids::IMPLICIT_VR_LITTLE_ENDIAN),
+		]
+	}
 }
 
 impl Default for StowConfig {
 	fn default() -> Self {
-		Self { timeout: 30_000 }
+		Self {
+			timeout: 30_000,
+			concurrency: Self::default_concurrency(),
+			fallback_transfer_syntaxes: Self::default_fallback_transfer_syntaxes(),
+			validation: ValidationPolicy::default(),
+			coercion: Vec::new(),
+		}
 	}
 }
 
@@ -201,10 +651,10 @@ impl AppConfig {
 	/// 2. `config.toml` in the same folder as the executable binary
 	/// 3. From environment variables, prefixed with `DICOM_RST`
 	/// # Errors
-	/// Returns a [`config::ConfigError`] if source collection fails.
+	/// Returns a [`config::ConfigError`] if source collection fails or the configuration is invalid.
 	pub fn new() -> Result<Self, config::ConfigError> {
 		use config::{Config, Environment, File, FileFormat};
-		Config::builder()
+		let config: Self = Config::builder()
 			.add_source(File::from_str(
 				include_str!("defaults.yaml"),
 				FileFormat::Yaml,
@@ -216,7 +666,87 @@ impl AppConfig {
 				std::env::var("DICOM_RST_SERVER_HTTP_BASE_PATH").ok(),
 			)?
 			.build()?
-			.try_deserialize()
+			.try_deserialize()?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// Checks that the AETs referenced by group and composite AETs are configured
+	/// and that no AET references itself, directly or through other AETs.
+	///
+	/// # Errors
+	/// Returns a [`config::ConfigError`] for the first invalid reference.
+	pub fn validate(&self) -> Result<(), config::ConfigError> {
+		for ae_config in &self.aets {
+			self.validate_references(ae_config, &mut Vec::new())
+				.map_err(config::ConfigError::Message)?;
+			#[cfg(feature = "s3")]
+			if let BackendConfig::S3(s3_config) = &ae_config.backend {
+				s3_config.validate().map_err(|err| {
+					config::ConfigError::Message(format!("Invalid AET {}: {err}", ae_config.aet))
+				})?;
+			}
+		}
+		Ok(())
+	}
+
+	/// `parents` contains the group and composite AETs on the path to `ae_config` to detect cycles.
+	fn validate_references<'a>(
+		&'a self,
+		ae_config: &'a ApplicationEntityConfig,
+		parents: &mut Vec<&'a str>,
+	) -> Result<(), String> {
+		let aet = ae_config.aet.as_str();
+		if parents.contains(&aet) {
+			parents.push(aet);
+			return Err(format!(
+				"AET {aet} references itself ({})",
+				parents.join(" -> ")
+			));
+		}
+
+		let references: Vec<&AE> = match &ae_config.backend {
+			BackendConfig::Group(config) => config.members.iter().collect(),
+			BackendConfig::Composite(config) => {
+				[Service::Qido, Service::Wado, Service::Stow, Service::Mwl]
+					.into_iter()
+					.filter_map(|service| config.services.get(service))
+					.collect()
+			}
+			_ => return Ok(()),
+		};
+
+		parents.push(aet);
+		for reference in references {
+			let target = self
+				.aets
+				.iter()
+				.find(|ae_config| ae_config.aet == *reference)
+				.ok_or_else(|| format!("AET {aet} references unknown AET {reference}"))?;
+			self.validate_references(target, parents)?;
+		}
+		parents.pop();
+		Ok(())
+	}
+
+	/// Returns the configuration of the AET that implements the service of `aet`.
+	/// Composite AETs are followed to the AET that provides the service, so that its
+	/// service settings (e.g. `qido-rs` or `stow-rs`) apply to the requests of the composite AET.
+	/// Returns [`None`] if the AET is not configured or the service is disabled.
+	pub fn service_aet(&self, aet: &str, service: Service) -> Option<&ApplicationEntityConfig> {
+		let mut ae_config = self.aets.iter().find(|ae_config| ae_config.aet == aet)?;
+		// Cyclic references are rejected by `validate`, the bound only guards against misuse.
+		for _ in 0..self.aets.len() {
+			let BackendConfig::Composite(config) = &ae_config.backend else {
+				return Some(ae_config);
+			};
+			let target = config.services.get(service)?;
+			ae_config = self
+				.aets
+				.iter()
+				.find(|ae_config| ae_config.aet == *target)?;
+		}
+		None
 	}
 }
 
@@ -244,12 +774,34 @@ pub struct HttpServerConfig {
 	pub interface: IpAddr,
 	pub port: u16,
 	pub max_upload_size: usize,
+	/// The maximum size of a whole request body in bytes, e.g. of a STOW-RS request with many instances.
+	/// Not limited if not configured.
+	#[serde(default)]
+	pub max_request_size: Option<u64>,
 	pub request_timeout: u64,
 	pub graceful_shutdown: bool,
 	pub base_path: String,
+	/// The URL under which clients reach the HTTP server (including the base path),
+	/// e.g. if it is deployed behind a reverse proxy.
+	#[serde(default)]
+	pub public_url: Option<String>,
+	/// The maximum number of QIDO-RS matches that are cached for paging, across all result sets.
+	#[serde(default = "HttpServerConfig::default_search_cache_size")]
+	pub search_cache_size: usize,
+	/// The maximum number of instances that are cached to extract their bulk data.
+	#[serde(default = "HttpServerConfig::default_instance_cache_size")]
+	pub instance_cache_size: usize,
 }
 
 impl HttpServerConfig {
+	pub const fn default_search_cache_size() -> usize {
+		100_000
+	}
+
+	pub const fn default_instance_cache_size() -> usize {
+		8
+	}
+
 	const WILDCARD_ADDRESSES: [&'static str; 3] =
 		["0.0.0.0", "::", "0000:0000:0000:0000:0000:0000:0000:0000"];
 
@@ -267,6 +819,13 @@ impl HttpServerConfig {
 
 		Ok(url)
 	}
+
+	/// Returns the configured public URL, or the [base URL](Self::base_url) if not configured.
+	pub fn public_url(&self) -> Result<url::Url, url::ParseError> {
+		self.public_url
+			.as_deref()
+			.map_or_else(|| self.base_url(), url::Url::parse)
+	}
 }
 
 impl Default for HttpServerConfig {
@@ -276,8 +835,12 @@ impl Default for HttpServerConfig {
 			port: 8080,
 			graceful_shutdown: true,
 			max_upload_size: 50_000_000, // 50 MB
-			request_timeout: 60_000,     // 1 min
+			max_request_size: None,
+			request_timeout: 60_000, // 1 min
 			base_path: String::from("/"),
+			public_url: None,
+			search_cache_size: Self::default_search_cache_size(),
+			instance_cache_size: Self::default_instance_cache_size(),
 		}
 	}
 }
@@ -361,3 +924,149 @@ where
 	tracing::Level::from_str(&value)
 		.map_err(|_| Error::unknown_variant(&value, &["TRACE", "DEBUG", "INFO", "WARN", "ERROR"]))
 }
+
+/// Deserializer for [`Tag`], which accepts keywords (e.g. `PatientID`) and tags (e.g. `(0010,0020)`).
+fn deserialize_tag<'de, D>(deserializer: D) -> Result<Tag, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	let value = String::deserialize(deserializer)?;
+
+	StandardDataDictionary
+		.parse_tag(&value)
+		.ok_or_else(|| Error::custom(format!("unknown attribute {value}")))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use config::{Config, File, FileFormat};
+
+	fn config(yaml: &str) -> AppConfig {
+		Config::builder()
+			.add_source(File::from_str(yaml, FileFormat::Yaml))
+			.build()
+			.unwrap()
+			.try_deserialize()
+			.unwrap()
+	}
+
+	#[test]
+	fn validates_references() {
+		let valid = config(
+			"
+            aets:
+              - aet: PACS
+                backend: MEMORY
+              - aet: ALL
+                backend: GROUP
+                members:
+                  - PACS
+              - aet: ROUTED
+                backend: COMPOSITE
+                services:
+                  qido-rs: ALL
+                  stow-rs: PACS
+            ",
+		);
+		assert!(valid.validate().is_ok());
+
+		let unknown = config(
+			"
+            aets:
+              - aet: ALL
+                backend: GROUP
+                members:
+                  - PACS
+            ",
+		);
+		let message = unknown.validate().unwrap_err().to_string();
+		assert_eq!(message, "AET ALL references unknown AET PACS");
+
+		let cyclic = config(
+			"
+            aets:
+              - aet: A
+                backend: COMPOSITE
+                services:
+                  qido-rs: B
+              - aet: B
+                backend: GROUP
+                members:
+                  - A
+            ",
+		);
+		let message = cyclic.validate().unwrap_err().to_string();
+		assert_eq!(message, "AET A references itself (A -> B -> A)");
+	}
+
+	#[cfg(feature = "s3")]
+	#[test]
+	fn key_templates_with_unknown_prefix_require_an_index() {
+		let s3 = |key_template: &str, index: &str| {
+			config(&format!(
+				"
+                aets:
+                  - aet: S3
+                    backend: S3
+                    endpoint: http://localhost:9000
+                    bucket: dicom
+                    concurrency: 8
+                    key-template: '{key_template}'
+                    {index}
+                "
+			))
+		};
+
+		let message = s3(
+			"{PatientID}/{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}",
+			"",
+		)
+		.validate()
+		.unwrap_err()
+		.to_string();
+		assert!(message.starts_with("Invalid AET S3: "), "{message}");
+		assert!(s3(
+			"{PatientID}/{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}",
+			"index: {}"
+		)
+		.validate()
+		.is_ok());
+		assert!(s3(
+			"{StudyInstanceUID}/{Modality}/{SeriesInstanceUID}/{SOPInstanceUID}",
+			""
+		)
+		.validate()
+		.is_ok());
+	}
+
+	#[test]
+	fn follows_composite_aets_to_the_service_aet() {
+		let config = config(
+			"
+            aets:
+              - aet: PACS
+                backend: MEMORY
+              - aet: ROUTED
+                backend: COMPOSITE
+                services:
+                  qido-rs: PACS
+              - aet: OUTER
+                backend: COMPOSITE
+                services:
+                  qido-rs: ROUTED
+            ",
+		);
+
+		let service_aet = |aet, service| {
+			config
+				.service_aet(aet, service)
+				.map(|ae_config| ae_config.aet.as_str())
+		};
+		assert_eq!(service_aet("PACS", Service::Qido), Some("PACS"));
+		assert_eq!(service_aet("ROUTED", Service::Qido), Some("PACS"));
+		assert_eq!(service_aet("OUTER", Service::Qido), Some("PACS"));
+		assert_eq!(service_aet("OUTER", Service::Stow), None);
+		assert_eq!(service_aet("UNKNOWN", Service::Qido), None);
+	}
+}
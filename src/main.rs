@@ -7,9 +7,14 @@ pub(crate) mod rendering;
 pub(crate) mod types;
 pub(crate) mod utils;
 
+use crate::api::qido::SearchResultCache;
+use crate::api::wado::InstanceCache;
+use crate::backend::dicomweb::DicomWebClients;
 use crate::backend::dimse::association;
 use crate::backend::dimse::cmove::MoveMediator;
 use crate::backend::dimse::StoreServiceClassProvider;
+use crate::backend::index::InstanceIndexes;
+use crate::backend::memory::MemoryStores;
 use crate::config::{AppConfig, HttpServerConfig};
 use crate::types::AE;
 use association::pool::AssociationPools;
@@ -64,6 +69,11 @@ pub struct AppState {
 	pub config: AppConfig,
 	pub pools: AssociationPools,
 	pub mediator: MoveMediator,
+	pub search_results: SearchResultCache,
+	pub instances: InstanceCache,
+	pub indexes: InstanceIndexes,
+	pub clients: DicomWebClients,
+	pub stores: MemoryStores,
 }
 
 fn init_sentry(config: &AppConfig) -> sentry::ClientInitGuard {
@@ -107,11 +117,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn run(config: AppConfig) -> anyhow::Result<()> {
 	let mediator = MoveMediator::new(&config);
 	let pools = AssociationPools::new(&config);
+	let indexes = InstanceIndexes::new(&config)?;
+	let clients = DicomWebClients::new(&config)?;
+	let stores = MemoryStores::new(&config)?;
+	backend::filesystem::qido::spawn_scans(&config, &indexes);
+	#[cfg(feature = "s3")]
+	backend::s3::qido::spawn_scans(&config, &indexes);
 
 	let app_state = AppState {
 		config: config.clone(),
 		mediator: mediator.clone(),
 		pools,
+		search_results: SearchResultCache::new(config.server.http.search_cache_size),
+		instances: InstanceCache::new(config.server.http.instance_cache_size),
+		indexes,
+		clients,
+		stores,
 	};
 
 	for dimse_config in config.server.dimse {
@@ -166,7 +187,7 @@ async fn run(config: AppConfig) -> anyhow::Result<()> {
 	info!(
 		server.address = server_addr.ip().to_string(),
 		server.port = server_addr.port(),
-		url.full = config.server.http.base_url()?.as_str(),
+		url.full = config.server.http.public_url()?.as_str(),
 		"Started DICOMweb server"
 	);
 	if config.server.http.graceful_shutdown {
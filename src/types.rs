@@ -1,6 +1,9 @@
+use dicom::core::chrono::FixedOffset;
 use dicom::core::PrimitiveValue;
 use dicom::dictionary_std::uids;
+use serde::Deserialize;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 /// UI (Unique Identifier) value representation.
 pub type UI = String;
@@ -70,3 +73,62 @@ impl From<QueryRetrieveLevel> for PrimitiveValue {
 		Self::Str(level.to_string())
 	}
 }
+
+/// A timezone offset from UTC in the format `&ZZXX` (e.g. `+0100` or `-0500`),
+/// as used by Timezone Offset From UTC (0008,0201).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimezoneOffset(FixedOffset);
+
+impl TimezoneOffset {
+	pub const UTC: Self = Self(FixedOffset::east_opt(0).expect("offset should be valid"));
+
+	pub const fn as_fixed_offset(self) -> FixedOffset {
+		self.0
+	}
+}
+
+impl FromStr for TimezoneOffset {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let invalid = || format!("{value} is not a timezone offset in the format &ZZXX");
+
+		// An unencoded `+` in a query string is decoded as a space.
+		let value = value.trim_end();
+		let (sign, digits) = match value.split_at_checked(1) {
+			Some(("+" | " ", digits)) => (1, digits),
+			Some(("-", digits)) => (-1, digits),
+			_ => return Err(invalid()),
+		};
+		if digits.len() != 4 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+			return Err(invalid());
+		}
+		let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+		let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+		if minutes >= 60 {
+			return Err(invalid());
+		}
+
+		FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+			.map(Self)
+			.ok_or_else(invalid)
+	}
+}
+
+impl TryFrom<String> for TimezoneOffset {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		Self::from_str(&value)
+	}
+}
+
+impl Display for TimezoneOffset {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		let seconds = self.0.local_minus_utc();
+		let sign = if seconds < 0 { '-' } else { '+' };
+		let minutes = seconds.unsigned_abs() / 60;
+		write!(f, "{sign}{:02}{:02}", minutes / 60, minutes % 60)
+	}
+}
@@ -1 +1,2 @@
 pub mod multipart;
+pub mod xml;
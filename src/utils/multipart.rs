@@ -1,18 +1,29 @@
-use axum::extract::{FromRequest, Request};
+use crate::AppState;
+use axum::body::Body;
+use axum::extract::{FromRef, FromRequest, Request};
 use axum::http::header::CONTENT_TYPE;
 use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::RequestExt;
+use bytes::{Bytes, BytesMut};
+use multer::{Constraints, SizeLimit};
 
 /// This uses the `multer` crate (just like axum with the `multipart` feature enabled) to parse
 /// request bodies to DICOM files.
 /// `axum::extract::Multipart` cannot be used because the Content-Type is not multiform/form-data.
-pub struct DicomMultipart<'a>(multer::Multipart<'a>);
+///
+/// The request body is streamed, so that only a single part has to be kept in memory.
+/// The size of each part is limited by `server.http.max-upload-size`,
+/// the size of the whole request body by `server.http.max-request-size`.
+pub struct DicomMultipart<'a> {
+	multipart: multer::Multipart<'a>,
+	root_type: Option<String>,
+	max_part_size: u64,
+}
 
 impl<'a> DicomMultipart<'a> {
 	/// This implementation is based on [`multer::parse_boundary`],
 	/// but with multipart/related instead of multipart/form-data.
-	fn parse_boundary(content_type: &str) -> multer::Result<String> {
+	pub fn parse_boundary(content_type: &str) -> multer::Result<String> {
 		let mime = content_type
 			.parse::<mime::Mime>()
 			.map_err(multer::Error::DecodeContentType)?;
@@ -27,9 +38,63 @@ impl<'a> DicomMultipart<'a> {
 			.ok_or(multer::Error::NoBoundary)
 	}
 
+	pub(crate) fn new(
+		body: Body,
+		boundary: String,
+		root_type: Option<String>,
+		max_part_size: u64,
+		max_request_size: Option<u64>,
+	) -> Self {
+		// Parts are limited while they are read, so that a part that exceeds the limit
+		// does not end the stream (see `read_field`).
+		let size_limit = max_request_size.map_or_else(SizeLimit::new, |max_request_size| {
+			SizeLimit::new().whole_stream(max_request_size)
+		});
+		let constraints = Constraints::new().size_limit(size_limit);
+		let multipart =
+			multer::Multipart::with_constraints(body.into_data_stream(), boundary, constraints);
+		Self {
+			multipart,
+			root_type,
+			max_part_size,
+		}
+	}
+
+	/// Parses the `type` parameter, i.e. the media type of the root body part.
+	fn parse_root_type(content_type: &str) -> Option<String> {
+		let mime = content_type.parse::<mime::Mime>().ok()?;
+		mime.get_param("type")
+			.map(|root_type| root_type.as_str().to_ascii_lowercase())
+	}
+
+	/// The media type of the root body part (the `type` parameter of the Content-Type header).
+	pub fn root_type(&self) -> Option<&str> {
+		self.root_type.as_deref()
+	}
+
 	/// See [`multer::Multipart::next_field`]
 	pub async fn next_field(&mut self) -> multer::Result<Option<multer::Field<'a>>> {
-		self.0.next_field().await
+		self.multipart.next_field().await
+	}
+
+	/// Reads the data of the field.
+	///
+	/// If the field exceeds the part size limit, the rest of it is skipped and
+	/// [`multer::Error::FieldSizeExceeded`] is returned, so that the next field can still be read.
+	/// Other errors leave the request body in an unknown state.
+	pub async fn read_field(&self, mut field: multer::Field<'a>) -> multer::Result<Bytes> {
+		let mut data = BytesMut::new();
+		while let Some(chunk) = field.chunk().await? {
+			if (data.len() + chunk.len()) as u64 > self.max_part_size {
+				while field.chunk().await?.is_some() {}
+				return Err(multer::Error::FieldSizeExceeded {
+					limit: self.max_part_size,
+					field_name: field.name().map(str::to_owned),
+				});
+			}
+			data.extend_from_slice(&chunk);
+		}
+		Ok(data.freeze())
 	}
 }
 
@@ -51,22 +116,83 @@ impl IntoResponse for DicomMultipartRejection {
 
 impl<S> FromRequest<S> for DicomMultipart<'_>
 where
+	AppState: FromRef<S>,
 	S: Send + Sync,
 {
 	type Rejection = DicomMultipartRejection;
 
-	async fn from_request(request: Request, _state: &S) -> Result<Self, Self::Rejection> {
-		let boundary = request
+	async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+		let content_type = request
 			.headers()
 			.get(CONTENT_TYPE)
 			.map(HeaderValue::to_str)
 			.and_then(Result::ok)
-			.map(DicomMultipart::parse_boundary)
-			.and_then(Result::ok)
-			.ok_or(Self::Rejection::InvalidBoundary)?;
+			.unwrap_or_default();
+		let boundary =
+			Self::parse_boundary(content_type).map_err(|_| Self::Rejection::InvalidBoundary)?;
+		let root_type = Self::parse_root_type(content_type);
+
+		let http = &AppState::from_ref(state).config.server.http;
+		Ok(Self::new(
+			request.into_body(),
+			boundary,
+			root_type,
+			http.max_upload_size as u64,
+			http.max_request_size,
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn multipart(
+		parts: &[&str],
+		max_part_size: u64,
+		max_request_size: Option<u64>,
+	) -> DicomMultipart<'static> {
+		let body: String = parts
+			.iter()
+			.map(|part| format!("--boundary\r\nContent-Type: application/dicom\r\n\r\n{part}\r\n"))
+			.chain([String::from("--boundary--\r\n")])
+			.collect();
+		DicomMultipart::new(
+			Body::from(body),
+			String::from("boundary"),
+			None,
+			max_part_size,
+			max_request_size,
+		)
+	}
+
+	#[tokio::test]
+	async fn skips_parts_that_exceed_the_limit() {
+		let mut multipart = multipart(&["0123456789", "0123"], 8, None);
+
+		let field = multipart.next_field().await.unwrap().unwrap();
+		assert!(matches!(
+			multipart.read_field(field).await,
+			Err(multer::Error::FieldSizeExceeded { limit: 8, .. })
+		));
+		let field = multipart.next_field().await.unwrap().unwrap();
+		assert_eq!(multipart.read_field(field).await.unwrap(), "0123");
+		assert!(multipart.next_field().await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn limits_the_whole_request() {
+		let mut multipart = multipart(&["0123", "0123"], 8, Some(64));
 
-		let stream = request.with_limited_body().into_body();
-		let multipart = multer::Multipart::new(stream.into_data_stream(), boundary);
-		Ok(Self(multipart))
+		let result = async {
+			while let Some(field) = multipart.next_field().await? {
+				multipart.read_field(field).await?;
+			}
+			Ok(())
+		};
+		assert!(matches!(
+			result.await,
+			Err(multer::Error::StreamSizeExceeded { limit: 64 })
+		));
 	}
 }
@@ -0,0 +1,299 @@
+//! Serializer for the Native DICOM Model, the XML representation of DICOM data sets.
+//!
+//! <https://dicom.nema.org/medical/dicom/current/output/chtml/part19/chapter_A.html>
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use dicom::core::dictionary::DataDictionaryEntry;
+use dicom::core::header::{HasLength, Header};
+use dicom::core::ops::{AttributeSelector, AttributeSelectorStep};
+use dicom::core::value::Value;
+use dicom::core::{DataDictionary, PrimitiveValue, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{InMemDicomObject, StandardDataDictionary};
+use std::fmt::Write;
+
+const NATIVE_DICOM_MODEL_NAMESPACE: &str = "http://dicom.nema.org/PS3.19/models/NativeDICOM";
+
+/// The components of a person name, in the order defined by PS3.5.
+///
+/// <https://dicom.nema.org/medical/dicom/current/output/chtml/part05/sect_6.2.html#sect_6.2.1.1>
+const PERSON_NAME_COMPONENTS: [&str; 5] = [
+	"FamilyName",
+	"GivenName",
+	"MiddleName",
+	"NamePrefix",
+	"NameSuffix",
+];
+
+/// The component groups of a person name, separated by `=` in the DICOM encoding.
+const PERSON_NAME_GROUPS: [&str; 3] = ["Alphabetic", "Ideographic", "Phonetic"];
+
+/// Returns the URI under which the bulk data of the attribute with the given selector
+/// (including parent sequences and their items) can be retrieved.
+type BulkDataUriResolver<'a> = dyn Fn(&AttributeSelector) -> Option<String> + 'a;
+
+/// Wraps an [`InMemDicomObject`] for serialization to the Native DICOM Model.
+///
+/// Binary attributes (e.g. `OB` or `OW`) are written as `BulkData` references if a URI can be
+/// resolved for them, and as base64-encoded `InlineBinary` otherwise.
+/// Encapsulated pixel data can only be referenced and is written without a value if no URI is available.
+pub struct NativeDicomModel<'a> {
+	object: &'a InMemDicomObject,
+	bulk_data_uri: Option<&'a BulkDataUriResolver<'a>>,
+}
+
+impl<'a> NativeDicomModel<'a> {
+	pub const fn new(object: &'a InMemDicomObject) -> Self {
+		Self {
+			object,
+			bulk_data_uri: None,
+		}
+	}
+
+	/// Sets the resolver for bulk data URIs.
+	pub const fn with_bulk_data_uri(mut self, resolver: &'a BulkDataUriResolver<'a>) -> Self {
+		self.bulk_data_uri = Some(resolver);
+		self
+	}
+
+	/// Serializes the data set to a standalone XML document.
+	pub fn to_xml(&self) -> String {
+		let mut xml = String::new();
+		xml.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+		let _ = write!(
+			xml,
+			r#"<NativeDicomModel xmlns="{NATIVE_DICOM_MODEL_NAMESPACE}">"#
+		);
+		self.write_data_set(&mut xml, self.object, &mut Vec::new());
+		xml.push_str("</NativeDicomModel>");
+		xml
+	}
+
+	fn write_data_set(
+		&self,
+		xml: &mut String,
+		object: &InMemDicomObject,
+		path: &mut Vec<AttributeSelectorStep>,
+	) {
+		for element in object {
+			path.push(AttributeSelectorStep::Tag(element.tag()));
+			self.write_attribute(xml, element, path);
+			path.pop();
+		}
+	}
+
+	fn write_attribute(
+		&self,
+		xml: &mut String,
+		element: &InMemElement,
+		path: &mut Vec<AttributeSelectorStep>,
+	) {
+		let tag = element.tag();
+		let vr = element.vr();
+
+		let _ = write!(
+			xml,
+			r#"<DicomAttribute tag="{:04X}{:04X}" vr="{}""#,
+			tag.group(),
+			tag.element(),
+			vr.to_string()
+		);
+		if let Some(entry) = StandardDataDictionary.by_tag(tag) {
+			let _ = write!(xml, r#" keyword="{}""#, entry.alias());
+		}
+		xml.push('>');
+
+		match element.value() {
+			Value::Sequence(sequence) => {
+				for (index, item) in sequence.items().iter().enumerate() {
+					let _ = write!(xml, r#"<Item number="{}">"#, index + 1);
+					// Attributes of the item are selected through the index of the item
+					if let Some(step) = path.last_mut() {
+						*step = AttributeSelectorStep::Nested {
+							tag,
+							item: u32::try_from(index).unwrap_or(u32::MAX),
+						};
+					}
+					self.write_data_set(xml, item, path);
+					xml.push_str("</Item>");
+				}
+				if let Some(step) = path.last_mut() {
+					*step = AttributeSelectorStep::Tag(tag);
+				}
+			}
+			Value::PixelSequence(_) => {
+				if let Some(uri) = self.resolve_bulk_data_uri(path) {
+					write_bulk_data(xml, &uri);
+				}
+			}
+			Value::Primitive(value) => self.write_primitive(xml, vr, value, path),
+		}
+
+		xml.push_str("</DicomAttribute>");
+	}
+
+	fn write_primitive(
+		&self,
+		xml: &mut String,
+		vr: VR,
+		value: &PrimitiveValue,
+		path: &[AttributeSelectorStep],
+	) {
+		if value.is_empty() {
+			return;
+		}
+
+		match vr {
+			VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN => {
+				if let Some(uri) = self.resolve_bulk_data_uri(path) {
+					write_bulk_data(xml, &uri);
+				} else {
+					xml.push_str("<InlineBinary>");
+					xml.push_str(&BASE64.encode(value.to_bytes()));
+					xml.push_str("</InlineBinary>");
+				}
+			}
+			VR::PN => {
+				for (index, name) in value.to_multi_str().iter().enumerate() {
+					let _ = write!(xml, r#"<PersonName number="{}">"#, index + 1);
+					write_person_name(xml, name);
+					xml.push_str("</PersonName>");
+				}
+			}
+			VR::AT => {
+				if let PrimitiveValue::Tags(tags) = value {
+					for (index, tag) in tags.iter().enumerate() {
+						let _ = write!(
+							xml,
+							r#"<Value number="{}">{:04X}{:04X}</Value>"#,
+							index + 1,
+							tag.group(),
+							tag.element()
+						);
+					}
+				}
+			}
+			_ => {
+				for (index, value) in value.to_multi_str().iter().enumerate() {
+					let _ = write!(xml, r#"<Value number="{}">"#, index + 1);
+					push_escaped(xml, value.trim_end_matches([' ', '\0']));
+					xml.push_str("</Value>");
+				}
+			}
+		}
+	}
+
+	fn resolve_bulk_data_uri(&self, path: &[AttributeSelectorStep]) -> Option<String> {
+		let selector = AttributeSelector::new(path.iter().copied())?;
+		self.bulk_data_uri.and_then(|resolver| resolver(&selector))
+	}
+}
+
+fn write_bulk_data(xml: &mut String, uri: &str) {
+	xml.push_str(r#"<BulkData uri=""#);
+	push_escaped(xml, uri);
+	xml.push_str(r#""/>"#);
+}
+
+/// Writes the component groups and components of a single person name value.
+fn write_person_name(xml: &mut String, name: &str) {
+	for (group, group_value) in PERSON_NAME_GROUPS.iter().zip(name.split('=')) {
+		if group_value.is_empty() {
+			continue;
+		}
+		let _ = write!(xml, "<{group}>");
+		for (component, component_value) in
+			PERSON_NAME_COMPONENTS.iter().zip(group_value.split('^'))
+		{
+			let component_value = component_value.trim_end();
+			if component_value.is_empty() {
+				continue;
+			}
+			let _ = write!(xml, "<{component}>");
+			push_escaped(xml, component_value);
+			let _ = write!(xml, "</{component}>");
+		}
+		let _ = write!(xml, "</{group}>");
+	}
+}
+
+/// Appends the text to the XML document, escaping characters with a special meaning.
+fn push_escaped(xml: &mut String, text: &str) {
+	for c in text.chars() {
+		match c {
+			'&' => xml.push_str("&amp;"),
+			'<' => xml.push_str("&lt;"),
+			'>' => xml.push_str("&gt;"),
+			'"' => xml.push_str("&quot;"),
+			'\'' => xml.push_str("&apos;"),
+			c => xml.push(c),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dicom::core::value::DataSetSequence;
+	use dicom::core::DataElement;
+	use dicom::dictionary_std::tags;
+
+	#[test]
+	fn writes_person_name_components() {
+		let object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::PATIENT_NAME,
+			VR::PN,
+			"Mustermann^Max=Ideo^Graphic",
+		)]);
+		let xml = NativeDicomModel::new(&object).to_xml();
+
+		assert!(xml.contains(
+			r#"<DicomAttribute tag="00100010" vr="PN" keyword="PatientName"><PersonName number="1"><Alphabetic><FamilyName>Mustermann</FamilyName><GivenName>Max</GivenName></Alphabetic><Ideographic><FamilyName>Ideo</FamilyName><GivenName>Graphic</GivenName></Ideographic></PersonName></DicomAttribute>"#
+		));
+	}
+
+	#[test]
+	fn writes_sequence_items() {
+		let item = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::SCHEDULED_STATION_NAME,
+			VR::SH,
+			"CT<1>",
+		)]);
+		let object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::SCHEDULED_PROCEDURE_STEP_SEQUENCE,
+			VR::SQ,
+			DataSetSequence::from(vec![item]),
+		)]);
+		let xml = NativeDicomModel::new(&object).to_xml();
+
+		assert!(xml.contains(
+			r#"<Item number="1"><DicomAttribute tag="00400010" vr="SH" keyword="ScheduledStationName"><Value number="1">CT&lt;1&gt;</Value></DicomAttribute></Item>"#
+		));
+	}
+
+	#[test]
+	fn writes_bulk_data_references() {
+		let object = InMemDicomObject::from_element_iter([DataElement::new(
+			tags::PIXEL_DATA,
+			VR::OB,
+			PrimitiveValue::from(vec![1_u8, 2, 3, 4]),
+		)]);
+
+		let inline = NativeDicomModel::new(&object).to_xml();
+		assert!(inline.contains("<InlineBinary>AQIDBA==</InlineBinary>"));
+
+		let resolver = |selector: &AttributeSelector| {
+			let tag = selector.last_tag();
+			Some(format!(
+				"http://localhost/bulkdata/{:04X}{:04X}",
+				tag.group(),
+				tag.element()
+			))
+		};
+		let referenced = NativeDicomModel::new(&object)
+			.with_bulk_data_uri(&resolver)
+			.to_xml();
+		assert!(referenced.contains(r#"<BulkData uri="http://localhost/bulkdata/7FE00010"/>"#));
+	}
+}
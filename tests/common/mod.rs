@@ -1,3 +1,6 @@
+// Each integration test only uses some of the helpers.
+#![allow(dead_code)]
+
 use anyhow::{bail, Context};
 use dicom_web::DicomWebClient;
 use std::path::PathBuf;
@@ -5,7 +8,7 @@ use std::process::Stdio;
 use std::time::Duration;
 use testcontainers::core::{IntoContainerPort, WaitFor};
 use testcontainers::runners::AsyncRunner;
-use testcontainers::{ContainerAsync, GenericImage};
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
 use tokio::io::{AsyncBufReadExt, BufReader, Lines};
 use tokio::process::{Child, ChildStdout, Command};
 
@@ -19,6 +22,16 @@ pub async fn spawn_orthanc() -> anyhow::Result<ContainerAsync<GenericImage>> {
 		.context("failed to start Orthanc container")
 }
 
+pub async fn spawn_minio() -> anyhow::Result<ContainerAsync<GenericImage>> {
+	GenericImage::new("minio/minio", "latest")
+		.with_exposed_port(9000.tcp())
+		.with_wait_for(WaitFor::message_on_stdout("API:"))
+		.with_cmd(["server", "/data"])
+		.start()
+		.await
+		.context("failed to start MinIO container")
+}
+
 pub async fn spawn_dicomrst(config: &str) -> anyhow::Result<ServerProcess> {
 	let mut server = ServerProcess::spawn(config)?;
 	server.http_port = server.wait_until_started().await?;
@@ -107,10 +120,19 @@ pub async fn with_test_environment(
 		.context("failed to get mapped Orthanc DIMSE port")?;
 
 	let config = config.replace("${ORTHANC_PORT}", &orthanc_port.to_string());
-	let server = spawn_dicomrst(&config).await?;
+	with_server(&config, "ORTHANC", test).await
+}
+
+/// Spawns DICOM-RST and runs the test against the `DICOMweb` endpoints of the given AET.
+pub async fn with_server(
+	config: &str,
+	aet: &str,
+	test: impl AsyncFnOnce(DicomWebClient) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+	let server = spawn_dicomrst(config).await?;
 
 	let client = DicomWebClient::with_single_url(&format!(
-		"http://localhost:{}/aets/ORTHANC",
+		"http://localhost:{}/aets/{aet}",
 		server.http_port
 	));
 	test(client).await?;
@@ -0,0 +1,70 @@
+mod common;
+
+use anyhow::Context;
+use common::*;
+use dicom::dictionary_std::tags;
+use dicom::object::open_file;
+
+#[tokio::test]
+async fn serves_seeded_and_stored_instances() -> anyhow::Result<()> {
+	let seed = std::env::temp_dir().join(format!("dicom-rst-seed-{}", uuid::Uuid::new_v4()));
+	std::fs::create_dir_all(&seed)?;
+	std::fs::copy(
+		dicom_test_files::path("pydicom/CT_small.dcm").unwrap(),
+		seed.join("CT_small.dcm"),
+	)?;
+
+	let config = format!(
+		"
+        server:
+          http:
+            port: 0
+          dimse: []
+        aets:
+          - aet: MEMORY
+            backend: MEMORY
+            seed: {}
+    ",
+		seed.display()
+	);
+	let instance = open_file(dicom_test_files::path("pydicom/MR_small.dcm").unwrap()).unwrap();
+
+	let result = with_server(&config, "MEMORY", async |client| {
+		// The seeded instance is available without storing it first.
+		let studies = client
+			.query_studies()
+			.run()
+			.await
+			.context("QIDO-RS request failed")?;
+		assert_eq!(studies.len(), 1);
+
+		let response = client
+			.store_instances()
+			.with_instances(futures::stream::iter([instance.clone()]))
+			.run()
+			.await
+			.context("STOW-RS request failed")?;
+		let referenced_sop_sequence = response
+			.element(tags::REFERENCED_SOP_SEQUENCE)
+			.context("STOW-RS response is missing ReferencedSOPSequence")?;
+		assert!(
+			referenced_sop_sequence
+				.items()
+				.is_some_and(|items| items.len() == 1),
+			"STOW-RS response contains unexpected number of ReferencedSOPSequence items"
+		);
+
+		let studies = client
+			.query_studies()
+			.run()
+			.await
+			.context("QIDO-RS request failed")?;
+		assert_eq!(studies.len(), 2);
+
+		Ok(())
+	})
+	.await;
+
+	std::fs::remove_dir_all(&seed)?;
+	result
+}
@@ -0,0 +1,170 @@
+#![cfg(feature = "s3")]
+
+mod common;
+
+use anyhow::Context;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use common::*;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::{open_file, DefaultDicomObject};
+use testcontainers::core::IntoContainerPort;
+
+const BUCKET: &str = "dicom";
+
+fn s3_client(endpoint: &str) -> aws_sdk_s3::Client {
+	let config = aws_sdk_s3::config::Builder::new()
+		.endpoint_url(endpoint)
+		.region(Region::new("us-east-1"))
+		.credentials_provider(Credentials::new(
+			"minioadmin",
+			"minioadmin",
+			None,
+			None,
+			"test",
+		))
+		.force_path_style(true)
+		.behavior_version(BehaviorVersion::latest())
+		.build();
+	aws_sdk_s3::Client::from_conf(config)
+}
+
+fn s3_config(endpoint: &str) -> String {
+	format!(
+		"
+        server:
+          http:
+            port: 0
+          dimse: []
+        aets:
+          - aet: S3
+            backend: S3
+            endpoint: {endpoint}
+            bucket: {BUCKET}
+            region: us-east-1
+            concurrency: 4
+            endpoint-style: path
+            part-size: 5242880
+            metadata-sidecars: true
+            index: {{}}
+            credentials:
+              access-key: minioadmin
+              secret-key: minioadmin
+    "
+	)
+}
+
+fn object_key(instance: &DefaultDicomObject) -> anyhow::Result<String> {
+	let uid = |tag| -> anyhow::Result<String> {
+		Ok(instance
+			.element(tag)?
+			.to_str()?
+			.trim_end_matches('\0')
+			.to_owned())
+	};
+	Ok(format!(
+		"{}/{}/{}.dcm",
+		uid(tags::STUDY_INSTANCE_UID)?,
+		uid(tags::SERIES_INSTANCE_UID)?,
+		uid(tags::SOP_INSTANCE_UID)?
+	))
+}
+
+#[tokio::test]
+async fn stores_instances_in_s3() -> anyhow::Result<()> {
+	let minio = spawn_minio().await?;
+	let port = minio
+		.get_host_port_ipv4(9000.tcp())
+		.await
+		.context("failed to get mapped MinIO port")?;
+	let endpoint = format!("http://127.0.0.1:{port}");
+	let s3 = s3_client(&endpoint);
+	s3.create_bucket().bucket(BUCKET).send().await?;
+
+	let instance = open_file(dicom_test_files::path("pydicom/CT_small.dcm").unwrap()).unwrap();
+	// Larger than the part size, so that it is stored using a multipart upload.
+	let mut large_instance =
+		open_file(dicom_test_files::path("pydicom/MR_small.dcm").unwrap()).unwrap();
+	large_instance.put(DataElement::new(
+		tags::PIXEL_DATA,
+		VR::OW,
+		PrimitiveValue::from(vec![0_u8; 6 * 1024 * 1024]),
+	));
+	let mut keys = [object_key(&instance)?, object_key(&large_instance)?];
+	keys.sort();
+
+	with_server(&s3_config(&endpoint), "S3", async |client| {
+		// Storing the same instances again overwrites the existing objects.
+		for _ in 0..2 {
+			let response = client
+				.store_instances()
+				.with_instances(futures::stream::iter([
+					instance.clone(),
+					large_instance.clone(),
+				]))
+				.run()
+				.await
+				.context("STOW-RS request failed")?;
+
+			let referenced_sop_sequence = response
+				.element(tags::REFERENCED_SOP_SEQUENCE)
+				.context("STOW-RS response is missing ReferencedSOPSequence")?;
+			assert!(
+				referenced_sop_sequence
+					.items()
+					.is_some_and(|items| items.len() == 2),
+				"STOW-RS response contains unexpected number of ReferencedSOPSequence items"
+			);
+		}
+
+		// Stored instances are added to the index, so that they can be found with QIDO-RS.
+		let studies = client
+			.query_studies()
+			.run()
+			.await
+			.context("QIDO-RS request failed")?;
+		let mut study_uids: Vec<String> = studies
+			.iter()
+			.filter_map(|study| study.element(tags::STUDY_INSTANCE_UID).ok())
+			.filter_map(|element| element.to_str().ok())
+			.map(|uid| uid.trim_end_matches('\0').to_owned())
+			.collect();
+		study_uids.sort();
+		let mut expected_study_uids: Vec<String> = keys
+			.iter()
+			.filter_map(|key| key.split('/').next())
+			.map(str::to_owned)
+			.collect();
+		expected_study_uids.sort();
+		expected_study_uids.dedup();
+		assert_eq!(study_uids, expected_study_uids);
+
+		let objects = s3.list_objects_v2().bucket(BUCKET).send().await?;
+		let mut stored_keys: Vec<String> = objects
+			.contents()
+			.iter()
+			.filter_map(|object| object.key().map(str::to_owned))
+			.collect();
+		stored_keys.sort();
+		// Each instance has a metadata sidecar next to it.
+		let mut expected_keys: Vec<String> = keys
+			.iter()
+			.flat_map(|key| [key.clone(), key.replace(".dcm", ".json")])
+			.collect();
+		expected_keys.sort();
+		assert_eq!(stored_keys, expected_keys);
+
+		let large_object = s3
+			.head_object()
+			.bucket(BUCKET)
+			.key(object_key(&large_instance)?)
+			.send()
+			.await?;
+		assert!(large_object
+			.content_length()
+			.is_some_and(|length| length > 6 * 1024 * 1024));
+
+		Ok(())
+	})
+	.await
+}